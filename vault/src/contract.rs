@@ -1,23 +1,63 @@
+use std::convert::TryFrom;
 use std::env;
 use std::ops::{Add, Div, Mul, Sub};
 
 use crate::error::ContractError;
-use crate::msg::{ExecuteMsg, GlobalShortDeltaResponse, InstantiateMsg, QueryMsg};
+use crate::events::{
+    AmountChanged, AutoDeleverage, BuyUsdg, ChangePosition, CircuitBreaker, ClaimReferralRebate,
+    CollectFees, CoverBadDebt, DirectPoolDeposit, DistributeFees, LiquidateCrossMargin,
+    LiquidatePosition, PartialLiquidatePosition, PayKeeperReward, PositionKey as EventPositionKey,
+    PositionState, PositionTransferRequest, PriceImpact, RedeemSettlement, ReferralFee, SellUsdg,
+    SettlePosition, SettleSkewFunding, SkipSettlement, Swap, TrackedAmount, TransferPosition,
+    UpdateCrossMargin, UpdateFundingRate, UpdatePnl, UpdateSkewFunding, WithdrawFees,
+    WriteOffPosition,
+};
+use crate::msg::{
+    AdlCandidate, AdlRankingResponse, CircuitBreakerResponse, ClaimableRebate,
+    CrossMarginAccountResponse, CrossMarginPosition, DailyStatsResponse, ExecuteMsg,
+    FeeDistributionConfigResponse, FeeDistributionsResponse, FundingHistoryResponse,
+    FundingRateInfo, FundingRateInfoResponse, GlobalShortDeltaResponse, InstantiateMsg,
+    InsuranceFundResponse, LiquidationPriceResponse, OpenInterestResponse, OperationPauseStatus,
+    PauseMatrixResponse, PendingFee, PendingFeeDistributionResponse, PositionDeltaResponse,
+    PositionLeverageResponse, PositionResponse, PriceImpactResponse, QueryMsg,
+    RedemptionCollateralResponse, RedemptionCollateralUsdResponse, ReferralCodeResponse,
+    ReferralStatsResponse, SettlementStateResponse, SkewFundingResponse, StablePriceResponse,
+    StatsResponse, SwapHop, SwapRouteResponse, TargetUsdgAmountResponse, TokenPauseStatus,
+    TokenRiskConfigResponse, TradeHistoryResponse, TraderReferralResponse, UtilisationResponse,
+};
 use crate::state::{
-    AddressesStruct, Key, Position, StateVariablesStruct, ADDRESSES, APPROVED_ROUTERS1,
-    APPROVED_ROUTERS2, BUFFER_AMOUNTS, COMMULATIVE_FUNDING_RATES, ERRORS, FEE_RESERVES,
-    GLOBAL_SHORT_AVERAGE_PRIZES, GLOBAL_SHORT_SIZES, GOV, GUARANTEED_USD, IS_INITIALIZED,
-    IS_LEVERGE_ENABLED, IS_LIQUIDATOR, IS_MANAGER, IS_SWAP_ENABLED, LAST_FUNDING_TIMES,
-    MAX_GLOBAL_SHORT_SIZES, MAX_USDG_AMOUNTS, MIN_PROFIT_BASIS_POINTS, POOL_AMOUNTS, POSITIONS,
-    RSERVED_AMOUNTS, SHORTABLE_TOKENS, STABLE_TOKENS, STATE_VARIABLES, TOKEN_BALANCES,
-    TOKEN_DECIMALS, TOKEN_WEIGHTS, USDG_AMOUNTS, WHITELISTED_TOKENS,
+    AcceptedPrice, AddressesStruct, AdlConfig, BorrowRateModel, CircuitBreakerConfig,
+    DistributedFee, FeeDistribution, FeeDistributionConfig, FundingRateRecord, InsuranceFundRecord,
+    KeeperReward, Key, Operation, PartialLiquidationConfig, Position, PositionTransfer,
+    PriceImpactConfig, ReferralTier, SettlementPrice, SkewFundingConfig, StablePriceConfig,
+    StateVariablesStruct, TokenRiskConfig, TokenStats, TradeAction, TradeRecord, ACCOUNT_POSITIONS,
+    ADDRESSES, ADL_CONFIGS, APPROVED_ROUTERS1, APPROVED_ROUTERS2, BORROW_RATE_MODELS,
+    BUFFER_AMOUNTS, CIRCUIT_BREAKER_CONFIGS, COLLATERAL_POSITIONS, COMMULATIVE_FUNDING_RATES,
+    CROSS_MARGIN_BALANCES, DAILY_TOKEN_STATS, ERRORS, FEE_DISTRIBUTIONS, FEE_DISTRIBUTION_CONFIG,
+    FEE_DISTRIBUTION_EPOCH, FEE_RESERVES, FUNDING_HISTORY, FUNDING_HISTORY_RETENTION,
+    FUNDING_KEEPER_REWARD_USD, GLOBAL_LONG_AVERAGE_PRICES, GLOBAL_LONG_SIZES,
+    GLOBAL_SHORT_AVERAGE_PRIZES, GLOBAL_SHORT_SIZES, GOV, GUARANTEED_USD, INSURANCE_FUNDS,
+    INSURANCE_FUND_BASIS_POINTS, INSURANCE_FUND_RECORDS, INSURANCE_FUND_RECORD_COUNTS, IS_GUARDIAN,
+    IS_INITIALIZED, IS_LEVERGE_ENABLED, IS_LIQUIDATOR, IS_MANAGER, IS_SETTLEMENT_MODE,
+    IS_SWAP_ENABLED, LAST_ACCEPTED_PRICES, LAST_FEE_DISTRIBUTION_TIME, LAST_FUNDING_TIMES,
+    MAX_GLOBAL_LONG_SIZES, MAX_GLOBAL_SHORT_SIZES, MAX_USDG_AMOUNTS, MIN_PROFIT_BASIS_POINTS,
+    PARTIAL_LIQUIDATION_CONFIG, PAUSED_OPERATIONS, PAUSED_TOKENS, PENDING_KEEPER_REWARDS,
+    PENDING_POSITION_TRANSFERS, PENDING_SETTLEMENTS, POOL_AMOUNTS, POSITIONS, POSITION_TRADES,
+    PRICE_IMPACT_CONFIGS, PRICE_IMPACT_POOLS, REFERRAL_CODES, REFERRAL_TIERS, REFERRER_REBATES,
+    REFERRER_STATS, REFERRER_TIERS, RSERVED_AMOUNTS, SETTLEMENT_FAILURES, SETTLEMENT_PRICES,
+    SETTLEMENT_USDG_SUPPLY, SHORTABLE_TOKENS, SKEW_FUNDING_CONFIGS, SKEW_FUNDING_STATES,
+    STABLE_PRICE_CONFIGS, STABLE_TOKENS, STATE_VARIABLES, TOKEN_BALANCES, TOKEN_DECIMALS,
+    TOKEN_RISK_CONFIGS, TOKEN_STATS, TOKEN_WEIGHTS, TRADER_REFERRAL_CODES, TRADE_HISTORY,
+    TRADE_HISTORY_COUNTS, TRIPPED_CIRCUIT_BREAKERS, USDG_AMOUNTS, WHITELISTED_TOKENS,
 };
 use cosmwasm_std::{
-    entry_point, from_binary, to_binary, Binary, CosmosMsg, Deps, QuerierWrapper, StdError,
-    StdResult, Storage, WasmQuery,
+    entry_point, from_binary, to_binary, to_json_binary, Binary, CosmosMsg, Deps, Empty, Event,
+    Order, QuerierWrapper, Reply, StdError, StdResult, Storage, SubMsg, SubMsgResult, Uint128,
+    Uint256, WasmMsg, WasmQuery,
 };
 use cosmwasm_std::{Addr, DepsMut, Env, MessageInfo, Response};
 use cw2::set_contract_version;
+use cw_storage_plus::Bound;
 
 // version info for migration info
 const CONTRACT_NAME: &str = "vault.io:ft";
@@ -26,6 +66,9 @@ const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 const BASIS_POINTS_DIVISOR: u128 = 10000;
 const FUNDING_RATE_PRECISION: u128 = 1000000;
 const PRICE_PRECISION: u128 = 1000000000000000000000000;
+// 1 USD as quoted by getOraclePrice, it has to follow PRICE_PRECISION once a real price feed
+// replaces the hardcoded oracle price
+const ORACLE_ONE_USD: u128 = 1;
 const MIN_LEVERAGE: u128 = 10000; // 1x
 const USDG_DECIMALS: u128 = 18;
 const MAX_FEE_BASIS_POINTS: u128 = 500; // 5%
@@ -33,6 +76,17 @@ const MAX_LIQUIDATION_FEE_USD: u128 = 10000000000000000000000000000000; // 100 U
 const MIN_FUNDING_RATE_INTERVAL: u64 = 3600; //1 hour
 const MAX_FUNDING_RATE_FACTOR: u128 = 10000; // 1%
 
+// funding history is kept for 30 days unless governance sets another retention
+const DEFAULT_FUNDING_HISTORY_RETENTION: u64 = 30 * SECONDS_PER_DAY;
+// bounds the records removed by a single funding update
+const MAX_FUNDING_HISTORY_PRUNE: usize = 10;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const SECONDS_PER_YEAR: u128 = 365 * 24 * 60 * 60;
+const PRICE_IMPACT_PRECISION: u128 = 1000000000000000000;
+const MAX_PRICE_IMPACT_EXPONENT: u32 = 3;
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
 // ********** Instantiate **********
 
 #[cfg_attr(not(feature = "library"), entry_point)]
@@ -48,6 +102,7 @@ pub fn instantiate(
     IS_INITIALIZED.save(deps.storage, &false)?;
     IS_SWAP_ENABLED.save(deps.storage, &true)?;
     IS_LEVERGE_ENABLED.save(deps.storage, &true)?;
+    IS_SETTLEMENT_MODE.save(deps.storage, &false)?;
 
     Ok(Response::new().add_attribute("action", "instantiate"))
 }
@@ -104,6 +159,17 @@ pub fn execute(
         ExecuteMsg::SetIsLevergaeEnabaled {
             _is_leverage_enabled,
         } => try_set_is_leverage_enabled(deps, info, env, _is_leverage_enabled),
+        ExecuteMsg::SetGuardian {
+            guardian,
+            is_guardian,
+        } => try_set_guardian(deps, info, env, guardian, is_guardian),
+        ExecuteMsg::SetTokenPaused { token, is_paused } => {
+            try_set_token_paused(deps, info, env, token, is_paused)
+        }
+        ExecuteMsg::SetOperationPaused {
+            operation,
+            is_paused,
+        } => try_set_operation_paused(deps, info, env, operation, is_paused),
         ExecuteMsg::SetMaxGasPrice { max_gas_price } => {
             try_set_max_gas_price(deps, info, env, max_gas_price)
         }
@@ -118,6 +184,9 @@ pub fn execute(
         ExecuteMsg::SetMaxGlobalShortSize { token, amount } => {
             try_set_max_global_short_size(deps, info, env, token, amount)
         }
+        ExecuteMsg::SetMaxGlobalLongSize { token, amount } => {
+            try_set_max_global_long_size(deps, info, token, amount)
+        }
         ExecuteMsg::SetFees {
             tax_basis_points,
             stable_tax_basis_points,
@@ -190,13 +259,111 @@ pub fn execute(
             amount,
         } => try_upgrade_vault(deps, info, env, new_vault, token, amount),
         ExecuteMsg::DirectPoolDeposit { token } => try_direct_pool_deposit(deps, info, env, token),
-        ExecuteMsg::BuyUsdg { token, reciever } => try_buy_usdg(deps, info, env, token, reciever),
-        ExecuteMsg::SellUsdg { token, reciever } => try_sell_usdg(deps, info, env, token, reciever),
+        ExecuteMsg::BuyUsdg {
+            token,
+            reciever,
+            account,
+        } => try_buy_usdg(deps, info, env, token, reciever, account),
+        ExecuteMsg::SellUsdg {
+            token,
+            reciever,
+            account,
+        } => try_sell_usdg(deps, info, env, token, reciever, account),
         ExecuteMsg::Swap {
             token_in,
             token_out,
             reciever,
-        } => try_swap(deps, info, env, token_in, token_out, reciever),
+            account,
+        } => try_swap(deps, info, env, token_in, token_out, reciever, account),
+        ExecuteMsg::SwapWithRoute {
+            path,
+            min_out,
+            deadline,
+            reciever,
+            account,
+        } => try_swap_with_route(
+            deps,
+            info,
+            env,
+            SwapRoute {
+                path,
+                min_out,
+                deadline,
+                reciever,
+            },
+            account,
+        ),
+        ExecuteMsg::SetFeeDistributionConfig { config } => {
+            try_set_fee_distribution_config(deps, info, config)
+        }
+        ExecuteMsg::DistributeFees {} => try_distribute_fees(deps, info, env),
+        ExecuteMsg::RegisterReferralCode { code } => try_register_referral_code(deps, info, code),
+        ExecuteMsg::SetTraderReferralCode { code } => {
+            try_set_trader_referral_code(deps, info, code)
+        }
+        ExecuteMsg::SetReferralTier {
+            tier,
+            total_rebate_basis_points,
+            discount_share_basis_points,
+        } => try_set_referral_tier(
+            deps,
+            info,
+            tier,
+            total_rebate_basis_points,
+            discount_share_basis_points,
+        ),
+        ExecuteMsg::SetReferrerTier { referrer, tier } => {
+            try_set_referrer_tier(deps, info, referrer, tier)
+        }
+        ExecuteMsg::ClaimReferralRebates { tokens, reciever } => {
+            try_claim_referral_rebates(deps, info, tokens, reciever)
+        }
+        ExecuteMsg::SetTokenRiskConfig { token, config } => {
+            try_set_token_risk_config(deps, info, token, config)
+        }
+        ExecuteMsg::SetBorrowRateModel { token, model } => {
+            try_set_borrow_rate_model(deps, info, env, token, model)
+        }
+        ExecuteMsg::SetSkewFundingConfig { token, config } => {
+            try_set_skew_funding_config(deps, info, env, token, config)
+        }
+        ExecuteMsg::SetPriceImpactConfig { token, config } => {
+            try_set_price_impact_config(deps, info, token, config)
+        }
+        ExecuteMsg::SetAdlConfig {
+            collateral_token,
+            config,
+        } => try_set_adl_config(deps, info, collateral_token, config),
+        ExecuteMsg::AutoDeleverage {
+            collateral_token,
+            start_after,
+            limit,
+        } => try_auto_deleverage(deps, info, env, collateral_token, start_after, limit),
+        ExecuteMsg::SetFundingKeeperReward { reward_usd } => {
+            try_set_funding_keeper_reward(deps, info, reward_usd)
+        }
+        ExecuteMsg::UpdateFundingRates { tokens } => {
+            try_update_funding_rates(deps, info, env, tokens)
+        }
+        ExecuteMsg::SetFundingHistoryRetention { retention } => {
+            try_set_funding_history_retention(deps, info, retention)
+        }
+        ExecuteMsg::SetInsuranceFundBasisPoints { basis_points } => {
+            try_set_insurance_fund_basis_points(deps, info, basis_points)
+        }
+        ExecuteMsg::SetPartialLiquidationConfig { config } => {
+            try_set_partial_liquidation_config(deps, info, config)
+        }
+        ExecuteMsg::SetStablePriceConfig { token, config } => {
+            try_set_stable_price_config(deps, info, token, config)
+        }
+        ExecuteMsg::SetCircuitBreakerConfig { token, config } => {
+            try_set_circuit_breaker_config(deps, info, token, config)
+        }
+        ExecuteMsg::TripCircuitBreaker { token } => try_trip_circuit_breaker(deps, env, token),
+        ExecuteMsg::ResetCircuitBreaker { token } => {
+            try_reset_circuit_breaker(deps, info, env, token)
+        }
         ExecuteMsg::IncreasePosition {
             account,
             collateral_token,
@@ -234,6 +401,77 @@ pub fn execute(
             reciever,
         ),
 
+        ExecuteMsg::DepositCollateral {
+            account,
+            collateral_token,
+            index_token,
+            is_long,
+        } => try_deposit_collateral(
+            deps,
+            info,
+            env,
+            account,
+            collateral_token,
+            index_token,
+            is_long,
+        ),
+        ExecuteMsg::WithdrawCollateral {
+            account,
+            collateral_token,
+            index_token,
+            collateral_delta,
+            is_long,
+            reciever,
+        } => try_withdraw_collateral(
+            deps,
+            info,
+            env,
+            get_position_key(account, collateral_token, index_token, is_long)?,
+            collateral_delta,
+            reciever,
+        ),
+        ExecuteMsg::DepositCrossMargin { collateral_token } => {
+            try_deposit_cross_margin(deps, info, env, collateral_token)
+        }
+        ExecuteMsg::WithdrawCrossMargin {
+            collateral_token,
+            amount,
+            reciever,
+        } => try_withdraw_cross_margin(deps, info, env, collateral_token, amount, reciever),
+        ExecuteMsg::LiquidateCrossMargin {
+            account,
+            collateral_token,
+            fee_reciever,
+        } => try_liquidate_cross_margin(deps, info, env, account, collateral_token, fee_reciever),
+        ExecuteMsg::TransferPosition {
+            collateral_token,
+            index_token,
+            is_long,
+            to,
+            merge,
+        } => try_transfer_position(
+            deps,
+            get_position_key(info.sender, collateral_token, index_token, is_long)?,
+            to,
+            merge,
+        ),
+        ExecuteMsg::CancelPositionTransfer {
+            collateral_token,
+            index_token,
+            is_long,
+        } => try_cancel_position_transfer(
+            deps,
+            get_position_key(info.sender, collateral_token, index_token, is_long)?,
+        ),
+        ExecuteMsg::AcceptPositionTransfer {
+            account,
+            collateral_token,
+            index_token,
+            is_long,
+        } => {
+            let key = get_position_key(account, collateral_token, index_token, is_long)?;
+            try_accept_position_transfer(deps, info, env, key)
+        }
         ExecuteMsg::LiquidatePosition {
             account,
             collateral_token,
@@ -250,6 +488,42 @@ pub fn execute(
             is_long,
             fee_reciever,
         ),
+        ExecuteMsg::EnableSettlementMode { prices } => {
+            try_enable_settlement_mode(deps, info, env, prices)
+        }
+        ExecuteMsg::SettlePositions { limit } => try_settle_positions(deps, info, env, limit),
+        ExecuteMsg::SettlePosition { key } => try_settle_position(deps, info, env, key),
+        ExecuteMsg::RedeemSettlement { reciever } => {
+            try_redeem_settlement(deps, info, env, reciever)
+        }
+        ExecuteMsg::WriteOffPosition { key } => try_write_off_position(deps, info, env, key),
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, _env: Env, msg: Reply) -> Result<Response, ContractError> {
+    let key = PENDING_SETTLEMENTS.load(deps.storage, msg.id)?;
+    PENDING_SETTLEMENTS.remove(deps.storage, msg.id);
+
+    match msg.result {
+        SubMsgResult::Ok(_) => Ok(Response::new()),
+        SubMsgResult::Err(error) => {
+            // the failed settlement was reverted on its own, keep the position for a retry
+            SETTLEMENT_FAILURES.save(deps.storage, &key, &error)?;
+            let position_key = decode_position_key(&key)?;
+            Ok(Response::new().add_event(
+                SkipSettlement {
+                    key: event_position_key(
+                        &position_key.key_1,
+                        &position_key.key_2,
+                        &position_key.key_3,
+                        position_key.key_4,
+                    ),
+                    error,
+                }
+                .into(),
+            ))
+        }
     }
 }
 
@@ -265,7 +539,40 @@ pub fn query(deps: DepsMut, env: Env, msg: QueryMsg) -> StdResult<Binary> {
             collateral_token,
             index_token,
             is_long,
-        } => try_get_position(deps, account, collateral_token, index_token, is_long),
+        } => try_get_position(deps, env, account, collateral_token, index_token, is_long),
+        QueryMsg::GetTradeHistory {
+            account,
+            start_after,
+            limit,
+        } => try_get_trade_history(deps, account, start_after, limit),
+        QueryMsg::GetPositionTradeHistory {
+            account,
+            collateral_token,
+            index_token,
+            is_long,
+            start_after,
+            limit,
+        } => try_get_position_trade_history(
+            deps,
+            account,
+            collateral_token,
+            index_token,
+            is_long,
+            start_after,
+            limit,
+        ),
+        QueryMsg::GetStats { token } => try_get_stats(deps, token),
+        QueryMsg::GetDailyStats {
+            token,
+            start_after,
+            limit,
+        } => try_get_daily_stats(deps, token, start_after, limit),
+        QueryMsg::GetFundingRateInfo { tokens } => try_get_funding_rate_info(deps, env, tokens),
+        QueryMsg::GetFundingHistory {
+            token,
+            start_after,
+            limit,
+        } => try_get_funding_history(deps, token, start_after, limit),
         QueryMsg::GetUtilisation { token } => try_get_utilisation(deps, token),
         QueryMsg::GetPositionLeverage {
             account,
@@ -273,6 +580,16 @@ pub fn query(deps: DepsMut, env: Env, msg: QueryMsg) -> StdResult<Binary> {
             index_token,
             is_long,
         } => try_get_position_leverage(deps, account, collateral_token, index_token, is_long),
+        QueryMsg::GetCrossMarginAccount {
+            account,
+            collateral_token,
+        } => try_get_cross_margin_account(deps, env, account, collateral_token),
+        QueryMsg::GetLiquidationPrice {
+            account,
+            collateral_token,
+            index_token,
+            is_long,
+        } => try_get_liquidation_price(deps, env, account, collateral_token, index_token, is_long),
         QueryMsg::GetGlobalShortDelta { token } => try_global_short_delta(deps, token),
         QueryMsg::GetPositionDelta {
             account,
@@ -281,6 +598,46 @@ pub fn query(deps: DepsMut, env: Env, msg: QueryMsg) -> StdResult<Binary> {
             is_long,
         } => try_get_position_delta(deps, env, account, collateral_token, index_token, is_long),
         QueryMsg::GetTargetUsdgAmount { token } => try_get_target_usdg_amount(deps, env, token),
+        QueryMsg::GetPauseMatrix {} => try_get_pause_matrix(deps),
+        QueryMsg::GetSettlementState {} => try_get_settlement_state(deps),
+        QueryMsg::GetSwapRoute { path, amount_in } => try_get_swap_route(deps, path, amount_in),
+        QueryMsg::GetFeeDistributionConfig {} => try_get_fee_distribution_config(deps),
+        QueryMsg::GetPendingFeeDistribution {} => try_get_pending_fee_distribution(deps),
+        QueryMsg::GetFeeDistributions { start_after, limit } => {
+            try_get_fee_distributions(deps, start_after, limit)
+        }
+        QueryMsg::GetReferralCode { code } => try_get_referral_code(deps, code),
+        QueryMsg::GetTraderReferral { account } => try_get_trader_referral(deps, account),
+        QueryMsg::GetReferralStats { referrer } => try_get_referral_stats(deps, referrer),
+        QueryMsg::GetTokenRiskConfig { token } => try_get_token_risk_config(deps, token),
+        QueryMsg::GetOpenInterest { token } => try_get_open_interest(deps, token),
+        QueryMsg::GetSkewFunding { token } => try_get_skew_funding(deps, token),
+        QueryMsg::GetPositionPriceImpact {
+            collateral_token,
+            index_token,
+            is_long,
+            size_delta,
+            is_increase,
+        } => try_get_position_price_impact(
+            deps,
+            collateral_token,
+            index_token,
+            is_long,
+            size_delta,
+            is_increase,
+        ),
+        QueryMsg::GetAdlRanking {
+            collateral_token,
+            start_after,
+            limit,
+        } => try_get_adl_ranking(deps, env, collateral_token, start_after, limit),
+        QueryMsg::GetStablePrice { token } => try_get_stable_price(deps, token),
+        QueryMsg::GetCircuitBreaker { token } => try_get_circuit_breaker(deps, env, token),
+        QueryMsg::GetInsuranceFund {
+            token,
+            start_after,
+            limit,
+        } => try_get_insurance_fund(deps, token, start_after, limit),
     }
 }
 
@@ -467,6 +824,67 @@ fn try_set_is_leverage_enabled(
     Ok(Response::new().add_attribute("method", "set_is_leverage_enabled"))
 }
 
+fn try_set_guardian(
+    deps: DepsMut,
+    info: MessageInfo,
+    _env: Env,
+    guardian: Addr,
+    is_guardian: bool,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+
+    IS_GUARDIAN.save(deps.storage, guardian.clone(), &is_guardian)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "set_guardian")
+        .add_attribute("guardian", guardian.to_string())
+        .add_attribute("is_guardian", is_guardian.to_string()))
+}
+
+// guardians may pause a token, only gov may unpause it
+fn try_set_token_paused(
+    deps: DepsMut,
+    info: MessageInfo,
+    _env: Env,
+    token: Addr,
+    is_paused: bool,
+) -> Result<Response, ContractError> {
+    if is_paused {
+        only_guardian_or_gov(deps.storage, info.sender)?;
+    } else {
+        only_gov(deps.storage, info.sender);
+    }
+
+    PAUSED_TOKENS.save(deps.storage, token.clone(), &is_paused)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "set_token_paused")
+        .add_attribute("token", token.to_string())
+        .add_attribute("is_paused", is_paused.to_string()))
+}
+
+// guardians may pause an operation, only gov may unpause it
+fn try_set_operation_paused(
+    deps: DepsMut,
+    info: MessageInfo,
+    _env: Env,
+    operation: Operation,
+    is_paused: bool,
+) -> Result<Response, ContractError> {
+    if is_paused {
+        only_guardian_or_gov(deps.storage, info.sender)?;
+    } else {
+        only_gov(deps.storage, info.sender);
+    }
+
+    PAUSED_OPERATIONS.save(deps.storage, operation.as_str(), &is_paused)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "set_operation_paused")
+        .add_attribute("operation", operation.as_str())
+        .add_attribute("is_paused", is_paused.to_string()))
+}
+
 fn try_set_max_gas_price(
     deps: DepsMut,
     info: MessageInfo,
@@ -565,6 +983,18 @@ fn try_set_max_global_short_size(
     Ok(Response::new().add_attribute("method", "set_max_global_short_size"))
 }
 
+fn try_set_max_global_long_size(
+    deps: DepsMut,
+    info: MessageInfo,
+    _token: Addr,
+    _amount: u128,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+
+    MAX_GLOBAL_LONG_SIZES.save(deps.storage, _token, &_amount)?;
+
+    Ok(Response::new().add_attribute("method", "set_max_global_long_size"))
+}
 fn try_set_fees(
     deps: DepsMut,
     info: MessageInfo,
@@ -627,6 +1057,104 @@ fn try_set_funding_rate(
     Ok(Response::new().add_attribute("method", "set_funding_rate"))
 }
 
+fn try_set_borrow_rate_model(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    token: Addr,
+    model: Option<BorrowRateModel>,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+
+    if let Some(model) = &model {
+        if let BorrowRateModel::Kinked { kink, .. } = model {
+            _validate(*kink > 0 && *kink <= FUNDING_RATE_PRECISION, 86)?;
+        }
+        // the rate at full utilisation is the highest rate a model can charge
+        _validate(
+            get_borrow_rate(model, FUNDING_RATE_PRECISION) <= MAX_FUNDING_RATE_FACTOR,
+            86,
+        )?;
+    }
+
+    // accrue the rate of the previous model before switching
+    let mut events = vec![];
+    events.extend(update_cumulative_funding_rate(
+        deps.storage,
+        env,
+        token.clone(),
+        token.clone(),
+    )?);
+
+    match model {
+        Some(model) => BORROW_RATE_MODELS.save(deps.storage, token.clone(), &model)?,
+        None => BORROW_RATE_MODELS.remove(deps.storage, token.clone()),
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "set_borrow_rate_model")
+        .add_attribute("token", token)
+        .add_events(events))
+}
+
+fn try_set_skew_funding_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    token: Addr,
+    config: Option<SkewFundingConfig>,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+
+    // accrue funding with the previous config before switching
+    let mut events = vec![];
+    events.extend(update_skew_funding(deps.storage, env, token.clone())?);
+
+    match config {
+        Some(config) => {
+            _validate(config.funding_factor <= MAX_FUNDING_RATE_FACTOR, 87)?;
+            if !SKEW_FUNDING_CONFIGS.has(deps.storage, token.clone()) {
+                // restart the funding clock so the disabled period is not charged
+                let mut state = SKEW_FUNDING_STATES
+                    .may_load(deps.storage, token.clone())?
+                    .unwrap_or_default();
+                state.last_funding_time = 0;
+                SKEW_FUNDING_STATES.save(deps.storage, token.clone(), &state)?;
+            }
+            SKEW_FUNDING_CONFIGS.save(deps.storage, token.clone(), &config)?;
+        }
+        None => SKEW_FUNDING_CONFIGS.remove(deps.storage, token.clone()),
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "set_skew_funding_config")
+        .add_attribute("token", token)
+        .add_events(events))
+}
+
+fn try_set_price_impact_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: Addr,
+    config: Option<PriceImpactConfig>,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+
+    match config {
+        Some(config) => {
+            _validate(
+                config.exponent > 0 && config.exponent <= MAX_PRICE_IMPACT_EXPONENT,
+                88,
+            )?;
+            PRICE_IMPACT_CONFIGS.save(deps.storage, token.clone(), &config)?;
+        }
+        None => PRICE_IMPACT_CONFIGS.remove(deps.storage, token.clone()),
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "set_price_impact_config")
+        .add_attribute("token", token))
+}
 fn try_set_token_config(
     deps: DepsMut,
     info: MessageInfo,
@@ -679,11 +1207,38 @@ fn try_set_token_config(
     // Validate price feed
     // using hardcoded value as in cosmwasm there was no IvaultPricefeed interface avialable
 
-    get_max_price(_token);
+    get_max_price(deps.storage, _token);
 
     Ok(Response::new().add_attribute("method", "set_token_config"))
 }
 
+fn try_set_token_risk_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: Addr,
+    config: TokenRiskConfig,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+
+    let is_whitelisted = WHITELISTED_TOKENS
+        .may_load(deps.storage, token.clone())?
+        .unwrap_or_default();
+    _validate(is_whitelisted, 80)?;
+    _validate(
+        config.max_leverage == 0 || config.max_leverage > MIN_LEVERAGE,
+        80,
+    )?;
+    _validate(
+        config.max_position_size_usd == 0
+            || config.max_position_size_usd >= config.min_position_size_usd,
+        80,
+    )?;
+
+    TOKEN_RISK_CONFIGS.save(deps.storage, token.clone(), &config)?;
+    Ok(Response::new()
+        .add_attribute("method", "set_token_risk_config")
+        .add_attribute("token", token))
+}
 fn try_clear_token_config(
     deps: DepsMut,
     info: MessageInfo,
@@ -705,6 +1260,7 @@ fn try_clear_token_config(
     MIN_PROFIT_BASIS_POINTS.remove(deps.storage, _token.clone());
     MAX_USDG_AMOUNTS.remove(deps.storage, _token.clone());
     STABLE_TOKENS.remove(deps.storage, _token.clone());
+    STABLE_PRICE_CONFIGS.remove(deps.storage, _token.clone());
     SHORTABLE_TOKENS.remove(deps.storage, _token.clone());
 
     STATE_VARIABLES.save(deps.storage, &state_variables)?;
@@ -720,6 +1276,8 @@ fn try_withdraw_fees(
     _reciever: Addr,
 ) -> Result<Response, ContractError> {
     only_gov(deps.storage, info.sender.clone());
+    // once fees are split by distributeFees they can not be withdrawn around the split
+    _validate(!FEE_DISTRIBUTION_CONFIG.exists(deps.storage), 113)?;
 
     let mut fee_reserves = FEE_RESERVES.load(deps.storage, _token.clone())?;
 
@@ -732,47 +1290,377 @@ fn try_withdraw_fees(
     }
     fee_reserves = 0;
 
-    _transfer_out(info, _token.clone(), amount, _reciever)?;
-    FEE_RESERVES.save(deps.storage, _token, &fee_reserves)?;
+    let transfer = _transfer_out(
+        deps.storage,
+        info,
+        _token.clone(),
+        amount,
+        _reciever.clone(),
+    )?;
+    FEE_RESERVES.save(deps.storage, _token.clone(), &fee_reserves)?;
 
     Ok(Response::new()
         .add_attribute("method", "withdraw_fees")
-        .add_attribute("amount", amount.to_string()))
+        .add_attribute("amount", amount.to_string())
+        .add_submessages(transfer.messages)
+        .add_event(
+            WithdrawFees {
+                token: _token,
+                reciever: _reciever,
+                amount,
+            }
+            .into(),
+        ))
 }
 
-fn try_add_routers(
+fn try_set_fee_distribution_config(
     deps: DepsMut,
     info: MessageInfo,
-    _env: Env,
-    _router: Addr,
+    config: Option<FeeDistributionConfig>,
 ) -> Result<Response, ContractError> {
-    let mut approved_routers1 = APPROVED_ROUTERS1.load(deps.storage, _router.clone())?;
-    let mut approved_routers2 = APPROVED_ROUTERS2.load(deps.storage, _router)?;
-    approved_routers1 = true;
-    approved_routers2 = approved_routers1;
+    only_gov(deps.storage, info.sender);
 
-    APPROVED_ROUTERS1.save(deps.storage, info.sender.clone(), &approved_routers1)?;
-    APPROVED_ROUTERS2.save(deps.storage, info.sender, &approved_routers2)?;
+    let config = match config {
+        Some(config) => config,
+        None => {
+            FEE_DISTRIBUTION_CONFIG.remove(deps.storage);
+            return Ok(Response::new().add_attribute("method", "set_fee_distribution_config"));
+        }
+    };
+    let total_basis_points = config
+        .lp_basis_points
+        .add(config.staking_basis_points)
+        .add(config.treasury_basis_points);
+    _validate(total_basis_points == BASIS_POINTS_DIVISOR, 70)?;
+    if let Some(reward_token) = config.reward_token.clone() {
+        let is_whitelisted = WHITELISTED_TOKENS
+            .may_load(deps.storage, reward_token)?
+            .unwrap_or_default();
+        _validate(is_whitelisted, 72)?;
+    }
 
-    Ok(Response::new().add_attribute("method", "add_routers"))
+    FEE_DISTRIBUTION_CONFIG.save(deps.storage, &config)?;
+    Ok(Response::new().add_attribute("method", "set_fee_distribution_config"))
 }
 
-fn try_remove_routers(
+fn try_distribute_fees(
     deps: DepsMut,
     info: MessageInfo,
-    _env: Env,
-    _router: Addr,
+    env: Env,
 ) -> Result<Response, ContractError> {
-    let mut approved_routers1 = APPROVED_ROUTERS1.load(deps.storage, _router.clone())?;
-    let mut approved_routers2 = APPROVED_ROUTERS2.load(deps.storage, _router)?;
-    approved_routers1 = false;
-    approved_routers2 = approved_routers1;
+    // fees are converted at the current prices so only gov and the keepers choose when
+    let gov = GOV.load(deps.storage)?;
+    let is_liquidator = IS_LIQUIDATOR
+        .may_load(deps.storage, info.sender.clone())?
+        .unwrap_or_default();
+    _validate(info.sender == gov || is_liquidator, 112)?;
 
-    APPROVED_ROUTERS1.save(deps.storage, info.sender.clone(), &approved_routers1)?;
-    APPROVED_ROUTERS2.save(deps.storage, info.sender, &approved_routers2)?;
+    let config = FEE_DISTRIBUTION_CONFIG.may_load(deps.storage)?;
+    _validate(config.is_some(), 73)?;
+    let config = config.unwrap();
 
-    Ok(Response::new().add_attribute("method", "remove_routers"))
-}
+    let now = env.block.time.seconds();
+    let last_distribution_time = LAST_FEE_DISTRIBUTION_TIME
+        .may_load(deps.storage)?
+        .unwrap_or_default();
+    _validate(now >= last_distribution_time.add(config.epoch_duration), 71)?;
+
+    let epoch = FEE_DISTRIBUTION_EPOCH
+        .may_load(deps.storage)?
+        .unwrap_or_default();
+    let state_variables = STATE_VARIABLES.load(deps.storage)?;
+
+    // empty the fee reserves, converting them into the reward token when one is configured
+    let mut events = vec![];
+    let mut amounts = vec![];
+    let mut reward_amount = 0;
+    for token in state_variables.all_whitelisted_tokens {
+        let fee_reserves = FEE_RESERVES
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        if fee_reserves == 0 {
+            continue;
+        }
+
+        match config.reward_token.clone() {
+            Some(reward_token) if reward_token != token => {
+                // fees that can not be converted right now stay in feeReserves for the next epoch
+                let can_convert = can_convert_fees(
+                    deps.storage,
+                    env.clone(),
+                    &token,
+                    &reward_token,
+                    fee_reserves,
+                )
+                .unwrap_or(false);
+                if !can_convert {
+                    continue;
+                }
+                FEE_RESERVES.save(deps.storage, token.clone(), &0)?;
+                let (_, amount_out_after_fees, _, swap_events) = _swap(
+                    deps.storage,
+                    deps.querier,
+                    env.clone(),
+                    SwapOrder {
+                        token_in: token,
+                        token_out: reward_token,
+                        amount_in: fee_reserves,
+                        reciever: env.contract.address.clone(),
+                    },
+                    None,
+                )?;
+                events.extend(swap_events);
+                reward_amount = reward_amount.add(amount_out_after_fees);
+            }
+            Some(_) => {
+                FEE_RESERVES.save(deps.storage, token.clone(), &0)?;
+                reward_amount = reward_amount.add(fee_reserves);
+            }
+            None => {
+                FEE_RESERVES.save(deps.storage, token.clone(), &0)?;
+                amounts.push((token, fee_reserves));
+            }
+        }
+    }
+    if let Some(reward_token) = config.reward_token.clone() {
+        if reward_amount > 0 {
+            amounts.push((reward_token, reward_amount));
+        }
+    }
+
+    let mut response = Response::new();
+    let mut fees = vec![];
+    for (token, amount) in amounts {
+        let lp_amount = amount.mul(config.lp_basis_points).div(BASIS_POINTS_DIVISOR);
+        let staking_amount = amount
+            .mul(config.staking_basis_points)
+            .div(BASIS_POINTS_DIVISOR);
+        // rounding dust goes to the treasury
+        let treasury_amount = amount.sub(lp_amount).sub(staking_amount);
+
+        for (reciever, share) in [
+            (config.lp_reciever.clone(), lp_amount),
+            (config.staking_reciever.clone(), staking_amount),
+            (config.treasury.clone(), treasury_amount),
+        ] {
+            if share > 0 {
+                let transfer =
+                    _transfer_out(deps.storage, info.clone(), token.clone(), share, reciever)?;
+                response = response.add_submessages(transfer.messages);
+            }
+        }
+
+        events.push(
+            DistributeFees {
+                epoch,
+                token: token.clone(),
+                lp_amount,
+                staking_amount,
+                treasury_amount,
+            }
+            .into(),
+        );
+        fees.push(DistributedFee {
+            token,
+            lp_amount,
+            staking_amount,
+            treasury_amount,
+        });
+    }
+
+    let keeper_rewards = PENDING_KEEPER_REWARDS
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(token, amount)| KeeperReward { token, amount }))
+        .collect::<StdResult<Vec<_>>>()?;
+    for reward in keeper_rewards.iter() {
+        PENDING_KEEPER_REWARDS.remove(deps.storage, reward.token.clone());
+    }
+
+    FEE_DISTRIBUTIONS.save(
+        deps.storage,
+        epoch,
+        &FeeDistribution {
+            epoch,
+            timestamp: now,
+            fees,
+            keeper_rewards,
+        },
+    )?;
+    FEE_DISTRIBUTION_EPOCH.save(deps.storage, &epoch.add(1))?;
+    LAST_FEE_DISTRIBUTION_TIME.save(deps.storage, &now)?;
+
+    Ok(response
+        .add_attribute("method", "distribute_fees")
+        .add_attribute("epoch", epoch.to_string())
+        .add_events(events))
+}
+
+fn try_register_referral_code(
+    deps: DepsMut,
+    info: MessageInfo,
+    code: String,
+) -> Result<Response, ContractError> {
+    _validate(!code.is_empty(), 74)?;
+    _validate(!REFERRAL_CODES.has(deps.storage, &code), 74)?;
+
+    REFERRAL_CODES.save(deps.storage, &code, &info.sender)?;
+    Ok(Response::new()
+        .add_attribute("method", "register_referral_code")
+        .add_attribute("code", code)
+        .add_attribute("referrer", info.sender))
+}
+
+fn try_set_trader_referral_code(
+    deps: DepsMut,
+    info: MessageInfo,
+    code: String,
+) -> Result<Response, ContractError> {
+    let referrer = REFERRAL_CODES.may_load(deps.storage, &code)?;
+    _validate(referrer.is_some(), 75)?;
+    let referrer = referrer.unwrap();
+    _validate(referrer != info.sender, 76)?;
+
+    // move the trader from the previous referrer's count to the new one
+    if let Some(previous_code) =
+        TRADER_REFERRAL_CODES.may_load(deps.storage, info.sender.clone())?
+    {
+        if let Some(previous_referrer) = REFERRAL_CODES.may_load(deps.storage, &previous_code)? {
+            let mut stats = REFERRER_STATS
+                .may_load(deps.storage, previous_referrer.clone())?
+                .unwrap_or_default();
+            stats.referred_traders = stats.referred_traders.saturating_sub(1);
+            REFERRER_STATS.save(deps.storage, previous_referrer, &stats)?;
+        }
+    }
+    let mut stats = REFERRER_STATS
+        .may_load(deps.storage, referrer.clone())?
+        .unwrap_or_default();
+    stats.referred_traders = stats.referred_traders.add(1);
+    REFERRER_STATS.save(deps.storage, referrer.clone(), &stats)?;
+
+    TRADER_REFERRAL_CODES.save(deps.storage, info.sender.clone(), &code)?;
+    Ok(Response::new()
+        .add_attribute("method", "set_trader_referral_code")
+        .add_attribute("account", info.sender)
+        .add_attribute("code", code)
+        .add_attribute("referrer", referrer))
+}
+
+fn try_set_referral_tier(
+    deps: DepsMut,
+    info: MessageInfo,
+    tier: u64,
+    total_rebate_basis_points: u128,
+    discount_share_basis_points: u128,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+    _validate(total_rebate_basis_points <= BASIS_POINTS_DIVISOR, 77)?;
+    _validate(discount_share_basis_points <= BASIS_POINTS_DIVISOR, 77)?;
+
+    REFERRAL_TIERS.save(
+        deps.storage,
+        tier,
+        &ReferralTier {
+            total_rebate_basis_points,
+            discount_share_basis_points,
+        },
+    )?;
+    Ok(Response::new()
+        .add_attribute("method", "set_referral_tier")
+        .add_attribute("tier", tier.to_string()))
+}
+
+fn try_set_referrer_tier(
+    deps: DepsMut,
+    info: MessageInfo,
+    referrer: Addr,
+    tier: u64,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+    _validate(REFERRAL_TIERS.has(deps.storage, tier), 78)?;
+
+    REFERRER_TIERS.save(deps.storage, referrer.clone(), &tier)?;
+    Ok(Response::new()
+        .add_attribute("method", "set_referrer_tier")
+        .add_attribute("referrer", referrer)
+        .add_attribute("tier", tier.to_string()))
+}
+
+fn try_claim_referral_rebates(
+    deps: DepsMut,
+    info: MessageInfo,
+    tokens: Vec<Addr>,
+    reciever: Addr,
+) -> Result<Response, ContractError> {
+    let referrer = info.sender.clone();
+
+    let mut response = Response::new();
+    let mut claimed = false;
+    for token in tokens {
+        let rebate_key = (referrer.clone(), token.clone());
+        let amount = REFERRER_REBATES
+            .may_load(deps.storage, rebate_key.clone())?
+            .unwrap_or_default();
+        if amount == 0 {
+            continue;
+        }
+        REFERRER_REBATES.remove(deps.storage, rebate_key);
+        claimed = true;
+
+        let transfer = _transfer_out(
+            deps.storage,
+            info.clone(),
+            token.clone(),
+            amount,
+            reciever.clone(),
+        )?;
+        response = response.add_submessages(transfer.messages).add_event(
+            ClaimReferralRebate {
+                referrer: referrer.clone(),
+                token,
+                reciever: reciever.clone(),
+                amount,
+            }
+            .into(),
+        );
+    }
+    _validate(claimed, 79)?;
+
+    Ok(response.add_attribute("method", "claim_referral_rebates"))
+}
+fn try_add_routers(
+    deps: DepsMut,
+    info: MessageInfo,
+    _env: Env,
+    _router: Addr,
+) -> Result<Response, ContractError> {
+    let mut approved_routers1 = APPROVED_ROUTERS1.load(deps.storage, _router.clone())?;
+    let mut approved_routers2 = APPROVED_ROUTERS2.load(deps.storage, _router)?;
+    approved_routers1 = true;
+    approved_routers2 = approved_routers1;
+
+    APPROVED_ROUTERS1.save(deps.storage, info.sender.clone(), &approved_routers1)?;
+    APPROVED_ROUTERS2.save(deps.storage, info.sender, &approved_routers2)?;
+
+    Ok(Response::new().add_attribute("method", "add_routers"))
+}
+
+fn try_remove_routers(
+    deps: DepsMut,
+    info: MessageInfo,
+    _env: Env,
+    _router: Addr,
+) -> Result<Response, ContractError> {
+    let mut approved_routers1 = APPROVED_ROUTERS1.load(deps.storage, _router.clone())?;
+    let mut approved_routers2 = APPROVED_ROUTERS2.load(deps.storage, _router)?;
+    approved_routers1 = false;
+    approved_routers2 = approved_routers1;
+
+    APPROVED_ROUTERS1.save(deps.storage, info.sender.clone(), &approved_routers1)?;
+    APPROVED_ROUTERS2.save(deps.storage, info.sender, &approved_routers2)?;
+
+    Ok(Response::new().add_attribute("method", "remove_routers"))
+}
 
 fn try_set_usdg_amount(
     deps: DepsMut,
@@ -786,13 +1674,17 @@ fn try_set_usdg_amount(
     let usdg_amount = USDG_AMOUNTS.load(deps.storage, _token.clone())?;
 
     if _amount > usdg_amount {
-        _increase_usdg_amount(deps.storage, _token.clone(), _amount)?;
-        return Ok(Response::new().add_attribute("method", "set_usdg_Amount"));
+        let event = _increase_usdg_amount(deps.storage, _token, _amount.sub(usdg_amount))?;
+        return Ok(Response::new()
+            .add_attribute("method", "set_usdg_Amount")
+            .add_event(event));
     }
 
-    _decrease_usdg_amount(deps.storage, _token, _amount)?;
+    let event = _decrease_usdg_amount(deps.storage, _token, usdg_amount.sub(_amount))?;
 
-    Ok(Response::new().add_attribute("method", "set_usdg_Amount"))
+    Ok(Response::new()
+        .add_attribute("method", "set_usdg_Amount")
+        .add_event(event))
 }
 
 // the governance controlling this function should have a timelock
@@ -829,14 +1721,37 @@ fn try_direct_pool_deposit(
     env: Env,
     _token: Addr,
 ) -> Result<Response, ContractError> {
+    _validate_not_paused(deps.storage, Operation::DirectPoolDeposit, &[&_token])?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
     let whitelisted_tokens = WHITELISTED_TOKENS.load(deps.storage, _token.clone())?;
     _validate(whitelisted_tokens, 14)?;
 
-    let token_amount = _transfer_in(&deps, env.clone(), info, _token.clone())?;
+    let token_amount = _transfer_in(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        info,
+        _token.clone(),
+    )?;
     _validate(token_amount > 0, 15)?;
-    increase_pool_amount(deps.storage, deps.querier, env, _token, token_amount)?;
+    let event = _increase_pool_amount(
+        deps.storage,
+        deps.querier,
+        env,
+        _token.clone(),
+        token_amount,
+    )?;
 
-    Ok(Response::new().add_attribute("method", "direct_pool_deposit"))
+    Ok(Response::new()
+        .add_attribute("method", "direct_pool_deposit")
+        .add_event(event)
+        .add_event(
+            DirectPoolDeposit {
+                token: _token,
+                amount: token_amount,
+            }
+            .into(),
+        ))
 }
 
 fn try_buy_usdg(
@@ -845,17 +1760,35 @@ fn try_buy_usdg(
     env: Env,
     _token: Addr,
     _reciever: Addr,
+    account: Option<Addr>,
 ) -> Result<Response, ContractError> {
     _validate_manager(deps.storage, info.sender.clone());
+    let account = get_trader_account(deps.storage, &info, account)?;
+    _validate_not_paused(deps.storage, Operation::BuyUsdg, &[&_token])?;
+    _validate_circuit_breaker(deps.storage, env.clone(), Operation::BuyUsdg, &[&_token])?;
+    _validate_not_depegged(deps.storage, &_token)?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
     let whitelisted_tokens = WHITELISTED_TOKENS.load(deps.storage, _token.clone())?;
     let mut state_variables = STATE_VARIABLES.load(deps.storage)?;
     _validate(whitelisted_tokens, 16)?;
     state_variables.use_swap_pricing = true;
 
-    let token_amount = _transfer_in(&deps, env.clone(), info, _token.clone())?;
+    let token_amount = _transfer_in(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        info,
+        _token.clone(),
+    )?;
     _validate(token_amount > 0, 17)?;
 
-    update_cumulative_funding_rate(deps.storage, env.clone(), _token.clone(), _token.clone())?;
+    let mut events = vec![];
+    events.extend(update_cumulative_funding_rate(
+        deps.storage,
+        env.clone(),
+        _token.clone(),
+        _token.clone(),
+    )?);
 
     let price = 0; // getMinPrice(_token); uses Ivault interface so using hardcode value for the task
 
@@ -871,31 +1804,68 @@ fn try_buy_usdg(
     _validate(usdg_amount > 0, 18)?;
 
     let fee_basis_points = 0; // vaultUtils.getBuyUsdgFeeBasisPoints(_token, usdgAmount); uses VaultUtils interface so using hardcode value for the task
-    let amount_after_fees =
-        _collect_swap_fees(deps.storage, _token.clone(), token_amount, fee_basis_points)?;
+    let (amount_after_fees, fee_events) = _collect_swap_fees(
+        deps.storage,
+        env.clone(),
+        _token.clone(),
+        token_amount,
+        fee_basis_points,
+        "mint_burn",
+        Some(&account),
+    )?;
+    events.extend(fee_events);
+    let volume_usd = token_to_usd_min(_token.clone(), token_amount, deps.storage)?;
+    record_stats(
+        deps.storage,
+        env.clone(),
+        _token.clone(),
+        TokenStats {
+            mint_burn_volume_usd: volume_usd,
+            ..Default::default()
+        },
+    )?;
     let mut mint_amount = amount_after_fees.mul(price).div(PRICE_PRECISION);
     mint_amount = adjust_for_decimals(deps.storage, mint_amount, _token.clone(), addresses.usdg)?;
 
-    _increase_usdg_amount(deps.storage, _token.clone(), mint_amount)?;
+    events.push(_increase_usdg_amount(
+        deps.storage,
+        _token.clone(),
+        mint_amount,
+    )?);
     state_variables.use_swap_pricing = false;
     STATE_VARIABLES.save(deps.storage, &state_variables)?;
-    _increase_pool_amount(
+    events.push(_increase_pool_amount(
         deps.storage,
         deps.querier,
         env,
         _token.clone(),
         amount_after_fees,
-    )?;
+    )?);
 
     // IUSDG(usdg).mint(_receiver, mintAmount); // don.t have this function so it could have been a cw20 token function but haven't added
 
+    let attributes = vec![
+        ("reciever", _reciever.to_string()),
+        ("token", _token.to_string()),
+        ("token_amount", token_amount.to_string()),
+        ("mint_amount", mint_amount.to_string()),
+        ("fee_basis_points", fee_basis_points.to_string()),
+    ];
+    events.push(
+        BuyUsdg {
+            reciever: _reciever,
+            token: _token,
+            token_amount,
+            usdg_amount: mint_amount,
+            fee_basis_points,
+        }
+        .into(),
+    );
+
     Ok(Response::new()
         .add_attribute("method", "buy_usdg")
-        .add_attribute("reciever", _reciever)
-        .add_attribute("token", _token.to_string())
-        .add_attribute("token_amount", token_amount.to_string())
-        .add_attribute("mint_amount", mint_amount.to_string())
-        .add_attribute("fee_basis_points", fee_basis_points.to_string()))
+        .add_attributes(attributes)
+        .add_events(events))
 }
 
 fn try_sell_usdg(
@@ -904,22 +1874,47 @@ fn try_sell_usdg(
     env: Env,
     _token: Addr,
     _reciever: Addr,
+    account: Option<Addr>,
 ) -> Result<Response, ContractError> {
     _validate_manager(deps.storage, info.sender.clone());
+    let account = get_trader_account(deps.storage, &info, account)?;
+    _validate_not_paused(deps.storage, Operation::SellUsdg, &[&_token])?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
     let whitelisted_tokens = WHITELISTED_TOKENS.load(deps.storage, _token.clone())?;
     let mut state_variables = STATE_VARIABLES.load(deps.storage)?;
     let addresses = ADDRESSES.load(deps.storage)?;
     _validate(whitelisted_tokens, 19)?;
     state_variables.use_swap_pricing = true;
 
-    let usdg_amount = _transfer_in(&deps, env.clone(), info.clone(), addresses.usdg.clone())?;
+    let usdg_amount = _transfer_in(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        info.clone(),
+        addresses.usdg.clone(),
+    )?;
     _validate(usdg_amount > 0, 20)?;
-    update_cumulative_funding_rate(deps.storage, env.clone(), _token.clone(), _token.clone())?;
+    let mut events = vec![];
+    events.extend(update_cumulative_funding_rate(
+        deps.storage,
+        env.clone(),
+        _token.clone(),
+        _token.clone(),
+    )?);
     let redemption_amount =
         get_redemption_amount(deps.storage, env.clone(), _token.clone(), usdg_amount)?;
     _validate(redemption_amount > 0, 21)?;
-    _decrease_usdg_amount(deps.storage, _token.clone(), usdg_amount)?;
-    _decrease_pool_amount(deps.storage, env.clone(), _token.clone(), redemption_amount)?;
+    events.push(_decrease_usdg_amount(
+        deps.storage,
+        _token.clone(),
+        usdg_amount,
+    )?);
+    events.push(_decrease_pool_amount(
+        deps.storage,
+        env.clone(),
+        _token.clone(),
+        redemption_amount,
+    )?);
 
     // IUSDG(usdg).burn(address(this), usdgAmount);  // don.t have this function so it could have been a cw20 token function but haven't added
 
@@ -929,27 +1924,63 @@ fn try_sell_usdg(
     // be manually called to record the decrease in tokens
 
     let fee_basis_points = 0; // Used hardcoded as no IVaultUtils present in cosmwasm
-    let amount_out = _collect_swap_fees(
+    let (amount_out, fee_events) = _collect_swap_fees(
         deps.storage,
+        env.clone(),
         _token.clone(),
         redemption_amount,
         fee_basis_points,
+        "mint_burn",
+        Some(&account),
     )?;
+    events.extend(fee_events);
     _validate(amount_out > 0, 22)?;
-    _transfer_out(info, _token.clone(), amount_out, _reciever.clone())?;
+    let volume_usd = token_to_usd_min(_token.clone(), redemption_amount, deps.storage)?;
+    record_stats(
+        deps.storage,
+        env.clone(),
+        _token.clone(),
+        TokenStats {
+            mint_burn_volume_usd: volume_usd,
+            ..Default::default()
+        },
+    )?;
+    let transfer = _transfer_out(
+        deps.storage,
+        info,
+        _token.clone(),
+        amount_out,
+        _reciever.clone(),
+    )?;
 
     state_variables.use_swap_pricing = false;
     STATE_VARIABLES.save(deps.storage, &state_variables)?;
 
     _update_token_balance(deps.storage, deps.querier, env, addresses.usdg)?;
 
+    let attributes = vec![
+        ("reciever", _reciever.to_string()),
+        ("token", _token.to_string()),
+        ("usdg_amount", usdg_amount.to_string()),
+        ("amount_out", amount_out.to_string()),
+        ("fee_basis_points", fee_basis_points.to_string()),
+    ];
+    events.push(
+        SellUsdg {
+            reciever: _reciever,
+            token: _token,
+            usdg_amount,
+            token_amount: amount_out,
+            fee_basis_points,
+        }
+        .into(),
+    );
+
     Ok(Response::new()
         .add_attribute("method", "sell_usdg")
-        .add_attribute("reciever", _reciever.to_string())
-        .add_attribute("token", _token.to_string())
-        .add_attribute("usdg_amount", usdg_amount.to_string())
-        .add_attribute("amount_out", amount_out.to_string())
-        .add_attribute("fee_basis_points", fee_basis_points.to_string()))
+        .add_attributes(attributes)
+        .add_submessages(transfer.messages)
+        .add_events(events))
 }
 
 fn try_swap(
@@ -959,85 +1990,39 @@ fn try_swap(
     _token_in: Addr,
     _token_out: Addr,
     _reciever: Addr,
+    account: Option<Addr>,
 ) -> Result<Response, ContractError> {
-    let is_swap_enabled = IS_SWAP_ENABLED.load(deps.storage)?;
-    let whitelisted_tokens_in = WHITELISTED_TOKENS.load(deps.storage, _token_in.clone())?;
-    let whitelisted_tokens_out = WHITELISTED_TOKENS.load(deps.storage, _token_out.clone())?;
-    let mut state_variables = STATE_VARIABLES.load(deps.storage)?;
-    let addresses = ADDRESSES.load(deps.storage)?;
-
-    _validate(is_swap_enabled, 23)?;
-    _validate(whitelisted_tokens_in, 24)?;
-    _validate(whitelisted_tokens_out, 25)?;
-
-    state_variables.use_swap_pricing = true;
-
-    update_cumulative_funding_rate(
+    let account = get_trader_account(deps.storage, &info, account)?;
+    let amount_in = _transfer_in(
         deps.storage,
+        deps.querier,
         env.clone(),
+        info.clone(),
         _token_in.clone(),
-        _token_in.clone(),
-    )?;
-    update_cumulative_funding_rate(
-        deps.storage,
-        env.clone(),
-        _token_out.clone(),
-        _token_out.clone(),
     )?;
-
-    let amount_in = _transfer_in(&deps, env.clone(), info.clone(), _token_in.clone())?;
     _validate(amount_in > 0, 27)?;
 
-    let price_in = 0; // Hardcode as IVaultPriceFeed(priceFeed).getPrice(_token, false, includeAmmPrice, useSwapPricing); is not there in cosmwasm
-    let price_out = get_max_price(_token_out.clone());
-
-    let mut amount_out = amount_in.mul(price_in).div(PRICE_PRECISION);
-
-    amount_out = adjust_for_decimals(
+    let (amount_out, amount_out_after_fees, fee_basis_points, events) = _swap(
         deps.storage,
-        amount_out,
-        _token_in.clone(),
-        _token_out.clone(),
+        deps.querier,
+        env,
+        SwapOrder {
+            token_in: _token_in.clone(),
+            token_out: _token_out.clone(),
+            amount_in,
+            reciever: _reciever.clone(),
+        },
+        Some(&account),
     )?;
 
-    // adjust usdgAmounts by the same usdgAmount as debt is shifted between the assets
-    let mut usdg_amount = amount_in.mul(price_in).div(PRICE_PRECISION);
-    usdg_amount =
-        adjust_for_decimals(deps.storage, usdg_amount, _token_in.clone(), addresses.usdg)?;
-
-    let fee_basis_points = 0; //hardcoded as vaultUtils.getSwapFeeBasisPoints(_tokenIn, _tokenOut, usdgAmount); is not present is cosmwasm
-
-    let amount_out_after_fees = _collect_swap_fees(
+    let transfer = _transfer_out(
         deps.storage,
-        _token_in.clone(),
-        amount_out,
-        fee_basis_points,
-    )?;
-
-    _increase_usdg_amount(deps.storage, _token_in.clone(), usdg_amount)?;
-    _decrease_usdg_amount(deps.storage, _token_out.clone(), usdg_amount)?;
-
-    _decrease_pool_amount(deps.storage, env.clone(), _token_out.clone(), amount_out)?;
-    _validate_buffer_amount(deps.storage, _token_out.clone())?;
-
-    _transfer_out(
-        info.clone(),
+        info,
         _token_out.clone(),
         amount_out_after_fees,
         _reciever.clone(),
     )?;
 
-    state_variables.use_swap_pricing = false;
-
-    STATE_VARIABLES.load(deps.storage)?;
-    _increase_pool_amount(
-        deps.storage,
-        deps.querier,
-        env.clone(),
-        _token_in.clone(),
-        amount_in,
-    )?;
-
     Ok(Response::new()
         .add_attribute("method", "swap")
         .add_attribute("reciever", _reciever.to_string())
@@ -1046,57 +2031,381 @@ fn try_swap(
         .add_attribute("amount_in", amount_in.to_string())
         .add_attribute("amount_out", amount_out.to_string())
         .add_attribute("amount__out_after_fees", amount_out_after_fees.to_string())
-        .add_attribute("fee_basis_points", fee_basis_points.to_string()))
+        .add_attribute("fee_basis_points", fee_basis_points.to_string())
+        .add_submessages(transfer.messages)
+        .add_events(events))
 }
 
-fn try_increase_position(
+// the route of a SwapWithRoute message
+struct SwapRoute {
+    path: Vec<Addr>,
+    min_out: u128,
+    deadline: u64,
+    reciever: Addr,
+}
+
+fn try_swap_with_route(
     deps: DepsMut,
     info: MessageInfo,
     env: Env,
-    account: Addr,
-    collateral_token: Addr,
-    index_token: Addr,
-    size_delta: u128,
-    is_long: bool,
+    route: SwapRoute,
+    account: Option<Addr>,
 ) -> Result<Response, ContractError> {
-    let is_leverage_enabled = IS_LEVERGE_ENABLED.load(deps.storage)?;
-    let global_short_sizes = GLOBAL_SHORT_SIZES.load(deps.storage, index_token.clone())?;
-    let mut global_short_average_prizes =
-        GLOBAL_SHORT_AVERAGE_PRIZES.load(deps.storage, index_token.clone())?;
-
-    _validate(is_leverage_enabled, 28)?;
-    _validate_gas_price(deps.storage, env.clone());
-    _validate_router(deps.storage, info.clone(), account.clone());
-    validate_tokens(
-        deps.storage,
-        collateral_token.clone(),
-        index_token.clone(),
-        is_long.clone(),
-    );
-
-    // vaultUtils.validateIncreasePosition(_account, _collateralToken, _indexToken, _sizeDelta, _isLong); //not present in cosmwasm
-
-    update_cumulative_funding_rate(
+    let SwapRoute {
+        path,
+        min_out,
+        deadline,
+        reciever,
+    } = route;
+    let account = get_trader_account(deps.storage, &info, account)?;
+    _validate_swap_path(&path)?;
+    _validate(env.block.time.seconds() <= deadline, 68)?;
+
+    let mut amount = _transfer_in(
         deps.storage,
+        deps.querier,
         env.clone(),
-        collateral_token.clone(),
-        index_token.clone(),
+        info.clone(),
+        path[0].clone(),
     )?;
+    _validate(amount > 0, 27)?;
 
-    let key = get_position_key(
-        account.clone(),
-        collateral_token.clone(),
+    // intermediate tokens stay in the vault and are swapped again by the next hop
+    let mut events = vec![];
+    for (i, hop) in path.windows(2).enumerate() {
+        let hop_reciever = if i == path.len() - 2 {
+            reciever.clone()
+        } else {
+            env.contract.address.clone()
+        };
+        let (_, amount_out_after_fees, _, hop_events) = _swap(
+            deps.storage,
+            deps.querier,
+            env.clone(),
+            SwapOrder {
+                token_in: hop[0].clone(),
+                token_out: hop[1].clone(),
+                amount_in: amount,
+                reciever: hop_reciever,
+            },
+            Some(&account),
+        )?;
+        events.extend(hop_events);
+        amount = amount_out_after_fees;
+    }
+    _validate(amount >= min_out, 69)?;
+
+    let token_out = path[path.len() - 1].clone();
+    let transfer = _transfer_out(deps.storage, info, token_out, amount, reciever)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "swap_with_route")
+        .add_attribute("amount_out", amount.to_string())
+        .add_submessages(transfer.messages)
+        .add_events(events))
+}
+
+fn _validate_swap_path(path: &[Addr]) -> Result<Response, ContractError> {
+    _validate(path.len() >= 2, 67)?;
+    for hop in path.windows(2) {
+        _validate(hop[0] != hop[1], 67)?;
+    }
+    Ok(Response::default())
+}
+
+fn _validate_swap(
+    storage: &mut dyn Storage,
+    _token_in: &Addr,
+    _token_out: &Addr,
+) -> Result<Response, ContractError> {
+    let is_swap_enabled = IS_SWAP_ENABLED.load(storage)?;
+    let whitelisted_tokens_in = WHITELISTED_TOKENS
+        .may_load(storage, _token_in.clone())?
+        .unwrap_or(false);
+    let whitelisted_tokens_out = WHITELISTED_TOKENS
+        .may_load(storage, _token_out.clone())?
+        .unwrap_or(false);
+
+    _validate(is_swap_enabled, 23)?;
+    _validate_not_paused(storage, Operation::Swap, &[_token_in, _token_out])?;
+    _validate(!is_settlement_mode(storage)?, 59)?;
+    _validate(whitelisted_tokens_in, 24)?;
+    _validate(whitelisted_tokens_out, 25)?;
+    Ok(Response::default())
+}
+
+// mirrors the checks of `_swap` so distributeFees can skip a fee token that would make the
+// conversion revert, e.g. while swaps are disabled, paused or circuit broken
+fn can_convert_fees(
+    storage: &mut dyn Storage,
+    env: Env,
+    token_in: &Addr,
+    token_out: &Addr,
+    amount_in: u128,
+) -> Result<bool, ContractError> {
+    _validate_swap(storage, token_in, token_out)?;
+    _validate_circuit_breaker(storage, env, Operation::Swap, &[token_in, token_out])?;
+    _validate_not_depegged(storage, token_in)?;
+
+    let (amount_out, _, usdg_amount, _) =
+        get_swap_amounts(storage, token_in.clone(), token_out.clone(), amount_in)?;
+    let usdg_amount = USDG_AMOUNTS
+        .may_load(storage, token_in.clone())?
+        .unwrap_or_default()
+        .add(usdg_amount);
+    let max_usdg_amount = MAX_USDG_AMOUNTS
+        .may_load(storage, token_in.clone())?
+        .unwrap_or_default();
+    let pool_amount = POOL_AMOUNTS
+        .may_load(storage, token_out.clone())?
+        .unwrap_or_default();
+    let reserved_amount = RSERVED_AMOUNTS
+        .may_load(storage, token_out.clone())?
+        .unwrap_or_default();
+    let buffer_amount = BUFFER_AMOUNTS
+        .may_load(storage, token_out.clone())?
+        .unwrap_or_default();
+
+    Ok((max_usdg_amount == 0 || usdg_amount <= max_usdg_amount)
+        && pool_amount >= amount_out.add(reserved_amount.max(buffer_amount)))
+}
+
+// returns (amount_out, amount_out_after_fees, usdg_amount, fee_basis_points) without touching state
+fn get_swap_amounts(
+    storage: &mut dyn Storage,
+    _token_in: Addr,
+    _token_out: Addr,
+    amount_in: u128,
+) -> Result<(u128, u128, u128, u128), ContractError> {
+    let addresses = ADDRESSES.load(storage)?;
+
+    let price_in = get_min_price(storage, _token_in.clone());
+    let price_out = get_max_price(storage, _token_out.clone());
+
+    let mut amount_out = amount_in.mul(price_in).div(price_out);
+    amount_out = adjust_for_decimals(storage, amount_out, _token_in.clone(), _token_out.clone())?;
+
+    // adjust usdgAmounts by the same usdgAmount as debt is shifted between the assets
+    let mut usdg_amount = amount_in.mul(price_in).div(PRICE_PRECISION);
+    usdg_amount = adjust_for_decimals(storage, usdg_amount, _token_in.clone(), addresses.usdg)?;
+
+    let fee_basis_points = get_swap_fee_basis_points(storage, &_token_in, &_token_out)?;
+
+    let amount_out_after_fees = amount_out
+        .mul(BASIS_POINTS_DIVISOR.sub(fee_basis_points))
+        .div(BASIS_POINTS_DIVISOR);
+    Ok((
+        amount_out,
+        amount_out_after_fees,
+        usdg_amount,
+        fee_basis_points,
+    ))
+}
+
+// a single hop swap of tokens that are already held by the vault
+struct SwapOrder {
+    token_in: Addr,
+    token_out: Addr,
+    amount_in: u128,
+    reciever: Addr,
+}
+
+fn _swap(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    order: SwapOrder,
+    account: Option<&Addr>,
+) -> Result<(u128, u128, u128, Vec<Event>), ContractError> {
+    let SwapOrder {
+        token_in: _token_in,
+        token_out: _token_out,
+        amount_in,
+        reciever: _reciever,
+    } = order;
+    _validate_swap(storage, &_token_in, &_token_out)?;
+    _validate_circuit_breaker(
+        storage,
+        env.clone(),
+        Operation::Swap,
+        &[&_token_in, &_token_out],
+    )?;
+    // swapping in adds usdg debt against the token the same way minting does
+    _validate_not_depegged(storage, &_token_in)?;
+
+    let mut events = vec![];
+    events.extend(update_cumulative_funding_rate(
+        storage,
+        env.clone(),
+        _token_in.clone(),
+        _token_in.clone(),
+    )?);
+    events.extend(update_cumulative_funding_rate(
+        storage,
+        env.clone(),
+        _token_out.clone(),
+        _token_out.clone(),
+    )?);
+
+    let (amount_out, _, usdg_amount, fee_basis_points) =
+        get_swap_amounts(storage, _token_in.clone(), _token_out.clone(), amount_in)?;
+    let price_impact_usd =
+        get_swap_price_impact_usd(storage, &_token_in, &_token_out, usdg_amount)?;
+
+    let (amount_out_after_fees, fee_events) = _collect_swap_fees(
+        storage,
+        env.clone(),
+        _token_out.clone(),
+        amount_out,
+        fee_basis_points,
+        "swap",
+        account,
+    )?;
+    events.extend(fee_events);
+
+    let impact_tokens = get_price_impact_tokens(
+        storage,
+        _token_out.clone(),
+        price_impact_usd,
+        amount_out_after_fees,
+    )?;
+    events.extend(apply_price_impact_pool(
+        storage,
+        _token_out.clone(),
+        price_impact_usd,
+        impact_tokens,
+    )?);
+    let amount_out_after_fees = (amount_out_after_fees as i128 + impact_tokens) as u128;
+
+    // the volume counts for both sides of the swap
+    let volume_usd = token_to_usd_min(_token_in.clone(), amount_in, storage)?;
+    for token in [&_token_in, &_token_out] {
+        record_stats(
+            storage,
+            env.clone(),
+            token.clone(),
+            TokenStats {
+                swap_volume_usd: volume_usd,
+                ..Default::default()
+            },
+        )?;
+    }
+
+    events.push(_increase_usdg_amount(
+        storage,
+        _token_in.clone(),
+        usdg_amount,
+    )?);
+    events.push(_decrease_usdg_amount(
+        storage,
+        _token_out.clone(),
+        usdg_amount,
+    )?);
+
+    events.push(_decrease_pool_amount(
+        storage,
+        env.clone(),
+        _token_out.clone(),
+        amount_out,
+    )?);
+    _validate_buffer_amount(storage, _token_out.clone())?;
+
+    events.push(_increase_pool_amount(
+        storage,
+        querier,
+        env,
+        _token_in.clone(),
+        amount_in,
+    )?);
+
+    events.push(
+        Swap {
+            reciever: _reciever,
+            token_in: _token_in,
+            token_out: _token_out,
+            amount_in,
+            amount_out,
+            amount_out_after_fees,
+            fee_basis_points,
+        }
+        .into(),
+    );
+
+    Ok((amount_out, amount_out_after_fees, fee_basis_points, events))
+}
+
+fn try_increase_position(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    account: Addr,
+    collateral_token: Addr,
+    index_token: Addr,
+    size_delta: u128,
+    is_long: bool,
+) -> Result<Response, ContractError> {
+    let is_leverage_enabled = IS_LEVERGE_ENABLED.load(deps.storage)?;
+
+    _validate(is_leverage_enabled, 28)?;
+    _validate_not_paused(
+        deps.storage,
+        Operation::IncreasePosition,
+        &[&collateral_token, &index_token],
+    )?;
+    _validate_circuit_breaker(
+        deps.storage,
+        env.clone(),
+        Operation::IncreasePosition,
+        &[&collateral_token, &index_token],
+    )?;
+    if !is_long {
+        _validate_not_depegged(deps.storage, &collateral_token)?;
+    }
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
+    _validate_gas_price(deps.storage, env.clone());
+    _validate_router(deps.storage, info.clone(), account.clone());
+    validate_tokens(
+        deps.storage,
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long.clone(),
+    );
+
+    // vaultUtils.validateIncreasePosition(_account, _collateralToken, _indexToken, _sizeDelta, _isLong); //not present in cosmwasm
+
+    let mut events = vec![];
+    events.extend(update_cumulative_funding_rate(
+        deps.storage,
+        env.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+    )?);
+
+    let key = get_position_key(
+        account.clone(),
+        collateral_token.clone(),
         index_token.clone(),
         is_long.clone(),
     )?;
 
-    let mut positions = POSITIONS.load(deps.storage, &key)?;
-    let mut price = 0;
-    if is_long {
-        price = get_max_price(index_token.clone());
+    let mut positions = POSITIONS.may_load(deps.storage, &key)?.unwrap_or_default();
+    events.extend(update_skew_funding(
+        deps.storage,
+        env.clone(),
+        index_token.clone(),
+    )?);
+    events.extend(settle_skew_funding(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        &key,
+        &mut positions,
+    )?);
+    let price = if is_long {
+        get_max_price(deps.storage, index_token.clone())
     } else {
-        price = 0; // harcoded as IVaultPriceFeed(priceFeed).getPrice(_token, false, includeAmmPrice, useSwapPricing); is not present in cosmwasm
-    }
+        get_min_price(deps.storage, index_token.clone())
+    };
 
     if positions.size == 0 {
         positions.average_price = price;
@@ -1116,82 +2425,214 @@ fn try_increase_position(
         )?;
     }
 
-    let fee = collect_margin_fees(
+    let (fee, fee_events) = collect_margin_fees(
         account.clone(),
         collateral_token.clone(),
-        index_token.clone(),
-        is_long,
         size_delta,
         positions.size,
         positions.entry_funding_rate,
+        env.clone(),
         deps.storage,
     )?;
+    events.extend(fee_events);
 
-    let collateral_delta =
-        _transfer_in(&deps, env.clone(), info.clone(), collateral_token.clone())?;
+    let collateral_delta = _transfer_in(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        info.clone(),
+        collateral_token.clone(),
+    )?;
     let collateral_delta_usd =
         token_to_usd_min(collateral_token.clone(), collateral_delta, deps.storage)?;
 
-    positions.collateral.add(collateral_delta_usd);
+    positions.collateral = positions.collateral.add(collateral_delta_usd);
+    let required_collateral_usd = get_required_collateral_usd(
+        deps.storage,
+        index_token.clone(),
+        positions.size.add(size_delta),
+    )?;
+    events.extend(draw_cross_margin(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        &key,
+        &mut positions,
+        fee.add(required_collateral_usd),
+    )?);
     _validate(positions.collateral >= fee, 29)?;
-    positions.collateral.sub(fee);
-    positions.entry_funding_rate = 0; // Hardcoded
-    positions.size.add(size_delta);
+    positions.collateral = positions.collateral.sub(fee);
+
+    let price_impact_usd = get_position_price_impact_usd(
+        deps.storage,
+        index_token.clone(),
+        is_long,
+        size_delta,
+        true,
+    )?;
+    events.extend(apply_position_price_impact(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        collateral_token.clone(),
+        is_long,
+        &mut positions,
+        price_impact_usd,
+    )?);
+    positions.entry_funding_rate = get_entry_funding_rate(deps.storage, collateral_token.clone())?;
+    positions.size = positions.size.add(size_delta);
     positions.last_increased_time = env.block.time.seconds() as u128;
     _validate(positions.size > 0, 30)?;
     validate_position(positions.size, positions.collateral);
+    validate_position_risk(deps.storage, &key, positions.size, positions.collateral)?;
     // validateLiquidation(_account, _collateralToken, _indexToken, _isLong, true); // not present in cosmwasm
 
     // reserve tokens to pay profits on the position
-    let reserved_delta = usd_to_token_min(
-        collateral_token.clone(),
-        positions.reserve_amount,
-        deps.storage,
-    )?;
-    positions.reserve_amount.add(reserved_delta);
+    let reserved_delta = usd_to_token_max(collateral_token.clone(), size_delta, deps.storage)?;
+    positions.reserve_amount = positions.reserve_amount.add(reserved_delta);
 
-    increase_reserved_amount(deps.storage, collateral_token.clone(), reserved_delta)?;
+    events.push(increase_reserved_amount(
+        deps.storage,
+        collateral_token.clone(),
+        reserved_delta,
+    )?);
 
     if is_long {
         // guaranteedUsd stores the sum of (position.size - position.collateral) for all positions
         // if a fee is charged on the collateral then guaranteedUsd should be increased by that fee amount
         // since (position.size - position.collateral) would have increased by `fee`
-        increase_guarnteed_usd(deps.storage, collateral_token.clone(), size_delta.add(fee))?;
-        decrease_guarnteed_usd(deps.storage, collateral_token.clone(), collateral_delta_usd)?;
+        events.push(increase_guarnteed_usd(
+            deps.storage,
+            collateral_token.clone(),
+            size_delta.add(fee),
+        )?);
+        events.push(decrease_guarnteed_usd(
+            deps.storage,
+            collateral_token.clone(),
+            collateral_delta_usd,
+        )?);
 
         // treat the deposited collateral as part of the pool
-        increase_pool_amount(
+        events.push(_increase_pool_amount(
             deps.storage,
             deps.querier,
             env.clone(),
             collateral_token.clone(),
             collateral_delta,
-        )?;
+        )?);
         // fees need to be deducted from the pool since fees are deducted from position.collateral
         // and collateral is treated as part of the pool
         let amount = usd_to_token_min(collateral_token.clone(), fee, deps.storage)?;
-        _decrease_pool_amount(deps.storage, env.clone(), collateral_token.clone(), amount)?;
+        events.push(_decrease_pool_amount(
+            deps.storage,
+            env.clone(),
+            collateral_token.clone(),
+            amount,
+        )?);
+    }
+
+    if is_long {
+        let global_long_sizes = GLOBAL_LONG_SIZES
+            .may_load(deps.storage, index_token.clone())?
+            .unwrap_or_default();
+        let global_long_average_price = if global_long_sizes == 0 {
+            price
+        } else {
+            get_next_global_long_average_price(
+                index_token.clone(),
+                price,
+                size_delta,
+                deps.storage,
+            )?
+        };
+        GLOBAL_LONG_AVERAGE_PRICES.save(
+            deps.storage,
+            index_token.clone(),
+            &global_long_average_price,
+        )?;
+        events.push(increase_global_long_size(
+            deps.storage,
+            index_token.clone(),
+            size_delta,
+        )?);
     } else {
-        if global_short_sizes == 0 {
-            global_short_average_prizes = 0;
+        let global_short_sizes = GLOBAL_SHORT_SIZES
+            .may_load(deps.storage, index_token.clone())?
+            .unwrap_or_default();
+        let global_short_average_prizes = if global_short_sizes == 0 {
+            price
         } else {
-            global_short_average_prizes = get_next_global_short_average_price(
+            get_next_global_short_average_price(
                 index_token.clone(),
                 price,
                 size_delta,
                 deps.storage,
-            )?;
-        }
+            )?
+        };
+        GLOBAL_SHORT_AVERAGE_PRIZES.save(
+            deps.storage,
+            index_token.clone(),
+            &global_short_average_prizes,
+        )?;
+        events.push(increase_global_short_size(
+            deps.storage,
+            index_token.clone(),
+            size_delta,
+        )?);
     }
-    increase_global_short_size(deps.storage, index_token.clone(), size_delta)?;
-    POSITIONS.save(deps.storage, &key, &positions)?;
-
-    GLOBAL_SHORT_AVERAGE_PRIZES.save(
+    save_position(deps.storage, &key, &positions)?;
+    record_stats(
         deps.storage,
+        env.clone(),
         index_token.clone(),
-        &global_short_average_prizes,
+        TokenStats {
+            margin_volume_usd: size_delta,
+            ..Default::default()
+        },
+    )?;
+    record_trade(
+        deps.storage,
+        &account,
+        TradeRecord {
+            collateral_token: collateral_token.clone(),
+            index_token: index_token.clone(),
+            is_long,
+            action: TradeAction::Increase,
+            price,
+            size_delta,
+            collateral_delta_usd,
+            fee_usd: fee,
+            realised_pnl: 0,
+            timestamp: env.block.time.seconds(),
+        },
     )?;
 
+    events.push(
+        ChangePosition {
+            key: event_position_key(&account, &collateral_token, &index_token, is_long),
+            is_increase: true,
+            collateral_delta_usd,
+            size_delta,
+            price,
+            fee_usd: fee,
+        }
+        .into(),
+    );
+    events.push(
+        PositionState {
+            key: event_position_key(&account, &collateral_token, &index_token, is_long),
+            is_closed: false,
+            size: positions.size,
+            collateral: positions.collateral,
+            average_price: positions.average_price,
+            entry_funding_rate: positions.entry_funding_rate,
+            reserve_amount: positions.reserve_amount,
+            realised_pnl: positions.realised_pnl,
+            mark_price: price,
+        }
+        .into(),
+    );
+
     Ok(Response::new()
         .add_attribute("method", "increase_position")
         .add_attribute("account", account.to_string())
@@ -1201,7 +2642,8 @@ fn try_increase_position(
         .add_attribute("size_delta", size_delta.to_string())
         .add_attribute("is_long", is_long.to_string())
         .add_attribute("price", price.to_string())
-        .add_attribute("fee", fee.to_string()))
+        .add_attribute("fee", fee.to_string())
+        .add_events(events))
 }
 
 fn try_decrease_position(
@@ -1218,39 +2660,158 @@ fn try_decrease_position(
 ) -> Result<Response, ContractError> {
     _validate_gas_price(deps.storage, env.clone());
     _validate_router(deps.storage, info.clone(), account.clone());
-
-    // vaultUtils.validateDecreasePosition(_account, _collateralToken, _indexToken, _collateralDelta, _sizeDelta, _isLong, _receiver); // skipped not present in cosmwasm
-    update_cumulative_funding_rate(
+    _validate_not_paused(
+        deps.storage,
+        Operation::DecreasePosition,
+        &[&collateral_token, &index_token],
+    )?;
+    _validate_circuit_breaker(
         deps.storage,
         env.clone(),
-        collateral_token.clone(),
-        index_token.clone(),
+        Operation::DecreasePosition,
+        &[&collateral_token, &index_token],
     )?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
 
-    let key = get_position_key(
-        account.clone(),
-        collateral_token.clone(),
-        index_token.clone(),
-        is_long,
+    let key = get_position_key(account, collateral_token.clone(), index_token, is_long)?;
+    let (amount_out_after_fees, events) = _decrease_position(
+        deps.storage,
+        deps.querier,
+        env,
+        &key,
+        collateral_delta,
+        size_delta,
+        TradeAction::Decrease,
     )?;
 
-    let mut position = POSITIONS.load(deps.storage, &key)?;
+    let mut response = Response::new()
+        .add_attribute("method", "decrease_position")
+        .add_attribute("amount_out_after_fees", amount_out_after_fees.to_string())
+        .add_events(events);
+    if amount_out_after_fees > 0 {
+        let transfer = _transfer_out(
+            deps.storage,
+            info,
+            collateral_token,
+            amount_out_after_fees,
+            reciever,
+        )?;
+        response = response.add_submessages(transfer.messages);
+    }
+    Ok(response)
+}
+
+// decreases or closes a position and returns the collateral tokens to send to the reciever
+fn _decrease_position(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    key: &[u8],
+    collateral_delta: u128,
+    size_delta: u128,
+    action: TradeAction,
+) -> Result<(u128, Vec<Event>), ContractError> {
+    let position_key = decode_position_key(key)?;
+    let account = position_key.key_1;
+    let collateral_token = position_key.key_2;
+    let index_token = position_key.key_3;
+    let is_long = position_key.key_4;
+
+    // vaultUtils.validateDecreasePosition(_account, _collateralToken, _indexToken, _collateralDelta, _sizeDelta, _isLong, _receiver); // skipped not present in cosmwasm
+    let mut events = vec![];
+    events.extend(update_cumulative_funding_rate(
+        storage,
+        env.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+    )?);
+
+    let mut position = POSITIONS.load(storage, key)?;
+    events.extend(update_skew_funding(
+        storage,
+        env.clone(),
+        index_token.clone(),
+    )?);
+    events.extend(settle_skew_funding(
+        storage,
+        querier,
+        env.clone(),
+        key,
+        &mut position,
+    )?);
+    let price_impact_usd =
+        get_position_price_impact_usd(storage, index_token.clone(), is_long, size_delta, false)?;
+    events.extend(apply_position_price_impact(
+        storage,
+        querier,
+        env.clone(),
+        collateral_token.clone(),
+        is_long,
+        &mut position,
+        price_impact_usd,
+    )?);
     _validate(position.size > 0, 31)?;
     _validate(position.size >= size_delta, 32)?;
     _validate(position.collateral >= collateral_delta, 33)?;
 
+    if CROSS_MARGIN_BALANCES.has(storage, (account.clone(), collateral_token.clone())) {
+        // the realised loss, fees and the margin of what stays open are drawn from the account
+        let (has_profit, delta) = get_delta(
+            index_token.clone(),
+            position.size,
+            position.average_price,
+            is_long,
+            position.last_increased_time,
+            storage,
+            env.clone(),
+        )?;
+        let loss_usd = if has_profit {
+            0
+        } else {
+            delta.mul(size_delta).div(position.size)
+        };
+        let fee_usd = size_delta
+            .mul(STATE_VARIABLES.load(storage)?.margin_fee_basis_points)
+            .div(BASIS_POINTS_DIVISOR);
+        let required_collateral_usd = if size_delta < position.size {
+            get_required_collateral_usd(
+                storage,
+                index_token.clone(),
+                position.size.sub(size_delta),
+            )?
+        } else {
+            0
+        };
+        events.extend(draw_cross_margin(
+            storage,
+            querier,
+            env.clone(),
+            key,
+            &mut position,
+            loss_usd
+                .add(fee_usd)
+                .add(collateral_delta)
+                .add(required_collateral_usd),
+        )?);
+    }
+
     let collateral = position.collateral;
 
     // scrop variables to avoid stack too deep errors
     {
         let rserve_delta = position.reserve_amount.mul(size_delta).div(position.size);
         position.reserve_amount = position.reserve_amount.sub(rserve_delta);
-        decrease_reserved_amount(deps.storage, collateral_token.clone(), rserve_delta)?;
+        save_position(storage, key, &position)?;
+        events.push(decrease_reserved_amount(
+            storage,
+            collateral_token.clone(),
+            rserve_delta,
+        )?);
     }
 
-    let (usd_out, usd_out_after_fee) = reduce_collateral(
-        deps.storage,
-        deps.querier,
+    let (usd_out, usd_out_after_fee, fee_usd, realised_pnl, collateral_events) = reduce_collateral(
+        storage,
+        querier,
         env.clone(),
         account.clone(),
         collateral_token.clone(),
@@ -1259,12 +2820,23 @@ fn try_decrease_position(
         size_delta,
         is_long,
     )?;
+    events.extend(collateral_events);
+    // reduce_collateral updates the stored position
+    let mut position = POSITIONS.load(storage, key)?;
+
+    let price = if is_long {
+        get_min_price(storage, index_token.clone())
+    } else {
+        get_max_price(storage, index_token.clone())
+    };
 
-    if position.size != size_delta {
-        position.entry_funding_rate = 0; // hardcode as return vaultUtils.getEntryFundingRate(_collateralToken, _indexToken, _isLong); is not present in cosmwsm
+    let is_closed = position.size == size_delta;
+    if !is_closed {
+        position.entry_funding_rate = get_entry_funding_rate(storage, collateral_token.clone())?;
         position.size = position.size.sub(size_delta);
 
-        validate_position(position.size, collateral.clone());
+        validate_position(position.size, position.collateral);
+        validate_position_risk(storage, key, position.size, position.collateral)?;
 
         //      // validateLiquidation returns (state, fees)
         // function validateLiquidation(address _account, address _collateralToken, address _indexToken, bool _isLong, bool _raise) override public view returns (uint256, uint256) {
@@ -1272,1156 +2844,8802 @@ fn try_decrease_position(
         // }  Skipping this as not present in cosmwasm
 
         if is_long {
-            increase_guarnteed_usd(
-                deps.storage,
+            events.push(increase_guarnteed_usd(
+                storage,
                 collateral_token.clone(),
                 collateral.sub(position.collateral),
-            )?;
-            decrease_guarnteed_usd(deps.storage, collateral_token.clone(), size_delta)?;
+            )?);
+            events.push(decrease_guarnteed_usd(
+                storage,
+                collateral_token.clone(),
+                size_delta,
+            )?);
         }
 
-        let _price = if is_long {
-            get_min_price(index_token.clone())
-        } else {
-            get_max_price(index_token.clone())
-        };
+        save_position(storage, key, &position)?;
+    } else {
+        if is_long {
+            events.push(increase_guarnteed_usd(
+                storage,
+                collateral_token.clone(),
+                collateral,
+            )?);
+            events.push(decrease_guarnteed_usd(
+                storage,
+                collateral_token.clone(),
+                size_delta,
+            )?);
+        }
 
-        POSITIONS.remove(deps.storage, &key);
+        remove_position(storage, key)?;
     }
 
-    if is_long {
-        decrease_global_short_size(deps.storage, index_token.clone(), size_delta)?;
-    }
+    events.push(
+        ChangePosition {
+            key: event_position_key(&account, &collateral_token, &index_token, is_long),
+            is_increase: false,
+            collateral_delta_usd: collateral_delta,
+            size_delta,
+            price,
+            fee_usd,
+        }
+        .into(),
+    );
+    events.push(
+        PositionState {
+            key: event_position_key(&account, &collateral_token, &index_token, is_long),
+            is_closed,
+            size: position.size,
+            collateral: position.collateral,
+            average_price: position.average_price,
+            entry_funding_rate: position.entry_funding_rate,
+            reserve_amount: position.reserve_amount,
+            realised_pnl: position.realised_pnl,
+            mark_price: price,
+        }
+        .into(),
+    );
+
+    events.push(decrease_global_size(
+        storage,
+        index_token.clone(),
+        is_long,
+        size_delta,
+    )?);
+    record_stats(
+        storage,
+        env.clone(),
+        index_token.clone(),
+        TokenStats {
+            margin_volume_usd: size_delta,
+            ..Default::default()
+        },
+    )?;
+    record_trade(
+        storage,
+        &account,
+        TradeRecord {
+            collateral_token: collateral_token.clone(),
+            index_token: index_token.clone(),
+            is_long,
+            action,
+            price,
+            size_delta,
+            collateral_delta_usd: collateral_delta,
+            fee_usd,
+            realised_pnl,
+            timestamp: env.block.time.seconds(),
+        },
+    )?;
+    let mut amount_out_after_fees = 0;
     if usd_out > 0 {
         if is_long {
-            let amount =
-                usd_to_token_min(collateral_token.clone(), usd_out_after_fee, deps.storage)?;
-            _decrease_pool_amount(deps.storage, env.clone(), collateral_token.clone(), amount)?;
+            let amount = usd_to_token_min(collateral_token.clone(), usd_out_after_fee, storage)?;
+            events.push(_decrease_pool_amount(
+                storage,
+                env.clone(),
+                collateral_token.clone(),
+                amount,
+            )?);
         }
-        let amount_out_after_fees =
-            usd_to_token_min(collateral_token.clone(), usd_out_after_fee, deps.storage)?;
-        _transfer_out(
-            info.clone(),
-            collateral_token.clone(),
-            amount_out_after_fees,
-            reciever.clone(),
-        )?;
-        return Ok(Response::new()
-            .add_attribute("amount_out_after_fees", amount_out_after_fees.to_string()));
+        amount_out_after_fees =
+            usd_to_token_min(collateral_token.clone(), usd_out_after_fee, storage)?;
+    }
+
+    Ok((amount_out_after_fees, events))
+}
+
+fn try_set_adl_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    collateral_token: Addr,
+    config: Option<AdlConfig>,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+
+    match config {
+        Some(config) => {
+            _validate(
+                config.target_pnl_to_pool_basis_points < config.trigger_pnl_to_pool_basis_points,
+                91,
+            )?;
+            ADL_CONFIGS.save(deps.storage, collateral_token.clone(), &config)?;
+        }
+        None => ADL_CONFIGS.remove(deps.storage, collateral_token.clone()),
     }
 
     Ok(Response::new()
-        .add_attribute("method", "decrease_position")
-        .add_attribute("amount_out_after_fees", 0.to_string()))
+        .add_attribute("method", "set_adl_config")
+        .add_attribute("collateral_token", collateral_token))
 }
 
-fn try_liquidate_position(
+// deleverages a page of the positions using a collateral token, keepers walk the pages with the
+// last_key of the ranking query
+fn try_auto_deleverage(
     deps: DepsMut,
     info: MessageInfo,
     env: Env,
-    account: Addr,
     collateral_token: Addr,
-    index_token: Addr,
-    is_long: bool,
-    _fee_reciever: Addr,
+    start_after: Option<Binary>,
+    limit: Option<u32>,
 ) -> Result<Response, ContractError> {
-    let mut state_variables = STATE_VARIABLES.load(deps.storage)?;
-    let is_liquidator = IS_LIQUIDATOR.load(deps.storage, info.sender.clone())?;
-    if state_variables.in_private_liquidation_mode {
-        _validate(is_liquidator, 34)?;
-    }
+    let gov = GOV.load(deps.storage)?;
+    let is_liquidator = IS_LIQUIDATOR
+        .may_load(deps.storage, info.sender.clone())?
+        .unwrap_or_default();
+    _validate(info.sender == gov || is_liquidator, 92)?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
 
-    // set includeAmmPrice to false to prevent manipulated liquidations
-    state_variables.include_amm_price = false;
+    let config = ADL_CONFIGS.may_load(deps.storage, collateral_token.clone())?;
+    _validate(config.is_some(), 89)?;
+    let config = config.unwrap();
 
-    update_cumulative_funding_rate(
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let (total_profit_usd, pool_usd, candidates, last_key) = get_adl_candidates(
         deps.storage,
         env.clone(),
         collateral_token.clone(),
-        index_token.clone(),
+        start_after,
+        limit,
     )?;
-    let key = get_position_key(
-        account.clone(),
-        collateral_token.clone(),
-        index_token.clone(),
-        is_long,
+    let pnl_to_pool_basis_points = get_pnl_to_pool_basis_points(total_profit_usd, pool_usd);
+    _validate(
+        pnl_to_pool_basis_points > config.trigger_pnl_to_pool_basis_points,
+        90,
     )?;
-    let position = POSITIONS.load(deps.storage, &key)?;
-    _validate(position.size > 0, 35)?;
 
-    let (liquidation_state, margin_fees) = (1, 1); // hardcode as vaultUtils.validateLiquidation(_account, _collateralToken, _indexToken, _isLong, _raise); not present in cosmwasm
-    _validate(liquidation_state != 0, 36)?;
+    // profit that has to be realised to get back to the target ratio
+    let target_profit_usd = pool_usd
+        .mul(config.target_pnl_to_pool_basis_points)
+        .div(BASIS_POINTS_DIVISOR);
+    let mut excess_profit_usd = total_profit_usd.saturating_sub(target_profit_usd);
 
-    if liquidation_state == 2 {
-        // max leverage exceeded but there is collateral remaining after deducting losses so decreasePosition instead
-        state_variables.include_amm_price = true;
-        return Ok(Response::default());
-    }
-    let fee_tokens = usd_to_token_min(collateral_token.clone(), margin_fees, deps.storage)?;
-    let mut fee_reserves = FEE_RESERVES.load(deps.storage, collateral_token.clone())?;
-    fee_reserves = fee_reserves.add(fee_tokens);
-    decrease_reserved_amount(
-        deps.storage,
-        collateral_token.clone(),
-        position.reserve_amount,
-    )?;
+    let mut response = Response::new();
+    let mut deleveraged = 0;
+    for candidate in candidates {
+        if excess_profit_usd == 0 {
+            break;
+        }
 
-    if is_long {
-        decrease_guarnteed_usd(
+        let mut size_delta = if candidate.profit_usd <= excess_profit_usd {
+            candidate.size
+        } else {
+            // round up so that the excess is always covered
+            excess_profit_usd
+                .mul(candidate.size)
+                .add(candidate.profit_usd.sub(1))
+                .div(candidate.profit_usd)
+                .min(candidate.size)
+        };
+        // close the whole position when the remainder would be below the minimum size
+        let min_position_size_usd = TOKEN_RISK_CONFIGS
+            .may_load(deps.storage, candidate.index_token.clone())?
+            .unwrap_or_default()
+            .min_position_size_usd;
+        if candidate.size.sub(size_delta) < min_position_size_usd {
+            size_delta = candidate.size;
+        }
+        let realised_profit_usd = candidate.profit_usd.mul(size_delta).div(candidate.size);
+        excess_profit_usd = excess_profit_usd.saturating_sub(realised_profit_usd);
+
+        let key = get_position_key(
+            candidate.account.clone(),
+            candidate.collateral_token.clone(),
+            candidate.index_token.clone(),
+            candidate.is_long,
+        )?;
+        let (amount_out_after_fees, events) = _decrease_position(
             deps.storage,
-            collateral_token.clone(),
-            position.size.sub(position.collateral),
+            deps.querier,
+            env.clone(),
+            &key,
+            0,
+            size_delta,
+            TradeAction::AutoDeleverage,
         )?;
-        let amount = usd_to_token_min(collateral_token.clone(), margin_fees, deps.storage)?;
-        _decrease_pool_amount(deps.storage, env.clone(), collateral_token.clone(), amount)?;
+        response = response.add_events(events).add_event(
+            AutoDeleverage {
+                key: event_position_key(
+                    &candidate.account,
+                    &candidate.collateral_token,
+                    &candidate.index_token,
+                    candidate.is_long,
+                ),
+                size_delta,
+                profit_usd: candidate.profit_usd,
+                pnl_to_pool_basis_points,
+            }
+            .into(),
+        );
+        if amount_out_after_fees > 0 {
+            let transfer = _transfer_out(
+                deps.storage,
+                info.clone(),
+                candidate.collateral_token,
+                amount_out_after_fees,
+                candidate.account,
+            )?;
+            response = response.add_submessages(transfer.messages);
+        }
+        deleveraged += 1;
     }
 
-    let _mark_price = if is_long {
-        get_min_price(index_token.clone())
-    } else {
-        get_max_price(index_token.clone())
-    };
+    Ok(response
+        .add_attribute("method", "auto_deleverage")
+        .add_attribute("deleveraged", deleveraged.to_string())
+        .add_attribute(
+            "last_key",
+            last_key.map(|key| key.to_base64()).unwrap_or_default(),
+        ))
+}
 
-    if is_long && margin_fees < position.collateral {
-        let remaining_collateral = position.collateral.sub(margin_fees);
-        let amount =
-            usd_to_token_min(collateral_token.clone(), remaining_collateral, deps.storage)?;
+fn try_set_funding_keeper_reward(
+    deps: DepsMut,
+    info: MessageInfo,
+    reward_usd: u128,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+    FUNDING_KEEPER_REWARD_USD.save(deps.storage, &reward_usd)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "set_funding_keeper_reward")
+        .add_attribute("reward_usd", reward_usd.to_string()))
+}
+
+// brings the cumulative funding rates of the tokens up to date, the sender is rewarded out of the
+// fee reserves of every token for which a full funding interval had elapsed
+fn try_update_funding_rates(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    tokens: Vec<Addr>,
+) -> Result<Response, ContractError> {
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
+    _validate(tokens.len() <= MAX_LIMIT as usize, 104)?;
+    let reward_usd = FUNDING_KEEPER_REWARD_USD
+        .may_load(deps.storage)?
+        .unwrap_or_default();
+
+    let mut events = vec![];
+    let mut rewards: Vec<KeeperReward> = vec![];
+    for token in tokens {
+        let is_whitelisted = WHITELISTED_TOKENS
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        _validate(is_whitelisted, 105)?;
 
-        increase_pool_amount(
+        events.extend(update_skew_funding(
             deps.storage,
-            deps.querier,
             env.clone(),
-            collateral_token.clone(),
-            amount,
+            token.clone(),
+        )?);
+        let funding_event = update_cumulative_funding_rate(
+            deps.storage,
+            env.clone(),
+            token.clone(),
+            token.clone(),
+        )?;
+        let is_updated = funding_event.is_some();
+        events.extend(funding_event);
+        if !is_updated || reward_usd == 0 {
+            continue;
+        }
+
+        let fee_reserves = FEE_RESERVES
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        let amount = usd_to_token_min(token.clone(), reward_usd, deps.storage)?.min(fee_reserves);
+        if amount == 0 {
+            continue;
+        }
+        FEE_RESERVES.save(deps.storage, token.clone(), &fee_reserves.sub(amount))?;
+        let pending_rewards = PENDING_KEEPER_REWARDS
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        PENDING_KEEPER_REWARDS.save(deps.storage, token.clone(), &pending_rewards.add(amount))?;
+        let amount_usd = token_to_usd_min(token.clone(), amount, deps.storage)?;
+        record_stats(
+            deps.storage,
+            env.clone(),
+            token.clone(),
+            TokenStats {
+                keeper_rewards_usd: amount_usd,
+                ..Default::default()
+            },
         )?;
+        events.push(
+            PayKeeperReward {
+                keeper: info.sender.clone(),
+                token: token.clone(),
+                amount,
+            }
+            .into(),
+        );
+        match rewards.iter_mut().find(|reward| reward.token == token) {
+            Some(reward) => reward.amount = reward.amount.add(amount),
+            None => rewards.push(KeeperReward { token, amount }),
+        }
     }
 
-    if !is_long {
-        decrease_global_short_size(deps.storage, index_token.clone(), position.size)?;
+    // a single transfer per reward token
+    let mut response = Response::new();
+    for reward in rewards {
+        let transfer = _transfer_out(
+            deps.storage,
+            info.clone(),
+            reward.token,
+            reward.amount,
+            info.sender.clone(),
+        )?;
+        response = response.add_submessages(transfer.messages);
     }
 
-    POSITIONS.remove(deps.storage, &key);
+    Ok(response
+        .add_attribute("method", "update_funding_rates")
+        .add_events(events))
+}
 
-    // pay the fee receiver using the pool, we assume that in general the liquidated amount should be sufficient to cover
-    // the liquidation fees
-    let amount = usd_to_token_min(
-        collateral_token.clone(),
-        state_variables.liquidation_fee_usd,
-        deps.storage,
-    )?;
-    _decrease_pool_amount(deps.storage, env.clone(), collateral_token.clone(), amount)?;
+// a retention of 0 keeps the funding history forever
+fn try_set_funding_history_retention(
+    deps: DepsMut,
+    info: MessageInfo,
+    retention: u64,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+    FUNDING_HISTORY_RETENTION.save(deps.storage, &retention)?;
 
-    state_variables.include_amm_price = true;
+    Ok(Response::new()
+        .add_attribute("method", "set_funding_history_retention")
+        .add_attribute("retention", retention.to_string()))
+}
 
-    STATE_VARIABLES.save(deps.storage, &state_variables)?;
+fn try_set_insurance_fund_basis_points(
+    deps: DepsMut,
+    info: MessageInfo,
+    basis_points: u128,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+    _validate(basis_points <= BASIS_POINTS_DIVISOR, 93)?;
+    INSURANCE_FUND_BASIS_POINTS.save(deps.storage, &basis_points)?;
 
     Ok(Response::new()
-        .add_attribute("method", "liquidate_position")
-        .add_attribute("acount", account.to_string())
-        .add_attribute("collateral_token", collateral_token.to_string())
-        .add_attribute("index_token", index_token.to_string())
-        .add_attribute("is_long", is_long.to_string())
-        .add_attribute("size", position.size.to_string())
-        .add_attribute("collateral", position.collateral.to_string())
-        .add_attribute("reserve_amount", position.reserve_amount.to_string())
-        .add_attribute("realised_pnl", position.realised_pnl.to_string())
-        .add_attribute("mark_price", _mark_price.to_string()))
+        .add_attribute("method", "set_insurance_fund_basis_points")
+        .add_attribute("basis_points", basis_points.to_string()))
 }
 
-// Query
+fn try_set_partial_liquidation_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    config: Option<PartialLiquidationConfig>,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
 
-fn try_get_redemption_collateral(deps: DepsMut, token: Addr) -> StdResult<Binary> {
-    let stable_token = STABLE_TOKENS.load(deps.storage, token.clone())?;
-    let pool_amounts = POOL_AMOUNTS.load(deps.storage, token.clone())?;
-    let guaranteed_usd = GUARANTEED_USD.load(deps.storage, token.clone())?;
-    let reserved_amount = RSERVED_AMOUNTS.load(deps.storage, token.clone())?;
-    if stable_token {
-        return to_binary(&pool_amounts);
+    match config {
+        Some(config) => {
+            _validate(config.buffer_basis_points < BASIS_POINTS_DIVISOR, 94)?;
+            PARTIAL_LIQUIDATION_CONFIG.save(deps.storage, &config)?;
+        }
+        None => PARTIAL_LIQUIDATION_CONFIG.remove(deps.storage),
     }
 
-    let collateral = usd_to_token_min(token.clone(), guaranteed_usd, deps.storage)?;
-    let res = collateral.add(pool_amounts).sub(reserved_amount);
-    to_binary(&(res))
+    Ok(Response::new().add_attribute("method", "set_partial_liquidation_config"))
 }
 
-fn try_get_redemption_collateral_usd(deps: DepsMut, token: Addr) -> StdResult<Binary> {
-    let stable_token = STABLE_TOKENS.load(deps.storage, token.clone())?;
-    let pool_amounts = POOL_AMOUNTS.load(deps.storage, token.clone())?;
-    let guaranteed_usd = GUARANTEED_USD.load(deps.storage, token.clone())?;
-    let reserved_amount = RSERVED_AMOUNTS.load(deps.storage, token.clone())?;
-    if stable_token {
-        return to_binary(&pool_amounts);
+fn try_set_stable_price_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: Addr,
+    config: Option<StablePriceConfig>,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+
+    match config {
+        Some(config) => {
+            let is_stable = STABLE_TOKENS
+                .may_load(deps.storage, token.clone())?
+                .unwrap_or_default();
+            _validate(is_stable, 109)?;
+            _validate(
+                config.clamp_basis_points < config.depeg_basis_points
+                    && config.depeg_basis_points < BASIS_POINTS_DIVISOR,
+                114,
+            )?;
+            STABLE_PRICE_CONFIGS.save(deps.storage, token.clone(), &config)?;
+        }
+        None => STABLE_PRICE_CONFIGS.remove(deps.storage, token.clone()),
     }
 
-    let collateral = usd_to_token_min(token.clone(), guaranteed_usd, deps.storage)?;
-    let amount = collateral.add(pool_amounts).sub(reserved_amount);
-    let res = token_to_usd_min(token.clone(), amount, deps.storage)?;
-    to_binary(&(res))
+    Ok(Response::new()
+        .add_attribute("method", "set_stable_price_config")
+        .add_attribute("token", token))
 }
 
-fn try_get_utilisation(deps: DepsMut, token: Addr) -> StdResult<Binary> {
-    let pool_amounts = POOL_AMOUNTS.load(deps.storage, token.clone())?;
-    let reserved_amoints = RSERVED_AMOUNTS.load(deps.storage, token.clone())?;
-    if pool_amounts == 0 {
-        return to_binary(&0);
+fn try_set_circuit_breaker_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: Addr,
+    config: Option<CircuitBreakerConfig>,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+
+    match config {
+        Some(config) => {
+            _validate(config.max_deviation_basis_points > 0, 108)?;
+            CIRCUIT_BREAKER_CONFIGS.save(deps.storage, token.clone(), &config)?;
+        }
+        None => {
+            CIRCUIT_BREAKER_CONFIGS.remove(deps.storage, token.clone());
+            TRIPPED_CIRCUIT_BREAKERS.remove(deps.storage, token.clone());
+        }
     }
-    let res = reserved_amoints
-        .mul(FUNDING_RATE_PRECISION)
-        .div(pool_amounts);
-    to_binary(&(res))
+
+    Ok(Response::new()
+        .add_attribute("method", "set_circuit_breaker_config")
+        .add_attribute("token", token))
 }
 
-fn try_get_position(
+// operations that see a deviating price fail and so cannot persist it, anyone can trip the
+// breaker instead so that it stays halted until the price is confirmed
+fn try_trip_circuit_breaker(
     deps: DepsMut,
-    account: Addr,
-    collateral_token: Addr,
-    index_token: Addr,
-    is_long: bool,
-) -> StdResult<Binary> {
-    let key = get_position_key(
-        account.clone(),
-        collateral_token.clone(),
-        index_token.clone(),
-        is_long,
-    )
-    .unwrap();
-    let position = POSITIONS.load(deps.storage, &key)?;
-    let realised_pnl;
-    if position.realised_pnl > 0 {
-        realised_pnl = position.realised_pnl;
-    } else {
-        realised_pnl = 0 - position.realised_pnl;
-    }
-    let res = Position {
-        size: position.size,
-        collateral: position.collateral,
-        average_price: position.average_price,
-        entry_funding_rate: position.entry_funding_rate,
-        reserve_amount: position.reserve_amount,
-        realised_pnl,
-        last_increased_time: position.last_increased_time,
-    };
-    to_binary(&(res))
+    env: Env,
+    token: Addr,
+) -> Result<Response, ContractError> {
+    let config = CIRCUIT_BREAKER_CONFIGS.may_load(deps.storage, token.clone())?;
+    _validate(config.is_some(), 107)?;
+    let config = config.unwrap();
+
+    // anyone can persist a trip while the price deviates within the window
+    roll_accepted_price(deps.storage, env, token.clone(), &config)?;
+    let (price, deviation_basis_points, last_accepted_price) =
+        get_price_deviation(deps.storage, token.clone())?;
+    let last_accepted_price = last_accepted_price.unwrap_or(AcceptedPrice {
+        price,
+        timestamp: 0,
+    });
+    _validate(
+        deviation_basis_points > config.max_deviation_basis_points,
+        107,
+    )?;
+    TRIPPED_CIRCUIT_BREAKERS.save(deps.storage, token.clone(), &true)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "trip_circuit_breaker")
+        .add_event(
+            CircuitBreaker {
+                token,
+                price,
+                last_accepted_price: last_accepted_price.price,
+                is_tripped: true,
+            }
+            .into(),
+        ))
 }
 
-fn try_get_position_leverage(
+fn try_reset_circuit_breaker(
     deps: DepsMut,
-    account: Addr,
-    collateral_token: Addr,
-    index_token: Addr,
-    is_long: bool,
-) -> StdResult<Binary> {
-    let key = get_position_key(
-        account.clone(),
-        collateral_token.clone(),
-        index_token.clone(),
-        is_long,
-    )
-    .unwrap();
-    let position = POSITIONS.load(deps.storage, &key)?;
-    _validate(position.collateral > 0, 37).unwrap();
-    let res = position
-        .size
-        .mul(BASIS_POINTS_DIVISOR)
-        .div(position.collateral);
-    to_binary(&(res))
+    info: MessageInfo,
+    env: Env,
+    token: Addr,
+) -> Result<Response, ContractError> {
+    only_guardian_or_gov(deps.storage, info.sender)?;
+
+    let (price, _, last_accepted_price) = get_price_deviation(deps.storage, token.clone())?;
+    LAST_ACCEPTED_PRICES.save(
+        deps.storage,
+        token.clone(),
+        &AcceptedPrice {
+            price,
+            timestamp: env.block.time.seconds(),
+        },
+    )?;
+    TRIPPED_CIRCUIT_BREAKERS.remove(deps.storage, token.clone());
+
+    Ok(Response::new()
+        .add_attribute("method", "reset_circuit_breaker")
+        .add_event(
+            CircuitBreaker {
+                token,
+                price,
+                last_accepted_price: last_accepted_price
+                    .map(|accepted| accepted.price)
+                    .unwrap_or_default(),
+                is_tripped: false,
+            }
+            .into(),
+        ))
 }
 
-fn try_global_short_delta(deps: DepsMut, token: Addr) -> StdResult<Binary> {
-    let global_short_sizes = GLOBAL_SHORT_SIZES.load(deps.storage, token.clone())?;
-    let global_short_average_prices =
-        GLOBAL_SHORT_AVERAGE_PRIZES.load(deps.storage, token.clone())?;
-    if global_short_sizes == 0 {
-        let res = GlobalShortDeltaResponse {
-            has_profit: false,
-            delta: 0,
+// returns the unrealised profit of up to limit positions using a collateral token after
+// start_after and the pool value of the token in usd, together with the profitable positions of
+// the page, most profitable first and ties broken by leverage, and the last key of the page
+fn get_adl_candidates(
+    storage: &mut dyn Storage,
+    env: Env,
+    collateral_token: Addr,
+    start_after: Option<Binary>,
+    limit: usize,
+) -> Result<(u128, u128, Vec<AdlCandidate>, Option<Binary>), ContractError> {
+    let start = start_after.as_deref().map(Bound::exclusive);
+    let keys = COLLATERAL_POSITIONS
+        .prefix(&collateral_token)
+        .keys(storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+    let last_key = keys.last().cloned().map(Binary::from);
+
+    let mut total_profit_usd = 0;
+    let mut candidates = vec![];
+    for key in keys {
+        let position_key = decode_position_key(&key)?;
+        let position = POSITIONS.may_load(storage, &key)?.unwrap_or_default();
+        if position.size == 0 {
+            continue;
+        }
+        let (has_profit, delta) = get_delta(
+            position_key.key_3.clone(),
+            position.size,
+            position.average_price,
+            position_key.key_4,
+            position.last_increased_time,
+            storage,
+            env.clone(),
+        )?;
+        if !has_profit || delta == 0 {
+            continue;
+        }
+        total_profit_usd = total_profit_usd.add(delta);
+        let leverage = if position.collateral == 0 {
+            u128::MAX
+        } else {
+            position
+                .size
+                .mul(BASIS_POINTS_DIVISOR)
+                .div(position.collateral)
         };
-        return to_binary(&res);
-    }
-    let next_price = get_max_price(token.clone());
-    let mut price_delta = 0;
-    if global_short_average_prices > next_price {
-        price_delta = global_short_average_prices.sub(next_price);
-    } else {
-        price_delta = next_price.sub(global_short_average_prices);
+        let (liquidation_price, _) = get_liquidation_price(
+            storage,
+            env.clone(),
+            &position,
+            position_key.key_2.clone(),
+            position_key.key_3.clone(),
+            position_key.key_4,
+        )?;
+        candidates.push(AdlCandidate {
+            account: position_key.key_1,
+            collateral_token: position_key.key_2,
+            index_token: position_key.key_3,
+            is_long: position_key.key_4,
+            size: position.size,
+            collateral: position.collateral,
+            profit_usd: delta,
+            leverage,
+            liquidation_price,
+        });
     }
-    let delta = global_short_average_prices
-        .mul(price_delta)
-        .div(global_short_average_prices);
-    let has_profit = global_short_average_prices > next_price;
-    let res = GlobalShortDeltaResponse { has_profit, delta };
-    to_binary(&(res))
+    candidates.sort_by(|a, b| {
+        b.profit_usd
+            .cmp(&a.profit_usd)
+            .then(b.leverage.cmp(&a.leverage))
+    });
+
+    let pool_amounts = POOL_AMOUNTS
+        .may_load(storage, collateral_token.clone())?
+        .unwrap_or_default();
+    let pool_usd = token_to_usd_min(collateral_token, pool_amounts, storage)?;
+    Ok((total_profit_usd, pool_usd, candidates, last_key))
 }
 
-fn try_get_position_delta(
+fn get_pnl_to_pool_basis_points(total_profit_usd: u128, pool_usd: u128) -> u128 {
+    if pool_usd == 0 {
+        if total_profit_usd == 0 {
+            return 0;
+        }
+        return u128::MAX;
+    }
+    total_profit_usd.mul(BASIS_POINTS_DIVISOR).div(pool_usd)
+}
+// adds the sent collateral to an open position without changing its size
+fn try_deposit_collateral(
     deps: DepsMut,
+    info: MessageInfo,
     env: Env,
     account: Addr,
     collateral_token: Addr,
     index_token: Addr,
     is_long: bool,
-) -> StdResult<Binary> {
-    let key = get_position_key(
-        account.clone(),
-        collateral_token.clone(),
-        index_token.clone(),
-        is_long,
-    )
-    .unwrap();
-    let position = POSITIONS.load(deps.storage, &key)?;
-
-    let res = get_delta(
-        index_token.clone(),
-        position.size,
-        position.average_price,
-        is_long,
-        position.last_increased_time,
+) -> Result<Response, ContractError> {
+    _validate_router(deps.storage, info.clone(), account.clone());
+    _validate_not_paused(
         deps.storage,
-        env,
+        Operation::IncreasePosition,
+        &[&collateral_token, &index_token],
     )?;
-    to_binary(&(res))
-}
+    _validate_circuit_breaker(
+        deps.storage,
+        env.clone(),
+        Operation::IncreasePosition,
+        &[&collateral_token, &index_token],
+    )?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
 
-fn try_get_target_usdg_amount(deps: DepsMut, env: Env, token: Addr) -> StdResult<Binary> {
-    let supply = get_token_balance_of(
+    let (key, mut position, mut events) = settle_position_funding(
+        deps.storage,
         deps.querier,
-        env.contract.address.clone(),
-        env.contract.address,
-    )
-    .unwrap();
-    let token_weights = TOKEN_WEIGHTS.load(deps.storage, token)?;
-    let state_variables = STATE_VARIABLES.load(deps.storage)?;
-    if supply == 0 {
-        return to_binary(&0);
-    }
-    let res = token_weights
-        .mul(supply)
-        .div(state_variables.total_token_weights);
-    to_binary(&(res))
-}
-
-// Helper Functions
+        env.clone(),
+        &account,
+        &collateral_token,
+        &index_token,
+        is_long,
+    )?;
 
-fn only_gov(storage: &mut dyn Storage, addr: Addr) {
-    let gov_addr = GOV.load(storage).unwrap();
-    _validate(addr == gov_addr, 53).unwrap();
-}
-fn _validate(_condition: bool, _error_code: u64) -> Result<Response, ContractError> {
-    if !_condition {
-        return Err(ContractError::CustomError {
-            val: format!("Error code: {}", _error_code),
-        });
-    }
-    Ok(Response::default())
-}
+    let collateral_delta = _transfer_in(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        info.clone(),
+        collateral_token.clone(),
+    )?;
+    _validate(collateral_delta > 0, 97)?;
+    let collateral_delta_usd =
+        token_to_usd_min(collateral_token.clone(), collateral_delta, deps.storage)?;
+    position.collateral = position.collateral.add(collateral_delta_usd);
 
-fn _validate_manager(storage: &mut dyn Storage, addr: Addr) {
-    let state_variables = STATE_VARIABLES.load(storage).unwrap();
-    let is_manager = IS_MANAGER.load(storage, addr).unwrap();
-    if state_variables.in_manager_mode {
-        _validate(is_manager, 54).unwrap();
+    if is_long {
+        // the deposited collateral is treated as part of the pool
+        events.push(decrease_guarnteed_usd(
+            deps.storage,
+            collateral_token.clone(),
+            collateral_delta_usd,
+        )?);
+        events.push(_increase_pool_amount(
+            deps.storage,
+            deps.querier,
+            env.clone(),
+            collateral_token.clone(),
+            collateral_delta,
+        )?);
     }
-}
-
-fn all_whitelisted_tokens_length(storage: &mut dyn Storage) -> Result<usize, ContractError> {
-    let state_variables = STATE_VARIABLES.load(storage)?;
-
-    Ok(state_variables.all_whitelisted_tokens.len())
-}
 
-fn get_max_price(_token: Addr) -> u128 {
-    return 1;
-}
+    let (leverage, liquidation_price, position_events) = save_collateral_change(
+        deps.storage,
+        env,
+        &key,
+        &position,
+        collateral_delta_usd,
+        true,
+    )?;
+    events.extend(position_events);
 
-fn get_min_price(_token: Addr) -> u128 {
-    return 1;
+    Ok(Response::new()
+        .add_attribute("method", "deposit_collateral")
+        .add_attribute("leverage", leverage.to_string())
+        .add_attribute("liquidation_price", liquidation_price.to_string())
+        .add_events(events))
 }
 
-fn _transfer_out(
+// removes collateralDelta usd of collateral from an open position without changing its size
+fn try_withdraw_collateral(
+    deps: DepsMut,
     info: MessageInfo,
-    token: Addr,
-    amount: u128,
+    env: Env,
+    key: Vec<u8>,
+    collateral_delta: u128,
     reciever: Addr,
 ) -> Result<Response, ContractError> {
-    let tranfr_msg = cw20::Cw20ExecuteMsg::Transfer {
-        recipient: reciever.to_string(),
-        amount: amount.into(),
-    };
-    let msg = CosmosMsg::Wasm(cosmwasm_std::WasmMsg::Execute {
-        contract_addr: token.to_string(),
-        msg: to_binary(&tranfr_msg)?,
-        funds: info.funds,
-    });
-    Ok(Response::new()
-        .add_attribute("action", "transfer_out")
-        .add_message(msg))
-}
-
-fn _transfer_in(
-    deps: &DepsMut,
-    env: Env,
-    _info: MessageInfo,
-    token: Addr,
-) -> Result<u128, ContractError> {
-    let mut token_balances = TOKEN_BALANCES.load(deps.storage, token.clone())?;
-    let prev_balance = token_balances;
-    let next_balance = get_token_balance_of(deps.querier, env.contract.address, token.clone())?;
-
-    token_balances = next_balance;
-    Ok(next_balance.sub(prev_balance))
-}
+    let position_key = decode_position_key(&key)?;
+    let account = position_key.key_1;
+    let collateral_token = position_key.key_2;
+    let index_token = position_key.key_3;
+    let is_long = position_key.key_4;
 
-fn _increase_usdg_amount(
-    storage: &mut dyn Storage,
-    token: Addr,
-    amount: u128,
-) -> Result<Response, ContractError> {
-    let mut usdg_amount = USDG_AMOUNTS.load(storage, token.clone())?;
-    let max_usdg_amount = MAX_USDG_AMOUNTS.load(storage, token.clone())?;
-    usdg_amount += amount;
+    _validate_router(deps.storage, info.clone(), account.clone());
+    _validate_not_paused(
+        deps.storage,
+        Operation::DecreasePosition,
+        &[&collateral_token, &index_token],
+    )?;
+    _validate_circuit_breaker(
+        deps.storage,
+        env.clone(),
+        Operation::DecreasePosition,
+        &[&collateral_token, &index_token],
+    )?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
+    _validate(collateral_delta > 0, 97)?;
 
-    if max_usdg_amount != 0 {
+    let (_, mut position, mut events) = settle_position_funding(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        &account,
+        &collateral_token,
+        &index_token,
+        is_long,
+    )?;
+    // the accrued borrow fee is charged first so that it can not be withdrawn
+    let (fee, fee_events) = collect_margin_fees(
+        account.clone(),
+        collateral_token.clone(),
+        0,
+        position.size,
+        position.entry_funding_rate,
+        env.clone(),
+        deps.storage,
+    )?;
+    events.extend(fee_events);
+    _validate(position.collateral >= fee.add(collateral_delta), 33)?;
+    position.collateral = position.collateral.sub(fee).sub(collateral_delta);
+    position.entry_funding_rate = get_entry_funding_rate(deps.storage, collateral_token.clone())?;
+
+    let amount_out = usd_to_token_min(collateral_token.clone(), collateral_delta, deps.storage)?;
+    if is_long {
+        events.push(increase_guarnteed_usd(
+            deps.storage,
+            collateral_token.clone(),
+            collateral_delta.add(fee),
+        )?);
+        // the fee leaves the pool for the fee reserves like the withdrawn collateral
+        let fee_tokens = usd_to_token_min(collateral_token.clone(), fee, deps.storage)?;
+        events.push(_decrease_pool_amount(
+            deps.storage,
+            env.clone(),
+            collateral_token.clone(),
+            amount_out.add(fee_tokens),
+        )?);
+    }
+
+    let (leverage, liquidation_price, position_events) =
+        save_collateral_change(deps.storage, env, &key, &position, collateral_delta, false)?;
+    events.extend(position_events);
+
+    let transfer = _transfer_out(deps.storage, info, collateral_token, amount_out, reciever)?;
+    Ok(Response::new()
+        .add_attribute("method", "withdraw_collateral")
+        .add_attribute("leverage", leverage.to_string())
+        .add_attribute("liquidation_price", liquidation_price.to_string())
+        .add_submessages(transfer.messages)
+        .add_events(events))
+}
+
+// updates the funding rates of a position's tokens and settles its skew funding
+fn settle_position_funding(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    account: &Addr,
+    collateral_token: &Addr,
+    index_token: &Addr,
+    is_long: bool,
+) -> Result<(Vec<u8>, Position, Vec<Event>), ContractError> {
+    let mut events = vec![];
+    events.extend(update_cumulative_funding_rate(
+        storage,
+        env.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+    )?);
+    let key = get_position_key(
+        account.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )?;
+    let mut position = POSITIONS.may_load(storage, &key)?.unwrap_or_default();
+    _validate(position.size > 0, 31)?;
+
+    events.extend(update_skew_funding(
+        storage,
+        env.clone(),
+        index_token.clone(),
+    )?);
+    events.extend(settle_skew_funding(
+        storage,
+        querier,
+        env,
+        &key,
+        &mut position,
+    )?);
+    Ok((key, position, events))
+}
+
+// validates a position after its collateral changed and saves it, returns its leverage and
+// liquidation price
+fn save_collateral_change(
+    storage: &mut dyn Storage,
+    env: Env,
+    key: &[u8],
+    position: &Position,
+    collateral_delta_usd: u128,
+    is_increase: bool,
+) -> Result<(u128, u128, Vec<Event>), ContractError> {
+    let position_key = decode_position_key(key)?;
+    let account = &position_key.key_1;
+    let collateral_token = &position_key.key_2;
+    let index_token = &position_key.key_3;
+    let is_long = position_key.key_4;
+
+    validate_position(position.size, position.collateral);
+    validate_position_risk(storage, key, position.size, position.collateral)?;
+    let (liquidation_state, _, _) = validate_liquidation(
+        storage,
+        env.clone(),
+        position,
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )?;
+    _validate(liquidation_state == 0, 96)?;
+    save_position(storage, key, position)?;
+
+    let price = if is_long {
+        get_min_price(storage, index_token.clone())
+    } else {
+        get_max_price(storage, index_token.clone())
+    };
+    let events = vec![
+        ChangePosition {
+            key: event_position_key(account, collateral_token, index_token, is_long),
+            is_increase,
+            collateral_delta_usd,
+            size_delta: 0,
+            price,
+            fee_usd: 0,
+        }
+        .into(),
+        PositionState {
+            key: event_position_key(account, collateral_token, index_token, is_long),
+            is_closed: false,
+            size: position.size,
+            collateral: position.collateral,
+            average_price: position.average_price,
+            entry_funding_rate: position.entry_funding_rate,
+            reserve_amount: position.reserve_amount,
+            realised_pnl: position.realised_pnl,
+            mark_price: price,
+        }
+        .into(),
+    ];
+
+    let leverage = position
+        .size
+        .mul(BASIS_POINTS_DIVISOR)
+        .div(position.collateral);
+    let (liquidation_price, _) = get_liquidation_price(
+        storage,
+        env,
+        position,
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )?;
+    Ok((leverage, liquidation_price, events))
+}
+
+// adds the sent collateral to the cross margin account of the sender, this switches the positions
+// of the sender using the collateral token to cross margin
+fn try_deposit_cross_margin(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    collateral_token: Addr,
+) -> Result<Response, ContractError> {
+    _validate_not_paused(
+        deps.storage,
+        Operation::IncreasePosition,
+        &[&collateral_token],
+    )?;
+    _validate_circuit_breaker(
+        deps.storage,
+        env.clone(),
+        Operation::IncreasePosition,
+        &[&collateral_token],
+    )?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
+    let is_whitelisted = WHITELISTED_TOKENS
+        .may_load(deps.storage, collateral_token.clone())?
+        .unwrap_or_default();
+    _validate(is_whitelisted, 116)?;
+
+    let amount = _transfer_in(
+        deps.storage,
+        deps.querier,
+        env,
+        info.clone(),
+        collateral_token.clone(),
+    )?;
+    _validate(amount > 0, 97)?;
+
+    let balance = CROSS_MARGIN_BALANCES
+        .may_load(
+            deps.storage,
+            (info.sender.clone(), collateral_token.clone()),
+        )?
+        .unwrap_or_default()
+        .add(amount);
+    CROSS_MARGIN_BALANCES.save(
+        deps.storage,
+        (info.sender.clone(), collateral_token.clone()),
+        &balance,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "deposit_cross_margin")
+        .add_event(
+            UpdateCrossMargin {
+                account: info.sender,
+                collateral_token,
+                delta: amount,
+                is_increase: true,
+                balance,
+            }
+            .into(),
+        ))
+}
+
+// withdraws from the cross margin account of the sender as long as the account stays above its
+// maintenance margin, the account goes back to isolated margin once it is empty
+fn try_withdraw_cross_margin(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    collateral_token: Addr,
+    amount: u128,
+    reciever: Addr,
+) -> Result<Response, ContractError> {
+    _validate_not_paused(
+        deps.storage,
+        Operation::DecreasePosition,
+        &[&collateral_token],
+    )?;
+    _validate_circuit_breaker(
+        deps.storage,
+        env.clone(),
+        Operation::DecreasePosition,
+        &[&collateral_token],
+    )?;
+    // balances stay withdrawable in settlement mode, the positions of the account are valued at
+    // the settlement prices
+    _validate(amount > 0, 97)?;
+
+    let account = info.sender.clone();
+    let (balance, equity, maintenance_margin, _) =
+        get_cross_margin_health(deps.storage, env, &account, &collateral_token)?;
+    _validate(amount <= balance, 101)?;
+    // the balance is valued at the current price, the same way as in the equity
+    let amount_usd = token_to_usd_min(collateral_token.clone(), amount, deps.storage)?;
+    _validate(equity.saturating_sub(amount_usd) >= maintenance_margin, 102)?;
+
+    let balance = balance.sub(amount);
+    if balance == 0 {
+        CROSS_MARGIN_BALANCES.remove(deps.storage, (account.clone(), collateral_token.clone()));
+    } else {
+        CROSS_MARGIN_BALANCES.save(
+            deps.storage,
+            (account.clone(), collateral_token.clone()),
+            &balance,
+        )?;
+    }
+
+    let transfer = _transfer_out(
+        deps.storage,
+        info,
+        collateral_token.clone(),
+        amount,
+        reciever,
+    )?;
+    Ok(Response::new()
+        .add_attribute("method", "withdraw_cross_margin")
+        .add_submessages(transfer.messages)
+        .add_event(
+            UpdateCrossMargin {
+                account,
+                collateral_token,
+                delta: amount,
+                is_increase: false,
+                balance,
+            }
+            .into(),
+        ))
+}
+
+// closes every position of a cross margin account once its equity is below the maintenance
+// margin of its positions, profitable positions are closed at market into the account and the
+// balance of the account then pays for the losses of the liquidated positions before the
+// insurance fund, whatever is left stays in the account
+fn try_liquidate_cross_margin(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    account: Addr,
+    collateral_token: Addr,
+    fee_reciever: Addr,
+) -> Result<Response, ContractError> {
+    let state_variables = STATE_VARIABLES.load(deps.storage)?;
+    if state_variables.in_private_liquidation_mode {
+        let is_liquidator = IS_LIQUIDATOR
+            .may_load(deps.storage, info.sender.clone())?
+            .unwrap_or_default();
+        _validate(is_liquidator, 34)?;
+    }
+    _validate_not_paused(
+        deps.storage,
+        Operation::LiquidatePosition,
+        &[&collateral_token],
+    )?;
+    _validate_circuit_breaker(
+        deps.storage,
+        env.clone(),
+        Operation::LiquidatePosition,
+        &[&collateral_token],
+    )?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
+    _validate(
+        CROSS_MARGIN_BALANCES.has(deps.storage, (account.clone(), collateral_token.clone())),
+        100,
+    )?;
+
+    // funding is settled first so that the collateral of every position is up to date
+    let mut events = vec![];
+    for (_, position_key, _) in
+        get_cross_margin_positions(deps.storage, &account, &collateral_token)?
+    {
+        let (key, position, funding_events) = settle_position_funding(
+            deps.storage,
+            deps.querier,
+            env.clone(),
+            &account,
+            &collateral_token,
+            &position_key.key_3,
+            position_key.key_4,
+        )?;
+        save_position(deps.storage, &key, &position)?;
+        events.extend(funding_events);
+    }
+
+    let (_, equity, maintenance_margin, positions) =
+        get_cross_margin_health(deps.storage, env.clone(), &account, &collateral_token)?;
+    _validate(equity < maintenance_margin, 103)?;
+
+    // profitable positions are closed at market and their collateral and profit are credited to
+    // the account so that they cover the losing positions that are liquidated afterwards
+    let mut losing_positions = vec![];
+    for (key, position_key, position) in positions.iter().cloned() {
+        let (has_profit, delta) = get_delta(
+            position_key.key_3.clone(),
+            position.size,
+            position.average_price,
+            position_key.key_4,
+            position.last_increased_time,
+            deps.storage,
+            env.clone(),
+        )?;
+        if !has_profit || delta == 0 {
+            losing_positions.push((key, position, delta));
+            continue;
+        }
+        let (amount_out_after_fees, decrease_events) = _decrease_position(
+            deps.storage,
+            deps.querier,
+            env.clone(),
+            &key,
+            0,
+            position.size,
+            TradeAction::Liquidation,
+        )?;
+        events.extend(decrease_events);
+        let balance = CROSS_MARGIN_BALANCES
+            .may_load(deps.storage, (account.clone(), collateral_token.clone()))?
+            .unwrap_or_default()
+            .add(amount_out_after_fees);
+        CROSS_MARGIN_BALANCES.save(
+            deps.storage,
+            (account.clone(), collateral_token.clone()),
+            &balance,
+        )?;
+        events.push(
+            UpdateCrossMargin {
+                account: account.clone(),
+                collateral_token: collateral_token.clone(),
+                delta: amount_out_after_fees,
+                is_increase: true,
+                balance,
+            }
+            .into(),
+        );
+    }
+
+    let margin_fee_basis_points = state_variables.margin_fee_basis_points;
+    let mut response = Response::new();
+    for (key, mut position, loss_usd) in losing_positions {
+        let margin_fees = position
+            .size
+            .mul(margin_fee_basis_points)
+            .div(BASIS_POINTS_DIVISOR)
+            .add(get_pending_funding_fee(
+                deps.storage,
+                env.clone(),
+                collateral_token.clone(),
+                &position,
+            )?);
+
+        // the account covers the losses and fees of the position as far as it can
+        events.extend(draw_cross_margin(
+            deps.storage,
+            deps.querier,
+            env.clone(),
+            &key,
+            &mut position,
+            loss_usd
+                .add(margin_fees)
+                .add(state_variables.liquidation_fee_usd),
+        )?);
+        save_position(deps.storage, &key, &position)?;
+
+        let (liquidation_events, transfer) = _liquidate_position(
+            deps.storage,
+            deps.querier,
+            env.clone(),
+            info.clone(),
+            &key,
+            &position,
+            Liquidation {
+                margin_fees,
+                loss_usd,
+                fee_reciever: fee_reciever.clone(),
+            },
+        )?;
+        events.extend(liquidation_events);
+        response = response.add_submessages(transfer.messages);
+    }
+    // an emptied account goes back to isolated margin
+    let balance = CROSS_MARGIN_BALANCES
+        .may_load(deps.storage, (account.clone(), collateral_token.clone()))?
+        .unwrap_or_default();
+    if balance == 0 {
+        CROSS_MARGIN_BALANCES.remove(deps.storage, (account.clone(), collateral_token.clone()));
+    }
+
+    events.push(
+        LiquidateCrossMargin {
+            account,
+            collateral_token,
+            equity,
+            maintenance_margin,
+            positions: positions.len() as u32,
+            fee_reciever,
+        }
+        .into(),
+    );
+    Ok(response
+        .add_attribute("method", "liquidate_cross_margin")
+        .add_events(events))
+}
+
+// offers a position of the sender to another account, the position only moves once the receiver
+// accepts the transfer
+fn try_transfer_position(
+    deps: DepsMut,
+    key: Vec<u8>,
+    to: Addr,
+    merge: bool,
+) -> Result<Response, ContractError> {
+    let position_key = decode_position_key(&key)?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
+    _validate(position_key.key_1 != to, 98)?;
+    let position = POSITIONS.may_load(deps.storage, &key)?.unwrap_or_default();
+    _validate(position.size > 0, 31)?;
+
+    PENDING_POSITION_TRANSFERS.save(
+        deps.storage,
+        &key,
+        &PositionTransfer {
+            to: to.clone(),
+            merge,
+        },
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "transfer_position")
+        .add_attribute("to", to.clone())
+        .add_event(
+            PositionTransferRequest {
+                key: event_position_key(
+                    &position_key.key_1,
+                    &position_key.key_2,
+                    &position_key.key_3,
+                    position_key.key_4,
+                ),
+                is_cancelled: false,
+                to,
+                merge,
+            }
+            .into(),
+        ))
+}
+
+fn try_cancel_position_transfer(deps: DepsMut, key: Vec<u8>) -> Result<Response, ContractError> {
+    let position_key = decode_position_key(&key)?;
+    let transfer = PENDING_POSITION_TRANSFERS.may_load(deps.storage, &key)?;
+    _validate(transfer.is_some(), 117)?;
+    let transfer = transfer.unwrap();
+    PENDING_POSITION_TRANSFERS.remove(deps.storage, &key);
+
+    Ok(Response::new()
+        .add_attribute("method", "cancel_position_transfer")
+        .add_event(
+            PositionTransferRequest {
+                key: event_position_key(
+                    &position_key.key_1,
+                    &position_key.key_2,
+                    &position_key.key_3,
+                    position_key.key_4,
+                ),
+                is_cancelled: true,
+                to: transfer.to,
+                merge: transfer.merge,
+            }
+            .into(),
+        ))
+}
+
+// moves a position to the receiver of its pending transfer, an existing position of the receiver
+// is only merged with it when the sender requested it
+fn try_accept_position_transfer(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    key: Vec<u8>,
+) -> Result<Response, ContractError> {
+    let position_key = decode_position_key(&key)?;
+    let account = position_key.key_1;
+    let collateral_token = position_key.key_2;
+    let index_token = position_key.key_3;
+    let is_long = position_key.key_4;
+
+    let transfer = PENDING_POSITION_TRANSFERS.may_load(deps.storage, &key)?;
+    _validate(
+        matches!(&transfer, Some(transfer) if transfer.to == info.sender),
+        117,
+    )?;
+    let PositionTransfer { to, merge } = transfer.unwrap();
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
+
+    let (_, position, mut events) = settle_position_funding(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        &account,
+        &collateral_token,
+        &index_token,
+        is_long,
+    )?;
+    let to_key = get_position_key(
+        to.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )?;
+    let mut to_position = POSITIONS
+        .may_load(deps.storage, &to_key)?
+        .unwrap_or_default();
+    let merged = to_position.size > 0;
+
+    if merged {
+        _validate(merge, 99)?;
+        events.extend(settle_skew_funding(
+            deps.storage,
+            deps.querier,
+            env.clone(),
+            &to_key,
+            &mut to_position,
+        )?);
+        // the average price keeps the combined delta equal to the sum of both deltas
+        let size = Uint256::from(position.size);
+        let to_size = Uint256::from(to_position.size);
+        let average_price = Uint256::from(position.average_price);
+        let to_average_price = Uint256::from(to_position.average_price);
+        let next_average_price = (size + to_size) * average_price * to_average_price
+            / (size * to_average_price + to_size * average_price);
+        to_position.average_price = Uint128::try_from(next_average_price)
+            .map_err(StdError::from)?
+            .u128();
+        // the size weighted entry funding rate keeps the borrow fees owed by both positions
+        let next_entry_funding_rate = (Uint256::from(to_position.entry_funding_rate) * to_size
+            + Uint256::from(position.entry_funding_rate) * size)
+            / (size + to_size);
+        to_position.entry_funding_rate = Uint128::try_from(next_entry_funding_rate)
+            .map_err(StdError::from)?
+            .u128();
+        to_position.size = to_position.size.add(position.size);
+        to_position.collateral = to_position.collateral.add(position.collateral);
+        to_position.reserve_amount = to_position.reserve_amount.add(position.reserve_amount);
+        to_position.realised_pnl = to_position.realised_pnl.add(position.realised_pnl);
+        to_position.last_increased_time = to_position
+            .last_increased_time
+            .max(position.last_increased_time);
+        validate_position(to_position.size, to_position.collateral);
+    } else {
+        // the cross margin account of the receiver doesn't back the position until it draws on it
+        to_position = position;
+        to_position.cross_margin = false;
+    }
+    // the position counts toward the limits of the receiver either way and may not be
+    // liquidatable without the cross margin account of the sender
+    let (liquidation_state, _, _) = validate_liquidation(
+        deps.storage,
+        env,
+        &to_position,
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )?;
+    _validate(liquidation_state == 0, 96)?;
+    validate_position_risk(
+        deps.storage,
+        &to_key,
+        to_position.size,
+        to_position.collateral,
+    )?;
+
+    remove_position(deps.storage, &key)?;
+    save_position(deps.storage, &to_key, &to_position)?;
+
+    events.push(
+        TransferPosition {
+            key: event_position_key(&account, &collateral_token, &index_token, is_long),
+            to: to.clone(),
+            size: to_position.size,
+            collateral: to_position.collateral,
+            merged,
+        }
+        .into(),
+    );
+
+    Ok(Response::new()
+        .add_attribute("method", "transfer_position")
+        .add_attribute("to", to)
+        .add_events(events))
+}
+
+fn try_liquidate_position(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    account: Addr,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+    _fee_reciever: Addr,
+) -> Result<Response, ContractError> {
+    let mut state_variables = STATE_VARIABLES.load(deps.storage)?;
+    let is_liquidator = IS_LIQUIDATOR.load(deps.storage, info.sender.clone())?;
+    if state_variables.in_private_liquidation_mode {
+        _validate(is_liquidator, 34)?;
+    }
+    _validate_not_paused(
+        deps.storage,
+        Operation::LiquidatePosition,
+        &[&collateral_token, &index_token],
+    )?;
+    _validate_circuit_breaker(
+        deps.storage,
+        env.clone(),
+        Operation::LiquidatePosition,
+        &[&collateral_token, &index_token],
+    )?;
+    _validate(!is_settlement_mode(deps.storage)?, 59)?;
+
+    // set includeAmmPrice to false to prevent manipulated liquidations
+    state_variables.include_amm_price = false;
+
+    let mut events = vec![];
+    events.extend(update_cumulative_funding_rate(
+        deps.storage,
+        env.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+    )?);
+    let key = get_position_key(
+        account.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )?;
+    let position = POSITIONS.load(deps.storage, &key)?;
+    _validate(position.size > 0, 35)?;
+    // positions drawing on a cross margin account are only liquidated together with the account
+    _validate(
+        !position.cross_margin
+            || !CROSS_MARGIN_BALANCES
+                .has(deps.storage, (account.clone(), collateral_token.clone())),
+        100,
+    )?;
+
+    let (liquidation_state, margin_fees, loss_usd) = validate_liquidation(
+        deps.storage,
+        env.clone(),
+        &position,
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )?;
+    _validate(liquidation_state != 0, 36)?;
+
+    if liquidation_state == 2 {
+        // max leverage exceeded but there is collateral remaining after deducting losses so decreasePosition instead,
+        // large positions are only decreased until they are back under max leverage
+        let size_delta =
+            get_partial_liquidation_size(deps.storage, &position, index_token.clone(), loss_usd)?;
+        let (amount_out_after_fees, decrease_events) = _decrease_position(
+            deps.storage,
+            deps.querier,
+            env.clone(),
+            &key,
+            0,
+            size_delta,
+            TradeAction::Liquidation,
+        )?;
+        events.extend(decrease_events);
+        record_stats(
+            deps.storage,
+            env.clone(),
+            index_token.clone(),
+            TokenStats {
+                liquidation_volume_usd: size_delta,
+                liquidations: 1,
+                ..Default::default()
+            },
+        )?;
+        let mut response = Response::new().add_attribute("method", "liquidate_position");
+        if amount_out_after_fees > 0 {
+            let transfer = _transfer_out(
+                deps.storage,
+                info.clone(),
+                collateral_token.clone(),
+                amount_out_after_fees,
+                account.clone(),
+            )?;
+            response = response.add_submessages(transfer.messages);
+        }
+        if size_delta < position.size {
+            // the liquidator is paid for the closed share of the position out of its remaining collateral
+            let fee_usd = state_variables
+                .liquidation_fee_usd
+                .mul(size_delta)
+                .div(position.size);
+            let mut remaining = POSITIONS.load(deps.storage, &key)?;
+            _validate(remaining.collateral > fee_usd, 95)?;
+            remaining.collateral = remaining.collateral.sub(fee_usd);
+            save_position(deps.storage, &key, &remaining)?;
+
+            let fee_tokens = usd_to_token_min(collateral_token.clone(), fee_usd, deps.storage)?;
+            record_stats(
+                deps.storage,
+                env.clone(),
+                collateral_token.clone(),
+                TokenStats {
+                    liquidation_fees_usd: fee_usd,
+                    ..Default::default()
+                },
+            )?;
+            // the collateral of longs is part of the pool, the collateral of shorts is held
+            // outside of it so paying the fee out of it leaves the pool unchanged
+            if is_long {
+                events.push(increase_guarnteed_usd(
+                    deps.storage,
+                    collateral_token.clone(),
+                    fee_usd,
+                )?);
+                events.push(_decrease_pool_amount(
+                    deps.storage,
+                    env,
+                    collateral_token.clone(),
+                    fee_tokens,
+                )?);
+            }
+            let transfer = _transfer_out(
+                deps.storage,
+                info,
+                collateral_token.clone(),
+                fee_tokens,
+                _fee_reciever.clone(),
+            )?;
+            response = response.add_submessages(transfer.messages);
+
+            let mark_price = if is_long {
+                get_min_price(deps.storage, index_token.clone())
+            } else {
+                get_max_price(deps.storage, index_token.clone())
+            };
+            events.push(
+                PartialLiquidatePosition {
+                    key: event_position_key(&account, &collateral_token, &index_token, is_long),
+                    size_delta,
+                    size: remaining.size,
+                    collateral: remaining.collateral,
+                    fee_usd,
+                    mark_price,
+                    fee_reciever: _fee_reciever,
+                }
+                .into(),
+            );
+        }
+        return Ok(response.add_events(events));
+    }
+    let mark_price = if is_long {
+        get_min_price(deps.storage, index_token.clone())
+    } else {
+        get_max_price(deps.storage, index_token.clone())
+    };
+    let attributes = vec![
+        ("acount", account.to_string()),
+        ("collateral_token", collateral_token.to_string()),
+        ("index_token", index_token.to_string()),
+        ("is_long", is_long.to_string()),
+        ("size", position.size.to_string()),
+        ("collateral", position.collateral.to_string()),
+        ("reserve_amount", position.reserve_amount.to_string()),
+        ("realised_pnl", position.realised_pnl.to_string()),
+        ("mark_price", mark_price.to_string()),
+    ];
+    let (liquidation_events, transfer) = _liquidate_position(
+        deps.storage,
+        deps.querier,
+        env,
+        info,
+        &key,
+        &position,
+        Liquidation {
+            margin_fees,
+            loss_usd,
+            fee_reciever: _fee_reciever,
+        },
+    )?;
+    events.extend(liquidation_events);
+
+    state_variables.include_amm_price = true;
+
+    STATE_VARIABLES.save(deps.storage, &state_variables)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "liquidate_position")
+        .add_attributes(attributes)
+        .add_submessages(transfer.messages)
+        .add_events(events))
+}
+
+// emergency shutdown, prices are frozen at the provided snapshot and the vault only allows
+// positions to be settled and USDG to be redeemed pro rata afterwards
+fn try_enable_settlement_mode(
+    deps: DepsMut,
+    info: MessageInfo,
+    _env: Env,
+    prices: Vec<SettlementPrice>,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+    _validate(!is_settlement_mode(deps.storage)?, 60)?;
+
+    for settlement_price in prices.iter() {
+        _validate(settlement_price.price > 0, 61)?;
+        SETTLEMENT_PRICES.save(
+            deps.storage,
+            settlement_price.token.clone(),
+            &settlement_price.price,
+        )?;
+    }
+
+    // every whitelisted token needs a frozen price and its USDG debt becomes redeemable
+    let state_variables = STATE_VARIABLES.load(deps.storage)?;
+    let mut usdg_supply: u128 = 0;
+    for token in state_variables.all_whitelisted_tokens {
+        let is_whitelisted = WHITELISTED_TOKENS
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        if !is_whitelisted {
+            continue;
+        }
+        _validate(SETTLEMENT_PRICES.has(deps.storage, token.clone()), 62)?;
+        let usdg_amount = USDG_AMOUNTS
+            .may_load(deps.storage, token)?
+            .unwrap_or_default();
+        usdg_supply = usdg_supply.add(usdg_amount);
+    }
+
+    SETTLEMENT_USDG_SUPPLY.save(deps.storage, &usdg_supply)?;
+    IS_SETTLEMENT_MODE.save(deps.storage, &true)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "enable_settlement_mode")
+        .add_attribute("usdg_supply", usdg_supply.to_string()))
+}
+
+// closes open positions at the settlement price, anyone can call this until no positions remain
+fn try_settle_positions(
+    deps: DepsMut,
+    _info: MessageInfo,
+    env: Env,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    _validate(is_settlement_mode(deps.storage)?, 63)?;
+
+    // positions that failed to settle are only retried once every other position is settled
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let mut keys = vec![];
+    for key in POSITIONS.keys(deps.storage, None, None, Order::Ascending) {
+        let key = key?;
+        if !SETTLEMENT_FAILURES.has(deps.storage, &key) {
+            keys.push(key);
+            if keys.len() == limit {
+                break;
+            }
+        }
+    }
+    if keys.is_empty() {
+        keys = SETTLEMENT_FAILURES
+            .keys(deps.storage, None, None, Order::Ascending)
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?;
+    }
+
+    // every position is settled in its own submessage so a failing position only reverts itself
+    let mut submessages = vec![];
+    for (id, key) in keys.into_iter().enumerate() {
+        let id = id as u64;
+        SETTLEMENT_FAILURES.remove(deps.storage, &key);
+        PENDING_SETTLEMENTS.save(deps.storage, id, &key)?;
+        let msg = WasmMsg::Execute {
+            contract_addr: env.contract.address.to_string(),
+            msg: to_json_binary(&ExecuteMsg::SettlePosition {
+                key: Binary::from(key),
+            })?,
+            funds: vec![],
+        };
+        submessages.push(SubMsg::reply_always(msg, id));
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "settle_positions")
+        .add_attribute("settled", submessages.len().to_string())
+        .add_submessages(submessages))
+}
+
+fn try_settle_position(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    key: Binary,
+) -> Result<Response, ContractError> {
+    _validate(is_settlement_mode(deps.storage)?, 63)?;
+    _validate(info.sender == env.contract.address, 111)?;
+
+    let position = match POSITIONS.may_load(deps.storage, key.as_slice())? {
+        Some(position) => position,
+        None => return Ok(Response::new().add_attribute("method", "settle_position")),
+    };
+    let position_key = decode_position_key(key.as_slice())?;
+    let (amount_out, events) =
+        settle_position(deps.storage, deps.querier, env, key.as_slice(), position)?;
+
+    let mut response = Response::new()
+        .add_attribute("method", "settle_position")
+        .add_events(events);
+    if amount_out > 0 {
+        let transfer = _transfer_out(
+            deps.storage,
+            info,
+            position_key.key_2,
+            amount_out,
+            position_key.key_1,
+        )?;
+        response = response.add_submessages(transfer.messages);
+    }
+    Ok(response)
+}
+
+// removes a position that failed to settle without paying out anything, its reserves are released
+// and the collateral is left to the pool so that it is redeemed by the LPs
+fn try_write_off_position(
+    deps: DepsMut,
+    info: MessageInfo,
+    _env: Env,
+    key: Binary,
+) -> Result<Response, ContractError> {
+    only_gov(deps.storage, info.sender);
+    _validate(is_settlement_mode(deps.storage)?, 63)?;
+    _validate(SETTLEMENT_FAILURES.has(deps.storage, key.as_slice()), 115)?;
+
+    let position = POSITIONS.load(deps.storage, key.as_slice())?;
+    let position_key = decode_position_key(key.as_slice())?;
+    let collateral_token = position_key.key_2;
+    let index_token = position_key.key_3;
+    let is_long = position_key.key_4;
+
+    // the accounting of a position that can't be settled may be off, so nothing here may fail
+    let reserved_amount = RSERVED_AMOUNTS
+        .may_load(deps.storage, collateral_token.clone())?
+        .unwrap_or_default()
+        .saturating_sub(position.reserve_amount);
+    RSERVED_AMOUNTS.save(deps.storage, collateral_token.clone(), &reserved_amount)?;
+    let mut events = vec![decrease_global_size(
+        deps.storage,
+        index_token.clone(),
+        is_long,
+        position.size,
+    )?];
+    if is_long {
+        let guaranteed_usd = GUARANTEED_USD
+            .may_load(deps.storage, collateral_token.clone())?
+            .unwrap_or_default()
+            .saturating_sub(position.size.saturating_sub(position.collateral));
+        GUARANTEED_USD.save(deps.storage, collateral_token.clone(), &guaranteed_usd)?;
+    } else {
+        let collateral =
+            usd_to_token_min(collateral_token.clone(), position.collateral, deps.storage)?;
+        let pool_amount = POOL_AMOUNTS
+            .may_load(deps.storage, collateral_token.clone())?
+            .unwrap_or_default()
+            .add(collateral);
+        POOL_AMOUNTS.save(deps.storage, collateral_token.clone(), &pool_amount)?;
+    }
+
+    remove_position(deps.storage, key.as_slice())?;
+    SETTLEMENT_FAILURES.remove(deps.storage, key.as_slice());
+    events.push(
+        WriteOffPosition {
+            key: event_position_key(
+                &position_key.key_1,
+                &collateral_token,
+                &index_token,
+                is_long,
+            ),
+            size: position.size,
+            collateral: position.collateral,
+        }
+        .into(),
+    );
+
+    Ok(Response::new()
+        .add_attribute("method", "write_off_position")
+        .add_events(events))
+}
+
+// USDG holders, who are the LPs of this vault, burn USDG for a pro rata share of poolAmounts
+// once every position has been settled
+fn try_redeem_settlement(
+    deps: DepsMut,
+    info: MessageInfo,
+    env: Env,
+    _reciever: Addr,
+) -> Result<Response, ContractError> {
+    _validate(is_settlement_mode(deps.storage)?, 63)?;
+    let has_open_positions = POSITIONS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .next()
+        .is_some();
+    _validate(!has_open_positions, 64)?;
+
+    let addresses = ADDRESSES.load(deps.storage)?;
+    let usdg_amount = _transfer_in(
+        deps.storage,
+        deps.querier,
+        env.clone(),
+        info.clone(),
+        addresses.usdg.clone(),
+    )?;
+    _validate(usdg_amount > 0, 65)?;
+    let usdg_supply = SETTLEMENT_USDG_SUPPLY.load(deps.storage)?;
+    _validate(usdg_amount <= usdg_supply, 66)?;
+
+    let state_variables = STATE_VARIABLES.load(deps.storage)?;
+    let mut response = Response::new();
+    for token in state_variables.all_whitelisted_tokens {
+        let is_whitelisted = WHITELISTED_TOKENS
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        if !is_whitelisted {
+            continue;
+        }
+        // the redeemed USDG is no longer a debt of any token
+        let token_usdg_amount = USDG_AMOUNTS
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        let usdg_delta = Uint128::from(token_usdg_amount)
+            .multiply_ratio(usdg_amount, usdg_supply)
+            .u128();
+        if usdg_delta > 0 {
+            response = response.add_event(_decrease_usdg_amount(
+                deps.storage,
+                token.clone(),
+                usdg_delta,
+            )?);
+        }
+        let pool_amount = POOL_AMOUNTS
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        let amount_out = Uint128::from(pool_amount)
+            .multiply_ratio(usdg_amount, usdg_supply)
+            .u128();
+        if amount_out == 0 {
+            continue;
+        }
+        let event = _decrease_pool_amount(deps.storage, env.clone(), token.clone(), amount_out)?;
+        let transfer = _transfer_out(
+            deps.storage,
+            info.clone(),
+            token.clone(),
+            amount_out,
+            _reciever.clone(),
+        )?;
+        response = response
+            .add_submessages(transfer.messages)
+            .add_event(event)
+            .add_event(
+                RedeemSettlement {
+                    reciever: _reciever.clone(),
+                    token,
+                    usdg_amount,
+                    amount_out,
+                }
+                .into(),
+            );
+    }
+    SETTLEMENT_USDG_SUPPLY.save(deps.storage, &usdg_supply.sub(usdg_amount))?;
+
+    // IUSDG(usdg).burn(address(this), usdgAmount);  // don.t have this function so it could have been a cw20 token function but haven't added
+    _update_token_balance(deps.storage, deps.querier, env, addresses.usdg)?;
+
+    Ok(response
+        .add_attribute("method", "redeem_settlement")
+        .add_attribute("reciever", _reciever.to_string())
+        .add_attribute("usdg_amount", usdg_amount.to_string()))
+}
+
+// Query
+
+fn try_get_redemption_collateral(deps: DepsMut, token: Addr) -> StdResult<Binary> {
+    let stable_token = STABLE_TOKENS.load(deps.storage, token.clone())?;
+    let pool_amounts = POOL_AMOUNTS.load(deps.storage, token.clone())?;
+    let guaranteed_usd = GUARANTEED_USD.load(deps.storage, token.clone())?;
+    let reserved_amount = RSERVED_AMOUNTS.load(deps.storage, token.clone())?;
+    if stable_token {
+        return to_binary(&RedemptionCollateralResponse {
+            amount: pool_amounts,
+        });
+    }
+
+    let collateral = usd_to_token_min(token.clone(), guaranteed_usd, deps.storage)?;
+    let res = RedemptionCollateralResponse {
+        amount: collateral.add(pool_amounts).sub(reserved_amount),
+    };
+    to_binary(&(res))
+}
+
+fn try_get_redemption_collateral_usd(deps: DepsMut, token: Addr) -> StdResult<Binary> {
+    let stable_token = STABLE_TOKENS.load(deps.storage, token.clone())?;
+    let pool_amounts = POOL_AMOUNTS.load(deps.storage, token.clone())?;
+    let guaranteed_usd = GUARANTEED_USD.load(deps.storage, token.clone())?;
+    let reserved_amount = RSERVED_AMOUNTS.load(deps.storage, token.clone())?;
+    if stable_token {
+        return to_binary(&RedemptionCollateralUsdResponse {
+            amount_usd: pool_amounts,
+        });
+    }
+
+    let collateral = usd_to_token_min(token.clone(), guaranteed_usd, deps.storage)?;
+    let amount = collateral.add(pool_amounts).sub(reserved_amount);
+    let res = RedemptionCollateralUsdResponse {
+        amount_usd: token_to_usd_min(token.clone(), amount, deps.storage)?,
+    };
+    to_binary(&(res))
+}
+
+fn try_get_trade_history(
+    deps: DepsMut,
+    account: Addr,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<Binary> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let trades = TRADE_HISTORY
+        .prefix(account)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let res = TradeHistoryResponse { trades };
+    to_json_binary(&(res))
+}
+
+fn try_get_position_trade_history(
+    deps: DepsMut,
+    account: Addr,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<Binary> {
+    let key = get_position_key(account.clone(), collateral_token, index_token, is_long)
+        .map_err(|e| StdError::generic_err(e.to_string()))?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let indexes = POSITION_TRADES
+        .prefix(&key)
+        .keys(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let mut trades = vec![];
+    for index in indexes {
+        let trade = TRADE_HISTORY.load(deps.storage, (account.clone(), index))?;
+        trades.push((index, trade));
+    }
+    let res = TradeHistoryResponse { trades };
+    to_json_binary(&(res))
+}
+
+fn try_get_stats(deps: DepsMut, token: Addr) -> StdResult<Binary> {
+    let res = StatsResponse {
+        stats: TOKEN_STATS
+            .may_load(deps.storage, token)?
+            .unwrap_or_default(),
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_daily_stats(
+    deps: DepsMut,
+    token: Addr,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<Binary> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let days = DAILY_TOKEN_STATS
+        .prefix(token)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let res = DailyStatsResponse { days };
+    to_json_binary(&(res))
+}
+
+fn try_get_funding_rate_info(deps: DepsMut, env: Env, tokens: Vec<Addr>) -> StdResult<Binary> {
+    let mut rates = vec![];
+    for token in tokens.into_iter().take(MAX_LIMIT as usize) {
+        let utilisation = get_utilisation(deps.storage, token.clone())?;
+        let model = get_borrow_rate_model(deps.storage, token.clone())?;
+        let last_funding_time = LAST_FUNDING_TIMES
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        let next_funding_rate = if last_funding_time == 0 {
+            0
+        } else {
+            get_next_funding_rate(deps.storage, env.clone(), token.clone())
+                .map_err(|e| StdError::generic_err(e.to_string()))?
+        };
+
+        rates.push(FundingRateInfo {
+            funding_rate: get_borrow_rate(&model, utilisation),
+            next_funding_rate,
+            cumulative_funding_rate: COMMULATIVE_FUNDING_RATES
+                .may_load(deps.storage, token.clone())?
+                .unwrap_or_default(),
+            last_funding_time,
+            token,
+        });
+    }
+
+    let res = FundingRateInfoResponse { rates };
+    to_json_binary(&(res))
+}
+
+fn try_get_funding_history(
+    deps: DepsMut,
+    token: Addr,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<Binary> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let history = FUNDING_HISTORY
+        .prefix(token)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let res = FundingHistoryResponse {
+        retention: FUNDING_HISTORY_RETENTION
+            .may_load(deps.storage)?
+            .unwrap_or(DEFAULT_FUNDING_HISTORY_RETENTION),
+        history,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_utilisation(deps: DepsMut, token: Addr) -> StdResult<Binary> {
+    let utilisation = get_utilisation(deps.storage, token.clone())?;
+    let model = get_borrow_rate_model(deps.storage, token)?;
+    let funding_interval = STATE_VARIABLES.load(deps.storage)?.funding_interval as u128;
+
+    let borrow_rate = get_borrow_rate(&model, utilisation);
+    let annualised_borrow_rate = if funding_interval == 0 {
+        0
+    } else {
+        borrow_rate.mul(SECONDS_PER_YEAR).div(funding_interval)
+    };
+    let res = UtilisationResponse {
+        utilisation,
+        model,
+        borrow_rate,
+        annualised_borrow_rate,
+    };
+    to_binary(&(res))
+}
+
+fn try_get_position(
+    deps: DepsMut,
+    env: Env,
+    account: Addr,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+) -> StdResult<Binary> {
+    let key = get_position_key(
+        account.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )
+    .unwrap();
+    let position = POSITIONS.load(deps.storage, &key)?;
+    let realised_pnl;
+    if position.realised_pnl > 0 {
+        realised_pnl = position.realised_pnl;
+    } else {
+        realised_pnl = 0 - position.realised_pnl;
+    }
+    let (liquidation_price, _) = get_liquidation_price(
+        deps.storage,
+        env,
+        &position,
+        collateral_token,
+        index_token,
+        is_long,
+    )?;
+    let res = PositionResponse {
+        size: position.size,
+        collateral: position.collateral,
+        average_price: position.average_price,
+        entry_funding_rate: position.entry_funding_rate,
+        reserve_amount: position.reserve_amount,
+        realised_pnl,
+        last_increased_time: position.last_increased_time,
+        entry_skew_funding: position.entry_skew_funding,
+        cross_margin: position.cross_margin,
+        liquidation_price,
+    };
+    to_binary(&(res))
+}
+
+fn try_get_cross_margin_account(
+    deps: DepsMut,
+    env: Env,
+    account: Addr,
+    collateral_token: Addr,
+) -> StdResult<Binary> {
+    let (balance, equity, maintenance_margin, positions) =
+        get_cross_margin_health(deps.storage, env, &account, &collateral_token)
+            .map_err(|e| StdError::generic_err(e.to_string()))?;
+
+    let balance_usd = token_to_usd_min(collateral_token.clone(), balance, deps.storage)?;
+    let res = CrossMarginAccountResponse {
+        balance,
+        balance_usd,
+        equity,
+        maintenance_margin,
+        is_liquidatable: CROSS_MARGIN_BALANCES.has(deps.storage, (account, collateral_token))
+            && equity < maintenance_margin,
+        positions: positions
+            .into_iter()
+            .map(|(_, position_key, position)| CrossMarginPosition {
+                index_token: position_key.key_3,
+                is_long: position_key.key_4,
+                size: position.size,
+                collateral: position.collateral,
+            })
+            .collect(),
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_liquidation_price(
+    deps: DepsMut,
+    env: Env,
+    account: Addr,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+) -> StdResult<Binary> {
+    let key = get_position_key(
+        account,
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )
+    .map_err(|e| StdError::generic_err(e.to_string()))?;
+    let position = POSITIONS.load(deps.storage, &key)?;
+    let (liquidation_price, pending_funding_usd) = get_liquidation_price(
+        deps.storage,
+        env,
+        &position,
+        collateral_token,
+        index_token,
+        is_long,
+    )?;
+
+    let res = LiquidationPriceResponse {
+        liquidation_price,
+        pending_funding_usd,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_position_leverage(
+    deps: DepsMut,
+    account: Addr,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+) -> StdResult<Binary> {
+    let key = get_position_key(
+        account.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )
+    .unwrap();
+    let position = POSITIONS.load(deps.storage, &key)?;
+    _validate(position.collateral > 0, 37).unwrap();
+    let res = PositionLeverageResponse {
+        leverage: position
+            .size
+            .mul(BASIS_POINTS_DIVISOR)
+            .div(position.collateral),
+    };
+    to_binary(&(res))
+}
+
+fn try_global_short_delta(deps: DepsMut, token: Addr) -> StdResult<Binary> {
+    let global_short_sizes = GLOBAL_SHORT_SIZES.load(deps.storage, token.clone())?;
+    let global_short_average_prices =
+        GLOBAL_SHORT_AVERAGE_PRIZES.load(deps.storage, token.clone())?;
+    if global_short_sizes == 0 {
+        let res = GlobalShortDeltaResponse {
+            has_profit: false,
+            delta: 0,
+        };
+        return to_binary(&res);
+    }
+    let next_price = get_max_price(deps.storage, token.clone());
+    let mut price_delta = 0;
+    if global_short_average_prices > next_price {
+        price_delta = global_short_average_prices.sub(next_price);
+    } else {
+        price_delta = next_price.sub(global_short_average_prices);
+    }
+    let delta = global_short_average_prices
+        .mul(price_delta)
+        .div(global_short_average_prices);
+    let has_profit = global_short_average_prices > next_price;
+    let res = GlobalShortDeltaResponse { has_profit, delta };
+    to_binary(&(res))
+}
+
+fn try_get_position_delta(
+    deps: DepsMut,
+    env: Env,
+    account: Addr,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+) -> StdResult<Binary> {
+    let key = get_position_key(
+        account.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )
+    .unwrap();
+    let position = POSITIONS.load(deps.storage, &key)?;
+
+    let (has_profit, delta) = get_delta(
+        index_token.clone(),
+        position.size,
+        position.average_price,
+        is_long,
+        position.last_increased_time,
+        deps.storage,
+        env,
+    )?;
+    let res = PositionDeltaResponse { has_profit, delta };
+    to_binary(&(res))
+}
+
+fn try_get_target_usdg_amount(deps: DepsMut, env: Env, token: Addr) -> StdResult<Binary> {
+    let supply = get_token_balance_of(
+        deps.querier,
+        env.contract.address.clone(),
+        env.contract.address,
+    )
+    .unwrap();
+    let token_weights = TOKEN_WEIGHTS.load(deps.storage, token)?;
+    let state_variables = STATE_VARIABLES.load(deps.storage)?;
+    if supply == 0 {
+        return to_binary(&TargetUsdgAmountResponse { amount: 0 });
+    }
+    let res = TargetUsdgAmountResponse {
+        amount: token_weights
+            .mul(supply)
+            .div(state_variables.total_token_weights),
+    };
+    to_binary(&(res))
+}
+
+fn try_get_pause_matrix(deps: DepsMut) -> StdResult<Binary> {
+    let is_swap_enabled = IS_SWAP_ENABLED.load(deps.storage)?;
+    let is_leverage_enabled = IS_LEVERGE_ENABLED.load(deps.storage)?;
+
+    let mut operations = Vec::new();
+    for operation in Operation::ALL {
+        let is_paused = PAUSED_OPERATIONS
+            .may_load(deps.storage, operation.as_str())?
+            .unwrap_or_default();
+        operations.push(OperationPauseStatus {
+            operation,
+            is_paused,
+        });
+    }
+
+    let all_whitelisted_tokens = STATE_VARIABLES
+        .may_load(deps.storage)?
+        .map(|state_variables| state_variables.all_whitelisted_tokens)
+        .unwrap_or_default();
+    let mut tokens = Vec::new();
+    for token in all_whitelisted_tokens {
+        let is_paused = PAUSED_TOKENS
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        tokens.push(TokenPauseStatus { token, is_paused });
+    }
+
+    let res = PauseMatrixResponse {
+        is_swap_enabled,
+        is_leverage_enabled,
+        operations,
+        tokens,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_settlement_state(deps: DepsMut) -> StdResult<Binary> {
+    let is_settlement_mode = is_settlement_mode(deps.storage)?;
+    let prices = SETTLEMENT_PRICES
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(token, price)| SettlementPrice { token, price }))
+        .collect::<StdResult<Vec<_>>>()?;
+    let outstanding_usdg = SETTLEMENT_USDG_SUPPLY
+        .may_load(deps.storage)?
+        .unwrap_or_default();
+    let has_open_positions = POSITIONS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .next()
+        .is_some();
+    let failed_positions = SETTLEMENT_FAILURES
+        .keys(deps.storage, None, None, Order::Ascending)
+        .count() as u64;
+
+    let res = SettlementStateResponse {
+        is_settlement_mode,
+        prices,
+        outstanding_usdg,
+        has_open_positions,
+        failed_positions,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_swap_route(deps: DepsMut, path: Vec<Addr>, amount_in: u128) -> StdResult<Binary> {
+    _validate_swap_path(&path).map_err(|e| StdError::generic_err(e.to_string()))?;
+
+    let mut hops = vec![];
+    let mut amount = amount_in;
+    for hop in path.windows(2) {
+        _validate_swap(deps.storage, &hop[0], &hop[1])
+            .map_err(|e| StdError::generic_err(e.to_string()))?;
+        let (amount_out, amount_out_after_fees, usdg_amount, fee_basis_points) =
+            get_swap_amounts(deps.storage, hop[0].clone(), hop[1].clone(), amount)
+                .map_err(|e| StdError::generic_err(e.to_string()))?;
+        let price_impact_usd =
+            get_swap_price_impact_usd(deps.storage, &hop[0], &hop[1], usdg_amount)?;
+        let impact_tokens = get_price_impact_tokens(
+            deps.storage,
+            hop[1].clone(),
+            price_impact_usd,
+            amount_out_after_fees,
+        )?;
+        let amount_out_after_fees = (amount_out_after_fees as i128 + impact_tokens) as u128;
+        hops.push(SwapHop {
+            token_in: hop[0].clone(),
+            token_out: hop[1].clone(),
+            amount_in: amount,
+            amount_out,
+            amount_out_after_fees,
+            fee_basis_points,
+            price_impact_usd,
+        });
+        amount = amount_out_after_fees;
+    }
+    let res = SwapRouteResponse {
+        hops,
+        amount_out: amount,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_fee_distribution_config(deps: DepsMut) -> StdResult<Binary> {
+    let res = FeeDistributionConfigResponse {
+        config: FEE_DISTRIBUTION_CONFIG.may_load(deps.storage)?,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_pending_fee_distribution(deps: DepsMut) -> StdResult<Binary> {
+    let epoch = FEE_DISTRIBUTION_EPOCH
+        .may_load(deps.storage)?
+        .unwrap_or_default();
+    let last_distribution_time = LAST_FEE_DISTRIBUTION_TIME
+        .may_load(deps.storage)?
+        .unwrap_or_default();
+    let epoch_duration = FEE_DISTRIBUTION_CONFIG
+        .may_load(deps.storage)?
+        .map(|config| config.epoch_duration)
+        .unwrap_or_default();
+
+    let all_whitelisted_tokens = STATE_VARIABLES
+        .may_load(deps.storage)?
+        .map(|state_variables| state_variables.all_whitelisted_tokens)
+        .unwrap_or_default();
+    let mut fees = Vec::new();
+    for token in all_whitelisted_tokens {
+        let amount = FEE_RESERVES
+            .may_load(deps.storage, token.clone())?
+            .unwrap_or_default();
+        if amount > 0 {
+            fees.push(PendingFee { token, amount });
+        }
+    }
+
+    let keeper_rewards = PENDING_KEEPER_REWARDS
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(token, amount)| KeeperReward { token, amount }))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let res = PendingFeeDistributionResponse {
+        epoch,
+        next_distribution_time: last_distribution_time.add(epoch_duration),
+        fees,
+        keeper_rewards,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_fee_distributions(
+    deps: DepsMut,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<Binary> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let distributions = FEE_DISTRIBUTIONS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(_, distribution)| distribution))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let res = FeeDistributionsResponse { distributions };
+    to_json_binary(&(res))
+}
+
+fn try_get_referral_code(deps: DepsMut, code: String) -> StdResult<Binary> {
+    let owner = REFERRAL_CODES.may_load(deps.storage, &code)?;
+    let res = ReferralCodeResponse { code, owner };
+    to_json_binary(&(res))
+}
+
+fn try_get_trader_referral(deps: DepsMut, account: Addr) -> StdResult<Binary> {
+    let code = TRADER_REFERRAL_CODES.may_load(deps.storage, account)?;
+    let referrer = match code.clone() {
+        Some(code) => REFERRAL_CODES.may_load(deps.storage, &code)?,
+        None => None,
+    };
+    let res = TraderReferralResponse { code, referrer };
+    to_json_binary(&(res))
+}
+
+fn try_get_referral_stats(deps: DepsMut, referrer: Addr) -> StdResult<Binary> {
+    let tier = REFERRER_TIERS
+        .may_load(deps.storage, referrer.clone())?
+        .unwrap_or_default();
+    let tier_config = REFERRAL_TIERS.may_load(deps.storage, tier)?;
+    let stats = REFERRER_STATS
+        .may_load(deps.storage, referrer.clone())?
+        .unwrap_or_default();
+    let claimable = REFERRER_REBATES
+        .prefix(referrer)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(token, amount)| ClaimableRebate { token, amount }))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let res = ReferralStatsResponse {
+        tier,
+        tier_config,
+        stats,
+        claimable,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_token_risk_config(deps: DepsMut, token: Addr) -> StdResult<Binary> {
+    let config = TOKEN_RISK_CONFIGS
+        .may_load(deps.storage, token)?
+        .unwrap_or_default();
+    let max_leverage = get_max_leverage(deps.storage, &config)?;
+    let res = TokenRiskConfigResponse {
+        config,
+        max_leverage,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_open_interest(deps: DepsMut, token: Addr) -> StdResult<Binary> {
+    let long_size = GLOBAL_LONG_SIZES
+        .may_load(deps.storage, token.clone())?
+        .unwrap_or_default();
+    let long_average_price = GLOBAL_LONG_AVERAGE_PRICES
+        .may_load(deps.storage, token.clone())?
+        .unwrap_or_default();
+    let max_long_size = MAX_GLOBAL_LONG_SIZES
+        .may_load(deps.storage, token.clone())?
+        .unwrap_or_default();
+    let short_size = GLOBAL_SHORT_SIZES
+        .may_load(deps.storage, token.clone())?
+        .unwrap_or_default();
+    let short_average_price = GLOBAL_SHORT_AVERAGE_PRIZES
+        .may_load(deps.storage, token.clone())?
+        .unwrap_or_default();
+    let max_short_size = MAX_GLOBAL_SHORT_SIZES
+        .may_load(deps.storage, token)?
+        .unwrap_or_default();
+
+    let res = OpenInterestResponse {
+        long_size,
+        long_average_price,
+        max_long_size,
+        remaining_long_capacity: (max_long_size != 0)
+            .then(|| max_long_size.saturating_sub(long_size)),
+        short_size,
+        short_average_price,
+        max_short_size,
+        remaining_short_capacity: (max_short_size != 0)
+            .then(|| max_short_size.saturating_sub(short_size)),
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_skew_funding(deps: DepsMut, token: Addr) -> StdResult<Binary> {
+    let config = SKEW_FUNDING_CONFIGS.may_load(deps.storage, token.clone())?;
+    let state = SKEW_FUNDING_STATES
+        .may_load(deps.storage, token.clone())?
+        .unwrap_or_default();
+    let (next_long_funding_rate, next_short_funding_rate) = match &config {
+        Some(config) => get_skew_funding_rates(deps.storage, token, config.funding_factor)?,
+        None => (0, 0),
+    };
+
+    let res = SkewFundingResponse {
+        config,
+        cumulative_long_funding: state.cumulative_long_funding,
+        cumulative_short_funding: state.cumulative_short_funding,
+        last_funding_time: state.last_funding_time,
+        next_long_funding_rate,
+        next_short_funding_rate,
+        pool_usd: state.pool_usd,
+        bad_debt_usd: state.bad_debt_usd,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_position_price_impact(
+    deps: DepsMut,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+    size_delta: u128,
+    is_increase: bool,
+) -> StdResult<Binary> {
+    let price_impact_usd =
+        get_position_price_impact_usd(deps.storage, index_token, is_long, size_delta, is_increase)?;
+    let impact_pool_amount = PRICE_IMPACT_POOLS
+        .may_load(deps.storage, collateral_token)?
+        .unwrap_or_default();
+
+    let res = PriceImpactResponse {
+        price_impact_usd,
+        impact_pool_amount,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_adl_ranking(
+    deps: DepsMut,
+    env: Env,
+    collateral_token: Addr,
+    start_after: Option<Binary>,
+    limit: Option<u32>,
+) -> StdResult<Binary> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let (total_profit_usd, pool_usd, candidates, last_key) = get_adl_candidates(
+        deps.storage,
+        env,
+        collateral_token.clone(),
+        start_after,
+        limit,
+    )
+    .map_err(|e| StdError::generic_err(e.to_string()))?;
+    let pnl_to_pool_basis_points = get_pnl_to_pool_basis_points(total_profit_usd, pool_usd);
+    let is_adl_required = ADL_CONFIGS
+        .may_load(deps.storage, collateral_token)?
+        .map(|config| pnl_to_pool_basis_points > config.trigger_pnl_to_pool_basis_points)
+        .unwrap_or_default();
+
+    let res = AdlRankingResponse {
+        total_profit_usd,
+        pool_usd,
+        pnl_to_pool_basis_points,
+        is_adl_required,
+        positions: candidates,
+        last_key,
+    };
+    to_json_binary(&(res))
+}
+fn try_get_stable_price(deps: DepsMut, token: Addr) -> StdResult<Binary> {
+    let res = StablePriceResponse {
+        config: STABLE_PRICE_CONFIGS.may_load(deps.storage, token.clone())?,
+        oracle_price: get_oracle_price(deps.storage, token.clone()),
+        min_price: get_min_price(deps.storage, token.clone()),
+        max_price: get_max_price(deps.storage, token.clone()),
+        is_depegged: is_depegged(deps.storage, token)?,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_circuit_breaker(deps: DepsMut, env: Env, token: Addr) -> StdResult<Binary> {
+    let config = CIRCUIT_BREAKER_CONFIGS.may_load(deps.storage, token.clone())?;
+    let (price, deviation_basis_points, last_accepted_price) =
+        get_price_deviation(deps.storage, token.clone())?;
+    let is_tripped = TRIPPED_CIRCUIT_BREAKERS
+        .may_load(deps.storage, token)?
+        .unwrap_or_default();
+    // a deviation within the window halts the token even before the breaker is tripped
+    let is_deviating = match (&config, &last_accepted_price) {
+        (Some(config), Some(accepted)) => {
+            deviation_basis_points > config.max_deviation_basis_points
+                && accepted.timestamp.add(config.window) > env.block.time.seconds()
+        }
+        _ => false,
+    };
+
+    let res = CircuitBreakerResponse {
+        config,
+        last_accepted_price,
+        price,
+        deviation_basis_points,
+        is_tripped: is_tripped || is_deviating,
+    };
+    to_json_binary(&(res))
+}
+
+fn try_get_insurance_fund(
+    deps: DepsMut,
+    token: Addr,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<Binary> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let history = INSURANCE_FUND_RECORDS
+        .prefix(token.clone())
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let res = InsuranceFundResponse {
+        balance: INSURANCE_FUNDS
+            .may_load(deps.storage, token)?
+            .unwrap_or_default(),
+        basis_points: INSURANCE_FUND_BASIS_POINTS
+            .may_load(deps.storage)?
+            .unwrap_or_default(),
+        history,
+    };
+    to_json_binary(&(res))
+}
+
+// Helper Functions
+
+fn only_gov(storage: &mut dyn Storage, addr: Addr) {
+    let gov_addr = GOV.load(storage).unwrap();
+    _validate(addr == gov_addr, 53).unwrap();
+}
+
+fn _validate(_condition: bool, _error_code: u64) -> Result<Response, ContractError> {
+    if !_condition {
+        return Err(ContractError::CustomError {
+            val: format!("Error code: {}", _error_code),
+        });
+    }
+    Ok(Response::default())
+}
+
+fn _validate_manager(storage: &mut dyn Storage, addr: Addr) {
+    let state_variables = STATE_VARIABLES.load(storage).unwrap();
+    let is_manager = IS_MANAGER.load(storage, addr).unwrap();
+    if state_variables.in_manager_mode {
+        _validate(is_manager, 54).unwrap();
+    }
+}
+
+fn only_guardian_or_gov(storage: &mut dyn Storage, addr: Addr) -> Result<Response, ContractError> {
+    let gov_addr = GOV.load(storage)?;
+    let is_guardian = IS_GUARDIAN
+        .may_load(storage, addr.clone())?
+        .unwrap_or_default();
+    _validate(addr == gov_addr || is_guardian, 58)
+}
+
+fn _validate_not_paused(
+    storage: &mut dyn Storage,
+    operation: Operation,
+    tokens: &[&Addr],
+) -> Result<Response, ContractError> {
+    let is_operation_paused = PAUSED_OPERATIONS
+        .may_load(storage, operation.as_str())?
+        .unwrap_or_default();
+    _validate(!is_operation_paused, 56)?;
+
+    for token in tokens {
+        let is_token_paused = PAUSED_TOKENS
+            .may_load(storage, (*token).clone())?
+            .unwrap_or_default();
+        _validate(!is_token_paused, 57)?;
+    }
+    Ok(Response::default())
+}
+
+// rejects operations on tokens whose price moved more than the breaker allows since the last
+// accepted price, the accepted price rolls forward once it is older than the window
+fn _validate_circuit_breaker(
+    storage: &mut dyn Storage,
+    env: Env,
+    operation: Operation,
+    tokens: &[&Addr],
+) -> Result<Response, ContractError> {
+    for token in tokens {
+        let config = match CIRCUIT_BREAKER_CONFIGS.may_load(storage, (*token).clone())? {
+            Some(config) => config,
+            None => continue,
+        };
+        let is_allowed = match operation {
+            Operation::DecreasePosition => config.allow_decrease,
+            Operation::LiquidatePosition => config.allow_liquidation,
+            Operation::SellUsdg | Operation::DirectPoolDeposit => true,
+            _ => false,
+        };
+        if is_allowed {
+            continue;
+        }
+
+        let is_tripped = TRIPPED_CIRCUIT_BREAKERS
+            .may_load(storage, (*token).clone())?
+            .unwrap_or_default();
+        _validate(!is_tripped, 106)?;
+        roll_accepted_price(storage, env.clone(), (*token).clone(), &config)?;
+        let (_, deviation_basis_points, _) = get_price_deviation(storage, (*token).clone())?;
+        _validate(
+            deviation_basis_points <= config.max_deviation_basis_points,
+            106,
+        )?;
+    }
+    Ok(Response::default())
+}
+
+// once the last accepted price is older than the window, a current price within the max deviation
+// becomes the new accepted price and starts a new window
+fn roll_accepted_price(
+    storage: &mut dyn Storage,
+    env: Env,
+    token: Addr,
+    config: &CircuitBreakerConfig,
+) -> StdResult<()> {
+    let now = env.block.time.seconds();
+    let (price, deviation_basis_points, last_accepted_price) =
+        get_price_deviation(storage, token.clone())?;
+    let is_expired = last_accepted_price
+        .map(|accepted| accepted.timestamp.add(config.window) <= now)
+        .unwrap_or(true);
+    // a price that moved too far is never accepted, even after a quiet period, so the breaker can
+    // still be tripped on it
+    if is_expired && deviation_basis_points <= config.max_deviation_basis_points {
+        LAST_ACCEPTED_PRICES.save(
+            storage,
+            token,
+            &AcceptedPrice {
+                price,
+                timestamp: now,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+// returns the current price of a token and how far it is from the last accepted price, the
+// larger of the min and max price deviations is used
+fn get_price_deviation(
+    storage: &mut dyn Storage,
+    token: Addr,
+) -> StdResult<(u128, u128, Option<AcceptedPrice>)> {
+    let max_price = get_max_price(storage, token.clone());
+    let min_price = get_min_price(storage, token.clone());
+    let last_accepted_price = LAST_ACCEPTED_PRICES.may_load(storage, token)?;
+    let deviation_basis_points = match &last_accepted_price {
+        Some(accepted) if accepted.price > 0 => max_price
+            .abs_diff(accepted.price)
+            .max(min_price.abs_diff(accepted.price))
+            .mul(BASIS_POINTS_DIVISOR)
+            .div(accepted.price),
+        _ => 0,
+    };
+    Ok((max_price, deviation_basis_points, last_accepted_price))
+}
+
+fn all_whitelisted_tokens_length(storage: &mut dyn Storage) -> Result<usize, ContractError> {
+    let state_variables = STATE_VARIABLES.load(storage)?;
+
+    Ok(state_variables.all_whitelisted_tokens.len())
+}
+
+// in settlement mode every price is frozen at the snapshot provided by gov
+fn get_max_price(storage: &mut dyn Storage, _token: Addr) -> u128 {
+    if let Some(price) = get_settlement_price(storage, _token.clone()) {
+        return price;
+    }
+    let price = get_oracle_price(storage, _token.clone());
+    get_stable_price(storage, _token, price)
+}
+
+fn get_min_price(storage: &mut dyn Storage, _token: Addr) -> u128 {
+    if let Some(price) = get_settlement_price(storage, _token.clone()) {
+        return price;
+    }
+    let price = get_oracle_price(storage, _token.clone());
+    get_stable_price(storage, _token, price)
+}
+
+fn get_oracle_price(_storage: &mut dyn Storage, _token: Addr) -> u128 {
+    return ORACLE_ONE_USD;
+}
+
+// clamps the price of a stable token toward 1 USD, prices below the band are kept so that a
+// depegged stable is not valued at 1 USD
+fn get_stable_price(storage: &mut dyn Storage, token: Addr, price: u128) -> u128 {
+    match STABLE_PRICE_CONFIGS.may_load(storage, token).unwrap() {
+        Some(config) => {
+            let price = clamp_stable_price(&config, to_price_precision(price));
+            Uint128::from(price)
+                .multiply_ratio(ORACLE_ONE_USD, PRICE_PRECISION)
+                .u128()
+        }
+        None => price,
+    }
+}
+
+// stable price bands are measured in PRICE_PRECISION so that bands of a few basis points don't
+// round down to 0 in the units of the oracle
+fn to_price_precision(oracle_price: u128) -> u128 {
+    Uint128::from(oracle_price)
+        .multiply_ratio(PRICE_PRECISION, ORACLE_ONE_USD)
+        .u128()
+}
+
+fn clamp_stable_price(config: &StablePriceConfig, price: u128) -> u128 {
+    let band = PRICE_PRECISION
+        .mul(config.clamp_basis_points)
+        .div(BASIS_POINTS_DIVISOR);
+    if price > PRICE_PRECISION.add(band) {
+        return PRICE_PRECISION.add(band);
+    }
+    if price.abs_diff(PRICE_PRECISION) <= band {
+        return PRICE_PRECISION;
+    }
+    price
+}
+
+fn is_depegged(storage: &mut dyn Storage, token: Addr) -> StdResult<bool> {
+    let config = match STABLE_PRICE_CONFIGS.may_load(storage, token.clone())? {
+        Some(config) => config,
+        None => return Ok(false),
+    };
+    let price = get_oracle_price(storage, token);
+    Ok(is_depegged_price(&config, to_price_precision(price)))
+}
+
+fn is_depegged_price(config: &StablePriceConfig, price: u128) -> bool {
+    let deviation_basis_points = price
+        .abs_diff(PRICE_PRECISION)
+        .mul(BASIS_POINTS_DIVISOR)
+        .div(PRICE_PRECISION);
+    deviation_basis_points > config.depeg_basis_points
+}
+
+fn _validate_not_depegged(
+    storage: &mut dyn Storage,
+    token: &Addr,
+) -> Result<Response, ContractError> {
+    if is_settlement_mode(storage)? {
+        return Ok(Response::default());
+    }
+    _validate(!is_depegged(storage, token.clone())?, 110)
+}
+
+fn get_settlement_price(storage: &mut dyn Storage, token: Addr) -> Option<u128> {
+    if !is_settlement_mode(storage).unwrap() {
+        return None;
+    }
+    SETTLEMENT_PRICES.may_load(storage, token).unwrap()
+}
+
+fn is_settlement_mode(storage: &mut dyn Storage) -> StdResult<bool> {
+    Ok(IS_SETTLEMENT_MODE.may_load(storage)?.unwrap_or_default())
+}
+
+fn _transfer_out(
+    storage: &mut dyn Storage,
+    _info: MessageInfo,
+    token: Addr,
+    amount: u128,
+    reciever: Addr,
+) -> Result<Response, ContractError> {
+    // the cw20 transfer only runs after this execution, so the recorded balance is synced here
+    let token_balance = TOKEN_BALANCES
+        .may_load(storage, token.clone())?
+        .unwrap_or_default();
+    TOKEN_BALANCES.save(storage, token.clone(), &token_balance.sub(amount))?;
+
+    let tranfr_msg = cw20::Cw20ExecuteMsg::Transfer {
+        recipient: reciever.to_string(),
+        amount: amount.into(),
+    };
+    let msg = CosmosMsg::Wasm(cosmwasm_std::WasmMsg::Execute {
+        contract_addr: token.to_string(),
+        msg: to_binary(&tranfr_msg)?,
+        // the tokens come from the vault, the caller's native funds stay in the vault
+        funds: vec![],
+    });
+    Ok(Response::new()
+        .add_attribute("action", "transfer_out")
+        .add_message(msg))
+}
+
+fn _transfer_in(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    _info: MessageInfo,
+    token: Addr,
+) -> Result<u128, ContractError> {
+    let prev_balance = TOKEN_BALANCES
+        .may_load(storage, token.clone())?
+        .unwrap_or_default();
+    let next_balance = get_token_balance_of(querier, env.contract.address, token.clone())?;
+    TOKEN_BALANCES.save(storage, token, &next_balance)?;
+
+    Ok(next_balance.sub(prev_balance))
+}
+
+fn _increase_usdg_amount(
+    storage: &mut dyn Storage,
+    token: Addr,
+    amount: u128,
+) -> Result<Event, ContractError> {
+    let mut usdg_amount = USDG_AMOUNTS.load(storage, token.clone())?;
+    let max_usdg_amount = MAX_USDG_AMOUNTS.load(storage, token.clone())?;
+    usdg_amount += amount;
+
+    if max_usdg_amount != 0 {
         _validate(usdg_amount <= max_usdg_amount, 51)?;
     }
 
-    USDG_AMOUNTS.save(storage, token.clone(), &usdg_amount)?;
+    USDG_AMOUNTS.save(storage, token.clone(), &usdg_amount)?;
+
+    Ok(AmountChanged {
+        amount: TrackedAmount::UsdgAmount,
+        token,
+        delta: amount,
+        is_increase: true,
+        value: usdg_amount,
+    }
+    .into())
+}
+
+fn _decrease_usdg_amount(
+    storage: &mut dyn Storage,
+    token: Addr,
+    amount: u128,
+) -> Result<Event, ContractError> {
+    let mut usdg_amount = USDG_AMOUNTS.load(storage, token.clone())?;
+
+    // since USDG can be minted using multiple assets
+    // it is possible for the USDG debt for a single asset to be less than zero
+    // the USDG debt is capped to zero for this case
+
+    if usdg_amount <= amount {
+        usdg_amount = 0;
+    } else {
+        usdg_amount -= amount;
+    }
+    USDG_AMOUNTS.save(storage, token.clone(), &usdg_amount)?;
+
+    Ok(AmountChanged {
+        amount: TrackedAmount::UsdgAmount,
+        token,
+        delta: amount,
+        is_increase: false,
+        value: usdg_amount,
+    }
+    .into())
+}
+
+pub fn get_token_balance_of(
+    querier: QuerierWrapper,
+    user_address: Addr,
+    cw20_contract_addr: Addr,
+) -> Result<u128, ContractError> {
+    let query_msg = cw20::Cw20QueryMsg::Balance {
+        address: user_address.to_string(),
+    };
+    let msg = querier.query(&cosmwasm_std::QueryRequest::Wasm(WasmQuery::Smart {
+        contract_addr: cw20_contract_addr.to_string(),
+        msg: to_binary(&query_msg)?,
+    }))?;
+
+    Ok(msg)
+}
+
+fn update_cumulative_funding_rate(
+    storage: &mut dyn Storage,
+    env: Env,
+    _collateral_token: Addr,
+    _index_token: Addr,
+) -> Result<Option<Event>, ContractError> {
+    let should_update = true;
+
+    if !should_update {
+        return Ok(None);
+    }
+    let mut last_funding_times = LAST_FUNDING_TIMES
+        .may_load(storage, _collateral_token.clone())?
+        .unwrap_or_default();
+    let state_variables = STATE_VARIABLES.load(storage).unwrap();
+    if last_funding_times == 0 {
+        last_funding_times = env
+            .block
+            .time
+            .seconds()
+            .div(state_variables.funding_interval)
+            .mul(state_variables.funding_interval);
+        LAST_FUNDING_TIMES.save(storage, _collateral_token, &last_funding_times)?;
+        return Ok(None);
+    }
+    if last_funding_times.add(state_variables.funding_interval) > env.block.time.seconds() {
+        return Ok(None);
+    }
+
+    let funding_rate =
+        get_next_funding_rate(storage, env.clone(), _collateral_token.clone()).unwrap();
+    let mut commulative_funding_rates = COMMULATIVE_FUNDING_RATES
+        .may_load(storage, _collateral_token.clone())?
+        .unwrap_or_default();
+    commulative_funding_rates = commulative_funding_rates.add(funding_rate);
+    last_funding_times = env
+        .block
+        .time
+        .seconds()
+        .div(state_variables.funding_interval)
+        .mul(state_variables.funding_interval);
+
+    COMMULATIVE_FUNDING_RATES.save(
+        storage,
+        _collateral_token.clone(),
+        &commulative_funding_rates,
+    )?;
+    LAST_FUNDING_TIMES
+        .save(storage, _collateral_token.clone(), &last_funding_times)
+        .unwrap();
+    let utilisation = get_utilisation(storage, _collateral_token.clone())?;
+    record_funding_history(
+        storage,
+        _collateral_token.clone(),
+        last_funding_times,
+        FundingRateRecord {
+            cumulative_funding_rate: commulative_funding_rates,
+            utilisation,
+            funding_rate,
+        },
+    )?;
+
+    Ok(Some(
+        UpdateFundingRate {
+            token: _collateral_token,
+            funding_rate,
+            cumulative_funding_rate: commulative_funding_rates,
+            last_funding_time: last_funding_times,
+        }
+        .into(),
+    ))
+}
+
+// funding per unit of size for one interval as (longs, shorts), the dominant side pays and
+// the minority side receives the same total spread over its smaller size
+fn get_skew_funding_rates(
+    storage: &mut dyn Storage,
+    index_token: Addr,
+    funding_factor: u128,
+) -> StdResult<(i128, i128)> {
+    let long_size = GLOBAL_LONG_SIZES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+    let short_size = GLOBAL_SHORT_SIZES
+        .may_load(storage, index_token)?
+        .unwrap_or_default();
+    let total_size = long_size.add(short_size);
+    if total_size == 0 || long_size == short_size {
+        return Ok((0, 0));
+    }
+
+    let rate = funding_factor
+        .mul(long_size.abs_diff(short_size))
+        .div(total_size);
+    if long_size > short_size {
+        let received = if short_size == 0 {
+            0
+        } else {
+            rate.mul(long_size).div(short_size)
+        };
+        return Ok((rate as i128, -(received as i128)));
+    }
+    let received = if long_size == 0 {
+        0
+    } else {
+        rate.mul(short_size).div(long_size)
+    };
+    Ok((-(received as i128), rate as i128))
+}
+
+fn update_skew_funding(
+    storage: &mut dyn Storage,
+    env: Env,
+    index_token: Addr,
+) -> StdResult<Option<Event>> {
+    let config = match SKEW_FUNDING_CONFIGS.may_load(storage, index_token.clone())? {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+    let funding_interval = STATE_VARIABLES.load(storage)?.funding_interval;
+    let now = env.block.time.seconds();
+    let mut state = SKEW_FUNDING_STATES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+
+    if state.last_funding_time == 0 {
+        state.last_funding_time = now.div(funding_interval).mul(funding_interval);
+        SKEW_FUNDING_STATES.save(storage, index_token, &state)?;
+        return Ok(None);
+    }
+    if state.last_funding_time.add(funding_interval) > now {
+        return Ok(None);
+    }
+
+    let intervals = now.sub(state.last_funding_time).div(funding_interval) as i128;
+    let (long_rate, short_rate) =
+        get_skew_funding_rates(storage, index_token.clone(), config.funding_factor)?;
+    let long_funding_rate = long_rate * intervals;
+    let short_funding_rate = short_rate * intervals;
+
+    state.cumulative_long_funding += long_funding_rate;
+    state.cumulative_short_funding += short_funding_rate;
+    state.last_funding_time = now.div(funding_interval).mul(funding_interval);
+    SKEW_FUNDING_STATES.save(storage, index_token.clone(), &state)?;
+
+    Ok(Some(
+        UpdateSkewFunding {
+            token: index_token,
+            long_funding_rate,
+            short_funding_rate,
+            cumulative_long_funding: state.cumulative_long_funding,
+            cumulative_short_funding: state.cumulative_short_funding,
+            last_funding_time: state.last_funding_time,
+        }
+        .into(),
+    ))
+}
+
+// moves the skew funding accrued since the last settlement between the position's collateral
+// and the funding pool of the index token, the caller is responsible for saving the position
+fn settle_skew_funding(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    key: &[u8],
+    position: &mut Position,
+) -> Result<Vec<Event>, ContractError> {
+    let position_key = decode_position_key(key)?;
+    let account = position_key.key_1;
+    let collateral_token = position_key.key_2;
+    let index_token = position_key.key_3;
+    let is_long = position_key.key_4;
+
+    let mut state = SKEW_FUNDING_STATES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+    let cumulative_funding = if is_long {
+        state.cumulative_long_funding
+    } else {
+        state.cumulative_short_funding
+    };
+    let funding_delta = cumulative_funding - position.entry_skew_funding;
+    position.entry_skew_funding = cumulative_funding;
+    if position.size == 0 || funding_delta == 0 {
+        return Ok(vec![]);
+    }
+
+    let funding_usd = (position.size as i128) * funding_delta / (FUNDING_RATE_PRECISION as i128);
+    let mut events = vec![];
+    let mut shortfall_usd = 0;
+    if funding_usd > 0 {
+        // funding that exceeds the collateral is lost and leaves the position liquidatable
+        let paid = (funding_usd as u128).min(position.collateral);
+        position.collateral = position.collateral.sub(paid);
+        state.pool_usd = state.pool_usd.add(paid);
+        record_stats(
+            storage,
+            env.clone(),
+            index_token.clone(),
+            TokenStats {
+                funding_fees_usd: paid,
+                ..Default::default()
+            },
+        )?;
+        if is_long {
+            // the collateral of longs is already in the pool, size - collateral grows by the paid amount
+            events.push(increase_guarnteed_usd(
+                storage,
+                collateral_token.clone(),
+                paid,
+            )?);
+        } else {
+            // the collateral of shorts is kept outside of the pool so the paid tokens move into it
+            let amount = usd_to_token_min(collateral_token.clone(), paid, storage)?;
+            events.push(_increase_pool_amount(
+                storage,
+                querier,
+                env,
+                collateral_token.clone(),
+                amount,
+            )?);
+        }
+    } else if funding_usd < 0 {
+        // receivers are only paid out of what payers put into the funding pool
+        let owed = funding_usd.unsigned_abs();
+        let received = owed.min(state.pool_usd);
+        shortfall_usd = owed.sub(received);
+        state.pool_usd = state.pool_usd.sub(received);
+        state.bad_debt_usd = state.bad_debt_usd.add(shortfall_usd);
+        position.collateral = position.collateral.add(received);
+        if is_long {
+            events.push(decrease_guarnteed_usd(
+                storage,
+                collateral_token.clone(),
+                received,
+            )?);
+        } else {
+            let amount = usd_to_token_min(collateral_token.clone(), received, storage)?;
+            events.push(_decrease_pool_amount(
+                storage,
+                env,
+                collateral_token.clone(),
+                amount,
+            )?);
+        }
+    }
+    SKEW_FUNDING_STATES.save(storage, index_token.clone(), &state)?;
+
+    events.push(
+        SettleSkewFunding {
+            key: event_position_key(&account, &collateral_token, &index_token, is_long),
+            funding_usd,
+            shortfall_usd,
+            pool_usd: state.pool_usd,
+        }
+        .into(),
+    );
+    Ok(events)
+}
+
+fn get_impact_for_imbalance(config: &PriceImpactConfig, imbalance: u128) -> StdResult<u128> {
+    let impact = Uint256::from(imbalance)
+        .checked_pow(config.exponent)?
+        .checked_mul(Uint256::from(config.factor))?
+        / Uint256::from(PRICE_IMPACT_PRECISION);
+    Ok(Uint128::try_from(impact)?.u128())
+}
+
+// positive when the trade shrinks the imbalance and negative when it grows it
+fn get_price_impact_usd(
+    config: &PriceImpactConfig,
+    imbalance_before: u128,
+    imbalance_after: u128,
+) -> StdResult<i128> {
+    let impact_before = get_impact_for_imbalance(config, imbalance_before)? as i128;
+    let impact_after = get_impact_for_imbalance(config, imbalance_after)? as i128;
+    Ok(impact_before - impact_after)
+}
+
+fn get_position_price_impact_usd(
+    storage: &mut dyn Storage,
+    index_token: Addr,
+    is_long: bool,
+    size_delta: u128,
+    is_increase: bool,
+) -> StdResult<i128> {
+    let config = match PRICE_IMPACT_CONFIGS.may_load(storage, index_token.clone())? {
+        Some(config) => config,
+        None => return Ok(0),
+    };
+    let long_size = GLOBAL_LONG_SIZES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+    let short_size = GLOBAL_SHORT_SIZES
+        .may_load(storage, index_token)?
+        .unwrap_or_default();
+
+    let (mut next_long_size, mut next_short_size) = (long_size, short_size);
+    let side = if is_long {
+        &mut next_long_size
+    } else {
+        &mut next_short_size
+    };
+    *side = if is_increase {
+        side.add(size_delta)
+    } else {
+        side.saturating_sub(size_delta)
+    };
+
+    get_price_impact_usd(
+        &config,
+        long_size.abs_diff(short_size),
+        next_long_size.abs_diff(next_short_size),
+    )
+}
+
+// deviation of usdgAmounts from the target weights before and after the swap, in usd
+fn get_swap_price_impact_usd(
+    storage: &mut dyn Storage,
+    _token_in: &Addr,
+    _token_out: &Addr,
+    usdg_amount: u128,
+) -> StdResult<i128> {
+    let state_variables = STATE_VARIABLES.load(storage)?;
+    if state_variables.total_token_weights == 0 {
+        return Ok(0);
+    }
+    let mut total_usdg_amounts = 0;
+    for token in state_variables.all_whitelisted_tokens.iter() {
+        total_usdg_amounts = total_usdg_amounts.add(
+            USDG_AMOUNTS
+                .may_load(storage, token.clone())?
+                .unwrap_or_default(),
+        );
+    }
+
+    let usdg_unit = 10_u128.pow(USDG_DECIMALS as u32);
+    let mut price_impact_usd = 0;
+    for (token, is_token_in) in [(_token_in, true), (_token_out, false)] {
+        let config = match PRICE_IMPACT_CONFIGS.may_load(storage, token.clone())? {
+            Some(config) => config,
+            None => continue,
+        };
+        let usdg_amounts = USDG_AMOUNTS
+            .may_load(storage, token.clone())?
+            .unwrap_or_default();
+        let token_weight = TOKEN_WEIGHTS
+            .may_load(storage, token.clone())?
+            .unwrap_or_default();
+        let target_usdg_amount = token_weight
+            .mul(total_usdg_amounts)
+            .div(state_variables.total_token_weights);
+        let next_usdg_amounts = if is_token_in {
+            usdg_amounts.add(usdg_amount)
+        } else {
+            usdg_amounts.saturating_sub(usdg_amount)
+        };
+
+        price_impact_usd += get_price_impact_usd(
+            &config,
+            usdg_amounts.abs_diff(target_usdg_amount).div(usdg_unit),
+            next_usdg_amounts
+                .abs_diff(target_usdg_amount)
+                .div(usdg_unit),
+        )?;
+    }
+    Ok(price_impact_usd)
+}
+
+// converts a price impact into tokens, rebates are limited by the impact pool and charges by
+// max_charge, the result is positive for rebates and negative for charges
+fn get_price_impact_tokens(
+    storage: &mut dyn Storage,
+    token: Addr,
+    price_impact_usd: i128,
+    max_charge: u128,
+) -> StdResult<i128> {
+    if price_impact_usd > 0 {
+        let impact_pool_amount = PRICE_IMPACT_POOLS
+            .may_load(storage, token.clone())?
+            .unwrap_or_default();
+        let rebate = usd_to_token_min(token, price_impact_usd as u128, storage)?;
+        return Ok(rebate.min(impact_pool_amount) as i128);
+    }
+    if price_impact_usd < 0 {
+        let charge = usd_to_token_max(token, price_impact_usd.unsigned_abs(), storage)?;
+        return Ok(-(charge.min(max_charge) as i128));
+    }
+    Ok(0)
+}
+
+// charges are added to the impact pool and rebates are paid out of it
+fn apply_price_impact_pool(
+    storage: &mut dyn Storage,
+    token: Addr,
+    price_impact_usd: i128,
+    impact_tokens: i128,
+) -> StdResult<Option<Event>> {
+    if impact_tokens == 0 {
+        return Ok(None);
+    }
+    let mut impact_pool_amount = PRICE_IMPACT_POOLS
+        .may_load(storage, token.clone())?
+        .unwrap_or_default();
+    if impact_tokens > 0 {
+        impact_pool_amount = impact_pool_amount.sub(impact_tokens as u128);
+    } else {
+        impact_pool_amount = impact_pool_amount.add(impact_tokens.unsigned_abs());
+    }
+    PRICE_IMPACT_POOLS.save(storage, token.clone(), &impact_pool_amount)?;
+
+    Ok(Some(
+        PriceImpact {
+            token,
+            price_impact_usd,
+            impact_tokens: impact_tokens.unsigned_abs(),
+            impact_pool_amount,
+        }
+        .into(),
+    ))
+}
+
+// settles the price impact of a position change against its collateral,
+// the caller is responsible for saving the position
+fn apply_position_price_impact(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    collateral_token: Addr,
+    is_long: bool,
+    position: &mut Position,
+    price_impact_usd: i128,
+) -> Result<Vec<Event>, ContractError> {
+    let max_charge = usd_to_token_min(collateral_token.clone(), position.collateral, storage)?;
+    let impact_tokens = get_price_impact_tokens(
+        storage,
+        collateral_token.clone(),
+        price_impact_usd,
+        max_charge,
+    )?;
+    if impact_tokens == 0 {
+        return Ok(vec![]);
+    }
+
+    let mut events = vec![];
+    events.extend(apply_price_impact_pool(
+        storage,
+        collateral_token.clone(),
+        price_impact_usd,
+        impact_tokens,
+    )?);
+    let amount = impact_tokens.unsigned_abs();
+    let impact_usd = token_to_usd_min(collateral_token.clone(), amount, storage)?;
+    if impact_tokens > 0 {
+        position.collateral = position.collateral.add(impact_usd);
+        // the collateral of longs is part of the pool
+        if is_long {
+            events.push(_increase_pool_amount(
+                storage,
+                querier,
+                env,
+                collateral_token.clone(),
+                amount,
+            )?);
+            events.push(decrease_guarnteed_usd(
+                storage,
+                collateral_token,
+                impact_usd,
+            )?);
+        }
+    } else {
+        position.collateral = position.collateral.saturating_sub(impact_usd);
+        if is_long {
+            events.push(_decrease_pool_amount(
+                storage,
+                env,
+                collateral_token.clone(),
+                amount,
+            )?);
+            events.push(increase_guarnteed_usd(
+                storage,
+                collateral_token,
+                impact_usd,
+            )?);
+        }
+    }
+    Ok(events)
+}
+
+// appends a trade to the ledger of the account and indexes it by position
+fn record_trade(
+    storage: &mut dyn Storage,
+    account: &Addr,
+    trade: TradeRecord,
+) -> Result<(), ContractError> {
+    let index = TRADE_HISTORY_COUNTS
+        .may_load(storage, account.clone())?
+        .unwrap_or_default();
+    let key = get_position_key(
+        account.clone(),
+        trade.collateral_token.clone(),
+        trade.index_token.clone(),
+        trade.is_long,
+    )?;
+    POSITION_TRADES.save(storage, (&key, index), &Empty {})?;
+    TRADE_HISTORY.save(storage, (account.clone(), index), &trade)?;
+    TRADE_HISTORY_COUNTS.save(storage, account.clone(), &index.add(1))?;
+    Ok(())
+}
+
+// adds to the all time and daily stats of a token and snapshots its open interest
+fn record_stats(
+    storage: &mut dyn Storage,
+    env: Env,
+    token: Addr,
+    delta: TokenStats,
+) -> StdResult<()> {
+    let long_open_interest = GLOBAL_LONG_SIZES
+        .may_load(storage, token.clone())?
+        .unwrap_or_default();
+    let short_open_interest = GLOBAL_SHORT_SIZES
+        .may_load(storage, token.clone())?
+        .unwrap_or_default();
+    let day = env.block.time.seconds().div(SECONDS_PER_DAY);
+
+    let mut stats = TOKEN_STATS
+        .may_load(storage, token.clone())?
+        .unwrap_or_default();
+    stats.add(&delta);
+    stats.long_open_interest = long_open_interest;
+    stats.short_open_interest = short_open_interest;
+    TOKEN_STATS.save(storage, token.clone(), &stats)?;
+
+    let mut daily_stats = DAILY_TOKEN_STATS
+        .may_load(storage, (token.clone(), day))?
+        .unwrap_or_default();
+    daily_stats.add(&delta);
+    daily_stats.long_open_interest = long_open_interest;
+    daily_stats.short_open_interest = short_open_interest;
+    DAILY_TOKEN_STATS.save(storage, (token, day), &daily_stats)?;
+    Ok(())
+}
+
+// stores a funding update and prunes records that are older than the retention
+fn record_funding_history(
+    storage: &mut dyn Storage,
+    token: Addr,
+    funding_time: u64,
+    record: FundingRateRecord,
+) -> StdResult<()> {
+    FUNDING_HISTORY.save(storage, (token.clone(), funding_time), &record)?;
+
+    let retention = FUNDING_HISTORY_RETENTION
+        .may_load(storage)?
+        .unwrap_or(DEFAULT_FUNDING_HISTORY_RETENTION);
+    if retention == 0 || funding_time <= retention {
+        return Ok(());
+    }
+    let expired = FUNDING_HISTORY
+        .prefix(token.clone())
+        .keys(
+            storage,
+            None,
+            Some(Bound::exclusive(funding_time.sub(retention))),
+            Order::Ascending,
+        )
+        .take(MAX_FUNDING_HISTORY_PRUNE)
+        .collect::<StdResult<Vec<_>>>()?;
+    for time in expired {
+        FUNDING_HISTORY.remove(storage, (token.clone(), time));
+    }
+    Ok(())
+}
+
+fn get_next_funding_rate(
+    storage: &mut dyn Storage,
+    env: Env,
+    token: Addr,
+) -> Result<u128, ContractError> {
+    let last_funding_times = LAST_FUNDING_TIMES.load(storage, token.clone()).unwrap();
+    let state_variables = STATE_VARIABLES.load(storage).unwrap();
+
+    if last_funding_times.add(state_variables.funding_interval) > env.block.time.seconds() {
+        return Ok(0);
+    }
+    let intervals = env
+        .block
+        .time
+        .seconds()
+        .sub(last_funding_times)
+        .div(state_variables.funding_interval);
+
+    let utilisation = get_utilisation(storage, token.clone())?;
+    let model = get_borrow_rate_model(storage, token)?;
+
+    Ok(get_borrow_rate(&model, utilisation).mul(intervals as u128))
+}
+
+// reserved / pool in FUNDING_RATE_PRECISION
+fn get_utilisation(storage: &mut dyn Storage, token: Addr) -> StdResult<u128> {
+    let pool_amounts = POOL_AMOUNTS
+        .may_load(storage, token.clone())?
+        .unwrap_or_default();
+    if pool_amounts == 0 {
+        return Ok(0);
+    }
+    let reserved_amounts = RSERVED_AMOUNTS
+        .may_load(storage, token)?
+        .unwrap_or_default();
+    Ok(reserved_amounts
+        .mul(FUNDING_RATE_PRECISION)
+        .div(pool_amounts))
+}
+
+fn get_borrow_rate_model(storage: &mut dyn Storage, token: Addr) -> StdResult<BorrowRateModel> {
+    if let Some(model) = BORROW_RATE_MODELS.may_load(storage, token.clone())? {
+        return Ok(model);
+    }
+    let state_variables = STATE_VARIABLES.load(storage)?;
+    let is_stable = STABLE_TOKENS.may_load(storage, token)?.unwrap_or_default();
+    let factor = if is_stable {
+        state_variables.stable_funding_rate_factor
+    } else {
+        state_variables.funding_rate_factor
+    };
+    Ok(BorrowRateModel::Linear { factor })
+}
+
+// borrow rate per funding interval for the given utilisation
+fn get_borrow_rate(model: &BorrowRateModel, utilisation: u128) -> u128 {
+    match model {
+        BorrowRateModel::Linear { factor } => factor.mul(utilisation).div(FUNDING_RATE_PRECISION),
+        BorrowRateModel::Kinked {
+            base_rate,
+            slope1,
+            kink,
+            slope2,
+        } => {
+            let below_kink = utilisation.min(*kink);
+            let above_kink = utilisation.saturating_sub(*kink);
+            base_rate
+                .add(slope1.mul(below_kink).div(FUNDING_RATE_PRECISION))
+                .add(slope2.mul(above_kink).div(FUNDING_RATE_PRECISION))
+        }
+        BorrowRateModel::Capped { factor, max_rate } => factor
+            .mul(utilisation)
+            .div(FUNDING_RATE_PRECISION)
+            .min(*max_rate),
+    }
+}
+
+fn adjust_for_decimals(
+    storage: &mut dyn Storage,
+    amount: u128,
+    token_div: Addr,
+    token_mul: Addr,
+) -> Result<u128, ContractError> {
+    let addresses = ADDRESSES.load(storage).unwrap();
+    let mut decimals_div = 0;
+    let mut decimals_mul = 0;
+    if token_div == addresses.usdg {
+        decimals_div = USDG_DECIMALS;
+    } else {
+        let token_decimals = TOKEN_DECIMALS.load(storage, token_div).unwrap();
+        decimals_div = token_decimals;
+    }
+
+    if token_mul == addresses.usdg {
+        decimals_mul = USDG_DECIMALS;
+    } else {
+        let token_decimals = TOKEN_DECIMALS.load(storage, token_mul).unwrap();
+        decimals_mul = token_decimals;
+    }
+    Ok(amount.mul(
+        10_u128
+            .pow(decimals_mul as u32)
+            .div(10_u128.pow(decimals_div as u32)),
+    ))
+}
+
+fn _collect_swap_fees(
+    storage: &mut dyn Storage,
+    env: Env,
+    token: Addr,
+    amount: u128,
+    fee_basis_point: u128,
+    fee_type: &'static str,
+    account: Option<&Addr>,
+) -> Result<(u128, Vec<Event>), ContractError> {
+    let mut after_fee_amount = amount
+        .mul(BASIS_POINTS_DIVISOR.sub(fee_basis_point))
+        .div(BASIS_POINTS_DIVISOR);
+    let mut fee_amount = amount.sub(after_fee_amount);
+
+    let mut events = vec![];
+    if let Some(account) = account {
+        let volume_usd = token_to_usd_min(token.clone(), amount, storage)?;
+        let (discount, rebate, referral_events) =
+            apply_referral(storage, account, token.clone(), fee_amount, volume_usd)?;
+        events.extend(referral_events);
+        after_fee_amount = after_fee_amount.add(discount);
+        fee_amount = fee_amount.sub(discount).sub(rebate);
+    }
+
+    let mut fee_reserves = FEE_RESERVES.load(storage, token.clone()).unwrap();
+    fee_reserves = fee_reserves.add(fee_amount);
+    FEE_RESERVES.save(storage, token.clone(), &fee_reserves)?;
+
+    let fee_usd = token_to_usd_min(token.clone(), fee_amount, storage)?;
+    let mut stats = TokenStats::default();
+    if fee_type == "swap" {
+        stats.swap_fees_usd = fee_usd;
+    } else {
+        stats.mint_burn_fees_usd = fee_usd;
+    }
+    record_stats(storage, env, token.clone(), stats)?;
+    events.push(
+        CollectFees {
+            token,
+            fee_type,
+            fee_usd,
+            fee_tokens: fee_amount,
+        }
+        .into(),
+    );
+    Ok((after_fee_amount, events))
+}
+
+fn get_swap_fee_basis_points(
+    storage: &mut dyn Storage,
+    _token_in: &Addr,
+    _token_out: &Addr,
+) -> StdResult<u128> {
+    let state_variables = STATE_VARIABLES.load(storage)?;
+    let is_stable_token_in = STABLE_TOKENS
+        .may_load(storage, _token_in.clone())?
+        .unwrap_or_default();
+    let is_stable_token_out = STABLE_TOKENS
+        .may_load(storage, _token_out.clone())?
+        .unwrap_or_default();
+    // dynamic fees from vaultUtils are not ported, only the base fee is charged
+    if is_stable_token_in && is_stable_token_out {
+        return Ok(state_variables.stable_swap_fee_basis_points);
+    }
+    Ok(state_variables.swap_fee_basis_points)
+}
+
+// splits a fee paid by a referred account into a trader discount and a referrer rebate
+// and returns (discount, rebate) in fee tokens, both are taken out of the fee by the caller
+fn apply_referral(
+    storage: &mut dyn Storage,
+    account: &Addr,
+    token: Addr,
+    fee_tokens: u128,
+    volume_usd: u128,
+) -> Result<(u128, u128, Vec<Event>), ContractError> {
+    let code = match TRADER_REFERRAL_CODES.may_load(storage, account.clone())? {
+        Some(code) => code,
+        None => return Ok((0, 0, vec![])),
+    };
+    let referrer = match REFERRAL_CODES.may_load(storage, &code)? {
+        Some(referrer) => referrer,
+        None => return Ok((0, 0, vec![])),
+    };
+
+    let tier = REFERRER_TIERS
+        .may_load(storage, referrer.clone())?
+        .unwrap_or_default();
+    let (discount, rebate) = match REFERRAL_TIERS.may_load(storage, tier)? {
+        Some(tier_config) => {
+            let total_rebate = fee_tokens
+                .mul(tier_config.total_rebate_basis_points)
+                .div(BASIS_POINTS_DIVISOR);
+            let discount = total_rebate
+                .mul(tier_config.discount_share_basis_points)
+                .div(BASIS_POINTS_DIVISOR);
+            (discount, total_rebate.sub(discount))
+        }
+        None => (0, 0),
+    };
+
+    let mut stats = REFERRER_STATS
+        .may_load(storage, referrer.clone())?
+        .unwrap_or_default();
+    stats.volume_usd = stats.volume_usd.add(volume_usd);
+    stats.rebates_usd = stats
+        .rebates_usd
+        .add(token_to_usd_min(token.clone(), rebate, storage)?);
+    stats.discounts_usd =
+        stats
+            .discounts_usd
+            .add(token_to_usd_min(token.clone(), discount, storage)?);
+    REFERRER_STATS.save(storage, referrer.clone(), &stats)?;
+
+    if rebate > 0 {
+        let rebate_key = (referrer.clone(), token.clone());
+        let rebates = REFERRER_REBATES
+            .may_load(storage, rebate_key.clone())?
+            .unwrap_or_default();
+        REFERRER_REBATES.save(storage, rebate_key, &rebates.add(rebate))?;
+    }
+
+    let event = ReferralFee {
+        account: account.clone(),
+        referrer,
+        code,
+        token,
+        fee_tokens,
+        discount_tokens: discount,
+        rebate_tokens: rebate,
+    };
+    Ok((discount, rebate, vec![event.into()]))
+}
+
+fn _increase_pool_amount(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    token: Addr,
+    amount: u128,
+) -> Result<Event, ContractError> {
+    let mut pool_amounts = POOL_AMOUNTS.load(storage, token.clone())?;
+    pool_amounts = pool_amounts.add(amount);
+    let balance = get_token_balance_of(querier, env.contract.address, token.clone())?;
+    _validate(pool_amounts <= balance, 49)?;
+
+    POOL_AMOUNTS.save(storage, token.clone(), &pool_amounts)?;
+
+    Ok(AmountChanged {
+        amount: TrackedAmount::PoolAmount,
+        token,
+        delta: amount,
+        is_increase: true,
+        value: pool_amounts,
+    }
+    .into())
+}
+
+fn get_redemption_amount(
+    storage: &mut dyn Storage,
+    _env: Env,
+    token: Addr,
+    usdg_amount: u128,
+) -> Result<u128, ContractError> {
+    let price = get_max_price(storage, token.clone());
+    let redemption_amount = usdg_amount.mul(PRICE_PRECISION).div(price);
+    let addresses = ADDRESSES.load(storage).unwrap();
+
+    Ok(adjust_for_decimals(
+        storage,
+        redemption_amount,
+        addresses.usdg,
+        token,
+    )?)
+}
+
+fn _decrease_pool_amount(
+    storage: &mut dyn Storage,
+    _env: Env,
+    token: Addr,
+    amount: u128,
+) -> Result<Event, ContractError> {
+    let mut pool_amounts = POOL_AMOUNTS.load(storage, token.clone())?;
+    let reserved_amounts = RSERVED_AMOUNTS.load(storage, token.clone())?;
+    pool_amounts = pool_amounts.sub(amount);
+    _validate(reserved_amounts <= pool_amounts, 50)?;
+
+    POOL_AMOUNTS.save(storage, token.clone(), &pool_amounts)?;
+
+    Ok(AmountChanged {
+        amount: TrackedAmount::PoolAmount,
+        token,
+        delta: amount,
+        is_increase: false,
+        value: pool_amounts,
+    }
+    .into())
+}
+
+fn _update_token_balance(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    token: Addr,
+) -> Result<Response, ContractError> {
+    let next_balance = get_token_balance_of(querier, env.contract.address, token.clone())?;
+    TOKEN_BALANCES.save(storage, token, &next_balance)?;
+    Ok(Response::default())
+}
+
+fn _validate_buffer_amount(
+    storage: &mut dyn Storage,
+    token: Addr,
+) -> Result<Response, ContractError> {
+    let pool_amounts = POOL_AMOUNTS.load(storage, token.clone()).unwrap();
+    let buffer_amounts = BUFFER_AMOUNTS.load(storage, token.clone()).unwrap();
+
+    if pool_amounts < buffer_amounts {
+        return Err(ContractError::CustomError {
+            val: "Vault: poolAmount < buffer".to_string(),
+        });
+    }
+    Ok(Response::default())
+}
+
+fn _validate_gas_price(storage: &mut dyn Storage, env: Env) {
+    let state_variables = STATE_VARIABLES.load(storage).unwrap();
+    if state_variables.max_gas_price == 0 {
+        return;
+    }
+    let tx_gas_price = 1;
+    _validate(tx_gas_price <= state_variables.max_gas_price, 55).unwrap();
+}
+
+// managers and routers act for the account they pass in, everyone else trades for themselves
+fn get_trader_account(
+    storage: &mut dyn Storage,
+    info: &MessageInfo,
+    account: Option<Addr>,
+) -> Result<Addr, ContractError> {
+    let account = match account {
+        Some(account) if account != info.sender => account,
+        _ => return Ok(info.sender.clone()),
+    };
+    let is_manager = IS_MANAGER
+        .may_load(storage, info.sender.clone())?
+        .unwrap_or_default();
+    let is_router = APPROVED_ROUTERS1
+        .may_load(storage, info.sender.clone())?
+        .unwrap_or_default();
+    _validate(is_manager || is_router, 41)?;
+    Ok(account)
+}
+
+fn _validate_router(storage: &mut dyn Storage, info: MessageInfo, account: Addr) {
+    let addresses = ADDRESSES.load(storage).unwrap();
+    let approved_routers1 = APPROVED_ROUTERS1.load(storage, info.sender).unwrap();
+    let mut approved_routers2 = APPROVED_ROUTERS2.load(storage, account).unwrap();
+    approved_routers2 = approved_routers1;
+    _validate(approved_routers2, 41).unwrap();
+}
+
+fn validate_tokens(
+    storage: &mut dyn Storage,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+) {
+    let whitelisted_tokens = WHITELISTED_TOKENS
+        .load(storage, collateral_token.clone())
+        .unwrap();
+    let stable_tokens_collateral = STABLE_TOKENS
+        .load(storage, collateral_token.clone())
+        .unwrap();
+    let stable_tokens_index = STABLE_TOKENS.load(storage, index_token.clone()).unwrap();
+    let shortable_tokens = SHORTABLE_TOKENS.load(storage, index_token.clone()).unwrap();
+    if is_long {
+        _validate(collateral_token == index_token, 42).unwrap();
+        _validate(whitelisted_tokens, 43).unwrap();
+        _validate(!stable_tokens_collateral, 44).unwrap();
+        return;
+    }
+    _validate(whitelisted_tokens, 45).unwrap();
+    _validate(stable_tokens_collateral, 46).unwrap();
+    _validate(!stable_tokens_index, 47).unwrap();
+    _validate(shortable_tokens, 48).unwrap();
+}
+
+fn get_position_key(
+    account: Addr,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+) -> Result<Vec<u8>, ContractError> {
+    let key = Key::new(account, collateral_token, index_token, is_long).as_bytes()?;
+    Ok(key)
+}
+
+// positions are only written through savePosition and removePosition so that
+// accountPositions and collateralPositions stay in sync
+fn save_position(
+    storage: &mut dyn Storage,
+    key: &[u8],
+    position: &Position,
+) -> Result<(), ContractError> {
+    let position_key = decode_position_key(key)?;
+    POSITIONS.save(storage, key, position)?;
+    ACCOUNT_POSITIONS.save(storage, (&position_key.key_1, key), &Empty {})?;
+    COLLATERAL_POSITIONS.save(storage, (&position_key.key_2, key), &Empty {})?;
+    Ok(())
+}
+
+fn remove_position(storage: &mut dyn Storage, key: &[u8]) -> Result<(), ContractError> {
+    let position_key = decode_position_key(key)?;
+    POSITIONS.remove(storage, key);
+    ACCOUNT_POSITIONS.remove(storage, (&position_key.key_1, key));
+    COLLATERAL_POSITIONS.remove(storage, (&position_key.key_2, key));
+    PENDING_POSITION_TRANSFERS.remove(storage, key);
+    Ok(())
+}
+
+fn event_position_key(
+    account: &Addr,
+    collateral_token: &Addr,
+    index_token: &Addr,
+    is_long: bool,
+) -> EventPositionKey {
+    EventPositionKey {
+        account: account.clone(),
+        collateral_token: collateral_token.clone(),
+        index_token: index_token.clone(),
+        is_long,
+    }
+}
+
+fn decode_position_key(key: &[u8]) -> Result<Key<Addr, Addr, Addr, bool>, ContractError> {
+    match bincode::deserialize(key) {
+        Ok(position_key) => Ok(position_key),
+        Err(_) => Err(ContractError::DeserializationFailed {}),
+    }
+}
+
+// closes a position at the frozen settlement price without charging fees and
+// returns the amount of collateral tokens owed to the account
+fn settle_position(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    key: &[u8],
+    mut position: Position,
+) -> Result<(u128, Vec<Event>), ContractError> {
+    let position_key = decode_position_key(key)?;
+    let account = position_key.key_1;
+    let collateral_token = position_key.key_2;
+    let index_token = position_key.key_3;
+    let is_long = position_key.key_4;
+
+    // skew funding accrued until settlement is settled like on any other decrease
+    let mut events = vec![];
+    events.extend(update_skew_funding(
+        storage,
+        env.clone(),
+        index_token.clone(),
+    )?);
+    events.extend(settle_skew_funding(
+        storage,
+        querier,
+        env.clone(),
+        key,
+        &mut position,
+    )?);
+
+    let price = get_min_price(storage, index_token.clone());
+    let mut delta = 0;
+    let mut has_profit = false;
+    if position.average_price > 0 {
+        let price_delta = position.average_price.abs_diff(price);
+        delta = position.size.mul(price_delta).div(position.average_price);
+        has_profit = if is_long {
+            price > position.average_price
+        } else {
+            position.average_price > price
+        };
+    }
+
+    events.push(decrease_reserved_amount(
+        storage,
+        collateral_token.clone(),
+        position.reserve_amount,
+    )?);
+
+    let usd_out = if has_profit {
+        position.collateral.add(delta)
+    } else {
+        position.collateral.saturating_sub(delta)
+    };
+
+    events.push(decrease_global_size(
+        storage,
+        index_token.clone(),
+        is_long,
+        position.size,
+    )?);
+    if is_long {
+        events.push(decrease_guarnteed_usd(
+            storage,
+            collateral_token.clone(),
+            position.size.saturating_sub(position.collateral),
+        )?);
+        // the collateral of longs is part of the pool so the whole payout leaves the pool
+        let amount = usd_to_token_min(collateral_token.clone(), usd_out, storage)?;
+        events.push(_decrease_pool_amount(
+            storage,
+            env.clone(),
+            collateral_token.clone(),
+            amount,
+        )?);
+    } else {
+        // profits of shorts are paid by the pool and losses are kept by the pool
+        if has_profit {
+            let amount = usd_to_token_min(collateral_token.clone(), delta, storage)?;
+            events.push(_decrease_pool_amount(
+                storage,
+                env.clone(),
+                collateral_token.clone(),
+                amount,
+            )?);
+        } else {
+            let loss = delta.min(position.collateral);
+            let amount = usd_to_token_min(collateral_token.clone(), loss, storage)?;
+            events.push(_increase_pool_amount(
+                storage,
+                querier,
+                env.clone(),
+                collateral_token.clone(),
+                amount,
+            )?);
+        }
+    }
+
+    // losses above the collateral are bad debt of the pool, the insurance fund pays for as
+    // much of it as it can
+    if !has_profit && delta > position.collateral {
+        events.extend(cover_bad_debt(
+            storage,
+            querier,
+            env,
+            key,
+            delta.sub(position.collateral),
+        )?);
+    }
+
+    remove_position(storage, key)?;
+
+    let amount_out = usd_to_token_min(collateral_token.clone(), usd_out, storage)?;
+    events.push(
+        SettlePosition {
+            key: event_position_key(&account, &collateral_token, &index_token, is_long),
+            size: position.size,
+            collateral: position.collateral,
+            price,
+            has_profit,
+            delta_usd: delta,
+            amount_out,
+        }
+        .into(),
+    );
+
+    Ok((amount_out, events))
+}
+
+fn get_next_average_price(
+    index_token: Addr,
+    size: u128,
+    average_price: u128,
+    is_long: bool,
+    next_price: u128,
+    size_delta: u128,
+    last_increased_time: u128,
+    env: Env,
+    storage: &mut dyn Storage,
+) -> StdResult<u128> {
+    let (has_profit, delta) = get_delta(
+        index_token,
+        size,
+        average_price,
+        is_long,
+        last_increased_time,
+        storage,
+        env,
+    )?;
+    let next_size = size + size_delta;
+    let divisor = if is_long {
+        if has_profit {
+            next_size + delta
+        } else {
+            next_size - delta
+        }
+    } else {
+        if has_profit {
+            next_size - delta
+        } else {
+            next_size + delta
+        }
+    };
+
+    let result = next_price * next_size / divisor;
+    Ok(result)
+}
+
+fn get_delta(
+    index_token: Addr,
+    size: u128,
+    average_price: u128,
+    is_long: bool,
+    last_increased_time: u128,
+    storage: &mut dyn Storage,
+    env: Env,
+) -> StdResult<(bool, u128)> {
+    let state_variables = STATE_VARIABLES.load(storage).unwrap();
+    let min_profit_basis_points = MIN_PROFIT_BASIS_POINTS
+        .load(storage, index_token.clone())
+        .unwrap();
+
+    // Get the appropriate price based on the is_long flag
+    let price = if is_long {
+        get_min_price(storage, index_token)
+    } else {
+        get_max_price(storage, index_token)
+    };
+
+    let price_delta = if average_price > price {
+        average_price - price
+    } else {
+        price - average_price
+    };
+
+    let delta = size * price_delta / average_price;
+
+    let has_profit = if is_long {
+        price > average_price
+    } else {
+        average_price > price
+    };
+
+    // Additional logic involving minProfitTime and minProfitBasisPoints
+    let min_bps = if env.block.time.seconds() as u128
+        > last_increased_time + state_variables.min_profit_time
+    {
+        0
+    } else {
+        min_profit_basis_points
+    };
+
+    let adjusted_delta = if has_profit && delta * BASIS_POINTS_DIVISOR <= size * min_bps {
+        0
+    } else {
+        delta
+    };
+
+    Ok((has_profit, adjusted_delta))
+}
+
+// the borrow fee a position owes since its entryFundingRate, cumulativeFundingRates of the
+// collateral token accrue with the borrow rate model of the token
+fn get_funding_fee(
+    storage: &mut dyn Storage,
+    collateral_token: Addr,
+    size: u128,
+    entry_funding_rate: u128,
+) -> StdResult<u128> {
+    if size == 0 {
+        return Ok(0);
+    }
+    let funding_rate =
+        get_entry_funding_rate(storage, collateral_token)?.saturating_sub(entry_funding_rate);
+    Ok(size.mul(funding_rate).div(FUNDING_RATE_PRECISION))
+}
+
+// the borrow fee a position owes including the intervals that have not been added to the
+// cumulativeFundingRates of the collateral token yet
+fn get_pending_funding_fee(
+    storage: &mut dyn Storage,
+    env: Env,
+    collateral_token: Addr,
+    position: &Position,
+) -> StdResult<u128> {
+    let last_funding_time = LAST_FUNDING_TIMES
+        .may_load(storage, collateral_token.clone())?
+        .unwrap_or_default();
+    let next_funding_rate = if last_funding_time == 0 {
+        0
+    } else {
+        get_next_funding_rate(storage, env, collateral_token.clone())
+            .map_err(|e| StdError::generic_err(e.to_string()))?
+    };
+    let funding_fee = get_funding_fee(
+        storage,
+        collateral_token,
+        position.size,
+        position.entry_funding_rate,
+    )?;
+    Ok(funding_fee.add(
+        position
+            .size
+            .mul(next_funding_rate)
+            .div(FUNDING_RATE_PRECISION),
+    ))
+}
+
+fn get_entry_funding_rate(storage: &mut dyn Storage, collateral_token: Addr) -> StdResult<u128> {
+    Ok(COMMULATIVE_FUNDING_RATES
+        .may_load(storage, collateral_token)?
+        .unwrap_or_default())
+}
+
+fn collect_margin_fees(
+    account: Addr,
+    collateral_token: Addr,
+    size_delta: u128,
+    size: u128,
+    entry_funding_rate: u128,
+    env: Env,
+    storage: &mut dyn Storage,
+) -> Result<(u128, Vec<Event>), ContractError> {
+    let state_variables = STATE_VARIABLES.load(storage)?;
+    let mut fee_reserves = FEE_RESERVES
+        .may_load(storage, collateral_token.clone())?
+        .unwrap_or_default();
+    let after_fee_usd = size_delta
+        .mul(BASIS_POINTS_DIVISOR.sub(state_variables.margin_fee_basis_points))
+        .div(BASIS_POINTS_DIVISOR);
+    let mut fee_usd = size_delta.sub(after_fee_usd);
+
+    let funding_fee = get_funding_fee(storage, collateral_token.clone(), size, entry_funding_rate)?;
+    fee_usd += funding_fee;
+
+    let mut fee_tokens = usd_to_token_min(collateral_token.clone(), fee_usd, storage)?;
+
+    let (discount, rebate, mut events) = apply_referral(
+        storage,
+        &account,
+        collateral_token.clone(),
+        fee_tokens,
+        size_delta,
+    )?;
+    // the discount is never charged to the position, the rebate is kept for the referrer
+    let discount_usd = token_to_usd_min(collateral_token.clone(), discount, storage)?;
+    fee_usd = fee_usd.saturating_sub(discount_usd);
+    fee_tokens = fee_tokens.sub(discount).sub(rebate);
+    let (fee_tokens, insurance_event) =
+        take_insurance_share(storage, collateral_token.clone(), fee_tokens)?;
+    events.extend(insurance_event);
+
+    fee_reserves = fee_reserves.add(fee_tokens);
+    FEE_RESERVES.save(storage, collateral_token.clone(), &fee_reserves)?;
+    // referral discounts only apply to the position fee
+    record_stats(
+        storage,
+        env,
+        collateral_token.clone(),
+        TokenStats {
+            margin_fees_usd: fee_usd.saturating_sub(funding_fee),
+            funding_fees_usd: funding_fee.min(fee_usd),
+            ..Default::default()
+        },
+    )?;
+
+    events.push(
+        CollectFees {
+            token: collateral_token,
+            fee_type: "margin",
+            fee_usd,
+            fee_tokens,
+        }
+        .into(),
+    );
+    Ok((fee_usd, events))
+}
+
+// the fees and losses validateLiquidation found for a position
+struct Liquidation {
+    margin_fees: u128,
+    loss_usd: u128,
+    fee_reciever: Addr,
+}
+
+// liquidates a position by closing it, the remaining collateral goes to the pool and the fee
+// receiver is paid out of the pool, returns the transfer to the fee receiver
+fn _liquidate_position(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    info: MessageInfo,
+    key: &[u8],
+    position: &Position,
+    liquidation: Liquidation,
+) -> Result<(Vec<Event>, Response), ContractError> {
+    let position_key = decode_position_key(key)?;
+    let account = position_key.key_1;
+    let collateral_token = position_key.key_2;
+    let index_token = position_key.key_3;
+    let is_long = position_key.key_4;
+    let Liquidation {
+        margin_fees,
+        loss_usd,
+        fee_reciever,
+    } = liquidation;
+
+    let liquidation_fee_usd = STATE_VARIABLES.load(storage)?.liquidation_fee_usd;
+    let mut events = vec![];
+    // validateLiquidation counted the pending skew funding so it is settled before closing
+    let mut position = position.clone();
+    events.extend(update_skew_funding(
+        storage,
+        env.clone(),
+        index_token.clone(),
+    )?);
+    events.extend(settle_skew_funding(
+        storage,
+        querier,
+        env.clone(),
+        key,
+        &mut position,
+    )?);
+    let fee_tokens = usd_to_token_min(collateral_token.clone(), margin_fees, storage)?;
+    let (fee_tokens, insurance_event) =
+        take_insurance_share(storage, collateral_token.clone(), fee_tokens)?;
+    events.extend(insurance_event);
+    let mut fee_reserves = FEE_RESERVES.load(storage, collateral_token.clone())?;
+    fee_reserves = fee_reserves.add(fee_tokens);
+    FEE_RESERVES.save(storage, collateral_token.clone(), &fee_reserves)?;
+    events.push(
+        CollectFees {
+            token: collateral_token.clone(),
+            fee_type: "margin",
+            fee_usd: margin_fees,
+            fee_tokens,
+        }
+        .into(),
+    );
+    events.push(decrease_reserved_amount(
+        storage,
+        collateral_token.clone(),
+        position.reserve_amount,
+    )?);
+
+    if is_long {
+        events.push(decrease_guarnteed_usd(
+            storage,
+            collateral_token.clone(),
+            position.size.sub(position.collateral),
+        )?);
+        let amount = usd_to_token_min(collateral_token.clone(), margin_fees, storage)?;
+        events.push(_decrease_pool_amount(
+            storage,
+            env.clone(),
+            collateral_token.clone(),
+            amount,
+        )?);
+    }
+
+    let mark_price = if is_long {
+        get_min_price(storage, index_token.clone())
+    } else {
+        get_max_price(storage, index_token.clone())
+    };
+
+    if !is_long && margin_fees < position.collateral {
+        let remaining_collateral = position.collateral.sub(margin_fees);
+        let amount = usd_to_token_min(collateral_token.clone(), remaining_collateral, storage)?;
+
+        events.push(_increase_pool_amount(
+            storage,
+            querier,
+            env.clone(),
+            collateral_token.clone(),
+            amount,
+        )?);
+    }
+
+    events.push(decrease_global_size(
+        storage,
+        index_token.clone(),
+        is_long,
+        position.size,
+    )?);
+
+    remove_position(storage, key)?;
+    record_trade(
+        storage,
+        &account,
+        TradeRecord {
+            collateral_token: collateral_token.clone(),
+            index_token: index_token.clone(),
+            is_long,
+            action: TradeAction::Liquidation,
+            price: mark_price,
+            size_delta: position.size,
+            collateral_delta_usd: position.collateral,
+            fee_usd: margin_fees,
+            realised_pnl: -(loss_usd as i128),
+            timestamp: env.block.time.seconds(),
+        },
+    )?;
+    record_stats(
+        storage,
+        env.clone(),
+        index_token.clone(),
+        TokenStats {
+            liquidation_volume_usd: position.size,
+            liquidations: 1,
+            ..Default::default()
+        },
+    )?;
+    // validateLiquidation counted the borrow fee in the margin fees
+    let funding_fees_usd = get_funding_fee(
+        storage,
+        collateral_token.clone(),
+        position.size,
+        position.entry_funding_rate,
+    )?
+    .min(margin_fees);
+    record_stats(
+        storage,
+        env.clone(),
+        collateral_token.clone(),
+        TokenStats {
+            margin_fees_usd: margin_fees.sub(funding_fees_usd),
+            funding_fees_usd,
+            liquidation_fees_usd: liquidation_fee_usd,
+            ..Default::default()
+        },
+    )?;
+
+    // pay the fee receiver using the pool, we assume that in general the liquidated amount should be sufficient to cover
+    // the liquidation fees
+    let amount = usd_to_token_min(collateral_token.clone(), liquidation_fee_usd, storage)?;
+    events.push(_decrease_pool_amount(
+        storage,
+        env.clone(),
+        collateral_token.clone(),
+        amount,
+    )?);
+    // the insurance fund only takes its share of the protocol fees, not of the liquidator's
+    let transfer = _transfer_out(
+        storage,
+        info.clone(),
+        collateral_token.clone(),
+        amount,
+        fee_reciever.clone(),
+    )?;
+
+    // losses and fees above the collateral are bad debt of the pool, the insurance fund pays
+    // for as much of it as it can
+    let bad_debt_usd = loss_usd
+        .add(margin_fees)
+        .add(liquidation_fee_usd)
+        .saturating_sub(position.collateral);
+    if bad_debt_usd > 0 {
+        events.extend(cover_bad_debt(
+            storage,
+            querier,
+            env.clone(),
+            key,
+            bad_debt_usd,
+        )?);
+    }
+
+    events.push(
+        LiquidatePosition {
+            key: event_position_key(&account, &collateral_token, &index_token, is_long),
+            size: position.size,
+            collateral: position.collateral,
+            reserve_amount: position.reserve_amount,
+            realised_pnl: position.realised_pnl,
+            mark_price,
+            fee_reciever,
+        }
+        .into(),
+    );
+
+    Ok((events, transfer))
+}
+
+// storage key, decoded key and position
+type AccountPosition = (Vec<u8>, Key<Addr, Addr, Addr, bool>, Position);
+
+// returns the open positions of an account that use the collateral token
+fn get_cross_margin_positions(
+    storage: &mut dyn Storage,
+    account: &Addr,
+    collateral_token: &Addr,
+) -> Result<Vec<AccountPosition>, ContractError> {
+    let keys = ACCOUNT_POSITIONS
+        .prefix(account)
+        .keys(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let mut account_positions = vec![];
+    for key in keys {
+        let position_key = decode_position_key(&key)?;
+        if position_key.key_2 != *collateral_token {
+            continue;
+        }
+        let position = POSITIONS.may_load(storage, &key)?.unwrap_or_default();
+        if position.size == 0 || !position.cross_margin {
+            continue;
+        }
+        account_positions.push((key, position_key, position));
+    }
+    Ok(account_positions)
+}
+
+// returns the balance, equity and maintenance margin of a cross margin account together with its
+// positions, the equity includes the unrealised pnl and pending funding of every position
+fn get_cross_margin_health(
+    storage: &mut dyn Storage,
+    env: Env,
+    account: &Addr,
+    collateral_token: &Addr,
+) -> Result<(u128, u128, u128, Vec<AccountPosition>), ContractError> {
+    let balance = CROSS_MARGIN_BALANCES
+        .may_load(storage, (account.clone(), collateral_token.clone()))?
+        .unwrap_or_default();
+    let positions = get_cross_margin_positions(storage, account, collateral_token)?;
+
+    let mut equity = token_to_usd_min(collateral_token.clone(), balance, storage)? as i128;
+    let mut maintenance_margin: u128 = 0;
+    for (_, position_key, position) in positions.iter() {
+        let (has_profit, delta) = get_delta(
+            position_key.key_3.clone(),
+            position.size,
+            position.average_price,
+            position_key.key_4,
+            position.last_increased_time,
+            storage,
+            env.clone(),
+        )?;
+        let pending_funding_usd = get_pending_skew_funding_usd(
+            storage,
+            env.clone(),
+            position_key.key_3.clone(),
+            position_key.key_4,
+            position,
+        )?;
+        let funding_fee =
+            get_pending_funding_fee(storage, env.clone(), collateral_token.clone(), position)?;
+        equity += position.collateral as i128 - pending_funding_usd - funding_fee as i128;
+        equity += if has_profit {
+            delta as i128
+        } else {
+            -(delta as i128)
+        };
+        maintenance_margin = maintenance_margin.add(get_maintenance_margin_usd(
+            storage,
+            position,
+            position_key.key_3.clone(),
+        )?);
+    }
+
+    Ok((
+        balance,
+        equity.max(0) as u128,
+        maintenance_margin,
+        positions,
+    ))
+}
+
+// returns the collateral a position of the given size needs to be opened
+fn get_required_collateral_usd(
+    storage: &mut dyn Storage,
+    index_token: Addr,
+    size: u128,
+) -> StdResult<u128> {
+    let config = TOKEN_RISK_CONFIGS
+        .may_load(storage, index_token)?
+        .unwrap_or_default();
+    let max_leverage = get_max_leverage(storage, &config)?;
+    let leverage_collateral_usd = size
+        .mul(BASIS_POINTS_DIVISOR)
+        .add(max_leverage.sub(1))
+        .div(max_leverage);
+    Ok(leverage_collateral_usd.max(config.min_collateral_usd))
+}
+
+// tops up the collateral of a position of a cross margin account to requiredUsd out of the
+// account balance and marks the position as drawing on the account, nothing is drawn when the
+// account has no cross margin account for the collateral token
+fn draw_cross_margin(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    key: &[u8],
+    position: &mut Position,
+    required_usd: u128,
+) -> Result<Vec<Event>, ContractError> {
+    let position_key = decode_position_key(key)?;
+    let account = &position_key.key_1;
+    let collateral_token = &position_key.key_2;
+    let is_long = position_key.key_4;
+
+    let balance = match CROSS_MARGIN_BALANCES
+        .may_load(storage, (account.clone(), collateral_token.clone()))?
+    {
+        Some(balance) => balance,
+        None => return Ok(vec![]),
+    };
+    position.cross_margin = true;
+    let balance_usd = token_to_usd_min(collateral_token.clone(), balance, storage)?;
+    let amount_usd = required_usd
+        .saturating_sub(position.collateral)
+        .min(balance_usd);
+    if amount_usd == 0 {
+        return Ok(vec![]);
+    }
+    let amount = usd_to_token_max(collateral_token.clone(), amount_usd, storage)?.min(balance);
+
+    let balance = balance.sub(amount);
+    CROSS_MARGIN_BALANCES.save(
+        storage,
+        (account.clone(), collateral_token.clone()),
+        &balance,
+    )?;
+    position.collateral = position.collateral.add(amount_usd);
+
+    let mut events = vec![];
+    if is_long {
+        // the drawn collateral is treated as part of the pool
+        events.push(decrease_guarnteed_usd(
+            storage,
+            collateral_token.clone(),
+            amount_usd,
+        )?);
+        events.push(_increase_pool_amount(
+            storage,
+            querier,
+            env,
+            collateral_token.clone(),
+            amount,
+        )?);
+    }
+    events.push(
+        UpdateCrossMargin {
+            account: account.clone(),
+            collateral_token: collateral_token.clone(),
+            delta: amount,
+            is_increase: false,
+            balance,
+        }
+        .into(),
+    );
+    Ok(events)
+}
+
+// returns the liquidation state of a position together with the margin fees to charge and its
+// loss in usd, 0 = healthy, 1 = liquidate, 2 = over max leverage so the position is closed instead,
+// the margin fees include the pending borrow fee
+fn validate_liquidation(
+    storage: &mut dyn Storage,
+    env: Env,
+    position: &Position,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+) -> Result<(u8, u128, u128), ContractError> {
+    let state_variables = STATE_VARIABLES.load(storage)?;
+    let (has_profit, delta) = get_delta(
+        index_token.clone(),
+        position.size,
+        position.average_price,
+        is_long,
+        position.last_increased_time,
+        storage,
+        env.clone(),
+    )?;
+    let loss_usd = if has_profit { 0 } else { delta };
+    let margin_fees = position
+        .size
+        .mul(state_variables.margin_fee_basis_points)
+        .div(BASIS_POINTS_DIVISOR)
+        .add(get_pending_funding_fee(
+            storage,
+            env.clone(),
+            collateral_token,
+            position,
+        )?);
+    // the same collateral as getLiquidationPrice, liquidations settle the pending funding
+    let (collateral, _) =
+        get_collateral_after_funding(storage, env, position, index_token.clone(), is_long)?;
+
+    if collateral < loss_usd {
+        return Ok((1, margin_fees, loss_usd));
+    }
+    let remaining_collateral = collateral.sub(loss_usd);
+    if remaining_collateral < margin_fees {
+        // cap the fees to the remaining collateral
+        return Ok((1, remaining_collateral, loss_usd));
+    }
+    if remaining_collateral < margin_fees.add(state_variables.liquidation_fee_usd) {
+        return Ok((1, margin_fees, loss_usd));
+    }
+
+    let config = TOKEN_RISK_CONFIGS
+        .may_load(storage, index_token)?
+        .unwrap_or_default();
+    let max_leverage = get_max_leverage(storage, &config)?;
+    if remaining_collateral.mul(max_leverage) < position.size.mul(BASIS_POINTS_DIVISOR) {
+        return Ok((2, margin_fees, loss_usd));
+    }
+    Ok((0, margin_fees, loss_usd))
+}
+
+// returns the index price at which a position can be liquidated together with its pending skew
+// funding, this is where its loss leaves less collateral after funding than the fees of closing
+// it or than what max leverage requires
+fn get_liquidation_price(
+    storage: &mut dyn Storage,
+    env: Env,
+    position: &Position,
+    collateral_token: Addr,
+    index_token: Addr,
+    is_long: bool,
+) -> StdResult<(u128, i128)> {
+    if position.size == 0 {
+        return Ok((0, 0));
+    }
+    // the pending borrow fee is charged on closing like the margin fee
+    let maintenance_margin_usd =
+        get_maintenance_margin_usd(storage, position, index_token.clone())?.add(
+            get_pending_funding_fee(storage, env.clone(), collateral_token, position)?,
+        );
+    let (collateral, pending_funding_usd) =
+        get_collateral_after_funding(storage, env, position, index_token, is_long)?;
+
+    let max_loss_usd = collateral.saturating_sub(maintenance_margin_usd);
+    let price_delta = max_loss_usd.mul(position.average_price).div(position.size);
+
+    let liquidation_price = if is_long {
+        position.average_price.saturating_sub(price_delta)
+    } else {
+        position.average_price.add(price_delta)
+    };
+    Ok((liquidation_price, pending_funding_usd))
+}
+
+// returns the collateral of a position after its pending skew funding together with the pending
+// funding, received funding only counts as far as the funding pool can pay it
+fn get_collateral_after_funding(
+    storage: &mut dyn Storage,
+    env: Env,
+    position: &Position,
+    index_token: Addr,
+    is_long: bool,
+) -> StdResult<(u128, i128)> {
+    let pending_funding_usd =
+        get_pending_skew_funding_usd(storage, env, index_token.clone(), is_long, position)?;
+    let collateral = if pending_funding_usd > 0 {
+        position
+            .collateral
+            .saturating_sub(pending_funding_usd.unsigned_abs())
+    } else {
+        let pool_usd = SKEW_FUNDING_STATES
+            .may_load(storage, index_token)?
+            .unwrap_or_default()
+            .pool_usd;
+        position
+            .collateral
+            .add(pending_funding_usd.unsigned_abs().min(pool_usd))
+    };
+    Ok((collateral, pending_funding_usd))
+}
+
+// returns the collateral a position needs after its losses to not be liquidatable, this is the
+// larger of the fees of closing it and the collateral max leverage requires
+fn get_maintenance_margin_usd(
+    storage: &mut dyn Storage,
+    position: &Position,
+    index_token: Addr,
+) -> StdResult<u128> {
+    let state_variables = STATE_VARIABLES.load(storage)?;
+    let config = TOKEN_RISK_CONFIGS
+        .may_load(storage, index_token)?
+        .unwrap_or_default();
+    let max_leverage = get_max_leverage(storage, &config)?;
+
+    // closing the position charges the margin fee on its whole size
+    let fees_usd = position
+        .size
+        .mul(state_variables.margin_fee_basis_points)
+        .div(BASIS_POINTS_DIVISOR)
+        .add(state_variables.liquidation_fee_usd);
+    let leverage_collateral_usd = position.size.mul(BASIS_POINTS_DIVISOR).div(max_leverage);
+    Ok(fees_usd.max(leverage_collateral_usd))
+}
+
+// returns the skew funding a position owes since it was last settled including intervals that
+// have not been added to the cumulative funding yet, negative when the position receives funding
+fn get_pending_skew_funding_usd(
+    storage: &mut dyn Storage,
+    env: Env,
+    index_token: Addr,
+    is_long: bool,
+    position: &Position,
+) -> StdResult<i128> {
+    let state = SKEW_FUNDING_STATES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+    let mut cumulative_funding = if is_long {
+        state.cumulative_long_funding
+    } else {
+        state.cumulative_short_funding
+    };
+
+    if let Some(config) = SKEW_FUNDING_CONFIGS.may_load(storage, index_token.clone())? {
+        let funding_interval = STATE_VARIABLES.load(storage)?.funding_interval;
+        let now = env.block.time.seconds();
+        if state.last_funding_time > 0 && state.last_funding_time.add(funding_interval) <= now {
+            let intervals = now.sub(state.last_funding_time).div(funding_interval) as i128;
+            let (long_rate, short_rate) =
+                get_skew_funding_rates(storage, index_token, config.funding_factor)?;
+            cumulative_funding += if is_long { long_rate } else { short_rate } * intervals;
+        }
+    }
+
+    Ok(
+        (position.size as i128) * (cumulative_funding - position.entry_skew_funding)
+            / (FUNDING_RATE_PRECISION as i128),
+    )
+}
+
+// returns the size to close when liquidating a position that is over max leverage, this is the whole
+// position unless a partial liquidation config applies to it
+fn get_partial_liquidation_size(
+    storage: &mut dyn Storage,
+    position: &Position,
+    index_token: Addr,
+    loss_usd: u128,
+) -> StdResult<u128> {
+    let config = match PARTIAL_LIQUIDATION_CONFIG.may_load(storage)? {
+        Some(config) if position.size >= config.min_size_usd => config,
+        _ => return Ok(position.size),
+    };
+    let state_variables = STATE_VARIABLES.load(storage)?;
+    let risk_config = TOKEN_RISK_CONFIGS
+        .may_load(storage, index_token)?
+        .unwrap_or_default();
+    let target_leverage = get_max_leverage(storage, &risk_config)?
+        .mul(BASIS_POINTS_DIVISOR.sub(config.buffer_basis_points))
+        .div(BASIS_POINTS_DIVISOR);
+
+    // closing sizeDelta realises its share of the loss and charges margin and liquidation fees
+    // on it, so the smallest sizeDelta with
+    // (size - sizeDelta) * BPD <= (collateral - loss - fees(sizeDelta)) * targetLeverage
+    // where fees(sizeDelta) = sizeDelta * (marginFeeBasisPoints / BPD + liquidationFeeUsd / size)
+    let divisor = Uint256::from(BASIS_POINTS_DIVISOR);
+    let size = Uint256::from(position.size);
+    let leverage = Uint256::from(target_leverage);
+    let remaining_collateral = Uint256::from(position.collateral.saturating_sub(loss_usd));
+    let fee_factor = Uint256::from(state_variables.margin_fee_basis_points) * size
+        + Uint256::from(state_variables.liquidation_fee_usd) * divisor;
+    let denominator = match (divisor * divisor * size).checked_sub(fee_factor * leverage) {
+        Ok(denominator) if !denominator.is_zero() => denominator,
+        _ => return Ok(position.size),
+    };
+    let excess = (size * divisor).saturating_sub(remaining_collateral * leverage);
+    let numerator = divisor * size * excess;
+    let size_delta =
+        Uint128::try_from((numerator + denominator - Uint256::one()) / denominator)?.u128();
+
+    if size_delta >= position.size
+        || position.size.sub(size_delta) < risk_config.min_position_size_usd
+    {
+        return Ok(position.size);
+    }
+    Ok(size_delta)
+}
+
+// moves insuranceFundBasisPoints of a fee into the insurance fund of the token and returns the
+// rest of the fee
+fn take_insurance_share(
+    storage: &mut dyn Storage,
+    token: Addr,
+    fee_tokens: u128,
+) -> StdResult<(u128, Option<Event>)> {
+    let basis_points = INSURANCE_FUND_BASIS_POINTS
+        .may_load(storage)?
+        .unwrap_or_default();
+    let share = fee_tokens.mul(basis_points).div(BASIS_POINTS_DIVISOR);
+    if share == 0 {
+        return Ok((fee_tokens, None));
+    }
+    let balance = INSURANCE_FUNDS
+        .may_load(storage, token.clone())?
+        .unwrap_or_default()
+        .add(share);
+    INSURANCE_FUNDS.save(storage, token.clone(), &balance)?;
+
+    Ok((
+        fee_tokens.sub(share),
+        Some(
+            AmountChanged {
+                amount: TrackedAmount::InsuranceFund,
+                token,
+                delta: share,
+                is_increase: true,
+                value: balance,
+            }
+            .into(),
+        ),
+    ))
+}
+
+// pays bad debt of a liquidated position out of the insurance fund of its collateral token, the
+// covered tokens go to the pool and whatever the fund cannot cover stays a loss of the pool
+fn cover_bad_debt(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    key: &[u8],
+    bad_debt_usd: u128,
+) -> Result<Vec<Event>, ContractError> {
+    let position_key = decode_position_key(key)?;
+    let account = &position_key.key_1;
+    let collateral_token = &position_key.key_2;
+    let index_token = &position_key.key_3;
+    let is_long = position_key.key_4;
+
+    let mut events = vec![];
+    let balance = INSURANCE_FUNDS
+        .may_load(storage, collateral_token.clone())?
+        .unwrap_or_default();
+    let bad_debt_tokens = usd_to_token_max(collateral_token.clone(), bad_debt_usd, storage)?;
+    let covered_amount = bad_debt_tokens.min(balance);
+    let covered_usd = token_to_usd_min(collateral_token.clone(), covered_amount, storage)?;
+    let uncovered_usd = if covered_amount == bad_debt_tokens {
+        0
+    } else {
+        bad_debt_usd.saturating_sub(covered_usd)
+    };
+
+    if covered_amount > 0 {
+        let balance = balance.sub(covered_amount);
+        INSURANCE_FUNDS.save(storage, collateral_token.clone(), &balance)?;
+        events.push(
+            AmountChanged {
+                amount: TrackedAmount::InsuranceFund,
+                token: collateral_token.clone(),
+                delta: covered_amount,
+                is_increase: false,
+                value: balance,
+            }
+            .into(),
+        );
+        events.push(_increase_pool_amount(
+            storage,
+            querier,
+            env.clone(),
+            collateral_token.clone(),
+            covered_amount,
+        )?);
+    }
+
+    let index = INSURANCE_FUND_RECORD_COUNTS
+        .may_load(storage, collateral_token.clone())?
+        .unwrap_or_default();
+    INSURANCE_FUND_RECORDS.save(
+        storage,
+        (collateral_token.clone(), index),
+        &InsuranceFundRecord {
+            account: account.clone(),
+            bad_debt_usd,
+            covered_amount,
+            uncovered_usd,
+            timestamp: env.block.time.seconds(),
+        },
+    )?;
+    INSURANCE_FUND_RECORD_COUNTS.save(storage, collateral_token.clone(), &index.add(1))?;
+
+    events.push(
+        CoverBadDebt {
+            key: event_position_key(account, collateral_token, index_token, is_long),
+            bad_debt_usd,
+            covered_amount,
+            uncovered_usd,
+        }
+        .into(),
+    );
+    Ok(events)
+}
+
+fn usd_to_token_min(token: Addr, usd_amount: u128, storage: &mut dyn Storage) -> StdResult<u128> {
+    if usd_amount == 0 {
+        return Ok(0);
+    }
+    let price = get_max_price(storage, token.clone());
+    usd_to_token(token, usd_amount, price, storage)
+}
+
+fn usd_to_token_max(token: Addr, usd_amount: u128, storage: &mut dyn Storage) -> StdResult<u128> {
+    if usd_amount == 0 {
+        return Ok(0);
+    }
+    let price = get_min_price(storage, token.clone());
+    usd_to_token(token, usd_amount, price, storage)
+}
+
+fn usd_to_token(
+    token: Addr,
+    usd_amount: u128,
+    price: u128,
+    storage: &mut dyn Storage,
+) -> StdResult<u128> {
+    let token_decimals = TOKEN_DECIMALS.load(storage, token.clone()).unwrap();
+    if usd_amount == 0 {
+        return Ok(0);
+    }
+    let decimals = token_decimals;
+    Ok(usd_amount.mul(10_u128.pow(decimals as u32).div(price)))
+}
+
+fn token_to_usd_min(token: Addr, token_amount: u128, storage: &mut dyn Storage) -> StdResult<u128> {
+    let token_decimals = TOKEN_DECIMALS.load(storage, token.clone()).unwrap();
+    if token_amount == 0 {
+        return Ok(0);
+    }
+    let price = get_min_price(storage, token);
+    let decimals = token_decimals;
+    Ok(token_amount.mul(price).div(10_u128.pow(decimals as u32)))
+}
+
+fn validate_position(size: u128, collateral: u128) {
+    if size == 0 {
+        _validate(collateral == 0, 39).unwrap();
+        return;
+    }
+    _validate(size >= collateral, 40).unwrap();
+}
+
+// checks the per index token limits, positions that are fully closed are not checked
+fn validate_position_risk(
+    storage: &mut dyn Storage,
+    key: &[u8],
+    size: u128,
+    collateral: u128,
+) -> Result<Response, ContractError> {
+    if size == 0 {
+        return Ok(Response::default());
+    }
+    let position_key = decode_position_key(key)?;
+    let account = position_key.key_1;
+    let index_token = position_key.key_3;
+    let config = TOKEN_RISK_CONFIGS
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+    let max_leverage = get_max_leverage(storage, &config)?;
+
+    _validate(collateral > 0, 81)?;
+    _validate(
+        size.mul(BASIS_POINTS_DIVISOR).div(collateral) <= max_leverage,
+        81,
+    )?;
+    _validate(collateral >= config.min_collateral_usd, 82)?;
+    _validate(size >= config.min_position_size_usd, 83)?;
+    if config.max_position_size_usd > 0 {
+        // the position is counted with its new size and the account's other positions as saved
+        let mut account_size = size;
+        for other_key in ACCOUNT_POSITIONS
+            .prefix(&account)
+            .keys(storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?
+        {
+            if other_key == key || decode_position_key(&other_key)?.key_3 != index_token {
+                continue;
+            }
+            let other_position = POSITIONS.may_load(storage, &other_key)?.unwrap_or_default();
+            account_size = account_size.add(other_position.size);
+        }
+        _validate(account_size <= config.max_position_size_usd, 84)?;
+    }
+    Ok(Response::default())
+}
+
+fn get_max_leverage(storage: &mut dyn Storage, config: &TokenRiskConfig) -> StdResult<u128> {
+    if config.max_leverage > 0 {
+        return Ok(config.max_leverage);
+    }
+    Ok(STATE_VARIABLES.load(storage)?.max_leverage)
+}
+fn increase_reserved_amount(
+    storage: &mut dyn Storage,
+    token: Addr,
+    amount: u128,
+) -> Result<Event, ContractError> {
+    let mut reserved_amounts = RSERVED_AMOUNTS.load(storage, token.clone()).unwrap();
+    let pool_amounts = POOL_AMOUNTS.load(storage, token.clone()).unwrap();
+    reserved_amounts = reserved_amounts.add(amount);
+    _validate(reserved_amounts <= pool_amounts, 52)?;
+    RSERVED_AMOUNTS
+        .save(storage, token.clone(), &reserved_amounts)
+        .unwrap();
+
+    Ok(AmountChanged {
+        amount: TrackedAmount::ReservedAmount,
+        token,
+        delta: amount,
+        is_increase: true,
+        value: reserved_amounts,
+    }
+    .into())
+}
+
+fn decrease_reserved_amount(
+    storage: &mut dyn Storage,
+    token: Addr,
+    amount: u128,
+) -> Result<Event, ContractError> {
+    let mut reserved_amounts = RSERVED_AMOUNTS.load(storage, token.clone()).unwrap();
+    reserved_amounts = reserved_amounts.sub(amount);
+    RSERVED_AMOUNTS
+        .save(storage, token.clone(), &reserved_amounts)
+        .unwrap();
+
+    Ok(AmountChanged {
+        amount: TrackedAmount::ReservedAmount,
+        token,
+        delta: amount,
+        is_increase: false,
+        value: reserved_amounts,
+    }
+    .into())
+}
+fn increase_guarnteed_usd(
+    storage: &mut dyn Storage,
+    token: Addr,
+    usdg_amount: u128,
+) -> Result<Event, ContractError> {
+    let mut guarnteed_usd = GUARANTEED_USD.load(storage, token.clone()).unwrap();
+    guarnteed_usd = guarnteed_usd.add(usdg_amount);
+    GUARANTEED_USD
+        .save(storage, token.clone(), &guarnteed_usd)
+        .unwrap();
+    Ok(AmountChanged {
+        amount: TrackedAmount::GuaranteedUsd,
+        token,
+        delta: usdg_amount,
+        is_increase: true,
+        value: guarnteed_usd,
+    }
+    .into())
+}
+
+fn decrease_guarnteed_usd(
+    storage: &mut dyn Storage,
+    token: Addr,
+    usdg_amount: u128,
+) -> Result<Event, ContractError> {
+    let mut guarnteed_usd = GUARANTEED_USD.load(storage, token.clone()).unwrap();
+    guarnteed_usd = guarnteed_usd.sub(usdg_amount);
+    GUARANTEED_USD
+        .save(storage, token.clone(), &guarnteed_usd)
+        .unwrap();
+    Ok(AmountChanged {
+        amount: TrackedAmount::GuaranteedUsd,
+        token,
+        delta: usdg_amount,
+        is_increase: false,
+        value: guarnteed_usd,
+    }
+    .into())
+}
+
+fn get_next_global_short_average_price(
+    index_token: Addr,
+    next_price: u128,
+    size_delta: u128,
+    storage: &mut dyn Storage,
+) -> StdResult<u128> {
+    let global_short_sizes = GLOBAL_SHORT_SIZES.load(storage, index_token.clone())?;
+    let mut global_short_average_prizes =
+        GLOBAL_SHORT_AVERAGE_PRIZES.load(storage, index_token.clone())?;
+    let size = global_short_sizes;
+    let average_price = global_short_average_prizes;
+    let price_delta = if average_price > next_price {
+        average_price - next_price
+    } else {
+        next_price - average_price
+    };
+
+    let delta = size * price_delta / average_price;
+    let has_profit = average_price > next_price;
+
+    let next_size = size + size_delta;
+    let divisor = if has_profit {
+        next_size - delta
+    } else {
+        next_size + delta
+    };
+
+    let result = next_price * next_size / divisor;
+    Ok(result)
+}
+
+fn get_next_global_long_average_price(
+    index_token: Addr,
+    next_price: u128,
+    size_delta: u128,
+    storage: &mut dyn Storage,
+) -> StdResult<u128> {
+    let size = GLOBAL_LONG_SIZES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+    let average_price = GLOBAL_LONG_AVERAGE_PRICES
+        .may_load(storage, index_token)?
+        .unwrap_or_default();
+    if average_price == 0 {
+        return Ok(next_price);
+    }
+    let price_delta = average_price.abs_diff(next_price);
+
+    let delta = size * price_delta / average_price;
+    let has_profit = next_price > average_price;
+
+    let next_size = size + size_delta;
+    let divisor = if has_profit {
+        next_size + delta
+    } else {
+        next_size - delta
+    };
+
+    let result = next_price * next_size / divisor;
+    Ok(result)
+}
+fn increase_global_short_size(
+    storage: &mut dyn Storage,
+    index_token: Addr,
+    amount: u128,
+) -> StdResult<Event> {
+    let mut global_short_sizes = GLOBAL_SHORT_SIZES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+    let max_global_short_sizes = MAX_GLOBAL_SHORT_SIZES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+
+    global_short_sizes = global_short_sizes.add(amount);
+    let max_size = max_global_short_sizes;
+    if max_size != 0 && global_short_sizes > max_size {
+        return Err(StdError::GenericErr {
+            msg: "Vault: max shorts exceeded".to_string(),
+        });
+    }
+    GLOBAL_SHORT_SIZES.save(storage, index_token.clone(), &global_short_sizes)?;
+
+    Ok(AmountChanged {
+        amount: TrackedAmount::GlobalShortSize,
+        token: index_token,
+        delta: amount,
+        is_increase: true,
+        value: global_short_sizes,
+    }
+    .into())
+}
+
+fn decrease_global_short_size(
+    storage: &mut dyn Storage,
+    index_token: Addr,
+    amount: u128,
+) -> StdResult<Event> {
+    let mut global_short_sizes = GLOBAL_SHORT_SIZES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+
+    let size = global_short_sizes;
+    if amount > size {
+        global_short_sizes = 0;
+    } else {
+        global_short_sizes = global_short_sizes.sub(amount);
+    }
+    GLOBAL_SHORT_SIZES.save(storage, index_token.clone(), &global_short_sizes)?;
+
+    Ok(AmountChanged {
+        amount: TrackedAmount::GlobalShortSize,
+        token: index_token,
+        delta: amount,
+        is_increase: false,
+        value: global_short_sizes,
+    }
+    .into())
+}
+
+fn increase_global_long_size(
+    storage: &mut dyn Storage,
+    index_token: Addr,
+    amount: u128,
+) -> StdResult<Event> {
+    let mut global_long_sizes = GLOBAL_LONG_SIZES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+    let max_size = MAX_GLOBAL_LONG_SIZES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default();
+
+    global_long_sizes = global_long_sizes.add(amount);
+    if max_size != 0 && global_long_sizes > max_size {
+        return Err(StdError::GenericErr {
+            msg: "Vault: max longs exceeded".to_string(),
+        });
+    }
+    GLOBAL_LONG_SIZES.save(storage, index_token.clone(), &global_long_sizes)?;
+
+    Ok(AmountChanged {
+        amount: TrackedAmount::GlobalLongSize,
+        token: index_token,
+        delta: amount,
+        is_increase: true,
+        value: global_long_sizes,
+    }
+    .into())
+}
+
+fn decrease_global_long_size(
+    storage: &mut dyn Storage,
+    index_token: Addr,
+    amount: u128,
+) -> StdResult<Event> {
+    let global_long_sizes = GLOBAL_LONG_SIZES
+        .may_load(storage, index_token.clone())?
+        .unwrap_or_default()
+        .saturating_sub(amount);
+    GLOBAL_LONG_SIZES.save(storage, index_token.clone(), &global_long_sizes)?;
+
+    Ok(AmountChanged {
+        amount: TrackedAmount::GlobalLongSize,
+        token: index_token,
+        delta: amount,
+        is_increase: false,
+        value: global_long_sizes,
+    }
+    .into())
+}
+
+fn decrease_global_size(
+    storage: &mut dyn Storage,
+    index_token: Addr,
+    is_long: bool,
+    amount: u128,
+) -> StdResult<Event> {
+    if is_long {
+        return decrease_global_long_size(storage, index_token, amount);
+    }
+    decrease_global_short_size(storage, index_token, amount)
+}
+fn reduce_collateral(
+    storage: &mut dyn Storage,
+    querier: QuerierWrapper,
+    env: Env,
+    account: Addr,
+    collateral_token: Addr,
+    index_token: Addr,
+    collateral_delta: u128,
+    size_delta: u128,
+    is_long: bool,
+) -> Result<(u128, u128, u128, i128, Vec<Event>), ContractError> {
+    let key = get_position_key(
+        account.clone(),
+        collateral_token.clone(),
+        index_token.clone(),
+        is_long,
+    )?;
+    let mut position = POSITIONS.load(storage, &key).unwrap();
+
+    let mut events = vec![];
+    let (fee, fee_events) = collect_margin_fees(
+        account.clone(),
+        collateral_token.clone(),
+        size_delta,
+        position.size,
+        position.entry_funding_rate,
+        env.clone(),
+        storage,
+    )?;
+    events.extend(fee_events);
+
+    let has_profit;
+    let adjusted_delta;
+
+    {
+        let (has_profit_value, delta) = get_delta(
+            index_token.clone(),
+            position.size,
+            position.average_price,
+            is_long,
+            position.last_increased_time,
+            storage,
+            env.clone(),
+        )?;
+        has_profit = has_profit_value;
+        adjusted_delta = size_delta * delta / position.size;
+    }
+    events.push(
+        UpdatePnl {
+            key: event_position_key(&account, &collateral_token, &index_token, is_long),
+            has_profit,
+            delta_usd: adjusted_delta,
+        }
+        .into(),
+    );
+
+    let mut usd_out: u128 = 0;
+
+    if has_profit && adjusted_delta > 0 {
+        usd_out = adjusted_delta;
+        position.realised_pnl += adjusted_delta;
+
+        if !is_long {
+            let token_amount = usd_to_token_min(collateral_token.clone(), adjusted_delta, storage)?;
+            events.push(_decrease_pool_amount(
+                storage,
+                env.clone(),
+                collateral_token.clone(),
+                token_amount,
+            )?);
+        }
+    }
+
+    if !has_profit && adjusted_delta > 0 {
+        position.collateral -= adjusted_delta;
 
-    Ok(Response::new()
-        .add_attribute("action", "increase_usdg_amount")
-        .add_attribute("token", token.to_string())
-        .add_attribute("amount", amount.to_string()))
-}
+        if !is_long {
+            let token_amount = usd_to_token_min(collateral_token.clone(), adjusted_delta, storage)?;
+            events.push(_increase_pool_amount(
+                storage,
+                querier,
+                env.clone(),
+                collateral_token.clone(),
+                token_amount,
+            )?);
+        }
 
-fn _decrease_usdg_amount(
-    storage: &mut dyn Storage,
-    token: Addr,
-    amount: u128,
-) -> Result<Response, ContractError> {
-    let mut usdg_amount = USDG_AMOUNTS.load(storage, token.clone())?;
+        // realisedPnl is unsigned, the signed pnl of every trade is kept in the trade ledger
+        position.realised_pnl = position.realised_pnl.saturating_sub(adjusted_delta);
+    }
 
-    // since USDG can be minted using multiple assets
-    // it is possible for the USDG debt for a single asset to be less than zero
-    // the USDG debt is capped to zero for this case
+    if collateral_delta > 0 {
+        usd_out += collateral_delta;
+        position.collateral -= collateral_delta;
+    }
 
-    if usdg_amount <= amount {
-        usdg_amount = 0;
-        return Ok(Response::new()
-            .add_attribute("action", "decrease_usdg_amount")
-            .add_attribute("token", token.to_string())
-            .add_attribute("amount", amount.to_string()));
+    if position.size == size_delta {
+        usd_out += position.collateral;
+        position.collateral = 0;
     }
-    usdg_amount -= amount;
-    USDG_AMOUNTS.save(storage, token.clone(), &usdg_amount)?;
 
-    Ok(Response::new()
-        .add_attribute("action", "decrease_usdg_amount")
-        .add_attribute("token", token.to_string())
-        .add_attribute("amount", amount.to_string()))
+    let usd_out_after_fee: u128 = if usd_out > fee {
+        usd_out - fee
+    } else {
+        position.collateral -= fee;
+        if is_long {
+            let fee_tokens = usd_to_token_min(collateral_token.clone(), fee, storage)?;
+            events.push(_decrease_pool_amount(
+                storage,
+                env.clone(),
+                collateral_token,
+                fee_tokens,
+            )?);
+        }
+        usd_out
+    };
+
+    save_position(storage, &key, &position)?;
+
+    let realised_pnl = if has_profit {
+        adjusted_delta as i128
+    } else {
+        -(adjusted_delta as i128)
+    };
+    Ok((usd_out, usd_out_after_fee, fee, realised_pnl, events))
 }
 
-pub fn get_token_balance_of(
-    querier: QuerierWrapper,
-    user_address: Addr,
-    cw20_contract_addr: Addr,
-) -> Result<u128, ContractError> {
-    let query_msg = cw20::Cw20QueryMsg::Balance {
-        address: user_address.to_string(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{
+        mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+        MOCK_CONTRACT_ADDR,
     };
-    let msg = querier.query(&cosmwasm_std::QueryRequest::Wasm(WasmQuery::Smart {
-        contract_addr: cw20_contract_addr.to_string(),
-        msg: to_binary(&query_msg)?,
-    }))?;
+    use cosmwasm_std::{from_json, ContractResult, OwnedDeps, SystemError, SystemResult};
+    use std::collections::HashMap;
+
+    const GOV_ADDR: &str = "gov";
+    const USDG: &str = "usdg";
+
+    fn setup() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            InstantiateMsg {},
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::Initialize {
+                _router: Addr::unchecked("router"),
+                _usdg: Addr::unchecked(USDG),
+                _price_feed: Addr::unchecked("price_feed"),
+                _liquidation_fee_usd: 5,
+                _funding_rate_factor: 0,
+                _stable_funding_rate_factor: 0,
+            },
+        )
+        .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, Addr::unchecked(USDG), &0)
+            .unwrap();
+        deps
+    }
 
-    Ok(msg)
-}
+    // whitelists a token without decimals so that one token is worth its price in usd
+    fn add_token(storage: &mut dyn Storage, token: &str, is_stable: bool) -> Addr {
+        let token = Addr::unchecked(token);
+        let mut state_variables = STATE_VARIABLES.load(storage).unwrap();
+        state_variables.whitelisted_token_count += 1;
+        state_variables.all_whitelisted_tokens.push(token.clone());
+        STATE_VARIABLES.save(storage, &state_variables).unwrap();
+        WHITELISTED_TOKENS
+            .save(storage, token.clone(), &true)
+            .unwrap();
+        TOKEN_DECIMALS.save(storage, token.clone(), &0).unwrap();
+        MIN_PROFIT_BASIS_POINTS
+            .save(storage, token.clone(), &0)
+            .unwrap();
+        STABLE_TOKENS
+            .save(storage, token.clone(), &is_stable)
+            .unwrap();
+        SHORTABLE_TOKENS
+            .save(storage, token.clone(), &!is_stable)
+            .unwrap();
+        for amounts in [
+            POOL_AMOUNTS,
+            RSERVED_AMOUNTS,
+            USDG_AMOUNTS,
+            MAX_USDG_AMOUNTS,
+            BUFFER_AMOUNTS,
+            GUARANTEED_USD,
+            FEE_RESERVES,
+            TOKEN_BALANCES,
+            COMMULATIVE_FUNDING_RATES,
+            GLOBAL_SHORT_SIZES,
+            GLOBAL_SHORT_AVERAGE_PRIZES,
+        ] {
+            amounts.save(storage, token.clone(), &0).unwrap();
+        }
+        token
+    }
 
-fn increase_pool_amount(
-    storage: &mut dyn Storage,
-    querier: QuerierWrapper,
-    env: Env,
-    token: Addr,
-    amount: u128,
-) -> Result<Response, ContractError> {
-    let pool_amounts = POOL_AMOUNTS.load(storage, token.clone())?;
-    let balance = get_token_balance_of(querier, env.contract.address, token.clone())?;
-    _validate(pool_amounts <= balance, 49)?;
-    Ok(Response::new()
-        .add_attribute("action", "increase_pool_amount")
-        .add_attribute("token", token.to_string())
-        .add_attribute("amount", amount.to_string()))
-}
+    fn error_code(code: u64) -> ContractError {
+        ContractError::CustomError {
+            val: format!("Error code: {}", code),
+        }
+    }
 
-fn update_cumulative_funding_rate(
-    storage: &mut dyn Storage,
-    env: Env,
-    _collateral_token: Addr,
-    _index_token: Addr,
-) -> Result<Response, ContractError> {
-    let should_update = true;
+    // answers cw20 balance queries with the given balance per token contract
+    fn set_balances(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        balances: &[(&str, u128)],
+    ) {
+        let balances: HashMap<String, u128> = balances
+            .iter()
+            .map(|(token, balance)| (token.to_string(), *balance))
+            .collect();
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, .. } => match balances.get(contract_addr) {
+                Some(balance) => {
+                    SystemResult::Ok(ContractResult::Ok(to_json_binary(balance).unwrap()))
+                }
+                None => SystemResult::Err(SystemError::NoSuchContract {
+                    addr: contract_addr.clone(),
+                }),
+            },
+            _ => panic!("unexpected query"),
+        });
+    }
 
-    if !should_update {
-        return Ok(Response::new().add_attribute("action", "update_funding_rate"));
+    // returns the token, recipient and amount of every cw20 transfer sent by a response
+    fn transfers(res: &Response) -> Vec<(String, String, u128)> {
+        res.messages
+            .iter()
+            .filter_map(|sub_msg| match &sub_msg.msg {
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr, msg, ..
+                }) => match from_json(msg).ok()? {
+                    cw20::Cw20ExecuteMsg::Transfer { recipient, amount } => {
+                        Some((contract_addr.clone(), recipient, amount.u128()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
     }
-    let mut last_funding_times = LAST_FUNDING_TIMES
-        .load(storage, _collateral_token.clone())
-        .unwrap();
-    let state_variables = STATE_VARIABLES.load(storage).unwrap();
-    if last_funding_times == 0 {
-        last_funding_times = env
-            .block
-            .time
-            .seconds()
-            .clone()
-            .div(state_variables.funding_interval)
-            .mul(state_variables.funding_interval);
-        return Ok(Response::new().add_attribute("action", "update_funding_rate"));
+
+    fn event_attributes(res: &Response, ty: &str) -> Vec<(String, String)> {
+        let event = res.events.iter().find(|event| event.ty == ty).unwrap();
+        event
+            .attributes
+            .iter()
+            .map(|attribute| (attribute.key.clone(), attribute.value.clone()))
+            .collect()
     }
-    if last_funding_times.add(state_variables.funding_interval) > env.block.time.seconds() {
-        return Ok(Response::new().add_attribute("action", "update_funding_rate"));
+
+    fn attributes(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
     }
 
-    let funding_rate =
-        get_next_funding_rate(storage, env.clone(), _collateral_token.clone()).unwrap();
-    let commulative_funding_rates = COMMULATIVE_FUNDING_RATES
-        .load(storage, _collateral_token.clone())
+    #[test]
+    fn redeem_settlement_pays_out_pool_amounts_pro_rata() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &1000)
+            .unwrap();
+        POOL_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &400)
+            .unwrap();
+        USDG_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &600)
+            .unwrap();
+        USDG_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &400)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, eth.clone(), &1000)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, usdc.clone(), &400)
+            .unwrap();
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::EnableSettlementMode {
+                prices: vec![
+                    SettlementPrice {
+                        token: eth.clone(),
+                        price: 2,
+                    },
+                    SettlementPrice {
+                        token: usdc.clone(),
+                        price: 1,
+                    },
+                ],
+            },
+        )
         .unwrap();
-    last_funding_times = env
-        .block
-        .time
-        .seconds()
-        .div(state_variables.funding_interval)
-        .mul(state_variables.funding_interval);
-
-    LAST_FUNDING_TIMES
-        .save(storage, _collateral_token.clone(), &last_funding_times)
+        assert_eq!(SETTLEMENT_USDG_SUPPLY.load(&deps.storage).unwrap(), 1000);
+
+        // a quarter of the usdg supply is sent in and redeemed
+        set_balances(&mut deps, &[(USDG, 250)]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("lp", &[]),
+            ExecuteMsg::RedeemSettlement {
+                reciever: Addr::unchecked("lp"),
+            },
+        )
         .unwrap();
 
-    Ok(Response::new()
-        .add_attribute("action", "update_funding_rate")
-        .add_attribute("collateral_token", _collateral_token.to_string())
-        .add_attribute(
-            "commulative_funding_rates",
-            commulative_funding_rates.to_string(),
-        ))
-}
+        assert_eq!(res.messages.len(), 2);
+        assert_eq!(POOL_AMOUNTS.load(&deps.storage, eth.clone()).unwrap(), 750);
+        assert_eq!(POOL_AMOUNTS.load(&deps.storage, usdc.clone()).unwrap(), 300);
+        assert_eq!(USDG_AMOUNTS.load(&deps.storage, eth).unwrap(), 450);
+        assert_eq!(USDG_AMOUNTS.load(&deps.storage, usdc).unwrap(), 300);
+        assert_eq!(SETTLEMENT_USDG_SUPPLY.load(&deps.storage).unwrap(), 750);
+        assert_eq!(
+            TOKEN_BALANCES
+                .load(&deps.storage, Addr::unchecked(USDG))
+                .unwrap(),
+            250
+        );
+
+        // the usdg that was already redeemed can't be redeemed again by someone else
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("mallory", &[]),
+            ExecuteMsg::RedeemSettlement {
+                reciever: Addr::unchecked("mallory"),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(65));
+
+        let state: SettlementStateResponse =
+            from_json(query(deps.as_mut(), mock_env(), QueryMsg::GetSettlementState {}).unwrap())
+                .unwrap();
+        assert_eq!(state.outstanding_usdg, 750);
+    }
 
-fn get_next_funding_rate(
-    storage: &mut dyn Storage,
-    env: Env,
-    token: Addr,
-) -> Result<u128, ContractError> {
-    let mut last_funding_times = LAST_FUNDING_TIMES.load(storage, token.clone()).unwrap();
-    let state_variables = STATE_VARIABLES.load(storage).unwrap();
+    #[test]
+    fn redeem_settlement_waits_for_open_positions() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        IS_SETTLEMENT_MODE.save(&mut deps.storage, &true).unwrap();
+        SETTLEMENT_USDG_SUPPLY
+            .save(&mut deps.storage, &1000)
+            .unwrap();
+        let key = get_position_key(Addr::unchecked("trader"), eth.clone(), eth, true).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 100,
+                collateral: 10,
+                ..Default::default()
+            },
+        )
+        .unwrap();
 
-    if last_funding_times.add(state_variables.funding_interval) > env.block.time.seconds() {
-        return Ok(0);
-    }
-    let intervals = env
-        .block
-        .time
-        .seconds()
-        .sub(last_funding_times.div(state_variables.funding_interval));
-    let pool_amounts = POOL_AMOUNTS.load(storage, token.clone())?;
-    if pool_amounts == 0 {
-        return Ok(0);
+        set_balances(&mut deps, &[(USDG, 250)]);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("lp", &[]),
+            ExecuteMsg::RedeemSettlement {
+                reciever: Addr::unchecked("lp"),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(64));
     }
-    let is_stable = STABLE_TOKENS.load(storage, token.clone())?;
-    let mut funding_rate_factor = 0;
-    if is_stable {
-        funding_rate_factor = state_variables.stable_funding_rate_factor;
-    } else {
-        funding_rate_factor = state_variables.funding_rate_factor;
+
+    #[test]
+    fn positions_that_always_fail_to_settle_are_written_off_by_gov() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &1000)
+            .unwrap();
+        USDG_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &1000)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, usdc.clone(), &1000)
+            .unwrap();
+        // the collateral of the short is missing from the vault's balance, so the pool can never
+        // take the loss of the short
+        set_balances(&mut deps, &[(USDG, 0), ("usdc", 1000)]);
+        let key =
+            get_position_key(Addr::unchecked("trader"), usdc.clone(), eth.clone(), false).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 1000,
+                collateral: 100,
+                average_price: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::EnableSettlementMode {
+                prices: vec![
+                    SettlementPrice {
+                        token: eth.clone(),
+                        price: 2,
+                    },
+                    SettlementPrice {
+                        token: usdc.clone(),
+                        price: 1,
+                    },
+                ],
+            },
+        )
+        .unwrap();
+
+        let write_off = || ExecuteMsg::WriteOffPosition {
+            key: Binary::from(key.clone()),
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            write_off(),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(115));
+
+        // every retry of the settlement submessage fails the same way
+        for _ in 0..2 {
+            let res = execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("keeper", &[]),
+                ExecuteMsg::SettlePositions { limit: None },
+            )
+            .unwrap();
+            assert_eq!(res.messages.len(), 1);
+            let err = execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(mock_env().contract.address.as_str(), &[]),
+                ExecuteMsg::SettlePosition {
+                    key: Binary::from(key.clone()),
+                },
+            )
+            .unwrap_err();
+            assert_eq!(err, error_code(49));
+            reply(
+                deps.as_mut(),
+                mock_env(),
+                Reply {
+                    id: res.messages[0].id,
+                    result: SubMsgResult::Err(err.to_string()),
+                },
+            )
+            .unwrap();
+        }
+        set_balances(&mut deps, &[(USDG, 500), ("usdc", 1000)]);
+        let redeem = || ExecuteMsg::RedeemSettlement {
+            reciever: Addr::unchecked("lp"),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("lp", &[]), redeem()).unwrap_err();
+        assert_eq!(err, error_code(64));
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            write_off(),
+        )
+        .unwrap();
+        assert_eq!(POSITIONS.may_load(&deps.storage, &key).unwrap(), None);
+        assert!(!SETTLEMENT_FAILURES.has(&deps.storage, &key));
+        // the collateral of the written off short is left to the pool
+        assert_eq!(
+            POOL_AMOUNTS.load(&deps.storage, usdc.clone()).unwrap(),
+            1100
+        );
+
+        execute(deps.as_mut(), mock_env(), mock_info("lp", &[]), redeem()).unwrap();
+        assert_eq!(POOL_AMOUNTS.load(&deps.storage, usdc).unwrap(), 550);
     }
-    let reserved_amounts = RSERVED_AMOUNTS.load(storage, token)?;
 
-    Ok(funding_rate_factor
-        .mul(reserved_amounts)
-        .mul(intervals as u128)
-        .div(pool_amounts))
-}
+    // accrues one interval of skew funding with three times as much long as short open interest
+    fn accrue_skew_funding(storage: &mut dyn Storage, index_token: &Addr) {
+        SKEW_FUNDING_CONFIGS
+            .save(
+                storage,
+                index_token.clone(),
+                &SkewFundingConfig {
+                    funding_factor: 1000,
+                },
+            )
+            .unwrap();
+        GLOBAL_LONG_SIZES
+            .save(storage, index_token.clone(), &3_000_000)
+            .unwrap();
+        GLOBAL_SHORT_SIZES
+            .save(storage, index_token.clone(), &1_000_000)
+            .unwrap();
+        let mut env = mock_env();
+        update_skew_funding(storage, env.clone(), index_token.clone()).unwrap();
+        env.block.time = env.block.time.plus_seconds(8 * 3600);
+        update_skew_funding(storage, env, index_token.clone()).unwrap();
+    }
 
-fn adjust_for_decimals(
-    storage: &mut dyn Storage,
-    amount: u128,
-    token_div: Addr,
-    token_mul: Addr,
-) -> Result<u128, ContractError> {
-    let addresses = ADDRESSES.load(storage).unwrap();
-    let mut decimals_div = 0;
-    let mut decimals_mul = 0;
-    if token_div == addresses.usdg {
-        decimals_div = USDG_DECIMALS;
-    } else {
-        let token_decimals = TOKEN_DECIMALS.load(storage, token_div).unwrap();
-        decimals_div = token_decimals;
+    fn skew_funding_positions(
+        storage: &mut dyn Storage,
+        eth: &Addr,
+        usdc: &Addr,
+    ) -> ((Vec<u8>, Position), (Vec<u8>, Position)) {
+        let long_key =
+            get_position_key(Addr::unchecked("long"), eth.clone(), eth.clone(), true).unwrap();
+        let short_key =
+            get_position_key(Addr::unchecked("short"), usdc.clone(), eth.clone(), false).unwrap();
+        POOL_AMOUNTS.save(storage, usdc.clone(), &10_000).unwrap();
+        let position = Position {
+            size: 3_000_000,
+            collateral: 100_000,
+            average_price: 1,
+            ..Default::default()
+        };
+        (
+            (long_key, position.clone()),
+            (
+                short_key,
+                Position {
+                    size: 1_000_000,
+                    ..position
+                },
+            ),
+        )
     }
 
-    if token_mul == addresses.usdg {
-        decimals_mul = USDG_DECIMALS;
-    } else {
-        let token_decimals = TOKEN_DECIMALS.load(storage, token_mul).unwrap();
-        decimals_mul = token_decimals;
+    #[test]
+    fn skew_funding_received_equals_funding_paid() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        let ((long_key, mut long), (short_key, mut short)) =
+            skew_funding_positions(&mut deps.storage, &eth, &usdc);
+        accrue_skew_funding(&mut deps.storage, &eth);
+
+        let deps = deps.as_mut();
+        let env = mock_env();
+        settle_skew_funding(
+            deps.storage,
+            deps.querier,
+            env.clone(),
+            &long_key,
+            &mut long,
+        )
+        .unwrap();
+        settle_skew_funding(deps.storage, deps.querier, env, &short_key, &mut short).unwrap();
+
+        // the dominant long side pays 0.05% of its size and the short side receives all of it
+        assert_eq!(long.collateral, 100_000 - 1500);
+        assert_eq!(short.collateral, 100_000 + 1500);
+        let state = SKEW_FUNDING_STATES.load(deps.storage, eth.clone()).unwrap();
+        assert_eq!(state.pool_usd, 0);
+        assert_eq!(state.bad_debt_usd, 0);
+        // the paid long collateral stays in the pool, the received short collateral leaves it
+        assert_eq!(GUARANTEED_USD.load(deps.storage, eth).unwrap(), 1500);
+        assert_eq!(
+            POOL_AMOUNTS.load(deps.storage, usdc).unwrap(),
+            10_000 - 1500
+        );
     }
-    Ok(amount.mul(
-        10_u128
-            .pow(decimals_mul as u32)
-            .div(10_u128.pow(decimals_div as u32)),
-    ))
-}
 
-fn _collect_swap_fees(
-    storage: &mut dyn Storage,
-    token: Addr,
-    amount: u128,
-    fee_basis_point: u128,
-) -> Result<u128, ContractError> {
-    let after_fee_amount = amount
-        .mul(BASIS_POINTS_DIVISOR)
-        .sub(fee_basis_point)
-        .div(BASIS_POINTS_DIVISOR);
-    let fee_amount = amount.sub(after_fee_amount);
-    let mut fee_reserves = FEE_RESERVES.load(storage, token).unwrap();
-    fee_reserves = fee_reserves.add(fee_amount);
+    #[test]
+    fn skew_funding_receivers_settling_first_are_capped_by_the_funding_pool() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        let ((long_key, mut long), (short_key, mut short)) =
+            skew_funding_positions(&mut deps.storage, &eth, &usdc);
+        accrue_skew_funding(&mut deps.storage, &eth);
+
+        let deps = deps.as_mut();
+        let env = mock_env();
+        settle_skew_funding(
+            deps.storage,
+            deps.querier,
+            env.clone(),
+            &short_key,
+            &mut short,
+        )
+        .unwrap();
+        settle_skew_funding(deps.storage, deps.querier, env, &long_key, &mut long).unwrap();
+
+        // nothing was paid in yet so the short side receives nothing and the owed funding is bad debt
+        assert_eq!(short.collateral, 100_000);
+        assert_eq!(long.collateral, 100_000 - 1500);
+        let state = SKEW_FUNDING_STATES.load(deps.storage, eth).unwrap();
+        assert_eq!(state.pool_usd, 1500);
+        assert_eq!(state.bad_debt_usd, 1500);
+        assert_eq!(POOL_AMOUNTS.load(deps.storage, usdc).unwrap(), 10_000);
+    }
 
-    Ok(after_fee_amount)
-}
+    #[test]
+    fn cross_margin_withdrawals_account_for_the_price_move_of_positions() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let trader = Addr::unchecked("trader");
+
+        set_balances(&mut deps, &[("eth", 1000)]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(trader.as_str(), &[]),
+            ExecuteMsg::DepositCrossMargin {
+                collateral_token: eth.clone(),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            CROSS_MARGIN_BALANCES
+                .load(&deps.storage, (trader.clone(), eth.clone()))
+                .unwrap(),
+            1000
+        );
+
+        // a long opened at 2 that is marked at 1 has lost half of its size
+        let key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 1000,
+                collateral: 100,
+                average_price: 2,
+                cross_margin: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let account: CrossMarginAccountResponse = from_json(
+            query(
+                deps.as_mut(),
+                mock_env(),
+                QueryMsg::GetCrossMarginAccount {
+                    account: trader.clone(),
+                    collateral_token: eth.clone(),
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(account.balance, 1000);
+        assert_eq!(account.balance_usd, 1000);
+        assert_eq!(account.equity, 1000 + 100 - 500);
+        // 50x max leverage needs 2% of the size
+        assert_eq!(account.maintenance_margin, 20);
+
+        let withdraw = |amount| ExecuteMsg::WithdrawCrossMargin {
+            collateral_token: eth.clone(),
+            amount,
+            reciever: trader.clone(),
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(trader.as_str(), &[]),
+            withdraw(581),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(102));
+
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(trader.as_str(), &[]),
+            withdraw(580),
+        )
+        .unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(
+            CROSS_MARGIN_BALANCES
+                .load(&deps.storage, (trader, eth))
+                .unwrap(),
+            420
+        );
+    }
 
-fn _increase_pool_amount(
-    storage: &mut dyn Storage,
-    querier: QuerierWrapper,
-    env: Env,
-    token: Addr,
-    amount: u128,
-) -> Result<Response, ContractError> {
-    let mut pool_amounts = POOL_AMOUNTS.load(storage, token.clone())?;
-    pool_amounts.add(amount);
-    let balance = get_token_balance_of(querier, env.contract.address, token.clone())?;
-    _validate(pool_amounts <= balance, 49)?;
+    #[test]
+    fn cross_margin_liquidations_credit_the_profit_of_winning_positions() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let btc = add_token(&mut deps.storage, "btc", false);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &10_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &1200)
+            .unwrap();
+        GUARANTEED_USD
+            .save(&mut deps.storage, eth.clone(), &900)
+            .unwrap();
+        GLOBAL_LONG_SIZES
+            .save(&mut deps.storage, eth.clone(), &1000)
+            .unwrap();
+        GLOBAL_SHORT_SIZES
+            .save(&mut deps.storage, btc.clone(), &200)
+            .unwrap();
+        GLOBAL_SHORT_AVERAGE_PRIZES
+            .save(&mut deps.storage, btc.clone(), &2)
+            .unwrap();
+        // the pool, the account balance and the collateral of the short
+        TOKEN_BALANCES
+            .save(&mut deps.storage, eth.clone(), &10_320)
+            .unwrap();
+        set_balances(&mut deps, &[("eth", 10_320)]);
+        let trader = Addr::unchecked("trader");
+        CROSS_MARGIN_BALANCES
+            .save(&mut deps.storage, (trader.clone(), eth.clone()), &300)
+            .unwrap();
+
+        // a long opened at 2 has lost 500, a short opened at 2 has made 100
+        let long_key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        save_position(
+            &mut deps.storage,
+            &long_key,
+            &Position {
+                size: 1000,
+                collateral: 100,
+                average_price: 2,
+                reserve_amount: 1000,
+                cross_margin: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let short_key = get_position_key(trader.clone(), eth.clone(), btc, false).unwrap();
+        save_position(
+            &mut deps.storage,
+            &short_key,
+            &Position {
+                size: 200,
+                collateral: 20,
+                average_price: 2,
+                reserve_amount: 200,
+                cross_margin: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
 
-    POOL_AMOUNTS.save(storage, token.clone(), &pool_amounts)?;
+        // the equity of 300 + 100 - 500 + 20 + 100 is below the maintenance margin of 25
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("liquidator", &[]),
+            ExecuteMsg::LiquidateCrossMargin {
+                account: trader.clone(),
+                collateral_token: eth.clone(),
+                fee_reciever: Addr::unchecked("liquidator"),
+            },
+        )
+        .unwrap();
 
-    Ok(Response::new()
-        .add_attribute("action", "increase_pool_amount")
-        .add_attribute("token", token.to_string())
-        .add_attribute("amount", amount.to_string()))
-}
+        // the short pays its collateral and profit of 120 less its margin fee of 1 into the account,
+        // the long draws its loss of 500, margin fee of 1 and liquidation fee of 5 less its collateral
+        assert!(!POSITIONS.has(&deps.storage, &long_key));
+        assert!(!POSITIONS.has(&deps.storage, &short_key));
+        assert_eq!(
+            CROSS_MARGIN_BALANCES
+                .load(&deps.storage, (trader, eth))
+                .unwrap(),
+            300 + 119 - 406
+        );
+    }
 
-fn get_redemption_amount(
-    storage: &mut dyn Storage,
-    _env: Env,
-    token: Addr,
-    usdg_amount: u128,
-) -> Result<u128, ContractError> {
-    let price = get_max_price(token.clone());
-    let redemption_amount = usdg_amount.mul(PRICE_PRECISION).div(price);
-    let addresses = ADDRESSES.load(storage).unwrap();
+    #[test]
+    fn cross_margin_deposits_only_credit_the_tokens_sent_in() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let deposit = |deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, account: &str| {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(account, &[]),
+                ExecuteMsg::DepositCrossMargin {
+                    collateral_token: eth.clone(),
+                },
+            )
+        };
 
-    Ok(adjust_for_decimals(
-        storage,
-        redemption_amount,
-        addresses.usdg,
-        token,
-    )?)
-}
+        set_balances(&mut deps, &[("eth", 1000)]);
+        deposit(&mut deps, "alice").unwrap();
+        // nothing new was sent in
+        assert_eq!(deposit(&mut deps, "mallory").unwrap_err(), error_code(97));
+        set_balances(&mut deps, &[("eth", 1300)]);
+        deposit(&mut deps, "bob").unwrap();
+
+        for (account, balance) in [("alice", 1000), ("bob", 300)] {
+            assert_eq!(
+                CROSS_MARGIN_BALANCES
+                    .load(&deps.storage, (Addr::unchecked(account), eth.clone()))
+                    .unwrap(),
+                balance
+            );
+        }
+        assert!(
+            !CROSS_MARGIN_BALANCES.has(&deps.storage, (Addr::unchecked("mallory"), eth.clone()))
+        );
+        assert_eq!(
+            TOKEN_BALANCES.load(&deps.storage, eth.clone()).unwrap(),
+            1300
+        );
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("bob", &[]),
+            ExecuteMsg::WithdrawCrossMargin {
+                collateral_token: eth.clone(),
+                amount: 300,
+                reciever: Addr::unchecked("bob"),
+            },
+        )
+        .unwrap();
+        assert_eq!(TOKEN_BALANCES.load(&deps.storage, eth).unwrap(), 1000);
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("alice", &[]),
+            ExecuteMsg::DepositCrossMargin {
+                collateral_token: Addr::unchecked("doge"),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(116));
+    }
 
-fn _decrease_pool_amount(
-    storage: &mut dyn Storage,
-    env: Env,
-    token: Addr,
-    amount: u128,
-) -> Result<Response, ContractError> {
-    let mut pool_amounts = POOL_AMOUNTS.load(storage, token.clone())?;
-    let reserved_amounts = RSERVED_AMOUNTS.load(storage, token.clone())?;
-    pool_amounts.sub(amount);
-    _validate(reserved_amounts <= pool_amounts, 50)?;
+    #[test]
+    fn cross_margin_balances_are_withdrawable_in_settlement_mode() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        set_balances(&mut deps, &[("eth", 1000)]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("trader", &[]),
+            ExecuteMsg::DepositCrossMargin {
+                collateral_token: eth.clone(),
+            },
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::EnableSettlementMode {
+                prices: vec![SettlementPrice {
+                    token: eth.clone(),
+                    price: 2,
+                }],
+            },
+        )
+        .unwrap();
 
-    POOL_AMOUNTS.save(storage, token.clone(), &pool_amounts)?;
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("trader", &[]),
+            ExecuteMsg::WithdrawCrossMargin {
+                collateral_token: eth.clone(),
+                amount: 1000,
+                reciever: Addr::unchecked("trader"),
+            },
+        )
+        .unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert!(!CROSS_MARGIN_BALANCES.has(&deps.storage, (Addr::unchecked("trader"), eth)));
+    }
 
-    Ok(Response::new()
-        .add_attribute("action", "decrease_pool_amount")
-        .add_attribute("token", token.to_string())
-        .add_attribute("amount", amount.to_string()))
-}
+    #[test]
+    fn liquidation_only_decreases_large_positions_back_under_max_leverage() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let liquidator = Addr::unchecked("liquidator");
+        IS_LIQUIDATOR
+            .save(&mut deps.storage, liquidator.clone(), &true)
+            .unwrap();
+        PARTIAL_LIQUIDATION_CONFIG
+            .save(
+                &mut deps.storage,
+                &PartialLiquidationConfig {
+                    buffer_basis_points: 1000,
+                    min_size_usd: 100_000,
+                },
+            )
+            .unwrap();
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &2_000_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &1_000_000)
+            .unwrap();
+        GUARANTEED_USD
+            .save(&mut deps.storage, eth.clone(), &981_000)
+            .unwrap();
+        GLOBAL_LONG_SIZES
+            .save(&mut deps.storage, eth.clone(), &1_000_000)
+            .unwrap();
+        set_balances(&mut deps, &[("eth", 2_000_000)]);
+
+        // just above 50x leverage
+        let trader = Addr::unchecked("trader");
+        let key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 1_000_000,
+                collateral: 19_000,
+                average_price: 1,
+                reserve_amount: 1_000_000,
+                ..Default::default()
+            },
+        )
+        .unwrap();
 
-fn _update_token_balance(
-    storage: &mut dyn Storage,
-    querier: QuerierWrapper,
-    env: Env,
-    token: Addr,
-) -> Result<Response, ContractError> {
-    let next_balance = get_token_balance_of(querier, token.clone(), env.contract.address)?;
-    let mut token_balances = TOKEN_BALANCES.load(storage, token.clone())?;
-    token_balances = next_balance;
-    TOKEN_BALANCES.save(storage, token, &token_balances)?;
-    Ok(Response::default())
-}
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(liquidator.as_str(), &[]),
+            ExecuteMsg::LiquidatePosition {
+                account: trader,
+                collateral_token: eth.clone(),
+                index_token: eth.clone(),
+                is_long: true,
+                fee_reciever: liquidator,
+            },
+        )
+        .unwrap();
 
-fn _validate_buffer_amount(
-    storage: &mut dyn Storage,
-    token: Addr,
-) -> Result<Response, ContractError> {
-    let pool_amounts = POOL_AMOUNTS.load(storage, token.clone()).unwrap();
-    let buffer_amounts = BUFFER_AMOUNTS.load(storage, token.clone()).unwrap();
+        // the position stays open at 45x, the max leverage less the 10% buffer
+        let position = POSITIONS.load(&deps.storage, &key).unwrap();
+        assert!(position.size > 0 && position.size < 1_000_000);
+        assert!(position.size * BASIS_POINTS_DIVISOR <= position.collateral * 45 * 10000);
+        let (liquidation_state, _, _) = validate_liquidation(
+            &mut deps.storage,
+            mock_env(),
+            &position,
+            eth.clone(),
+            eth,
+            true,
+        )
+        .unwrap();
+        assert_eq!(liquidation_state, 0);
+    }
 
-    if pool_amounts < buffer_amounts {
-        return Err(ContractError::CustomError {
-            val: "Vault: poolAmount < buffer".to_string(),
-        });
+    #[test]
+    fn partial_liquidation_fee_of_a_short_is_paid_without_touching_the_pool() {
+        let mut deps = setup();
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let mut state_variables = STATE_VARIABLES.load(&deps.storage).unwrap();
+        state_variables.liquidation_fee_usd = 1000;
+        STATE_VARIABLES
+            .save(&mut deps.storage, &state_variables)
+            .unwrap();
+        let liquidator = Addr::unchecked("liquidator");
+        IS_LIQUIDATOR
+            .save(&mut deps.storage, liquidator.clone(), &true)
+            .unwrap();
+        PARTIAL_LIQUIDATION_CONFIG
+            .save(
+                &mut deps.storage,
+                &PartialLiquidationConfig {
+                    buffer_basis_points: 1000,
+                    min_size_usd: 100_000,
+                },
+            )
+            .unwrap();
+        POOL_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &2_000_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &1_000_000)
+            .unwrap();
+        GLOBAL_SHORT_SIZES
+            .save(&mut deps.storage, eth.clone(), &1_000_000)
+            .unwrap();
+        GLOBAL_SHORT_AVERAGE_PRIZES
+            .save(&mut deps.storage, eth.clone(), &1)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, usdc.clone(), &2_019_000)
+            .unwrap();
+        set_balances(&mut deps, &[("usdc", 2_019_000)]);
+
+        // just above 50x leverage
+        let trader = Addr::unchecked("trader");
+        let key = get_position_key(trader.clone(), usdc.clone(), eth.clone(), false).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 1_000_000,
+                collateral: 19_000,
+                average_price: 1,
+                reserve_amount: 1_000_000,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(liquidator.as_str(), &[]),
+            ExecuteMsg::LiquidatePosition {
+                account: trader,
+                collateral_token: usdc.clone(),
+                index_token: eth,
+                is_long: false,
+                fee_reciever: liquidator.clone(),
+            },
+        )
+        .unwrap();
+
+        // the fee comes out of the collateral held outside of the pool
+        let position = POSITIONS.load(&deps.storage, &key).unwrap();
+        assert!(position.size > 0 && position.size < 1_000_000);
+        let fee_usd = 1000 * (1_000_000 - position.size) / 1_000_000;
+        assert!(fee_usd > 0);
+        assert_eq!(
+            transfers(&res),
+            vec![("usdc".to_string(), liquidator.to_string(), fee_usd)]
+        );
+        assert_eq!(
+            POOL_AMOUNTS.load(&deps.storage, usdc.clone()).unwrap(),
+            2_000_000
+        );
+        assert_eq!(
+            TOKEN_BALANCES.load(&deps.storage, usdc).unwrap(),
+            2_019_000 - fee_usd
+        );
     }
-    Ok(Response::default())
-}
 
-fn _validate_gas_price(storage: &mut dyn Storage, env: Env) {
-    let state_variables = STATE_VARIABLES.load(storage).unwrap();
-    if state_variables.max_gas_price == 0 {
-        return;
+    #[test]
+    fn positions_below_the_partial_liquidation_size_are_closed() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        PARTIAL_LIQUIDATION_CONFIG
+            .save(
+                &mut deps.storage,
+                &PartialLiquidationConfig {
+                    buffer_basis_points: 1000,
+                    min_size_usd: 100_000,
+                },
+            )
+            .unwrap();
+        let position = Position {
+            size: 99_000,
+            collateral: 1900,
+            average_price: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            get_partial_liquidation_size(&mut deps.storage, &position, eth, 0).unwrap(),
+            99_000
+        );
     }
-    let tx_gas_price = 1;
-    _validate(tx_gas_price <= state_variables.max_gas_price, 55).unwrap();
-}
 
-fn _validate_router(storage: &mut dyn Storage, info: MessageInfo, account: Addr) {
-    let addresses = ADDRESSES.load(storage).unwrap();
-    let approved_routers1 = APPROVED_ROUTERS1.load(storage, info.sender).unwrap();
-    let mut approved_routers2 = APPROVED_ROUTERS2.load(storage, account).unwrap();
-    approved_routers2 = approved_routers1;
-    _validate(approved_routers2, 41).unwrap();
-}
+    #[test]
+    fn circuit_breaker_halts_trading_until_reset() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetCircuitBreakerConfig {
+                token: eth.clone(),
+                config: Some(CircuitBreakerConfig {
+                    max_deviation_basis_points: 1000,
+                    window: 3600,
+                    allow_decrease: true,
+                    allow_liquidation: true,
+                }),
+            },
+        )
+        .unwrap();
+        let trip = ExecuteMsg::TripCircuitBreaker { token: eth.clone() };
+        let deposit = ExecuteMsg::DepositCrossMargin {
+            collateral_token: eth.clone(),
+        };
 
-fn validate_tokens(
-    storage: &mut dyn Storage,
-    collateral_token: Addr,
-    index_token: Addr,
-    is_long: bool,
-) {
-    let whitelisted_tokens = WHITELISTED_TOKENS
-        .load(storage, collateral_token.clone())
+        // the price doesn't deviate from the price accepted on the first check
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            trip.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(107));
+
+        // the price halves after a quiet period longer than the window
+        let mut env = mock_env();
+        LAST_ACCEPTED_PRICES
+            .save(
+                &mut deps.storage,
+                eth.clone(),
+                &AcceptedPrice {
+                    price: 2,
+                    timestamp: env.block.time.seconds() - 7200,
+                },
+            )
+            .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("trader", &[]),
+            deposit.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(106));
+        assert_eq!(
+            LAST_ACCEPTED_PRICES
+                .load(&deps.storage, eth.clone())
+                .unwrap()
+                .price,
+            2
+        );
+
+        // the price halves within the window
+        LAST_ACCEPTED_PRICES
+            .save(
+                &mut deps.storage,
+                eth.clone(),
+                &AcceptedPrice {
+                    price: 2,
+                    timestamp: env.block.time.seconds(),
+                },
+            )
+            .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("trader", &[]),
+            deposit.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(106));
+        execute(deps.as_mut(), env.clone(), mock_info("keeper", &[]), trip).unwrap();
+
+        // a tripped breaker stays tripped after the window expired
+        env.block.time = env.block.time.plus_seconds(3600);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("trader", &[]),
+            deposit.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(106));
+
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("trader", &[]),
+            ExecuteMsg::ResetCircuitBreaker { token: eth.clone() },
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(58));
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::ResetCircuitBreaker { token: eth.clone() },
+        )
         .unwrap();
-    let stable_tokens_collateral = STABLE_TOKENS
-        .load(storage, collateral_token.clone())
+
+        let breaker: CircuitBreakerResponse = from_json(
+            query(
+                deps.as_mut(),
+                env.clone(),
+                QueryMsg::GetCircuitBreaker { token: eth.clone() },
+            )
+            .unwrap(),
+        )
         .unwrap();
-    let stable_tokens_index = STABLE_TOKENS.load(storage, index_token.clone()).unwrap();
-    let shortable_tokens = SHORTABLE_TOKENS.load(storage, index_token.clone()).unwrap();
-    if is_long {
-        _validate(collateral_token == index_token, 42).unwrap();
-        _validate(whitelisted_tokens, 43).unwrap();
-        _validate(!stable_tokens_collateral, 44).unwrap();
-        return;
+        assert!(!breaker.is_tripped);
+        assert_eq!(breaker.deviation_basis_points, 0);
+        assert_eq!(
+            breaker.last_accepted_price,
+            Some(AcceptedPrice {
+                price: ORACLE_ONE_USD,
+                timestamp: env.block.time.seconds(),
+            })
+        );
+
+        set_balances(&mut deps, &[("eth", 1000)]);
+        execute(deps.as_mut(), env, mock_info("trader", &[]), deposit).unwrap();
     }
-    _validate(whitelisted_tokens, 45).unwrap();
-    _validate(stable_tokens_collateral, 46).unwrap();
-    _validate(!stable_tokens_index, 47).unwrap();
-    _validate(shortable_tokens, 48).unwrap();
-}
 
-fn get_position_key(
-    account: Addr,
-    collateral_token: Addr,
-    index_token: Addr,
-    is_long: bool,
-) -> Result<Vec<u8>, ContractError> {
-    let key = Key::new(account, collateral_token, index_token, is_long).as_bytes()?;
-    Ok(key)
-}
+    #[test]
+    fn stable_prices_are_clamped_and_depegs_detected() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        let set_config = |token: &Addr, clamp_basis_points, depeg_basis_points| {
+            ExecuteMsg::SetStablePriceConfig {
+                token: token.clone(),
+                config: Some(StablePriceConfig {
+                    clamp_basis_points,
+                    depeg_basis_points,
+                }),
+            }
+        };
 
-fn get_next_average_price(
-    index_token: Addr,
-    size: u128,
-    average_price: u128,
-    is_long: bool,
-    next_price: u128,
-    size_delta: u128,
-    last_increased_time: u128,
-    env: Env,
-    storage: &mut dyn Storage,
-) -> StdResult<u128> {
-    let (has_profit, delta) = get_delta(
-        index_token,
-        size,
-        average_price,
-        is_long,
-        last_increased_time,
-        storage,
-        env,
-    )?;
-    let next_size = size + size_delta;
-    let divisor = if is_long {
-        if has_profit {
-            next_size + delta
-        } else {
-            next_size - delta
-        }
-    } else {
-        if has_profit {
-            next_size - delta
-        } else {
-            next_size + delta
-        }
-    };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            set_config(&eth, 50, 500),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(109));
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            set_config(&usdc, 500, 500),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(114));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            set_config(&usdc, 50, 500),
+        )
+        .unwrap();
 
-    let result = next_price * next_size / divisor;
-    Ok(result)
-}
+        let config = STABLE_PRICE_CONFIGS
+            .load(&deps.storage, usdc.clone())
+            .unwrap();
+        // prices are in PRICE_PRECISION, the band is 0.5% and a depeg is a move of more than 5%
+        let usd = |basis_points: u128| PRICE_PRECISION * basis_points / BASIS_POINTS_DIVISOR;
+        assert_eq!(clamp_stable_price(&config, usd(9970)), usd(10000));
+        assert_eq!(clamp_stable_price(&config, usd(10030)), usd(10000));
+        assert_eq!(clamp_stable_price(&config, usd(10200)), usd(10050));
+        assert_eq!(clamp_stable_price(&config, usd(9900)), usd(9900));
+        assert!(!is_depegged_price(&config, usd(10000)));
+        assert!(!is_depegged_price(&config, usd(9600)));
+        assert!(is_depegged_price(&config, usd(9400)));
+        assert!(is_depegged_price(&config, usd(10600)));
+
+        // the oracle reports the token at its peg so it can still be minted against
+        assert!(!is_depegged(&mut deps.storage, usdc.clone()).unwrap());
+        assert!(_validate_not_depegged(&mut deps.storage, &usdc).is_ok());
+    }
 
-fn get_delta(
-    index_token: Addr,
-    size: u128,
-    average_price: u128,
-    is_long: bool,
-    last_increased_time: u128,
-    storage: &mut dyn Storage,
-    env: Env,
-) -> StdResult<(bool, u128)> {
-    let state_variables = STATE_VARIABLES.load(storage).unwrap();
-    let min_profit_basis_points = MIN_PROFIT_BASIS_POINTS
-        .load(storage, index_token.clone())
+    #[test]
+    fn guardians_pause_tokens_and_operations_until_gov_unpauses_them() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        set_balances(&mut deps, &[("eth", 1000)]);
+        let gov = mock_info(GOV_ADDR, &[]);
+        let guardian = mock_info("guardian", &[]);
+        let deposit = ExecuteMsg::DepositCrossMargin {
+            collateral_token: eth.clone(),
+        };
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            guardian.clone(),
+            ExecuteMsg::SetTokenPaused {
+                token: eth.clone(),
+                is_paused: true,
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(58));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            gov.clone(),
+            ExecuteMsg::SetGuardian {
+                guardian: guardian.sender.clone(),
+                is_guardian: true,
+            },
+        )
         .unwrap();
 
-    // Get the appropriate price based on the is_long flag
-    let price = if is_long {
-        0
-    } else {
-        get_max_price(index_token)
-    };
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            guardian.clone(),
+            ExecuteMsg::SetTokenPaused {
+                token: eth.clone(),
+                is_paused: true,
+            },
+        )
+        .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("trader", &[]),
+            deposit.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(57));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            gov.clone(),
+            ExecuteMsg::SetTokenPaused {
+                token: eth.clone(),
+                is_paused: false,
+            },
+        )
+        .unwrap();
 
-    let price_delta = if average_price > price {
-        average_price - price
-    } else {
-        price - average_price
-    };
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            guardian,
+            ExecuteMsg::SetOperationPaused {
+                operation: Operation::IncreasePosition,
+                is_paused: true,
+            },
+        )
+        .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("trader", &[]),
+            deposit.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(56));
+
+        let matrix: PauseMatrixResponse =
+            from_json(query(deps.as_mut(), mock_env(), QueryMsg::GetPauseMatrix {}).unwrap())
+                .unwrap();
+        let paused_operations: Vec<Operation> = matrix
+            .operations
+            .iter()
+            .filter(|status| status.is_paused)
+            .map(|status| status.operation)
+            .collect();
+        assert_eq!(paused_operations, vec![Operation::IncreasePosition]);
+        assert!(matrix.tokens.iter().all(|status| !status.is_paused));
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            gov,
+            ExecuteMsg::SetOperationPaused {
+                operation: Operation::IncreasePosition,
+                is_paused: false,
+            },
+        )
+        .unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("trader", &[]), deposit).unwrap();
+    }
 
-    let delta = size * price_delta / average_price;
+    fn setup_route_pools() -> (OwnedDeps<MockStorage, MockApi, MockQuerier>, Vec<Addr>) {
+        let mut deps = setup();
+        let path = vec![
+            add_token(&mut deps.storage, "eth", false),
+            add_token(&mut deps.storage, "usdc", true),
+            add_token(&mut deps.storage, "btc", false),
+        ];
+        for token in path.iter() {
+            POOL_AMOUNTS
+                .save(&mut deps.storage, token.clone(), &1_000_000)
+                .unwrap();
+            TOKEN_BALANCES
+                .save(&mut deps.storage, token.clone(), &1_000_000)
+                .unwrap();
+        }
+        // 1000 eth are sent in with the message
+        set_balances(
+            &mut deps,
+            &[("eth", 1_001_000), ("usdc", 1_000_000), ("btc", 1_000_000)],
+        );
+        (deps, path)
+    }
 
-    let has_profit = if is_long {
-        price > average_price
-    } else {
-        average_price > price
-    };
+    #[test]
+    fn swap_with_route_swaps_every_hop_and_checks_the_output() {
+        let env = mock_env();
+        let now = env.block.time.seconds();
+        let swap = |path: &[Addr], min_out, deadline| ExecuteMsg::SwapWithRoute {
+            path: path.to_vec(),
+            min_out,
+            deadline,
+            reciever: Addr::unchecked("trader"),
+            account: None,
+        };
 
-    // Additional logic involving minProfitTime and minProfitBasisPoints
-    let min_bps = if env.block.time.seconds() as u128
-        > last_increased_time + state_variables.min_profit_time
-    {
-        0
-    } else {
-        min_profit_basis_points
-    };
+        let (mut deps, path) = setup_route_pools();
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("trader", &[]),
+            swap(&path, 0, now - 1),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(68));
+        // each hop charges the 0.3% swap fee
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("trader", &[]),
+            swap(&path, 995, now),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(69));
+
+        let (mut deps, path) = setup_route_pools();
+        let res = execute(
+            deps.as_mut(),
+            env,
+            mock_info("trader", &[]),
+            swap(&path, 994, now),
+        )
+        .unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert!(res
+            .attributes
+            .contains(&cosmwasm_std::Attribute::new("amount_out", "994")));
+        // the fees of each hop leave the pool for the fee reserves of the token swapped out
+        assert_eq!(
+            POOL_AMOUNTS.load(&deps.storage, path[0].clone()).unwrap(),
+            1_001_000
+        );
+        assert_eq!(
+            POOL_AMOUNTS.load(&deps.storage, path[1].clone()).unwrap(),
+            1_000_000 - 3
+        );
+        assert_eq!(
+            POOL_AMOUNTS.load(&deps.storage, path[2].clone()).unwrap(),
+            1_000_000 - 997
+        );
+        assert_eq!(
+            FEE_RESERVES.load(&deps.storage, path[2].clone()).unwrap(),
+            3
+        );
+    }
 
-    let adjusted_delta = if has_profit && delta * BASIS_POINTS_DIVISOR <= size * min_bps {
-        0
-    } else {
-        delta
-    };
+    #[test]
+    fn distribute_fees_splits_the_fee_reserves_once_per_epoch() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        FEE_RESERVES
+            .save(&mut deps.storage, eth.clone(), &1001)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, eth.clone(), &1001)
+            .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetFeeDistributionConfig {
+                config: Some(FeeDistributionConfig {
+                    lp_reciever: Addr::unchecked("lp"),
+                    staking_reciever: Addr::unchecked("staking"),
+                    treasury: Addr::unchecked("treasury"),
+                    lp_basis_points: 5000,
+                    staking_basis_points: 3000,
+                    treasury_basis_points: 2000,
+                    epoch_duration: 3600,
+                    reward_token: None,
+                }),
+            },
+        )
+        .unwrap();
 
-    Ok((has_profit, adjusted_delta))
-}
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("anyone", &[]),
+            ExecuteMsg::DistributeFees {},
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(112));
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::DistributeFees {},
+        )
+        .unwrap();
+        assert_eq!(res.messages.len(), 3);
+        assert_eq!(FEE_RESERVES.load(&deps.storage, eth.clone()).unwrap(), 0);
+        // rounding dust goes to the treasury
+        assert_eq!(
+            FEE_DISTRIBUTIONS.load(&deps.storage, 0).unwrap().fees,
+            vec![DistributedFee {
+                token: eth,
+                lp_amount: 500,
+                staking_amount: 300,
+                treasury_amount: 201,
+            }]
+        );
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::DistributeFees {},
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(71));
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(3600);
+        execute(
+            deps.as_mut(),
+            env,
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::DistributeFees {},
+        )
+        .unwrap();
+        assert_eq!(FEE_DISTRIBUTION_EPOCH.load(&deps.storage).unwrap(), 2);
+    }
 
-fn collect_margin_fees(
-    account: Addr,
-    collateral_token: Addr,
-    index_token: Addr,
-    is_long: bool,
-    size_delta: u128,
-    size: u128,
-    entry_funding_rate: u128,
-    storage: &mut dyn Storage,
-) -> StdResult<u128> {
-    let fee_reserves = FEE_RESERVES
-        .load(storage, collateral_token.clone())
+    #[test]
+    fn fees_can_be_withdrawn_again_once_the_distribution_config_is_removed() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        FEE_RESERVES
+            .save(&mut deps.storage, eth.clone(), &1000)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, eth.clone(), &1000)
+            .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetFeeDistributionConfig {
+                config: Some(FeeDistributionConfig {
+                    lp_reciever: Addr::unchecked("lp"),
+                    staking_reciever: Addr::unchecked("staking"),
+                    treasury: Addr::unchecked("treasury"),
+                    lp_basis_points: 10000,
+                    staking_basis_points: 0,
+                    treasury_basis_points: 0,
+                    epoch_duration: 3600,
+                    reward_token: None,
+                }),
+            },
+        )
         .unwrap();
-    let mut fee_usd = 0; // Hardcoded
+        let withdraw = ExecuteMsg::WithdrawFees {
+            token: eth.clone(),
+            reciever: Addr::unchecked("treasury"),
+        };
 
-    let funding_fee = 0; // Hardcoded
-    fee_usd += funding_fee;
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            withdraw.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(113));
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetFeeDistributionConfig { config: None },
+        )
+        .unwrap();
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            withdraw,
+        )
+        .unwrap();
+        assert_eq!(
+            transfers(&res),
+            vec![("eth".to_string(), "treasury".to_string(), 1000)]
+        );
+        assert_eq!(FEE_RESERVES.load(&deps.storage, eth).unwrap(), 0);
+    }
 
-    let fee_tokens = usd_to_token_min(collateral_token.clone(), fee_usd, storage)?;
-    fee_reserves.add(fee_tokens);
-    FEE_RESERVES
-        .save(storage, collateral_token, &fee_reserves)
+    #[test]
+    fn referred_swaps_pay_a_discount_and_a_claimable_rebate() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        for token in [&eth, &usdc] {
+            POOL_AMOUNTS
+                .save(&mut deps.storage, token.clone(), &1_000_000)
+                .unwrap();
+            TOKEN_BALANCES
+                .save(&mut deps.storage, token.clone(), &1_000_000)
+                .unwrap();
+        }
+        set_balances(&mut deps, &[("eth", 1_100_000), ("usdc", 1_000_000)]);
+        let referrer = mock_info("referrer", &[]);
+        let trader = mock_info("trader", &[]);
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            referrer.clone(),
+            ExecuteMsg::RegisterReferralCode {
+                code: "code".to_string(),
+            },
+        )
+        .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            referrer.clone(),
+            ExecuteMsg::SetTraderReferralCode {
+                code: "code".to_string(),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(76));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            trader.clone(),
+            ExecuteMsg::SetTraderReferralCode {
+                code: "code".to_string(),
+            },
+        )
+        .unwrap();
+        // 20% of the fee goes back, half of it to the trader
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetReferralTier {
+                tier: 0,
+                total_rebate_basis_points: 2000,
+                discount_share_basis_points: 5000,
+            },
+        )
         .unwrap();
 
-    Ok(fee_usd)
-}
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            trader.clone(),
+            ExecuteMsg::Swap {
+                token_in: eth,
+                token_out: usdc.clone(),
+                reciever: trader.sender.clone(),
+                account: None,
+            },
+        )
+        .unwrap();
+        // the 0.3% swap fee is 300
+        assert!(res.attributes.contains(&cosmwasm_std::Attribute::new(
+            "amount__out_after_fees",
+            "99730"
+        )));
+        assert_eq!(FEE_RESERVES.load(&deps.storage, usdc.clone()).unwrap(), 240);
+        let stats = REFERRER_STATS
+            .load(&deps.storage, referrer.sender.clone())
+            .unwrap();
+        assert_eq!(stats.referred_traders, 1);
+        assert_eq!(stats.rebates_usd, 30);
+        assert_eq!(stats.discounts_usd, 30);
+
+        let claim = ExecuteMsg::ClaimReferralRebates {
+            tokens: vec![usdc],
+            reciever: referrer.sender.clone(),
+        };
+        let res = execute(deps.as_mut(), mock_env(), referrer.clone(), claim.clone()).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        let err = execute(deps.as_mut(), mock_env(), referrer, claim).unwrap_err();
+        assert_eq!(err, error_code(79));
+    }
 
-fn usd_to_token_min(token: Addr, usd_amount: u128, storage: &mut dyn Storage) -> StdResult<u128> {
-    if usd_amount == 0 {
-        return Ok(0);
+    #[test]
+    fn token_risk_config_limits_leverage_collateral_and_account_size() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        let config = TokenRiskConfig {
+            max_leverage: 10 * 10000,
+            min_collateral_usd: 10,
+            min_position_size_usd: 100,
+            max_position_size_usd: 10_000,
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetTokenRiskConfig {
+                token: Addr::unchecked("btc"),
+                config: config.clone(),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(80));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetTokenRiskConfig {
+                token: eth.clone(),
+                config,
+            },
+        )
+        .unwrap();
+
+        let trader = Addr::unchecked("trader");
+        let long_key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        let short_key = get_position_key(trader, usdc, eth, false).unwrap();
+        let storage = &mut deps.storage;
+        assert_eq!(
+            validate_position_risk(storage, &long_key, 1001, 100).unwrap_err(),
+            error_code(81)
+        );
+        assert_eq!(
+            validate_position_risk(storage, &long_key, 90, 9).unwrap_err(),
+            error_code(82)
+        );
+        assert_eq!(
+            validate_position_risk(storage, &long_key, 99, 10).unwrap_err(),
+            error_code(83)
+        );
+        validate_position_risk(storage, &long_key, 6000, 600).unwrap();
+        save_position(
+            storage,
+            &long_key,
+            &Position {
+                size: 6000,
+                collateral: 600,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // the short of the account on the same index token counts towards its max size
+        assert_eq!(
+            validate_position_risk(storage, &short_key, 4001, 1000).unwrap_err(),
+            error_code(84)
+        );
+        validate_position_risk(storage, &short_key, 4000, 1000).unwrap();
+        // the saved size of the position itself is replaced by its new size
+        validate_position_risk(storage, &long_key, 6000, 600).unwrap();
     }
-    return usd_to_token(token.clone(), usd_amount, get_max_price(token), storage);
-}
 
-fn usd_to_token_max(token: Addr, usd_amount: u128, storage: &mut dyn Storage) -> StdResult<u128> {
-    if usd_amount == 0 {
-        return Ok(0);
+    #[test]
+    fn global_long_sizes_are_capped() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetMaxGlobalLongSize {
+                token: eth.clone(),
+                amount: 1000,
+            },
+        )
+        .unwrap();
+
+        increase_global_long_size(&mut deps.storage, eth.clone(), 600).unwrap();
+        assert!(increase_global_long_size(&mut deps.storage, eth.clone(), 401).is_err());
+        decrease_global_long_size(&mut deps.storage, eth.clone(), 100).unwrap();
+        increase_global_long_size(&mut deps.storage, eth.clone(), 401).unwrap();
+
+        let open_interest: OpenInterestResponse = from_json(
+            query(
+                deps.as_mut(),
+                mock_env(),
+                QueryMsg::GetOpenInterest { token: eth },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(open_interest.long_size, 901);
+        assert_eq!(open_interest.max_long_size, 1000);
+        assert_eq!(open_interest.remaining_long_capacity, Some(99));
+        assert_eq!(open_interest.remaining_short_capacity, None);
     }
-    return usd_to_token(token.clone(), usd_amount, get_min_price(token), storage);
-}
 
-fn usd_to_token(
-    token: Addr,
-    usd_amount: u128,
-    price: u128,
-    storage: &mut dyn Storage,
-) -> StdResult<u128> {
-    let token_decimals = TOKEN_DECIMALS.load(storage, token.clone()).unwrap();
-    if usd_amount == 0 {
-        return Ok(0);
+    #[test]
+    fn kinked_borrow_rates_accrue_on_utilisation() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let set_model = |base_rate, slope2| ExecuteMsg::SetBorrowRateModel {
+            token: eth.clone(),
+            model: Some(BorrowRateModel::Kinked {
+                base_rate,
+                slope1: 1000,
+                kink: 800_000,
+                slope2,
+            }),
+        };
+        // the rate at full utilisation may not exceed 1% per interval
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            set_model(100, 50_000),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(86));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            set_model(100, 5000),
+        )
+        .unwrap();
+
+        // 90% utilisation charges the base rate, the slope up to the kink and half of slope2
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &1000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &900)
+            .unwrap();
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(2 * 8 * 3600);
+        update_cumulative_funding_rate(&mut deps.storage, env, eth.clone(), eth.clone()).unwrap();
+        assert_eq!(
+            COMMULATIVE_FUNDING_RATES.load(&deps.storage, eth).unwrap(),
+            2 * (100 + 800 + 500)
+        );
     }
-    let decimals = token_decimals;
-    Ok(usd_amount.mul(10_u128.pow(decimals as u32).div(price)))
-}
 
-fn token_to_usd_min(token: Addr, token_amount: u128, storage: &mut dyn Storage) -> StdResult<u128> {
-    let token_decimals = TOKEN_DECIMALS.load(storage, token.clone()).unwrap();
-    if token_amount == 0 {
-        return Ok(0);
+    #[test]
+    fn held_positions_pay_the_accrued_borrow_fee() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &200_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &100_000)
+            .unwrap();
+        GUARANTEED_USD
+            .save(&mut deps.storage, eth.clone(), &90_000)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, eth.clone(), &200_000)
+            .unwrap();
+        let trader = Addr::unchecked("trader");
+        APPROVED_ROUTERS1
+            .save(&mut deps.storage, trader.clone(), &true)
+            .unwrap();
+        APPROVED_ROUTERS2
+            .save(&mut deps.storage, trader.clone(), &true)
+            .unwrap();
+        let key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 100_000,
+                collateral: 10_000,
+                average_price: 1,
+                reserve_amount: 100_000,
+                entry_funding_rate: 200,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // the borrow rate accrued 0.3% since the position was opened
+        COMMULATIVE_FUNDING_RATES
+            .save(&mut deps.storage, eth.clone(), &3200)
+            .unwrap();
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(trader.as_str(), &[]),
+            ExecuteMsg::DecreasePosition {
+                account: trader.clone(),
+                collateral_token: eth.clone(),
+                index_token: eth.clone(),
+                collateral_delta: 0,
+                size_delta: 10_000,
+                is_long: true,
+                reciever: trader,
+            },
+        )
+        .unwrap();
+
+        // 10 of margin fee on the decreased size and 300 of borrow fee on the whole size
+        let position = POSITIONS.load(&deps.storage, &key).unwrap();
+        assert_eq!(position.collateral, 10_000 - 310);
+        assert_eq!(position.entry_funding_rate, 3200);
+        assert_eq!(FEE_RESERVES.load(&deps.storage, eth.clone()).unwrap(), 310);
+        let stats = TOKEN_STATS.load(&deps.storage, eth).unwrap();
+        assert_eq!((stats.margin_fees_usd, stats.funding_fees_usd), (10, 300));
     }
-    let price = 0; // hardcoded
-    let decimals = token_decimals;
-    return Ok(token_amount.mul(price).div(10_u128.pow(decimals as u32)));
-}
 
-fn validate_position(size: u128, collateral: u128) {
-    if size == 0 {
-        _validate(collateral == 0, 39).unwrap();
-        return;
+    #[test]
+    fn position_price_impact_charges_into_and_rebates_from_the_impact_pool() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetPriceImpactConfig {
+                token: eth.clone(),
+                config: Some(PriceImpactConfig {
+                    factor: PRICE_IMPACT_PRECISION,
+                    exponent: 2,
+                }),
+            },
+        )
+        .unwrap();
+        GLOBAL_LONG_SIZES
+            .save(&mut deps.storage, eth.clone(), &100)
+            .unwrap();
+
+        // longs grow the imbalance from 100 to 110 and shorts shrink it to 90
+        let charge =
+            get_position_price_impact_usd(&mut deps.storage, eth.clone(), true, 10, true).unwrap();
+        assert_eq!(charge, 100 * 100 - 110 * 110);
+        let rebate =
+            get_position_price_impact_usd(&mut deps.storage, eth.clone(), false, 10, true).unwrap();
+        assert_eq!(rebate, 100 * 100 - 90 * 90);
+
+        let deps = deps.as_mut();
+        let mut position = Position {
+            size: 1000,
+            collateral: 5000,
+            ..Default::default()
+        };
+        for (price_impact_usd, collateral, impact_pool_amount) in [
+            (charge, 5000 - 2100, 2100),
+            (rebate, 5000 - 2100 + 1900, 200),
+            // rebates are limited to what is in the impact pool
+            (rebate, 5000 - 2100 + 1900 + 200, 0),
+        ] {
+            apply_position_price_impact(
+                deps.storage,
+                deps.querier,
+                mock_env(),
+                usdc.clone(),
+                false,
+                &mut position,
+                price_impact_usd,
+            )
+            .unwrap();
+            assert_eq!(position.collateral, collateral);
+            assert_eq!(
+                PRICE_IMPACT_POOLS.load(deps.storage, usdc.clone()).unwrap(),
+                impact_pool_amount
+            );
+        }
     }
-    _validate(size >= collateral, 40).unwrap();
-}
 
-fn increase_reserved_amount(
-    storage: &mut dyn Storage,
-    token: Addr,
-    amount: u128,
-) -> Result<Response, ContractError> {
-    let mut reserved_amounts = RSERVED_AMOUNTS.load(storage, token.clone()).unwrap();
-    let mut pool_amounts = POOL_AMOUNTS.load(storage, token.clone()).unwrap();
-    reserved_amounts.add(amount);
-    _validate(reserved_amounts <= pool_amounts, 52)?;
-    RSERVED_AMOUNTS
-        .save(storage, token.clone(), &reserved_amounts)
+    #[test]
+    fn auto_deleveraging_closes_the_most_profitable_positions_first() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetAdlConfig {
+                collateral_token: usdc.clone(),
+                config: Some(AdlConfig {
+                    trigger_pnl_to_pool_basis_points: 2000,
+                    target_pnl_to_pool_basis_points: 1000,
+                }),
+            },
+        )
+        .unwrap();
+        POOL_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &10_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &6000)
+            .unwrap();
+        GLOBAL_SHORT_SIZES
+            .save(&mut deps.storage, eth.clone(), &6000)
+            .unwrap();
+        GLOBAL_SHORT_AVERAGE_PRIZES
+            .save(&mut deps.storage, eth.clone(), &2)
+            .unwrap();
+        // the pool and the collateral of both positions
+        TOKEN_BALANCES
+            .save(&mut deps.storage, usdc.clone(), &12_000)
+            .unwrap();
+        set_balances(&mut deps, &[("usdc", 12_000)]);
+
+        // shorts opened at 2 are in profit at the oracle price of 1
+        let mut keys = vec![];
+        for (account, size) in [("small", 2000), ("large", 4000)] {
+            let key = get_position_key(Addr::unchecked(account), usdc.clone(), eth.clone(), false)
+                .unwrap();
+            save_position(
+                &mut deps.storage,
+                &key,
+                &Position {
+                    size,
+                    collateral: 1000,
+                    average_price: 2,
+                    reserve_amount: size,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            keys.push(key);
+        }
+        let ranking: AdlRankingResponse = from_json(
+            query(
+                deps.as_mut(),
+                mock_env(),
+                QueryMsg::GetAdlRanking {
+                    collateral_token: usdc.clone(),
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap(),
+        )
         .unwrap();
+        assert_eq!(ranking.total_profit_usd, 3000);
+        assert_eq!(ranking.pnl_to_pool_basis_points, 3000);
+        assert!(ranking.is_adl_required);
+        assert_eq!(ranking.positions[0].account, Addr::unchecked("large"));
+
+        // a page only ranks the positions it holds
+        let mut start_after = None;
+        let mut profits = vec![];
+        loop {
+            let page: AdlRankingResponse = from_json(
+                query(
+                    deps.as_mut(),
+                    mock_env(),
+                    QueryMsg::GetAdlRanking {
+                        collateral_token: usdc.clone(),
+                        start_after,
+                        limit: Some(1),
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+            if page.last_key.is_none() {
+                assert!(page.positions.is_empty());
+                break;
+            }
+            assert_eq!(page.positions.len(), 1);
+            profits.push(page.total_profit_usd);
+            start_after = page.last_key;
+        }
+        profits.sort();
+        assert_eq!(profits, vec![1000, 2000]);
 
-    Ok(Response::new()
-        .add_attribute("acton", "increase_reserve_amount")
-        .add_attribute("token", token.to_string())
-        .add_attribute("amount", amount.to_string()))
-}
+        let deleverage = || ExecuteMsg::AutoDeleverage {
+            collateral_token: usdc.clone(),
+            start_after: None,
+            limit: None,
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("trader", &[]),
+            deleverage(),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(92));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            deleverage(),
+        )
+        .unwrap();
 
-fn decrease_reserved_amount(
-    storage: &mut dyn Storage,
-    token: Addr,
-    amount: u128,
-) -> Result<Response, ContractError> {
-    let mut reserved_amounts = RSERVED_AMOUNTS.load(storage, token.clone()).unwrap();
-    reserved_amounts.sub(amount);
-    RSERVED_AMOUNTS
-        .save(storage, token.clone(), &reserved_amounts)
+        // closing the large position realises the 2000 of profit above the target
+        assert_eq!(POSITIONS.may_load(&deps.storage, &keys[1]).unwrap(), None);
+        assert_eq!(POSITIONS.load(&deps.storage, &keys[0]).unwrap().size, 2000);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            deleverage(),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(90));
+    }
+
+    #[test]
+    fn insurance_fund_takes_a_fee_share_and_covers_bad_debt() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let set_basis_points =
+            |basis_points| ExecuteMsg::SetInsuranceFundBasisPoints { basis_points };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            set_basis_points(10_001),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(93));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            set_basis_points(2000),
+        )
         .unwrap();
 
-    Ok(Response::new()
-        .add_attribute("acton", "decrease_reserve_amount")
-        .add_attribute("token", token.to_string())
-        .add_attribute("amount", amount.to_string()))
-}
-fn increase_guarnteed_usd(
-    storage: &mut dyn Storage,
-    token: Addr,
-    usdg_amount: u128,
-) -> Result<Response, ContractError> {
-    let mut guarnteed_usd = GUARANTEED_USD.load(storage, token.clone()).unwrap();
-    guarnteed_usd.add(usdg_amount);
-    GUARANTEED_USD
-        .save(storage, token.clone(), &guarnteed_usd)
+        let (fee_tokens, _) = take_insurance_share(&mut deps.storage, eth.clone(), 100).unwrap();
+        assert_eq!(fee_tokens, 80);
+        set_balances(&mut deps, &[("eth", 20)]);
+
+        // the fund only holds 20 of the 30 of bad debt
+        let trader = Addr::unchecked("trader");
+        let key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        let deps_mut = deps.as_mut();
+        cover_bad_debt(deps_mut.storage, deps_mut.querier, mock_env(), &key, 30).unwrap();
+        assert_eq!(POOL_AMOUNTS.load(&deps.storage, eth.clone()).unwrap(), 20);
+
+        let fund: InsuranceFundResponse = from_json(
+            query(
+                deps.as_mut(),
+                mock_env(),
+                QueryMsg::GetInsuranceFund {
+                    token: eth,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap(),
+        )
         .unwrap();
-    Ok(Response::new()
-        .add_attribute("acton", "increase_guarnteed_usd")
-        .add_attribute("token", token.to_string())
-        .add_attribute("usdg_aount", usdg_amount.to_string()))
-}
+        assert_eq!(fund.balance, 0);
+        assert_eq!(fund.basis_points, 2000);
+        assert_eq!(fund.history.len(), 1);
+        let (index, record) = &fund.history[0];
+        assert_eq!(*index, 0);
+        assert_eq!(record.account, trader);
+        assert_eq!(record.bad_debt_usd, 30);
+        assert_eq!(record.covered_amount, 20);
+        assert_eq!(record.uncovered_usd, 10);
+    }
 
-fn decrease_guarnteed_usd(
-    storage: &mut dyn Storage,
-    token: Addr,
-    usdg_amount: u128,
-) -> Result<Response, ContractError> {
-    let mut guarnteed_usd = GUARANTEED_USD.load(storage, token.clone()).unwrap();
-    guarnteed_usd.sub(usdg_amount);
-    GUARANTEED_USD
-        .save(storage, token.clone(), &guarnteed_usd)
+    #[test]
+    fn insurance_fund_share_is_not_taken_from_the_liquidators_fee() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        INSURANCE_FUND_BASIS_POINTS
+            .save(&mut deps.storage, &2000)
+            .unwrap();
+        let liquidator = Addr::unchecked("liquidator");
+        IS_LIQUIDATOR
+            .save(&mut deps.storage, liquidator.clone(), &true)
+            .unwrap();
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &100_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &10_000)
+            .unwrap();
+        GUARANTEED_USD
+            .save(&mut deps.storage, eth.clone(), &9_986)
+            .unwrap();
+        GLOBAL_LONG_SIZES
+            .save(&mut deps.storage, eth.clone(), &10_000)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, eth.clone(), &100_000)
+            .unwrap();
+        set_balances(&mut deps, &[("eth", 100_000)]);
+
+        // the collateral is 1 short of the margin fee of 10 and the liquidation fee of 5
+        let trader = Addr::unchecked("trader");
+        let key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 10_000,
+                collateral: 14,
+                average_price: 1,
+                reserve_amount: 10_000,
+                ..Default::default()
+            },
+        )
         .unwrap();
-    Ok(Response::new()
-        .add_attribute("acton", "decrese_guarnteed_usd")
-        .add_attribute("token", token.to_string())
-        .add_attribute("usdg_aount", usdg_amount.to_string()))
-}
 
-fn get_next_global_short_average_price(
-    index_token: Addr,
-    next_price: u128,
-    size_delta: u128,
-    storage: &mut dyn Storage,
-) -> StdResult<u128> {
-    let global_short_sizes = GLOBAL_SHORT_SIZES.load(storage, index_token.clone())?;
-    let mut global_short_average_prizes =
-        GLOBAL_SHORT_AVERAGE_PRIZES.load(storage, index_token.clone())?;
-    let size = global_short_sizes;
-    let average_price = global_short_average_prizes;
-    let price_delta = if average_price > next_price {
-        average_price - next_price
-    } else {
-        next_price - average_price
-    };
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(liquidator.as_str(), &[]),
+            ExecuteMsg::LiquidatePosition {
+                account: trader,
+                collateral_token: eth.clone(),
+                index_token: eth.clone(),
+                is_long: true,
+                fee_reciever: liquidator.clone(),
+            },
+        )
+        .unwrap();
 
-    let delta = size * price_delta / average_price;
-    let has_profit = average_price > next_price;
+        // the liquidator is paid the whole liquidation fee, the fund takes 2 of the margin fee and
+        // covers the bad debt of 1 out of it
+        assert_eq!(
+            transfers(&res),
+            vec![("eth".to_string(), liquidator.to_string(), 5)]
+        );
+        assert_eq!(FEE_RESERVES.load(&deps.storage, eth.clone()).unwrap(), 8);
+        assert_eq!(INSURANCE_FUNDS.load(&deps.storage, eth).unwrap(), 1);
+    }
 
-    let next_size = size + size_delta;
-    let divisor = if has_profit {
-        next_size - delta
-    } else {
-        next_size + delta
-    };
+    #[test]
+    fn collateral_deposits_and_withdrawals_keep_the_position_size() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &2000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &1000)
+            .unwrap();
+        GUARANTEED_USD
+            .save(&mut deps.storage, eth.clone(), &900)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, eth.clone(), &2000)
+            .unwrap();
+        set_balances(&mut deps, &[("eth", 2050)]);
+
+        let trader = Addr::unchecked("trader");
+        APPROVED_ROUTERS1
+            .save(&mut deps.storage, trader.clone(), &true)
+            .unwrap();
+        APPROVED_ROUTERS2
+            .save(&mut deps.storage, trader.clone(), &true)
+            .unwrap();
+        let key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 1000,
+                collateral: 100,
+                average_price: 1,
+                reserve_amount: 1000,
+                ..Default::default()
+            },
+        )
+        .unwrap();
 
-    let result = next_price * next_size / divisor;
-    Ok(result)
-}
+        // collateral of longs moves into the pool
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(trader.as_str(), &[]),
+            ExecuteMsg::DepositCollateral {
+                account: trader.clone(),
+                collateral_token: eth.clone(),
+                index_token: eth.clone(),
+                is_long: true,
+            },
+        )
+        .unwrap();
+        let position = POSITIONS.load(&deps.storage, &key).unwrap();
+        assert_eq!((position.size, position.collateral), (1000, 150));
+        assert_eq!(POOL_AMOUNTS.load(&deps.storage, eth.clone()).unwrap(), 2050);
+        assert_eq!(
+            GUARANTEED_USD.load(&deps.storage, eth.clone()).unwrap(),
+            850
+        );
+
+        let withdraw = |collateral_delta| ExecuteMsg::WithdrawCollateral {
+            account: trader.clone(),
+            collateral_token: eth.clone(),
+            index_token: eth.clone(),
+            collateral_delta,
+            is_long: true,
+            reciever: trader.clone(),
+        };
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(trader.as_str(), &[]),
+            withdraw(120),
+        )
+        .unwrap();
+        assert_eq!(res.messages.len(), 1);
+        let position = POSITIONS.load(&deps.storage, &key).unwrap();
+        assert_eq!((position.size, position.collateral), (1000, 30));
+        assert_eq!(POOL_AMOUNTS.load(&deps.storage, eth.clone()).unwrap(), 1930);
+        assert_eq!(
+            GUARANTEED_USD.load(&deps.storage, eth.clone()).unwrap(),
+            970
+        );
+
+        // withdrawing 21 more would leave the position above the 50x max leverage
+        for (collateral_delta, code) in [(0, 97), (31, 33), (21, 81)] {
+            let err = execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(trader.as_str(), &[]),
+                withdraw(collateral_delta),
+            )
+            .unwrap_err();
+            assert_eq!(err, error_code(code));
+        }
+    }
 
-fn increase_global_short_size(
-    storage: &mut dyn Storage,
-    index_token: Addr,
-    amount: u128,
-) -> StdResult<()> {
-    let mut global_short_sizes = GLOBAL_SHORT_SIZES.load(storage, index_token.clone())?;
-    let mut max_global_short_sizes = MAX_GLOBAL_SHORT_SIZES.load(storage, index_token.clone())?;
+    #[test]
+    fn collateral_withdrawals_charge_the_accrued_borrow_fee_first() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &200_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &100_000)
+            .unwrap();
+        GUARANTEED_USD
+            .save(&mut deps.storage, eth.clone(), &90_000)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, eth.clone(), &200_000)
+            .unwrap();
+        set_balances(&mut deps, &[("eth", 200_000)]);
+        let trader = Addr::unchecked("trader");
+        APPROVED_ROUTERS1
+            .save(&mut deps.storage, trader.clone(), &true)
+            .unwrap();
+        APPROVED_ROUTERS2
+            .save(&mut deps.storage, trader.clone(), &true)
+            .unwrap();
+        let key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 100_000,
+                collateral: 10_000,
+                average_price: 1,
+                reserve_amount: 100_000,
+                entry_funding_rate: 200,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // the borrow rate accrued 0.3% since the position was opened
+        COMMULATIVE_FUNDING_RATES
+            .save(&mut deps.storage, eth.clone(), &3200)
+            .unwrap();
+        let withdraw = |collateral_delta| ExecuteMsg::WithdrawCollateral {
+            account: trader.clone(),
+            collateral_token: eth.clone(),
+            index_token: eth.clone(),
+            collateral_delta,
+            is_long: true,
+            reciever: trader.clone(),
+        };
 
-    global_short_sizes.add(amount);
-    let max_size = max_global_short_sizes;
-    GLOBAL_SHORT_SIZES.save(storage, index_token.clone(), &global_short_sizes)?;
-    if max_size != 0 {
-        if global_short_sizes <= max_size {
-            return Err(StdError::GenericErr {
-                msg: "Vault: max shorts exceeded".to_string(),
-            });
+        // 50x max leverage keeps 2000, so only 7700 are left after the borrow fee of 300
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(trader.as_str(), &[]),
+            withdraw(7700),
+        )
+        .unwrap();
+
+        let position = POSITIONS.load(&deps.storage, &key).unwrap();
+        assert_eq!(position.collateral, 2000);
+        assert_eq!(position.entry_funding_rate, 3200);
+        assert_eq!(FEE_RESERVES.load(&deps.storage, eth.clone()).unwrap(), 300);
+        assert_eq!(
+            POOL_AMOUNTS.load(&deps.storage, eth.clone()).unwrap(),
+            200_000 - 7700 - 300
+        );
+        assert_eq!(
+            GUARANTEED_USD.load(&deps.storage, eth.clone()).unwrap(),
+            90_000 + 7700 + 300
+        );
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(trader.as_str(), &[]),
+            withdraw(1),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(81));
+    }
+
+    #[test]
+    fn collateral_deposits_only_credit_the_tokens_sent_in() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &10_000)
+            .unwrap();
+        // the pool and the collateral of both shorts
+        TOKEN_BALANCES
+            .save(&mut deps.storage, usdc.clone(), &10_200)
+            .unwrap();
+
+        let mut keys = vec![];
+        for account in ["alice", "bob"] {
+            let account = Addr::unchecked(account);
+            APPROVED_ROUTERS1
+                .save(&mut deps.storage, account.clone(), &true)
+                .unwrap();
+            APPROVED_ROUTERS2
+                .save(&mut deps.storage, account.clone(), &true)
+                .unwrap();
+            let key = get_position_key(account, usdc.clone(), eth.clone(), false).unwrap();
+            save_position(
+                &mut deps.storage,
+                &key,
+                &Position {
+                    size: 1000,
+                    collateral: 100,
+                    average_price: 1,
+                    reserve_amount: 1000,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            keys.push(key);
         }
+        let deposit = |deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, account: &str| {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(account, &[]),
+                ExecuteMsg::DepositCollateral {
+                    account: Addr::unchecked(account),
+                    collateral_token: usdc.clone(),
+                    index_token: eth.clone(),
+                    is_long: false,
+                },
+            )
+        };
+
+        set_balances(&mut deps, &[("usdc", 10_250)]);
+        deposit(&mut deps, "alice").unwrap();
+        // nothing new was sent in
+        assert_eq!(deposit(&mut deps, "bob").unwrap_err(), error_code(97));
+        set_balances(&mut deps, &[("usdc", 10_330)]);
+        deposit(&mut deps, "bob").unwrap();
+
+        let collaterals: Vec<u128> = keys
+            .iter()
+            .map(|key| POSITIONS.load(&deps.storage, key).unwrap().collateral)
+            .collect();
+        assert_eq!(collaterals, vec![150, 180]);
+        assert_eq!(
+            TOKEN_BALANCES.load(&deps.storage, usdc.clone()).unwrap(),
+            10_330
+        );
+        assert_eq!(POOL_AMOUNTS.load(&deps.storage, usdc).unwrap(), 10_000);
     }
 
-    Ok(())
-}
+    #[test]
+    fn liquidation_prices_leave_the_maintenance_margin() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        let trader = Addr::unchecked("trader");
+        for (collateral_token, is_long) in [(&eth, true), (&usdc, false)] {
+            let key = get_position_key(
+                trader.clone(),
+                collateral_token.clone(),
+                eth.clone(),
+                is_long,
+            )
+            .unwrap();
+            save_position(
+                &mut deps.storage,
+                &key,
+                &Position {
+                    size: 1000,
+                    collateral: 100,
+                    average_price: 1000,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
 
-fn decrease_global_short_size(
-    storage: &mut dyn Storage,
-    index_token: Addr,
-    amount: u128,
-) -> StdResult<()> {
-    let mut global_short_sizes = GLOBAL_SHORT_SIZES.load(storage, index_token.clone())?;
+        // 50x max leverage keeps 20 of the 100 collateral, so the price may move by 8%
+        let mut liquidation_price = |collateral_token: &Addr, is_long| {
+            let res: LiquidationPriceResponse = from_json(
+                query(
+                    deps.as_mut(),
+                    mock_env(),
+                    QueryMsg::GetLiquidationPrice {
+                        account: trader.clone(),
+                        collateral_token: collateral_token.clone(),
+                        index_token: eth.clone(),
+                        is_long,
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+            assert_eq!(res.pending_funding_usd, 0);
+            res.liquidation_price
+        };
+        assert_eq!(liquidation_price(&eth, true), 920);
+        assert_eq!(liquidation_price(&usdc, false), 1080);
+    }
 
-    let size = global_short_sizes;
-    if amount > size {
-        global_short_sizes = 0;
-        return Ok(());
+    #[test]
+    fn liquidation_prices_include_the_pending_borrow_fee() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetBorrowRateModel {
+                token: eth.clone(),
+                model: Some(BorrowRateModel::Kinked {
+                    base_rate: 100,
+                    slope1: 1000,
+                    kink: 800_000,
+                    slope2: 5000,
+                }),
+            },
+        )
+        .unwrap();
+        // 90% utilisation charges 1400 per interval
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &10_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &9000)
+            .unwrap();
+        update_cumulative_funding_rate(&mut deps.storage, mock_env(), eth.clone(), eth.clone())
+            .unwrap();
+        let trader = Addr::unchecked("trader");
+        let key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        let position = Position {
+            size: 1000,
+            collateral: 100,
+            average_price: 1000,
+            ..Default::default()
+        };
+        save_position(&mut deps.storage, &key, &position).unwrap();
+
+        let mut liquidation_price = |env: Env| {
+            let res: LiquidationPriceResponse = from_json(
+                query(
+                    deps.as_mut(),
+                    env,
+                    QueryMsg::GetLiquidationPrice {
+                        account: trader.clone(),
+                        collateral_token: eth.clone(),
+                        index_token: eth.clone(),
+                        is_long: true,
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+            res.liquidation_price
+        };
+        assert_eq!(liquidation_price(mock_env()), 920);
+        // 10 intervals owe 14 of borrow fee that the position can no longer lose
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(10 * 8 * 3600);
+        assert_eq!(liquidation_price(env.clone()), 934);
+
+        // the liquidation check uses the same borrow fee
+        let (_, margin_fees, _) =
+            validate_liquidation(&mut deps.storage, env, &position, eth.clone(), eth, true)
+                .unwrap();
+        assert_eq!(margin_fees, 1 + 14);
     }
-    global_short_sizes.sub(amount);
-    GLOBAL_SHORT_SIZES.save(storage, index_token, &global_short_sizes)?;
-    Ok(())
-}
 
-fn reduce_collateral(
-    storage: &mut dyn Storage,
-    querier: QuerierWrapper,
-    env: Env,
-    account: Addr,
-    collateral_token: Addr,
-    index_token: Addr,
-    collateral_delta: u128,
-    size_delta: u128,
-    is_long: bool,
-) -> Result<(u128, u128), ContractError> {
-    let key = get_position_key(
-        account.clone(),
-        collateral_token.clone(),
-        index_token.clone(),
-        is_long,
-    )?;
-    let mut position = POSITIONS.load(storage, &key).unwrap();
+    #[test]
+    fn positions_are_transferred_or_merged_into_the_receivers_position() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        let key = |account| {
+            get_position_key(Addr::unchecked(account), usdc.clone(), eth.clone(), false).unwrap()
+        };
+        for (account, size, collateral, average_price) in [
+            ("alice", 1000, 100, 2),
+            ("bob", 3000, 100, 6),
+            ("erin", 1000, 1, 1),
+        ] {
+            save_position(
+                &mut deps.storage,
+                &key(account),
+                &Position {
+                    size,
+                    collateral,
+                    average_price,
+                    reserve_amount: size,
+                    cross_margin: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
 
-    let fee = collect_margin_fees(
-        account.clone(),
-        collateral_token.clone(),
-        index_token.clone(),
-        is_long,
-        size_delta,
-        position.size,
-        position.entry_funding_rate,
-        storage,
-    )?;
+        let transfer = |to: &str, merge| ExecuteMsg::TransferPosition {
+            collateral_token: usdc.clone(),
+            index_token: eth.clone(),
+            is_long: false,
+            to: Addr::unchecked(to),
+            merge,
+        };
+        let accept = |account: &str| ExecuteMsg::AcceptPositionTransfer {
+            account: Addr::unchecked(account),
+            collateral_token: usdc.clone(),
+            index_token: eth.clone(),
+            is_long: false,
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("alice", &[]),
+            transfer("alice", true),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(98));
+
+        // only the receiver accepts a transfer and a position it holds is only merged on request
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("alice", &[]),
+            transfer("bob", false),
+        )
+        .unwrap();
+        for (receiver, code) in [("carol", 117), ("bob", 99)] {
+            let err = execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(receiver, &[]),
+                accept("alice"),
+            )
+            .unwrap_err();
+            assert_eq!(err, error_code(code));
+        }
 
-    let has_profit;
-    let adjusted_delta;
+        // the merged average price keeps the combined profit of 500 and 2500
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("alice", &[]),
+            transfer("bob", true),
+        )
+        .unwrap();
+        assert_eq!(
+            POSITIONS.load(&deps.storage, &key("bob")).unwrap().size,
+            3000
+        );
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("bob", &[]),
+            accept("alice"),
+        )
+        .unwrap();
+        assert_eq!(
+            POSITIONS.may_load(&deps.storage, &key("alice")).unwrap(),
+            None
+        );
+        let position = POSITIONS.load(&deps.storage, &key("bob")).unwrap();
+        assert_eq!(position.size, 4000);
+        assert_eq!(position.collateral, 200);
+        assert_eq!(position.average_price, 4);
+        assert_eq!(position.reserve_amount, 4000);
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("bob", &[]),
+            transfer("carol", false),
+        )
+        .unwrap();
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("bob", &[]),
+            ExecuteMsg::CancelPositionTransfer {
+                collateral_token: usdc.clone(),
+                index_token: eth.clone(),
+                is_long: false,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            event_attributes(&res, "cancel_position_transfer")[4..],
+            attributes(&[("to", "carol"), ("merge", "false")])
+        );
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("carol", &[]),
+            accept("bob"),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(117));
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("bob", &[]),
+            transfer("carol", false),
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("carol", &[]),
+            accept("bob"),
+        )
+        .unwrap();
+        assert_eq!(
+            POSITIONS.may_load(&deps.storage, &key("bob")).unwrap(),
+            None
+        );
+        assert_eq!(
+            PENDING_POSITION_TRANSFERS
+                .may_load(&deps.storage, &key("bob"))
+                .unwrap(),
+            None
+        );
+        let position = POSITIONS.load(&deps.storage, &key("carol")).unwrap();
+        assert_eq!(position.size, 4000);
+        assert!(!position.cross_margin);
+
+        // a position that is only kept open by the cross margin account of the sender can't move
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("erin", &[]),
+            transfer("dave", false),
+        )
+        .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("dave", &[]),
+            accept("erin"),
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(96));
+    }
 
-    {
-        let (has_profit_value, delta) = get_delta(
-            index_token.clone(),
-            position.size,
-            position.average_price,
-            is_long,
-            position.last_increased_time,
-            storage,
-            env.clone(),
-        )?;
-        has_profit = has_profit_value;
-        adjusted_delta = size_delta * delta / position.size;
+    #[test]
+    fn transferred_positions_count_toward_the_receivers_max_position_size() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetTokenRiskConfig {
+                token: eth.clone(),
+                config: TokenRiskConfig {
+                    max_position_size_usd: 3000,
+                    ..Default::default()
+                },
+            },
+        )
+        .unwrap();
+        // bob already has a long on eth, so a short of alice is not merged into it
+        for (account, collateral_token, is_long) in [("alice", &usdc, false), ("bob", &eth, true)] {
+            let key = get_position_key(
+                Addr::unchecked(account),
+                collateral_token.clone(),
+                eth.clone(),
+                is_long,
+            )
+            .unwrap();
+            save_position(
+                &mut deps.storage,
+                &key,
+                &Position {
+                    size: 2000,
+                    collateral: 100,
+                    average_price: 1,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+
+        let transfer = |to: &str| ExecuteMsg::TransferPosition {
+            collateral_token: usdc.clone(),
+            index_token: eth.clone(),
+            is_long: false,
+            to: Addr::unchecked(to),
+            merge: false,
+        };
+        let mut accept = |receiver: &str| {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("alice", &[]),
+                transfer(receiver),
+            )
+            .unwrap();
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(receiver, &[]),
+                ExecuteMsg::AcceptPositionTransfer {
+                    account: Addr::unchecked("alice"),
+                    collateral_token: usdc.clone(),
+                    index_token: eth.clone(),
+                    is_long: false,
+                },
+            )
+        };
+        assert_eq!(accept("bob").unwrap_err(), error_code(84));
+        accept("carol").unwrap();
     }
 
-    let mut usd_out: u128 = 0;
+    #[test]
+    fn funding_keepers_are_rewarded_out_of_the_fee_reserves() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        FEE_RESERVES
+            .save(&mut deps.storage, eth.clone(), &15)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, eth.clone(), &15)
+            .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetFundingKeeperReward { reward_usd: 10 },
+        )
+        .unwrap();
 
-    if has_profit && adjusted_delta > 0 {
-        usd_out = adjusted_delta;
-        position.realised_pnl += adjusted_delta;
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::UpdateFundingRates {
+                tokens: vec![Addr::unchecked("btc")],
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err, error_code(105));
+
+        // only updates that complete a funding interval are rewarded, the last one is capped by the
+        // fee reserves
+        for (intervals, reward) in [(0, 0), (1, 10), (1, 0), (2, 5)] {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(intervals * 8 * 3600);
+            let res = execute(
+                deps.as_mut(),
+                env,
+                mock_info("keeper", &[]),
+                ExecuteMsg::UpdateFundingRates {
+                    tokens: vec![eth.clone()],
+                },
+            )
+            .unwrap();
+            assert_eq!(res.messages.len(), usize::from(reward > 0));
+        }
+        assert_eq!(FEE_RESERVES.load(&deps.storage, eth.clone()).unwrap(), 0);
+
+        let pending: PendingFeeDistributionResponse = from_json(
+            query(
+                deps.as_mut(),
+                mock_env(),
+                QueryMsg::GetPendingFeeDistribution {},
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            pending.keeper_rewards,
+            vec![KeeperReward {
+                token: eth,
+                amount: 15
+            }]
+        );
+    }
 
-        if !is_long {
-            let token_amount = usd_to_token_min(collateral_token.clone(), adjusted_delta, storage)?;
-            _decrease_pool_amount(storage, env.clone(), collateral_token.clone(), token_amount)?;
+    #[test]
+    fn funding_history_is_recorded_and_pruned_after_the_retention() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &1000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &500)
+            .unwrap();
+        BORROW_RATE_MODELS
+            .save(
+                &mut deps.storage,
+                eth.clone(),
+                &BorrowRateModel::Kinked {
+                    base_rate: 100,
+                    slope1: 1000,
+                    kink: 800_000,
+                    slope2: 5000,
+                },
+            )
+            .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(GOV_ADDR, &[]),
+            ExecuteMsg::SetFundingHistoryRetention {
+                retention: 2 * 8 * 3600,
+            },
+        )
+        .unwrap();
+
+        let mut funding_times = vec![];
+        for intervals in 0..5 {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(intervals * 8 * 3600);
+            update_cumulative_funding_rate(&mut deps.storage, env, eth.clone(), eth.clone())
+                .unwrap();
+            funding_times.push(LAST_FUNDING_TIMES.load(&deps.storage, eth.clone()).unwrap());
+        }
+
+        // the first update only starts the funding clock and the oldest record is pruned
+        let history: FundingHistoryResponse = from_json(
+            query(
+                deps.as_mut(),
+                mock_env(),
+                QueryMsg::GetFundingHistory {
+                    token: eth,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(history.retention, 2 * 8 * 3600);
+        let times: Vec<u64> = history.history.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, funding_times[2..].to_vec());
+        for (i, (_, record)) in history.history.iter().enumerate() {
+            assert_eq!(record.utilisation, 500_000);
+            assert_eq!(record.funding_rate, 100 + 500);
+            assert_eq!(record.cumulative_funding_rate, 600 * (i as u128 + 2));
         }
     }
 
-    if !has_profit && adjusted_delta > 0 {
-        position.collateral -= adjusted_delta;
+    #[test]
+    fn swaps_are_recorded_in_the_total_and_daily_stats() {
+        let (mut deps, path) = setup_route_pools();
+        let env = mock_env();
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("trader", &[]),
+            ExecuteMsg::SwapWithRoute {
+                path: path.clone(),
+                min_out: 0,
+                deadline: env.block.time.seconds(),
+                reciever: Addr::unchecked("trader"),
+                account: None,
+            },
+        )
+        .unwrap();
 
-        if !is_long {
-            let token_amount = usd_to_token_min(collateral_token.clone(), adjusted_delta, storage)?;
-            increase_pool_amount(
-                storage,
-                querier,
-                env.clone(),
-                collateral_token.clone(),
-                token_amount,
-            )?;
+        // the middle token is swapped out of the first hop and into the second one
+        let day = env.block.time.seconds() / SECONDS_PER_DAY;
+        for (token, swap_volume_usd) in path.into_iter().zip([1000, 1000 + 997, 997]) {
+            let total: StatsResponse = from_json(
+                query(
+                    deps.as_mut(),
+                    mock_env(),
+                    QueryMsg::GetStats {
+                        token: token.clone(),
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+            assert_eq!(total.stats.swap_volume_usd, swap_volume_usd);
+
+            let daily: DailyStatsResponse = from_json(
+                query(
+                    deps.as_mut(),
+                    mock_env(),
+                    QueryMsg::GetDailyStats {
+                        token,
+                        start_after: None,
+                        limit: None,
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+            assert_eq!(daily.days, vec![(day, total.stats)]);
+        }
+    }
+
+    #[test]
+    fn trades_are_recorded_per_account_and_per_position() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        let usdc = add_token(&mut deps.storage, "usdc", true);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &10_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, usdc.clone(), &4000)
+            .unwrap();
+        GLOBAL_SHORT_SIZES
+            .save(&mut deps.storage, eth.clone(), &4000)
+            .unwrap();
+        GLOBAL_SHORT_AVERAGE_PRIZES
+            .save(&mut deps.storage, eth.clone(), &2)
+            .unwrap();
+        set_balances(&mut deps, &[("usdc", 10_000)]);
+
+        let trader = Addr::unchecked("trader");
+        let key = get_position_key(trader.clone(), usdc.clone(), eth.clone(), false).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 4000,
+                collateral: 1000,
+                average_price: 2,
+                reserve_amount: 4000,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        record_trade(
+            &mut deps.storage,
+            &trader,
+            TradeRecord {
+                collateral_token: eth.clone(),
+                index_token: eth.clone(),
+                is_long: true,
+                action: TradeAction::Increase,
+                price: 1,
+                size_delta: 100,
+                collateral_delta_usd: 10,
+                fee_usd: 0,
+                realised_pnl: 0,
+                timestamp: mock_env().block.time.seconds(),
+            },
+        )
+        .unwrap();
+        for action in [TradeAction::Decrease, TradeAction::AutoDeleverage] {
+            let deps = deps.as_mut();
+            _decrease_position(
+                deps.storage,
+                deps.querier,
+                mock_env(),
+                &key,
+                0,
+                1000,
+                action,
+            )
+            .unwrap();
         }
 
-        position.realised_pnl -= adjusted_delta;
+        let history: TradeHistoryResponse = from_json(
+            query(
+                deps.as_mut(),
+                mock_env(),
+                QueryMsg::GetTradeHistory {
+                    account: trader.clone(),
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let indexes: Vec<u64> = history.trades.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indexes, vec![0, 1, 2]);
+
+        // the short sold at 2 realises 500 of profit on each 1000 closed at 1
+        let history: TradeHistoryResponse = from_json(
+            query(
+                deps.as_mut(),
+                mock_env(),
+                QueryMsg::GetPositionTradeHistory {
+                    account: trader,
+                    collateral_token: usdc,
+                    index_token: eth,
+                    is_long: false,
+                    start_after: Some(1),
+                    limit: None,
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(history.trades.len(), 1);
+        let (index, trade) = &history.trades[0];
+        assert_eq!(*index, 2);
+        assert_eq!(trade.action, TradeAction::AutoDeleverage);
+        assert_eq!(trade.size_delta, 1000);
+        assert_eq!(trade.realised_pnl, 500);
     }
 
-    if collateral_delta > 0 {
-        usd_out += collateral_delta;
-        position.collateral -= collateral_delta;
+    #[test]
+    fn swaps_emit_a_swap_event() {
+        let (mut deps, path) = setup_route_pools();
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("trader", &[]),
+            ExecuteMsg::Swap {
+                token_in: path[0].clone(),
+                token_out: path[1].clone(),
+                reciever: Addr::unchecked("trader"),
+                account: None,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            event_attributes(&res, "swap"),
+            attributes(&[
+                ("reciever", "trader"),
+                ("token_in", "eth"),
+                ("token_out", "usdc"),
+                ("amount_in", "1000"),
+                ("amount_out", "1000"),
+                ("amount_out_after_fees", "997"),
+                ("fee_basis_points", "30"),
+            ])
+        );
     }
 
-    if position.size == size_delta {
-        usd_out += position.collateral;
-        position.collateral = 0;
+    #[test]
+    fn position_changes_emit_the_change_and_the_new_state() {
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &200_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &100_000)
+            .unwrap();
+        GUARANTEED_USD
+            .save(&mut deps.storage, eth.clone(), &90_000)
+            .unwrap();
+        TOKEN_BALANCES
+            .save(&mut deps.storage, eth.clone(), &200_000)
+            .unwrap();
+        let trader = Addr::unchecked("trader");
+        APPROVED_ROUTERS1
+            .save(&mut deps.storage, trader.clone(), &true)
+            .unwrap();
+        APPROVED_ROUTERS2
+            .save(&mut deps.storage, trader.clone(), &true)
+            .unwrap();
+        let key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 100_000,
+                collateral: 10_000,
+                average_price: 1,
+                reserve_amount: 100_000,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(trader.as_str(), &[]),
+            ExecuteMsg::DecreasePosition {
+                account: trader.clone(),
+                collateral_token: eth.clone(),
+                index_token: eth,
+                collateral_delta: 0,
+                size_delta: 10_000,
+                is_long: true,
+                reciever: trader,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            event_attributes(&res, "decrease_position"),
+            attributes(&[
+                ("account", "trader"),
+                ("collateral_token", "eth"),
+                ("index_token", "eth"),
+                ("is_long", "true"),
+                ("collateral_delta_usd", "0"),
+                ("size_delta", "10000"),
+                ("price", "1"),
+                ("fee_usd", "10"),
+            ])
+        );
+        assert_eq!(
+            event_attributes(&res, "update_position"),
+            attributes(&[
+                ("account", "trader"),
+                ("collateral_token", "eth"),
+                ("index_token", "eth"),
+                ("is_long", "true"),
+                ("size", "90000"),
+                ("collateral", "9990"),
+                ("average_price", "1"),
+                ("entry_funding_rate", "0"),
+                ("reserve_amount", "90000"),
+                ("realised_pnl", "0"),
+                ("mark_price", "1"),
+            ])
+        );
     }
 
-    let usd_out_after_fee: u128 = if usd_out > fee {
-        usd_out - fee
-    } else {
-        position.collateral -= fee;
-        if is_long {
-            let fee_tokens = usd_to_token_min(collateral_token.clone(), fee, storage)?;
-            _decrease_pool_amount(storage, env.clone(), collateral_token, fee_tokens)?;
+    #[test]
+    fn query_responses_deserialize_into_their_declared_types() {
+        fn query_as<T: serde::de::DeserializeOwned>(
+            deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+            msg: QueryMsg,
+        ) -> T {
+            from_json(query(deps.as_mut(), mock_env(), msg).unwrap()).unwrap()
         }
-        usd_out
-    };
 
-    Ok((usd_out, usd_out_after_fee))
+        let mut deps = setup();
+        let eth = add_token(&mut deps.storage, "eth", false);
+        POOL_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &200_000)
+            .unwrap();
+        RSERVED_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &100_000)
+            .unwrap();
+        GUARANTEED_USD
+            .save(&mut deps.storage, eth.clone(), &90_000)
+            .unwrap();
+        USDG_AMOUNTS
+            .save(&mut deps.storage, eth.clone(), &50_000)
+            .unwrap();
+        TOKEN_WEIGHTS
+            .save(&mut deps.storage, eth.clone(), &1000)
+            .unwrap();
+        let mut state_variables = STATE_VARIABLES.load(&deps.storage).unwrap();
+        state_variables.total_token_weights = 4000;
+        STATE_VARIABLES
+            .save(&mut deps.storage, &state_variables)
+            .unwrap();
+        // the usdg supply is read from the balance of the vault
+        set_balances(&mut deps, &[(MOCK_CONTRACT_ADDR, 50_000)]);
+        let trader = Addr::unchecked("trader");
+        let key = get_position_key(trader.clone(), eth.clone(), eth.clone(), true).unwrap();
+        save_position(
+            &mut deps.storage,
+            &key,
+            &Position {
+                size: 100_000,
+                collateral: 10_000,
+                average_price: 1,
+                reserve_amount: 100_000,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let position = || QueryMsg::GetPosition {
+            account: trader.clone(),
+            collateral_token: eth.clone(),
+            index_token: eth.clone(),
+            is_long: true,
+        };
+
+        let res: RedemptionCollateralResponse = query_as(
+            &mut deps,
+            QueryMsg::GetRedemptionCollateral { token: eth.clone() },
+        );
+        assert_eq!(res.amount, 200_000 - 100_000 + 90_000);
+        let res: RedemptionCollateralUsdResponse = query_as(
+            &mut deps,
+            QueryMsg::GetRedemptionCollateralUsd { token: eth.clone() },
+        );
+        assert_eq!(res.amount_usd, 190_000);
+        let res: PositionResponse = query_as(&mut deps, position());
+        assert_eq!((res.size, res.collateral), (100_000, 10_000));
+        let res: UtilisationResponse =
+            query_as(&mut deps, QueryMsg::GetUtilisation { token: eth.clone() });
+        assert_eq!(res.utilisation, 500_000);
+        let res: PositionLeverageResponse = query_as(
+            &mut deps,
+            QueryMsg::GetPositionLeverage {
+                account: trader.clone(),
+                collateral_token: eth.clone(),
+                index_token: eth.clone(),
+                is_long: true,
+            },
+        );
+        assert_eq!(res.leverage, 100_000);
+        let res: GlobalShortDeltaResponse = query_as(
+            &mut deps,
+            QueryMsg::GetGlobalShortDelta { token: eth.clone() },
+        );
+        assert_eq!(res.delta, 0);
+        let res: PositionDeltaResponse = query_as(
+            &mut deps,
+            QueryMsg::GetPositionDelta {
+                account: trader.clone(),
+                collateral_token: eth.clone(),
+                index_token: eth.clone(),
+                is_long: true,
+            },
+        );
+        assert_eq!(res.delta, 0);
+        let res: TargetUsdgAmountResponse =
+            query_as(&mut deps, QueryMsg::GetTargetUsdgAmount { token: eth });
+        assert_eq!(res.amount, 12_500);
+        let _: PauseMatrixResponse = query_as(&mut deps, QueryMsg::GetPauseMatrix {});
+        let res: SettlementStateResponse = query_as(&mut deps, QueryMsg::GetSettlementState {});
+        assert!(!res.is_settlement_mode);
+    }
 }
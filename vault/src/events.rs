@@ -0,0 +1,761 @@
+// Every struct in this file is converted into a typed event. Events are emitted by the chain
+// as `wasm-<name>` and their attribute names are stable so indexers can rebuild the full
+// history of the vault. Amounts are token amounts unless the attribute ends in `_usd`.
+
+use cosmwasm_std::{Addr, Event};
+
+// per token amounts that are tracked by the vault
+pub enum TrackedAmount {
+    PoolAmount,
+    UsdgAmount,
+    ReservedAmount,
+    GuaranteedUsd,
+    GlobalShortSize,
+    GlobalLongSize,
+    InsuranceFund,
+}
+
+impl TrackedAmount {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrackedAmount::PoolAmount => "pool_amount",
+            TrackedAmount::UsdgAmount => "usdg_amount",
+            TrackedAmount::ReservedAmount => "reserved_amount",
+            TrackedAmount::GuaranteedUsd => "guaranteed_usd",
+            TrackedAmount::GlobalShortSize => "global_short_size",
+            TrackedAmount::GlobalLongSize => "global_long_size",
+            TrackedAmount::InsuranceFund => "insurance_fund",
+        }
+    }
+}
+
+// `wasm-pool_amount_changed`, `wasm-usdg_amount_changed`, `wasm-reserved_amount_changed`,
+// `wasm-guaranteed_usd_changed`, `wasm-global_short_size_changed`,
+// `wasm-global_long_size_changed` and `wasm-insurance_fund_changed`
+// attributes: token, delta, is_increase, value
+pub struct AmountChanged {
+    pub amount: TrackedAmount,
+    pub token: Addr,
+    pub delta: u128,
+    pub is_increase: bool,
+    // value stored after the change
+    pub value: u128,
+}
+
+impl From<AmountChanged> for Event {
+    fn from(event: AmountChanged) -> Self {
+        Event::new(format!("{}_changed", event.amount.as_str()))
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("delta", event.delta.to_string())
+            .add_attribute("is_increase", event.is_increase.to_string())
+            .add_attribute("value", event.value.to_string())
+    }
+}
+
+// `wasm-collect_fees`
+// attributes: token, fee_type, fee_usd, fee_tokens
+pub struct CollectFees {
+    pub token: Addr,
+    // one of `swap`, `mint_burn`, `margin` or `liquidation`
+    pub fee_type: &'static str,
+    pub fee_usd: u128,
+    pub fee_tokens: u128,
+}
+
+impl From<CollectFees> for Event {
+    fn from(event: CollectFees) -> Self {
+        Event::new("collect_fees")
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("fee_type", event.fee_type)
+            .add_attribute("fee_usd", event.fee_usd.to_string())
+            .add_attribute("fee_tokens", event.fee_tokens.to_string())
+    }
+}
+
+// `wasm-withdraw_fees`
+// attributes: token, reciever, amount
+pub struct WithdrawFees {
+    pub token: Addr,
+    pub reciever: Addr,
+    pub amount: u128,
+}
+
+impl From<WithdrawFees> for Event {
+    fn from(event: WithdrawFees) -> Self {
+        Event::new("withdraw_fees")
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("reciever", event.reciever.to_string())
+            .add_attribute("amount", event.amount.to_string())
+    }
+}
+
+// `wasm-update_funding_rate`
+// attributes: token, funding_rate, cumulative_funding_rate, last_funding_time
+pub struct UpdateFundingRate {
+    pub token: Addr,
+    // rate added to the cumulative rate by this update
+    pub funding_rate: u128,
+    pub cumulative_funding_rate: u128,
+    pub last_funding_time: u64,
+}
+
+impl From<UpdateFundingRate> for Event {
+    fn from(event: UpdateFundingRate) -> Self {
+        Event::new("update_funding_rate")
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("funding_rate", event.funding_rate.to_string())
+            .add_attribute(
+                "cumulative_funding_rate",
+                event.cumulative_funding_rate.to_string(),
+            )
+            .add_attribute("last_funding_time", event.last_funding_time.to_string())
+    }
+}
+
+// `wasm-circuit_breaker`
+// attributes: token, price, last_accepted_price, is_tripped
+pub struct CircuitBreaker {
+    pub token: Addr,
+    pub price: u128,
+    pub last_accepted_price: u128,
+    // false when gov or a guardian confirmed the price and reset the breaker
+    pub is_tripped: bool,
+}
+
+impl From<CircuitBreaker> for Event {
+    fn from(event: CircuitBreaker) -> Self {
+        Event::new("circuit_breaker")
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("price", event.price.to_string())
+            .add_attribute("last_accepted_price", event.last_accepted_price.to_string())
+            .add_attribute("is_tripped", event.is_tripped.to_string())
+    }
+}
+
+// `wasm-pay_keeper_reward`
+// attributes: keeper, token, amount
+pub struct PayKeeperReward {
+    pub keeper: Addr,
+    pub token: Addr,
+    // tokens taken from the fee reserves
+    pub amount: u128,
+}
+
+impl From<PayKeeperReward> for Event {
+    fn from(event: PayKeeperReward) -> Self {
+        Event::new("pay_keeper_reward")
+            .add_attribute("keeper", event.keeper.to_string())
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("amount", event.amount.to_string())
+    }
+}
+
+// `wasm-direct_pool_deposit`
+// attributes: token, amount
+pub struct DirectPoolDeposit {
+    pub token: Addr,
+    pub amount: u128,
+}
+
+impl From<DirectPoolDeposit> for Event {
+    fn from(event: DirectPoolDeposit) -> Self {
+        Event::new("direct_pool_deposit")
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("amount", event.amount.to_string())
+    }
+}
+
+// `wasm-buy_usdg`
+// attributes: reciever, token, token_amount, usdg_amount, fee_basis_points
+pub struct BuyUsdg {
+    pub reciever: Addr,
+    pub token: Addr,
+    pub token_amount: u128,
+    pub usdg_amount: u128,
+    pub fee_basis_points: u128,
+}
+
+impl From<BuyUsdg> for Event {
+    fn from(event: BuyUsdg) -> Self {
+        Event::new("buy_usdg")
+            .add_attribute("reciever", event.reciever.to_string())
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("token_amount", event.token_amount.to_string())
+            .add_attribute("usdg_amount", event.usdg_amount.to_string())
+            .add_attribute("fee_basis_points", event.fee_basis_points.to_string())
+    }
+}
+
+// `wasm-sell_usdg`
+// attributes: reciever, token, usdg_amount, token_amount, fee_basis_points
+pub struct SellUsdg {
+    pub reciever: Addr,
+    pub token: Addr,
+    pub usdg_amount: u128,
+    pub token_amount: u128,
+    pub fee_basis_points: u128,
+}
+
+impl From<SellUsdg> for Event {
+    fn from(event: SellUsdg) -> Self {
+        Event::new("sell_usdg")
+            .add_attribute("reciever", event.reciever.to_string())
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("usdg_amount", event.usdg_amount.to_string())
+            .add_attribute("token_amount", event.token_amount.to_string())
+            .add_attribute("fee_basis_points", event.fee_basis_points.to_string())
+    }
+}
+
+// `wasm-swap`
+// attributes: reciever, token_in, token_out, amount_in, amount_out, amount_out_after_fees,
+// fee_basis_points
+pub struct Swap {
+    pub reciever: Addr,
+    pub token_in: Addr,
+    pub token_out: Addr,
+    pub amount_in: u128,
+    pub amount_out: u128,
+    pub amount_out_after_fees: u128,
+    pub fee_basis_points: u128,
+}
+
+impl From<Swap> for Event {
+    fn from(event: Swap) -> Self {
+        Event::new("swap")
+            .add_attribute("reciever", event.reciever.to_string())
+            .add_attribute("token_in", event.token_in.to_string())
+            .add_attribute("token_out", event.token_out.to_string())
+            .add_attribute("amount_in", event.amount_in.to_string())
+            .add_attribute("amount_out", event.amount_out.to_string())
+            .add_attribute(
+                "amount_out_after_fees",
+                event.amount_out_after_fees.to_string(),
+            )
+            .add_attribute("fee_basis_points", event.fee_basis_points.to_string())
+    }
+}
+
+// identifies a position in every position event
+// attributes: account, collateral_token, index_token, is_long
+pub struct PositionKey {
+    pub account: Addr,
+    pub collateral_token: Addr,
+    pub index_token: Addr,
+    pub is_long: bool,
+}
+
+impl PositionKey {
+    fn to_event(&self, name: &str) -> Event {
+        Event::new(name)
+            .add_attribute("account", self.account.to_string())
+            .add_attribute("collateral_token", self.collateral_token.to_string())
+            .add_attribute("index_token", self.index_token.to_string())
+            .add_attribute("is_long", self.is_long.to_string())
+    }
+}
+
+// `wasm-increase_position` and `wasm-decrease_position`
+// attributes: account, collateral_token, index_token, is_long, collateral_delta_usd,
+// size_delta, price, fee_usd
+pub struct ChangePosition {
+    pub key: PositionKey,
+    pub is_increase: bool,
+    pub collateral_delta_usd: u128,
+    pub size_delta: u128,
+    pub price: u128,
+    pub fee_usd: u128,
+}
+
+impl From<ChangePosition> for Event {
+    fn from(event: ChangePosition) -> Self {
+        let name = if event.is_increase {
+            "increase_position"
+        } else {
+            "decrease_position"
+        };
+        event
+            .key
+            .to_event(name)
+            .add_attribute(
+                "collateral_delta_usd",
+                event.collateral_delta_usd.to_string(),
+            )
+            .add_attribute("size_delta", event.size_delta.to_string())
+            .add_attribute("price", event.price.to_string())
+            .add_attribute("fee_usd", event.fee_usd.to_string())
+    }
+}
+
+// `wasm-update_position` after every change of a position that stays open and
+// `wasm-close_position` when a position is removed
+// attributes: account, collateral_token, index_token, is_long, size, collateral, average_price,
+// entry_funding_rate, reserve_amount, realised_pnl, mark_price
+pub struct PositionState {
+    pub key: PositionKey,
+    pub is_closed: bool,
+    pub size: u128,
+    pub collateral: u128,
+    pub average_price: u128,
+    pub entry_funding_rate: u128,
+    pub reserve_amount: u128,
+    pub realised_pnl: u128,
+    pub mark_price: u128,
+}
+
+impl From<PositionState> for Event {
+    fn from(event: PositionState) -> Self {
+        let name = if event.is_closed {
+            "close_position"
+        } else {
+            "update_position"
+        };
+        event
+            .key
+            .to_event(name)
+            .add_attribute("size", event.size.to_string())
+            .add_attribute("collateral", event.collateral.to_string())
+            .add_attribute("average_price", event.average_price.to_string())
+            .add_attribute("entry_funding_rate", event.entry_funding_rate.to_string())
+            .add_attribute("reserve_amount", event.reserve_amount.to_string())
+            .add_attribute("realised_pnl", event.realised_pnl.to_string())
+            .add_attribute("mark_price", event.mark_price.to_string())
+    }
+}
+
+// `wasm-update_pnl`
+// attributes: account, collateral_token, index_token, is_long, has_profit, delta_usd
+pub struct UpdatePnl {
+    pub key: PositionKey,
+    pub has_profit: bool,
+    pub delta_usd: u128,
+}
+
+impl From<UpdatePnl> for Event {
+    fn from(event: UpdatePnl) -> Self {
+        event
+            .key
+            .to_event("update_pnl")
+            .add_attribute("has_profit", event.has_profit.to_string())
+            .add_attribute("delta_usd", event.delta_usd.to_string())
+    }
+}
+
+// `wasm-update_cross_margin`
+// attributes: account, collateral_token, delta, is_increase, balance
+// delta and balance are in collateral tokens
+pub struct UpdateCrossMargin {
+    pub account: Addr,
+    pub collateral_token: Addr,
+    pub delta: u128,
+    pub is_increase: bool,
+    pub balance: u128,
+}
+
+impl From<UpdateCrossMargin> for Event {
+    fn from(event: UpdateCrossMargin) -> Self {
+        Event::new("update_cross_margin")
+            .add_attribute("account", event.account.to_string())
+            .add_attribute("collateral_token", event.collateral_token.to_string())
+            .add_attribute("delta", event.delta.to_string())
+            .add_attribute("is_increase", event.is_increase.to_string())
+            .add_attribute("balance", event.balance.to_string())
+    }
+}
+
+// `wasm-liquidate_cross_margin`
+// attributes: account, collateral_token, equity, maintenance_margin, positions, fee_reciever
+pub struct LiquidateCrossMargin {
+    pub account: Addr,
+    pub collateral_token: Addr,
+    pub equity: u128,
+    pub maintenance_margin: u128,
+    // number of liquidated positions
+    pub positions: u32,
+    pub fee_reciever: Addr,
+}
+
+impl From<LiquidateCrossMargin> for Event {
+    fn from(event: LiquidateCrossMargin) -> Self {
+        Event::new("liquidate_cross_margin")
+            .add_attribute("account", event.account.to_string())
+            .add_attribute("collateral_token", event.collateral_token.to_string())
+            .add_attribute("equity", event.equity.to_string())
+            .add_attribute("maintenance_margin", event.maintenance_margin.to_string())
+            .add_attribute("positions", event.positions.to_string())
+            .add_attribute("fee_reciever", event.fee_reciever.to_string())
+    }
+}
+
+// `wasm-liquidate_position`
+// attributes: account, collateral_token, index_token, is_long, size, collateral,
+// reserve_amount, realised_pnl, mark_price, fee_reciever
+pub struct LiquidatePosition {
+    pub key: PositionKey,
+    pub size: u128,
+    pub collateral: u128,
+    pub reserve_amount: u128,
+    pub realised_pnl: u128,
+    pub mark_price: u128,
+    pub fee_reciever: Addr,
+}
+
+impl From<LiquidatePosition> for Event {
+    fn from(event: LiquidatePosition) -> Self {
+        event
+            .key
+            .to_event("liquidate_position")
+            .add_attribute("size", event.size.to_string())
+            .add_attribute("collateral", event.collateral.to_string())
+            .add_attribute("reserve_amount", event.reserve_amount.to_string())
+            .add_attribute("realised_pnl", event.realised_pnl.to_string())
+            .add_attribute("mark_price", event.mark_price.to_string())
+            .add_attribute("fee_reciever", event.fee_reciever.to_string())
+    }
+}
+
+// `wasm-partial_liquidate_position`
+// attributes: account, collateral_token, index_token, is_long, size_delta, size, collateral,
+// fee_usd, mark_price, fee_reciever
+pub struct PartialLiquidatePosition {
+    pub key: PositionKey,
+    pub size_delta: u128,
+    // size left open
+    pub size: u128,
+    // collateral left after the liquidation fee
+    pub collateral: u128,
+    pub fee_usd: u128,
+    pub mark_price: u128,
+    pub fee_reciever: Addr,
+}
+
+impl From<PartialLiquidatePosition> for Event {
+    fn from(event: PartialLiquidatePosition) -> Self {
+        event
+            .key
+            .to_event("partial_liquidate_position")
+            .add_attribute("size_delta", event.size_delta.to_string())
+            .add_attribute("size", event.size.to_string())
+            .add_attribute("collateral", event.collateral.to_string())
+            .add_attribute("fee_usd", event.fee_usd.to_string())
+            .add_attribute("mark_price", event.mark_price.to_string())
+            .add_attribute("fee_reciever", event.fee_reciever.to_string())
+    }
+}
+
+// `wasm-transfer_position`
+// attributes: account, collateral_token, index_token, is_long, to, size, collateral, merged
+pub struct TransferPosition {
+    pub key: PositionKey,
+    pub to: Addr,
+    // size and collateral of the position of the receiver after the transfer
+    pub size: u128,
+    pub collateral: u128,
+    pub merged: bool,
+}
+
+impl From<TransferPosition> for Event {
+    fn from(event: TransferPosition) -> Self {
+        event
+            .key
+            .to_event("transfer_position")
+            .add_attribute("to", event.to.to_string())
+            .add_attribute("size", event.size.to_string())
+            .add_attribute("collateral", event.collateral.to_string())
+            .add_attribute("merged", event.merged.to_string())
+    }
+}
+
+// `wasm-request_position_transfer` and `wasm-cancel_position_transfer`
+// attributes: account, collateral_token, index_token, is_long, to, merge
+pub struct PositionTransferRequest {
+    pub key: PositionKey,
+    pub is_cancelled: bool,
+    pub to: Addr,
+    pub merge: bool,
+}
+
+impl From<PositionTransferRequest> for Event {
+    fn from(event: PositionTransferRequest) -> Self {
+        let name = if event.is_cancelled {
+            "cancel_position_transfer"
+        } else {
+            "request_position_transfer"
+        };
+        event
+            .key
+            .to_event(name)
+            .add_attribute("to", event.to.to_string())
+            .add_attribute("merge", event.merge.to_string())
+    }
+}
+
+// `wasm-settle_position`
+// attributes: account, collateral_token, index_token, is_long, size, collateral, price,
+// has_profit, delta_usd, amount_out
+pub struct SettlePosition {
+    pub key: PositionKey,
+    pub size: u128,
+    pub collateral: u128,
+    pub price: u128,
+    pub has_profit: bool,
+    pub delta_usd: u128,
+    pub amount_out: u128,
+}
+
+impl From<SettlePosition> for Event {
+    fn from(event: SettlePosition) -> Self {
+        event
+            .key
+            .to_event("settle_position")
+            .add_attribute("size", event.size.to_string())
+            .add_attribute("collateral", event.collateral.to_string())
+            .add_attribute("price", event.price.to_string())
+            .add_attribute("has_profit", event.has_profit.to_string())
+            .add_attribute("delta_usd", event.delta_usd.to_string())
+            .add_attribute("amount_out", event.amount_out.to_string())
+    }
+}
+
+// `wasm-skip_settlement`
+// attributes: account, collateral_token, index_token, is_long, error
+pub struct SkipSettlement {
+    pub key: PositionKey,
+    pub error: String,
+}
+
+impl From<SkipSettlement> for Event {
+    fn from(event: SkipSettlement) -> Self {
+        event
+            .key
+            .to_event("skip_settlement")
+            .add_attribute("error", event.error)
+    }
+}
+
+// `wasm-write_off_position`
+// attributes: account, collateral_token, index_token, is_long, size, collateral
+pub struct WriteOffPosition {
+    pub key: PositionKey,
+    pub size: u128,
+    pub collateral: u128,
+}
+
+impl From<WriteOffPosition> for Event {
+    fn from(event: WriteOffPosition) -> Self {
+        event
+            .key
+            .to_event("write_off_position")
+            .add_attribute("size", event.size.to_string())
+            .add_attribute("collateral", event.collateral.to_string())
+    }
+}
+
+// `wasm-redeem_settlement`
+// attributes: reciever, token, usdg_amount, amount_out
+pub struct RedeemSettlement {
+    pub reciever: Addr,
+    pub token: Addr,
+    pub usdg_amount: u128,
+    pub amount_out: u128,
+}
+
+impl From<RedeemSettlement> for Event {
+    fn from(event: RedeemSettlement) -> Self {
+        Event::new("redeem_settlement")
+            .add_attribute("reciever", event.reciever.to_string())
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("usdg_amount", event.usdg_amount.to_string())
+            .add_attribute("amount_out", event.amount_out.to_string())
+    }
+}
+
+// `wasm-distribute_fees`
+// attributes: epoch, token, lp_amount, staking_amount, treasury_amount
+pub struct DistributeFees {
+    pub epoch: u64,
+    pub token: Addr,
+    pub lp_amount: u128,
+    pub staking_amount: u128,
+    pub treasury_amount: u128,
+}
+
+impl From<DistributeFees> for Event {
+    fn from(event: DistributeFees) -> Self {
+        Event::new("distribute_fees")
+            .add_attribute("epoch", event.epoch.to_string())
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("lp_amount", event.lp_amount.to_string())
+            .add_attribute("staking_amount", event.staking_amount.to_string())
+            .add_attribute("treasury_amount", event.treasury_amount.to_string())
+    }
+}
+
+// `wasm-referral_fee`
+// attributes: account, referrer, code, token, fee_tokens, discount_tokens, rebate_tokens
+pub struct ReferralFee {
+    pub account: Addr,
+    pub referrer: Addr,
+    pub code: String,
+    pub token: Addr,
+    // fee charged before the discount and rebate were taken out
+    pub fee_tokens: u128,
+    pub discount_tokens: u128,
+    pub rebate_tokens: u128,
+}
+
+impl From<ReferralFee> for Event {
+    fn from(event: ReferralFee) -> Self {
+        Event::new("referral_fee")
+            .add_attribute("account", event.account.to_string())
+            .add_attribute("referrer", event.referrer.to_string())
+            .add_attribute("code", event.code)
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("fee_tokens", event.fee_tokens.to_string())
+            .add_attribute("discount_tokens", event.discount_tokens.to_string())
+            .add_attribute("rebate_tokens", event.rebate_tokens.to_string())
+    }
+}
+
+// `wasm-claim_referral_rebate`
+// attributes: referrer, token, reciever, amount
+pub struct ClaimReferralRebate {
+    pub referrer: Addr,
+    pub token: Addr,
+    pub reciever: Addr,
+    pub amount: u128,
+}
+
+impl From<ClaimReferralRebate> for Event {
+    fn from(event: ClaimReferralRebate) -> Self {
+        Event::new("claim_referral_rebate")
+            .add_attribute("referrer", event.referrer.to_string())
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("reciever", event.reciever.to_string())
+            .add_attribute("amount", event.amount.to_string())
+    }
+}
+
+// `wasm-update_skew_funding`
+// attributes: token, long_funding_rate, short_funding_rate, cumulative_long_funding,
+// cumulative_short_funding, last_funding_time
+pub struct UpdateSkewFunding {
+    pub token: Addr,
+    // funding per unit of size added by this update, negative when the side receives
+    pub long_funding_rate: i128,
+    pub short_funding_rate: i128,
+    pub cumulative_long_funding: i128,
+    pub cumulative_short_funding: i128,
+    pub last_funding_time: u64,
+}
+
+impl From<UpdateSkewFunding> for Event {
+    fn from(event: UpdateSkewFunding) -> Self {
+        Event::new("update_skew_funding")
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("long_funding_rate", event.long_funding_rate.to_string())
+            .add_attribute("short_funding_rate", event.short_funding_rate.to_string())
+            .add_attribute(
+                "cumulative_long_funding",
+                event.cumulative_long_funding.to_string(),
+            )
+            .add_attribute(
+                "cumulative_short_funding",
+                event.cumulative_short_funding.to_string(),
+            )
+            .add_attribute("last_funding_time", event.last_funding_time.to_string())
+    }
+}
+
+// `wasm-settle_skew_funding`
+// attributes: account, collateral_token, index_token, is_long, funding_usd, shortfall_usd,
+// pool_usd
+pub struct SettleSkewFunding {
+    pub key: PositionKey,
+    // paid by the position when positive, received when negative
+    pub funding_usd: i128,
+    // funding the position should have received but the funding pool could not pay
+    pub shortfall_usd: u128,
+    pub pool_usd: u128,
+}
+
+impl From<SettleSkewFunding> for Event {
+    fn from(event: SettleSkewFunding) -> Self {
+        event
+            .key
+            .to_event("settle_skew_funding")
+            .add_attribute("funding_usd", event.funding_usd.to_string())
+            .add_attribute("shortfall_usd", event.shortfall_usd.to_string())
+            .add_attribute("pool_usd", event.pool_usd.to_string())
+    }
+}
+
+// `wasm-price_impact`
+// attributes: token, price_impact_usd, impact_tokens, impact_pool_amount
+pub struct PriceImpact {
+    pub token: Addr,
+    // rebated to the trade when positive, charged when negative
+    pub price_impact_usd: i128,
+    pub impact_tokens: u128,
+    // value of the impact pool after the change
+    pub impact_pool_amount: u128,
+}
+
+impl From<PriceImpact> for Event {
+    fn from(event: PriceImpact) -> Self {
+        Event::new("price_impact")
+            .add_attribute("token", event.token.to_string())
+            .add_attribute("price_impact_usd", event.price_impact_usd.to_string())
+            .add_attribute("impact_tokens", event.impact_tokens.to_string())
+            .add_attribute("impact_pool_amount", event.impact_pool_amount.to_string())
+    }
+}
+
+// `wasm-auto_deleverage`
+// attributes: account, collateral_token, index_token, is_long, size_delta, profit_usd,
+// pnl_to_pool_basis_points
+pub struct AutoDeleverage {
+    pub key: PositionKey,
+    pub size_delta: u128,
+    // unrealised profit of the whole position before it was reduced
+    pub profit_usd: u128,
+    // ratio that triggered the deleveraging
+    pub pnl_to_pool_basis_points: u128,
+}
+
+impl From<AutoDeleverage> for Event {
+    fn from(event: AutoDeleverage) -> Self {
+        event
+            .key
+            .to_event("auto_deleverage")
+            .add_attribute("size_delta", event.size_delta.to_string())
+            .add_attribute("profit_usd", event.profit_usd.to_string())
+            .add_attribute(
+                "pnl_to_pool_basis_points",
+                event.pnl_to_pool_basis_points.to_string(),
+            )
+    }
+}
+
+// `wasm-cover_bad_debt`
+// attributes: account, collateral_token, index_token, is_long, bad_debt_usd, covered_amount,
+// uncovered_usd
+pub struct CoverBadDebt {
+    pub key: PositionKey,
+    pub bad_debt_usd: u128,
+    // tokens taken from the insurance fund
+    pub covered_amount: u128,
+    // bad debt left to the pool
+    pub uncovered_usd: u128,
+}
+
+impl From<CoverBadDebt> for Event {
+    fn from(event: CoverBadDebt) -> Self {
+        event
+            .key
+            .to_event("cover_bad_debt")
+            .add_attribute("bad_debt_usd", event.bad_debt_usd.to_string())
+            .add_attribute("covered_amount", event.covered_amount.to_string())
+            .add_attribute("uncovered_usd", event.uncovered_usd.to_string())
+    }
+}
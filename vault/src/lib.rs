@@ -1,4 +1,5 @@
 pub mod contract;
 pub mod error;
+pub mod events;
 pub mod msg;
 pub mod state;
@@ -1,5 +1,12 @@
-use cosmwasm_schema::cw_serde;
-use cosmwasm_std::Addr;
+use cosmwasm_schema::{cw_serde, QueryResponses};
+use cosmwasm_std::{Addr, Binary};
+
+use crate::state::{
+    AcceptedPrice, AdlConfig, BorrowRateModel, CircuitBreakerConfig, FeeDistribution,
+    FeeDistributionConfig, FundingRateRecord, InsuranceFundRecord, KeeperReward, Operation,
+    PartialLiquidationConfig, PriceImpactConfig, ReferralStats, ReferralTier, SettlementPrice,
+    SkewFundingConfig, StablePriceConfig, TokenRiskConfig, TokenStats, TradeRecord,
+};
 
 #[cw_serde]
 pub struct InstantiateMsg {}
@@ -41,6 +48,18 @@ pub enum ExecuteMsg {
     SetIsLevergaeEnabaled {
         _is_leverage_enabled: bool,
     },
+    SetGuardian {
+        guardian: Addr,
+        is_guardian: bool,
+    },
+    SetTokenPaused {
+        token: Addr,
+        is_paused: bool,
+    },
+    SetOperationPaused {
+        operation: Operation,
+        is_paused: bool,
+    },
     SetMaxGasPrice {
         max_gas_price: u128,
     },
@@ -61,6 +80,10 @@ pub enum ExecuteMsg {
         token: Addr,
         amount: u128,
     },
+    SetMaxGlobalLongSize {
+        token: Addr,
+        amount: u128,
+    },
     SetFees {
         tax_basis_points: u128,
         stable_tax_basis_points: u128,
@@ -113,18 +136,115 @@ pub enum ExecuteMsg {
     DirectPoolDeposit {
         token: Addr,
     },
+    // account is the trader whose referral code applies, managers and routers pass the trader
+    // they act for and anyone else trades for themselves
     BuyUsdg {
         token: Addr,
         reciever: Addr,
+        account: Option<Addr>,
     },
     SellUsdg {
         token: Addr,
         reciever: Addr,
+        account: Option<Addr>,
     },
     Swap {
         token_in: Addr,
         token_out: Addr,
         reciever: Addr,
+        account: Option<Addr>,
+    },
+    SwapWithRoute {
+        path: Vec<Addr>,
+        min_out: u128,
+        deadline: u64,
+        reciever: Addr,
+        account: Option<Addr>,
+    },
+    // None stops distributing fees so that gov can withdraw them again
+    SetFeeDistributionConfig {
+        config: Option<FeeDistributionConfig>,
+    },
+    DistributeFees {},
+    RegisterReferralCode {
+        code: String,
+    },
+    SetTraderReferralCode {
+        code: String,
+    },
+    SetReferralTier {
+        tier: u64,
+        total_rebate_basis_points: u128,
+        discount_share_basis_points: u128,
+    },
+    SetReferrerTier {
+        referrer: Addr,
+        tier: u64,
+    },
+    ClaimReferralRebates {
+        tokens: Vec<Addr>,
+        reciever: Addr,
+    },
+    SetTokenRiskConfig {
+        token: Addr,
+        config: TokenRiskConfig,
+    },
+    // None falls back to the linear fundingRateFactor
+    SetBorrowRateModel {
+        token: Addr,
+        model: Option<BorrowRateModel>,
+    },
+    // None disables skew funding for the index token
+    SetSkewFundingConfig {
+        token: Addr,
+        config: Option<SkewFundingConfig>,
+    },
+    // None disables price impact for the token
+    SetPriceImpactConfig {
+        token: Addr,
+        config: Option<PriceImpactConfig>,
+    },
+    // None disables auto-deleveraging for the collateral token
+    SetAdlConfig {
+        collateral_token: Addr,
+        config: Option<AdlConfig>,
+    },
+    // ranks and deleverages up to limit positions after the position key start_after
+    AutoDeleverage {
+        collateral_token: Addr,
+        start_after: Option<Binary>,
+        limit: Option<u32>,
+    },
+    SetFundingKeeperReward {
+        reward_usd: u128,
+    },
+    UpdateFundingRates {
+        tokens: Vec<Addr>,
+    },
+    SetFundingHistoryRetention {
+        retention: u64,
+    },
+    SetInsuranceFundBasisPoints {
+        basis_points: u128,
+    },
+    SetPartialLiquidationConfig {
+        config: Option<PartialLiquidationConfig>,
+    },
+    SetStablePriceConfig {
+        token: Addr,
+        config: Option<StablePriceConfig>,
+    },
+    SetCircuitBreakerConfig {
+        token: Addr,
+        config: Option<CircuitBreakerConfig>,
+    },
+    // anyone can trip the breaker of a token whose price moved too far
+    TripCircuitBreaker {
+        token: Addr,
+    },
+    // accepts the current price of the token and resumes trading
+    ResetCircuitBreaker {
+        token: Addr,
     },
     IncreasePosition {
         account: Addr,
@@ -142,6 +262,54 @@ pub enum ExecuteMsg {
         is_long: bool,
         reciever: Addr,
     },
+    DepositCollateral {
+        account: Addr,
+        collateral_token: Addr,
+        index_token: Addr,
+        is_long: bool,
+    },
+    WithdrawCollateral {
+        account: Addr,
+        collateral_token: Addr,
+        index_token: Addr,
+        collateral_delta: u128,
+        is_long: bool,
+        reciever: Addr,
+    },
+    DepositCrossMargin {
+        collateral_token: Addr,
+    },
+    WithdrawCrossMargin {
+        collateral_token: Addr,
+        amount: u128,
+        reciever: Addr,
+    },
+    LiquidateCrossMargin {
+        account: Addr,
+        collateral_token: Addr,
+        fee_reciever: Addr,
+    },
+    // the position only moves once the receiver accepts it, a new request replaces the last one
+    TransferPosition {
+        collateral_token: Addr,
+        index_token: Addr,
+        is_long: bool,
+        to: Addr,
+        // merge into an existing position of the receiver instead of failing
+        merge: bool,
+    },
+    CancelPositionTransfer {
+        collateral_token: Addr,
+        index_token: Addr,
+        is_long: bool,
+    },
+    // called by the receiver, account is the sender of the transfer
+    AcceptPositionTransfer {
+        account: Addr,
+        collateral_token: Addr,
+        index_token: Addr,
+        is_long: bool,
+    },
     LiquidatePosition {
         account: Addr,
         collateral_token: Addr,
@@ -149,47 +317,465 @@ pub enum ExecuteMsg {
         is_long: bool,
         fee_reciever: Addr,
     },
+    EnableSettlementMode {
+        prices: Vec<SettlementPrice>,
+    },
+    SettlePositions {
+        limit: Option<u32>,
+    },
+    // only callable by the vault itself, see SettlePositions
+    SettlePosition {
+        key: Binary,
+    },
+    RedeemSettlement {
+        reciever: Addr,
+    },
+    // gov removes a position that keeps failing to settle so that redemptions can start
+    WriteOffPosition {
+        key: Binary,
+    },
 }
 
 #[cw_serde]
+#[derive(QueryResponses)]
 pub enum QueryMsg {
-    GetRedemptionCollateral {
-        token: Addr,
+    #[returns(RedemptionCollateralResponse)]
+    GetRedemptionCollateral { token: Addr },
+    #[returns(RedemptionCollateralUsdResponse)]
+    GetRedemptionCollateralUsd { token: Addr },
+    #[returns(PositionResponse)]
+    GetPosition {
+        account: Addr,
+        collateral_token: Addr,
+        index_token: Addr,
+        is_long: bool,
     },
-    GetRedemptionCollateralUsd {
-        token: Addr,
+    #[returns(TradeHistoryResponse)]
+    GetTradeHistory {
+        account: Addr,
+        start_after: Option<u64>,
+        limit: Option<u32>,
     },
-    GetPosition {
+    #[returns(TradeHistoryResponse)]
+    GetPositionTradeHistory {
         account: Addr,
         collateral_token: Addr,
         index_token: Addr,
         is_long: bool,
+        start_after: Option<u64>,
+        limit: Option<u32>,
     },
-    GetUtilisation {
+    #[returns(StatsResponse)]
+    GetStats { token: Addr },
+    #[returns(DailyStatsResponse)]
+    GetDailyStats {
         token: Addr,
+        // day since epoch
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    #[returns(FundingRateInfoResponse)]
+    GetFundingRateInfo { tokens: Vec<Addr> },
+    #[returns(FundingHistoryResponse)]
+    GetFundingHistory {
+        token: Addr,
+        start_after: Option<u64>,
+        limit: Option<u32>,
     },
+    #[returns(UtilisationResponse)]
+    GetUtilisation { token: Addr },
+    #[returns(PositionLeverageResponse)]
     GetPositionLeverage {
         account: Addr,
         collateral_token: Addr,
         index_token: Addr,
         is_long: bool,
     },
-    GetGlobalShortDelta {
-        token: Addr,
+    #[returns(CrossMarginAccountResponse)]
+    GetCrossMarginAccount {
+        account: Addr,
+        collateral_token: Addr,
+    },
+    #[returns(LiquidationPriceResponse)]
+    GetLiquidationPrice {
+        account: Addr,
+        collateral_token: Addr,
+        index_token: Addr,
+        is_long: bool,
     },
+    #[returns(GlobalShortDeltaResponse)]
+    GetGlobalShortDelta { token: Addr },
+    #[returns(PositionDeltaResponse)]
     GetPositionDelta {
         account: Addr,
         collateral_token: Addr,
         index_token: Addr,
         is_long: bool,
     },
-    GetTargetUsdgAmount {
+    #[returns(TargetUsdgAmountResponse)]
+    GetTargetUsdgAmount { token: Addr },
+    #[returns(PauseMatrixResponse)]
+    GetPauseMatrix {},
+    #[returns(SettlementStateResponse)]
+    GetSettlementState {},
+    #[returns(SwapRouteResponse)]
+    GetSwapRoute { path: Vec<Addr>, amount_in: u128 },
+    #[returns(FeeDistributionConfigResponse)]
+    GetFeeDistributionConfig {},
+    #[returns(PendingFeeDistributionResponse)]
+    GetPendingFeeDistribution {},
+    #[returns(FeeDistributionsResponse)]
+    GetFeeDistributions {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    #[returns(ReferralCodeResponse)]
+    GetReferralCode { code: String },
+    #[returns(TraderReferralResponse)]
+    GetTraderReferral { account: Addr },
+    #[returns(ReferralStatsResponse)]
+    GetReferralStats { referrer: Addr },
+    #[returns(TokenRiskConfigResponse)]
+    GetTokenRiskConfig { token: Addr },
+    #[returns(OpenInterestResponse)]
+    GetOpenInterest { token: Addr },
+    #[returns(SkewFundingResponse)]
+    GetSkewFunding { token: Addr },
+    #[returns(PriceImpactResponse)]
+    GetPositionPriceImpact {
+        collateral_token: Addr,
+        index_token: Addr,
+        is_long: bool,
+        size_delta: u128,
+        is_increase: bool,
+    },
+    #[returns(AdlRankingResponse)]
+    GetAdlRanking {
+        collateral_token: Addr,
+        start_after: Option<Binary>,
+        limit: Option<u32>,
+    },
+    #[returns(StablePriceResponse)]
+    GetStablePrice { token: Addr },
+    #[returns(CircuitBreakerResponse)]
+    GetCircuitBreaker { token: Addr },
+    #[returns(InsuranceFundResponse)]
+    GetInsuranceFund {
         token: Addr,
+        start_after: Option<u64>,
+        limit: Option<u32>,
     },
 }
 
+#[cw_serde]
+pub struct RedemptionCollateralResponse {
+    pub amount: u128,
+}
+
+#[cw_serde]
+pub struct RedemptionCollateralUsdResponse {
+    pub amount_usd: u128,
+}
+
+#[cw_serde]
+pub struct PositionResponse {
+    pub size: u128,
+    pub collateral: u128,
+    pub average_price: u128,
+    pub entry_funding_rate: u128,
+    pub reserve_amount: u128,
+    pub realised_pnl: u128,
+    pub last_increased_time: u128,
+    pub entry_skew_funding: i128,
+    pub cross_margin: bool,
+    pub liquidation_price: u128,
+}
+
+#[cw_serde]
+pub struct UtilisationResponse {
+    pub utilisation: u128,
+    pub model: BorrowRateModel,
+    // per funding interval
+    pub borrow_rate: u128,
+    pub annualised_borrow_rate: u128,
+}
+
+#[cw_serde]
+pub struct TradeHistoryResponse {
+    // (index in the account's ledger, trade) oldest first
+    pub trades: Vec<(u64, TradeRecord)>,
+}
+
+#[cw_serde]
+pub struct StatsResponse {
+    pub stats: TokenStats,
+}
+
+#[cw_serde]
+pub struct DailyStatsResponse {
+    // (day since epoch, stats) oldest first
+    pub days: Vec<(u64, TokenStats)>,
+}
+
+#[cw_serde]
+pub struct FundingRateInfo {
+    pub token: Addr,
+    // per funding interval at the current utilisation
+    pub funding_rate: u128,
+    // rate the next update adds to the cumulative rate
+    pub next_funding_rate: u128,
+    pub cumulative_funding_rate: u128,
+    pub last_funding_time: u64,
+}
+
+#[cw_serde]
+pub struct FundingRateInfoResponse {
+    pub rates: Vec<FundingRateInfo>,
+}
+
+#[cw_serde]
+pub struct FundingHistoryResponse {
+    pub retention: u64,
+    // (funding time, record) oldest first
+    pub history: Vec<(u64, FundingRateRecord)>,
+}
+
+#[cw_serde]
+pub struct PositionLeverageResponse {
+    pub leverage: u128,
+}
+
+#[cw_serde]
+pub struct LiquidationPriceResponse {
+    pub liquidation_price: u128,
+    // skew funding accrued since the position was last settled, negative when it is received
+    pub pending_funding_usd: i128,
+}
+
+#[cw_serde]
+pub struct CrossMarginPosition {
+    pub index_token: Addr,
+    pub is_long: bool,
+    pub size: u128,
+    pub collateral: u128,
+}
+
+#[cw_serde]
+pub struct CrossMarginAccountResponse {
+    // in collateral tokens
+    pub balance: u128,
+    pub balance_usd: u128,
+    // balance plus the collateral and unrealised pnl of every position drawing on the account
+    pub equity: u128,
+    pub maintenance_margin: u128,
+    pub is_liquidatable: bool,
+    pub positions: Vec<CrossMarginPosition>,
+}
+
 #[cw_serde]
 pub struct GlobalShortDeltaResponse {
     pub has_profit: bool,
     pub delta: u128,
 }
+
+#[cw_serde]
+pub struct PositionDeltaResponse {
+    pub has_profit: bool,
+    pub delta: u128,
+}
+
+#[cw_serde]
+pub struct TargetUsdgAmountResponse {
+    pub amount: u128,
+}
+
+#[cw_serde]
+pub struct OperationPauseStatus {
+    pub operation: Operation,
+    pub is_paused: bool,
+}
+
+#[cw_serde]
+pub struct TokenPauseStatus {
+    pub token: Addr,
+    pub is_paused: bool,
+}
+
+#[cw_serde]
+pub struct PauseMatrixResponse {
+    pub is_swap_enabled: bool,
+    pub is_leverage_enabled: bool,
+    pub operations: Vec<OperationPauseStatus>,
+    pub tokens: Vec<TokenPauseStatus>,
+}
+
+#[cw_serde]
+pub struct SettlementStateResponse {
+    pub is_settlement_mode: bool,
+    pub prices: Vec<SettlementPrice>,
+    pub outstanding_usdg: u128,
+    pub has_open_positions: bool,
+    pub failed_positions: u64,
+}
+
+#[cw_serde]
+pub struct SwapHop {
+    pub token_in: Addr,
+    pub token_out: Addr,
+    pub amount_in: u128,
+    pub amount_out: u128,
+    pub amount_out_after_fees: u128,
+    pub fee_basis_points: u128,
+    pub price_impact_usd: i128,
+}
+
+#[cw_serde]
+pub struct SwapRouteResponse {
+    pub hops: Vec<SwapHop>,
+    pub amount_out: u128,
+}
+
+#[cw_serde]
+pub struct FeeDistributionConfigResponse {
+    pub config: Option<FeeDistributionConfig>,
+}
+
+#[cw_serde]
+pub struct PendingFee {
+    pub token: Addr,
+    pub amount: u128,
+}
+
+#[cw_serde]
+pub struct PendingFeeDistributionResponse {
+    pub epoch: u64,
+    pub next_distribution_time: u64,
+    pub fees: Vec<PendingFee>,
+    pub keeper_rewards: Vec<KeeperReward>,
+}
+
+#[cw_serde]
+pub struct FeeDistributionsResponse {
+    pub distributions: Vec<FeeDistribution>,
+}
+
+#[cw_serde]
+pub struct ReferralCodeResponse {
+    pub code: String,
+    pub owner: Option<Addr>,
+}
+
+#[cw_serde]
+pub struct TraderReferralResponse {
+    pub code: Option<String>,
+    pub referrer: Option<Addr>,
+}
+
+#[cw_serde]
+pub struct ClaimableRebate {
+    pub token: Addr,
+    pub amount: u128,
+}
+
+#[cw_serde]
+pub struct ReferralStatsResponse {
+    pub tier: u64,
+    pub tier_config: Option<ReferralTier>,
+    pub stats: ReferralStats,
+    pub claimable: Vec<ClaimableRebate>,
+}
+
+#[cw_serde]
+pub struct TokenRiskConfigResponse {
+    pub config: TokenRiskConfig,
+    // max_leverage after falling back to the global value
+    pub max_leverage: u128,
+}
+
+#[cw_serde]
+pub struct OpenInterestResponse {
+    pub long_size: u128,
+    pub long_average_price: u128,
+    pub max_long_size: u128,
+    // None when longs are not capped
+    pub remaining_long_capacity: Option<u128>,
+    pub short_size: u128,
+    pub short_average_price: u128,
+    pub max_short_size: u128,
+    // None when shorts are not capped
+    pub remaining_short_capacity: Option<u128>,
+}
+
+#[cw_serde]
+pub struct SkewFundingResponse {
+    pub config: Option<SkewFundingConfig>,
+    pub cumulative_long_funding: i128,
+    pub cumulative_short_funding: i128,
+    pub last_funding_time: u64,
+    // funding per unit of size for the next interval, negative when the side receives
+    pub next_long_funding_rate: i128,
+    pub next_short_funding_rate: i128,
+    pub pool_usd: u128,
+    pub bad_debt_usd: u128,
+}
+
+#[cw_serde]
+pub struct PriceImpactResponse {
+    // rebated when positive, charged when negative
+    pub price_impact_usd: i128,
+    pub impact_pool_amount: u128,
+}
+
+#[cw_serde]
+pub struct AdlCandidate {
+    pub account: Addr,
+    pub collateral_token: Addr,
+    pub index_token: Addr,
+    pub is_long: bool,
+    pub size: u128,
+    pub collateral: u128,
+    pub profit_usd: u128,
+    pub leverage: u128,
+    pub liquidation_price: u128,
+}
+
+#[cw_serde]
+pub struct AdlRankingResponse {
+    // the profit and ratio only cover the positions of the page
+    pub total_profit_usd: u128,
+    pub pool_usd: u128,
+    pub pnl_to_pool_basis_points: u128,
+    pub is_adl_required: bool,
+    // the profitable positions of the page, most profitable first, ties are broken by leverage
+    pub positions: Vec<AdlCandidate>,
+    // the key to continue from, none once every position has been read
+    pub last_key: Option<Binary>,
+}
+
+#[cw_serde]
+pub struct StablePriceResponse {
+    pub config: Option<StablePriceConfig>,
+    // price reported by the price feed before clamping
+    pub oracle_price: u128,
+    pub min_price: u128,
+    pub max_price: u128,
+    pub is_depegged: bool,
+}
+
+#[cw_serde]
+pub struct CircuitBreakerResponse {
+    pub config: Option<CircuitBreakerConfig>,
+    pub last_accepted_price: Option<AcceptedPrice>,
+    pub price: u128,
+    // deviation of the current price from the last accepted price
+    pub deviation_basis_points: u128,
+    pub is_tripped: bool,
+}
+
+#[cw_serde]
+pub struct InsuranceFundResponse {
+    pub balance: u128,
+    pub basis_points: u128,
+    // (index, record) oldest first
+    pub history: Vec<(u64, InsuranceFundRecord)>,
+}
@@ -2,7 +2,7 @@ use std::fmt::Display;
 
 use bincode::{deserialize, serialize};
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, StdError};
+use cosmwasm_std::{Addr, Empty, StdError};
 use cw_storage_plus::{Item, Map};
 use schemars::JsonSchema;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
@@ -50,6 +50,230 @@ pub struct StateVariablesStruct {
 }
 
 #[cw_serde]
+pub struct SettlementPrice {
+    pub token: Addr,
+    pub price: u128,
+}
+
+#[cw_serde]
+pub struct FeeDistributionConfig {
+    pub lp_reciever: Addr,
+    pub staking_reciever: Addr,
+    pub treasury: Addr,
+    // splits of every distributed amount, must add up to BASIS_POINTS_DIVISOR
+    pub lp_basis_points: u128,
+    pub staking_basis_points: u128,
+    pub treasury_basis_points: u128,
+    pub epoch_duration: u64,
+    // when set, fees in other tokens are swapped into this token before they are split
+    pub reward_token: Option<Addr>,
+}
+
+#[cw_serde]
+pub struct DistributedFee {
+    pub token: Addr,
+    pub lp_amount: u128,
+    pub staking_amount: u128,
+    pub treasury_amount: u128,
+}
+
+#[cw_serde]
+pub struct KeeperReward {
+    pub token: Addr,
+    pub amount: u128,
+}
+
+#[cw_serde]
+pub struct FeeDistribution {
+    pub epoch: u64,
+    pub timestamp: u64,
+    pub fees: Vec<DistributedFee>,
+    // paid out of the fee reserves to funding keepers during the epoch
+    #[serde(default)]
+    pub keeper_rewards: Vec<KeeperReward>,
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub struct TokenRiskConfig {
+    // in basis points like maxLeverage, 0 falls back to the global maxLeverage
+    pub max_leverage: u128,
+    pub min_collateral_usd: u128,
+    pub min_position_size_usd: u128,
+    // summed over all positions of an account on the index token, 0 means no limit
+    pub max_position_size_usd: u128,
+}
+
+// borrow rates are charged per funding interval in FUNDING_RATE_PRECISION,
+// utilisation and kink use the same precision so 1000000 is 100%
+#[cw_serde]
+pub enum BorrowRateModel {
+    // factor * utilisation, same as fundingRateFactor
+    Linear {
+        factor: u128,
+    },
+    // base_rate + slope1 up to the kink, slope2 is applied to the utilisation above it
+    Kinked {
+        base_rate: u128,
+        slope1: u128,
+        kink: u128,
+        slope2: u128,
+    },
+    // factor * utilisation but never more than max_rate
+    Capped {
+        factor: u128,
+        max_rate: u128,
+    },
+}
+
+// enables funding between longs and shorts for an index token, the funding factor is the
+// rate per funding interval in FUNDING_RATE_PRECISION paid by the dominant side at full skew
+#[cw_serde]
+pub struct SkewFundingConfig {
+    pub funding_factor: u128,
+}
+
+// cumulative funding per unit of size in FUNDING_RATE_PRECISION,
+// positive values are paid by the side and negative values are received
+#[cw_serde]
+#[derive(Default)]
+pub struct SkewFundingState {
+    pub cumulative_long_funding: i128,
+    pub cumulative_short_funding: i128,
+    pub last_funding_time: u64,
+    // funding paid by positions that has not been received yet, receivers are only paid
+    // out of it and what it can't pay is recorded as bad debt
+    pub pool_usd: u128,
+    pub bad_debt_usd: u128,
+}
+
+// impact_usd = factor * imbalance ^ exponent / PRICE_IMPACT_PRECISION, imbalance is long - short
+// open interest for index tokens and the deviation of usdgAmounts from the target for swaps
+#[cw_serde]
+pub struct PriceImpactConfig {
+    pub factor: u128,
+    pub exponent: u32,
+}
+
+// auto-deleveraging starts when the unrealised profit of positions using a collateral token is
+// above trigger * poolAmounts and closes the most profitable positions until it is below target
+#[cw_serde]
+pub struct AdlConfig {
+    pub trigger_pnl_to_pool_basis_points: u128,
+    pub target_pnl_to_pool_basis_points: u128,
+}
+
+// usd amounts of everything traded and collected on a token, open interest is a snapshot taken
+// at the last update
+#[cw_serde]
+#[derive(Default)]
+pub struct TokenStats {
+    pub swap_volume_usd: u128,
+    pub mint_burn_volume_usd: u128,
+    pub margin_volume_usd: u128,
+    pub liquidation_volume_usd: u128,
+    pub swap_fees_usd: u128,
+    pub mint_burn_fees_usd: u128,
+    pub margin_fees_usd: u128,
+    pub funding_fees_usd: u128,
+    pub liquidation_fees_usd: u128,
+    pub liquidations: u64,
+    pub long_open_interest: u128,
+    pub short_open_interest: u128,
+    // funding keeper rewards paid out of the fee reserves
+    #[serde(default)]
+    pub keeper_rewards_usd: u128,
+}
+
+impl TokenStats {
+    pub fn add(&mut self, delta: &TokenStats) {
+        self.swap_volume_usd += delta.swap_volume_usd;
+        self.mint_burn_volume_usd += delta.mint_burn_volume_usd;
+        self.margin_volume_usd += delta.margin_volume_usd;
+        self.liquidation_volume_usd += delta.liquidation_volume_usd;
+        self.swap_fees_usd += delta.swap_fees_usd;
+        self.mint_burn_fees_usd += delta.mint_burn_fees_usd;
+        self.margin_fees_usd += delta.margin_fees_usd;
+        self.funding_fees_usd += delta.funding_fees_usd;
+        self.liquidation_fees_usd += delta.liquidation_fees_usd;
+        self.liquidations += delta.liquidations;
+        self.keeper_rewards_usd += delta.keeper_rewards_usd;
+    }
+}
+
+#[cw_serde]
+pub struct FundingRateRecord {
+    pub cumulative_funding_rate: u128,
+    pub utilisation: u128,
+    // rate added to the cumulative rate by the update
+    pub funding_rate: u128,
+}
+
+// opens, swaps and USDG mints of a token halt when its price moves more than
+// maxDeviationBasisPoints away from a last accepted price that is less than window seconds old,
+// until gov or a guardian confirms the new price
+#[cw_serde]
+pub struct CircuitBreakerConfig {
+    pub max_deviation_basis_points: u128,
+    pub window: u64,
+    // whether positions can still be decreased and liquidated while the breaker is tripped
+    pub allow_decrease: bool,
+    pub allow_liquidation: bool,
+}
+
+// prices of a stable token within clampBasisPoints of 1 USD are valued at exactly 1 USD and
+// prices above the band are capped at its upper edge, once the price moves more than
+// depegBasisPoints away from 1 USD no USDG is minted and no short is opened against the token
+#[cw_serde]
+pub struct StablePriceConfig {
+    pub clamp_basis_points: u128,
+    pub depeg_basis_points: u128,
+}
+
+#[cw_serde]
+pub struct AcceptedPrice {
+    pub price: u128,
+    pub timestamp: u64,
+}
+
+// liquidations of positions of at least minSizeUsd that are over max leverage only close enough
+// size to get back to maxLeverage * (1 - bufferBasisPoints) instead of the whole position
+#[cw_serde]
+pub struct PartialLiquidationConfig {
+    pub buffer_basis_points: u128,
+    pub min_size_usd: u128,
+}
+
+#[cw_serde]
+pub struct InsuranceFundRecord {
+    pub account: Addr,
+    pub bad_debt_usd: u128,
+    // tokens moved from the insurance fund into the pool
+    pub covered_amount: u128,
+    // bad debt that the insurance fund could not cover and the pool absorbed
+    pub uncovered_usd: u128,
+    pub timestamp: u64,
+}
+
+#[cw_serde]
+pub struct ReferralTier {
+    // share of every fee paid by a referred trader that is given back
+    pub total_rebate_basis_points: u128,
+    // share of the rebate that goes to the trader as a discount, the rest accrues to the referrer
+    pub discount_share_basis_points: u128,
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub struct ReferralStats {
+    pub referred_traders: u64,
+    pub volume_usd: u128,
+    pub rebates_usd: u128,
+    pub discounts_usd: u128,
+}
+
+#[cw_serde]
+#[derive(Default)]
 pub struct Position {
     pub size: u128,
     pub collateral: u128,
@@ -58,7 +282,81 @@ pub struct Position {
     pub reserve_amount: u128,
     pub realised_pnl: u128,
     pub last_increased_time: u128,
+    // cumulative skew funding of the position's side when funding was last settled
+    #[serde(default)]
+    pub entry_skew_funding: i128,
+    // set once the position draws margin from the cross margin account of its account and
+    // collateral token, only these positions are liquidated together with the account
+    #[serde(default)]
+    pub cross_margin: bool,
+}
+// a transfer of a position that waits for the receiver to accept it
+#[cw_serde]
+pub struct PositionTransfer {
+    pub to: Addr,
+    pub merge: bool,
+}
+
+#[cw_serde]
+#[derive(Copy)]
+pub enum TradeAction {
+    Increase,
+    Decrease,
+    Liquidation,
+    AutoDeleverage,
+}
+
+#[cw_serde]
+pub struct TradeRecord {
+    pub collateral_token: Addr,
+    pub index_token: Addr,
+    pub is_long: bool,
+    pub action: TradeAction,
+    pub price: u128,
+    pub size_delta: u128,
+    pub collateral_delta_usd: u128,
+    pub fee_usd: u128,
+    // negative for losses
+    pub realised_pnl: i128,
+    pub timestamp: u64,
+}
+
+#[cw_serde]
+#[derive(Copy)]
+pub enum Operation {
+    BuyUsdg,
+    SellUsdg,
+    Swap,
+    IncreasePosition,
+    DecreasePosition,
+    LiquidatePosition,
+    DirectPoolDeposit,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 7] = [
+        Operation::BuyUsdg,
+        Operation::SellUsdg,
+        Operation::Swap,
+        Operation::IncreasePosition,
+        Operation::DecreasePosition,
+        Operation::LiquidatePosition,
+        Operation::DirectPoolDeposit,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::BuyUsdg => "buy_usdg",
+            Operation::SellUsdg => "sell_usdg",
+            Operation::Swap => "swap",
+            Operation::IncreasePosition => "increase_position",
+            Operation::DecreasePosition => "decrease_position",
+            Operation::LiquidatePosition => "liquidate_position",
+            Operation::DirectPoolDeposit => "direct_pool_deposit",
+        }
+    }
 }
+
 pub type Bytes<'a> = &'a [u8];
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, JsonSchema)]
@@ -122,6 +420,27 @@ pub const IS_INITIALIZED: Item<bool> = Item::new("is_initialized"); // false at
 pub const IS_SWAP_ENABLED: Item<bool> = Item::new("isSwapEnabled"); // true at initialization
 pub const IS_LEVERGE_ENABLED: Item<bool> = Item::new("isLeverageEnabled"); // true at initialization
 
+// isSettlementMode is the emergency shutdown switch, once enabled prices are frozen at
+// settlementPrices, positions can only be settled and USDG can only be redeemed pro rata
+pub const IS_SETTLEMENT_MODE: Item<bool> = Item::new("is_settlement_mode"); // false at initialization
+pub const SETTLEMENT_PRICES: Map<Addr, u128> = Map::new("settlement_prices");
+
+// settlementUsdgSupply tracks the USDG debt that has not been redeemed since settlement started
+// and is used as the denominator for pro rata redemptions from poolAmounts
+pub const SETTLEMENT_USDG_SUPPLY: Item<u128> = Item::new("settlement_usdg_supply");
+// settlePositions settles every position in its own submessage, pendingSettlements maps the
+// submessage id to the position key and settlementFailures keeps the error of positions that
+// could not be settled so they are skipped until every other position is settled, gov can write
+// off positions that keep failing
+pub const PENDING_SETTLEMENTS: Map<u64, Vec<u8>> = Map::new("pending_settlements");
+pub const SETTLEMENT_FAILURES: Map<Bytes, String> = Map::new("settlement_failures");
+
+// feeDistributionConfig replaces withdrawFees to a single reciever, fees are split once per epoch
+pub const FEE_DISTRIBUTION_CONFIG: Item<FeeDistributionConfig> =
+    Item::new("fee_distribution_config");
+pub const FEE_DISTRIBUTION_EPOCH: Item<u64> = Item::new("fee_distribution_epoch"); // 0 at initialization
+pub const LAST_FEE_DISTRIBUTION_TIME: Item<u64> = Item::new("last_fee_distribution_time");
+
 // Mappings
 
 // Nested Mapping not supported as in solidity so created 2 mapping for this
@@ -132,12 +451,31 @@ pub const APPROVED_ROUTERS2: Map<Addr, bool> = Map::new("approved_routers1");
 pub const IS_LIQUIDATOR: Map<Addr, bool> = Map::new("is_liquidator");
 pub const IS_MANAGER: Map<Addr, bool> = Map::new("is_manager");
 
+// guardians can pause tokens and operations, only gov can unpause them
+pub const IS_GUARDIAN: Map<Addr, bool> = Map::new("is_guardian");
+
+// pausedTokens halts every operation that touches the token, e.g. when its oracle breaks
+pub const PAUSED_TOKENS: Map<Addr, bool> = Map::new("paused_tokens");
+
+pub const STABLE_PRICE_CONFIGS: Map<Addr, StablePriceConfig> = Map::new("stable_price_configs");
+pub const CIRCUIT_BREAKER_CONFIGS: Map<Addr, CircuitBreakerConfig> =
+    Map::new("circuit_breaker_configs");
+pub const LAST_ACCEPTED_PRICES: Map<Addr, AcceptedPrice> = Map::new("last_accepted_prices");
+// a tripped breaker stays tripped until gov or a guardian resets it, even if the price comes back
+pub const TRIPPED_CIRCUIT_BREAKERS: Map<Addr, bool> = Map::new("tripped_circuit_breakers");
+
+// pausedOperations halts a single operation for all tokens, keyed by Operation::as_str
+pub const PAUSED_OPERATIONS: Map<&str, bool> = Map::new("paused_operations");
+
 pub const WHITELISTED_TOKENS: Map<Addr, bool> = Map::new("whitelisted_tokens");
 pub const TOKEN_DECIMALS: Map<Addr, u128> = Map::new("token_decimals");
 pub const MIN_PROFIT_BASIS_POINTS: Map<Addr, u128> = Map::new("min_profit_basis_points");
 pub const STABLE_TOKENS: Map<Addr, bool> = Map::new("stable_tokens");
 pub const SHORTABLE_TOKENS: Map<Addr, bool> = Map::new("shortable_tokens");
 
+// tokenRiskConfigs are keyed by index token and checked whenever a position changes
+pub const TOKEN_RISK_CONFIGS: Map<Addr, TokenRiskConfig> = Map::new("token_risk_configs");
+
 // tokenBalances is used only to determine _transferIn values
 pub const TOKEN_BALANCES: Map<Addr, u128> = Map::new("token_balancess");
 
@@ -174,8 +512,69 @@ pub const COMMULATIVE_FUNDING_RATES: Map<Addr, u128> = Map::new("cumulative_Fund
 // lastFundingTimes tracks the last time funding was updated for a token
 pub const LAST_FUNDING_TIMES: Map<Addr, u64> = Map::new("last_funding_times");
 
+// skew funding is keyed by index token, tokens without a config do not pay skew funding
+pub const SKEW_FUNDING_CONFIGS: Map<Addr, SkewFundingConfig> = Map::new("skew_funding_configs");
+pub const SKEW_FUNDING_STATES: Map<Addr, SkewFundingState> = Map::new("skew_funding_states");
+
+// price impact charged on trades that worsen the balance is kept per token in the impact pool
+// and pays the rebates of trades that improve it
+pub const PRICE_IMPACT_CONFIGS: Map<Addr, PriceImpactConfig> = Map::new("price_impact_configs");
+pub const PRICE_IMPACT_POOLS: Map<Addr, u128> = Map::new("price_impact_pools");
+
+// (account, collateral token) => balance of a cross margin account in collateral tokens, positions
+// of the account using the collateral token draw margin from it and are liquidated together when
+// the equity of the account falls below their maintenance margin
+pub const CROSS_MARGIN_BALANCES: Map<(Addr, Addr), u128> = Map::new("cross_margin_balances");
+
+pub const PARTIAL_LIQUIDATION_CONFIG: Item<PartialLiquidationConfig> =
+    Item::new("partial_liquidation_config");
+
+// (account, index) => append only ledger of every trade of the account
+pub const TRADE_HISTORY: Map<(Addr, u64), TradeRecord> = Map::new("trade_history");
+pub const TRADE_HISTORY_COUNTS: Map<Addr, u64> = Map::new("trade_history_counts");
+// (position key, index) => trades of a position, the index points into the account's ledger
+pub const POSITION_TRADES: Map<(&[u8], u64), Empty> = Map::new("position_trades");
+
+pub const TOKEN_STATS: Map<Addr, TokenStats> = Map::new("token_stats");
+// (token, day since epoch) => stats of that day
+pub const DAILY_TOKEN_STATS: Map<(Addr, u64), TokenStats> = Map::new("daily_token_stats");
+
+// (token, funding time) => every update of the cumulative funding rate, records older than
+// fundingHistoryRetention seconds are pruned as new ones are written
+pub const FUNDING_HISTORY: Map<(Addr, u64), FundingRateRecord> = Map::new("funding_history");
+pub const FUNDING_HISTORY_RETENTION: Item<u64> = Item::new("funding_history_retention");
+
+// usd paid out of the fee reserves of a token to whoever brings its funding rate up to date
+// after a full funding interval
+pub const FUNDING_KEEPER_REWARD_USD: Item<u128> = Item::new("funding_keeper_reward_usd");
+// token => keeper rewards paid since the last fee distribution, they are recorded with it
+pub const PENDING_KEEPER_REWARDS: Map<Addr, u128> = Map::new("pending_keeper_rewards");
+
+// insuranceFundBasisPoints of every margin fee is kept in the insurance fund instead of feeReserves
+// of the collateral token and used to cover bad debt before the pool absorbs it
+pub const INSURANCE_FUND_BASIS_POINTS: Item<u128> = Item::new("insurance_fund_basis_points");
+pub const INSURANCE_FUNDS: Map<Addr, u128> = Map::new("insurance_funds");
+// (collateral token, index) => every use of the insurance fund
+pub const INSURANCE_FUND_RECORDS: Map<(Addr, u64), InsuranceFundRecord> =
+    Map::new("insurance_fund_records");
+pub const INSURANCE_FUND_RECORD_COUNTS: Map<Addr, u64> = Map::new("insurance_fund_record_counts");
+
+// adlConfigs are keyed by collateral token since profits are paid out of its pool
+pub const ADL_CONFIGS: Map<Addr, AdlConfig> = Map::new("adl_configs");
+
+// borrowRateModels overrides the linear fundingRateFactor per collateral token
+pub const BORROW_RATE_MODELS: Map<Addr, BorrowRateModel> = Map::new("borrow_rate_models");
+
 // positions tracks all open positions
 pub const POSITIONS: Map<Bytes, Position> = Map::new("positions");
+// accountPositions indexes the keys of positions by account, it is written together with
+// positions so account level checks don't have to range over every position
+pub const ACCOUNT_POSITIONS: Map<(&Addr, Bytes), Empty> = Map::new("account_positions");
+// collateralPositions indexes the keys of positions by collateral token for auto-deleveraging
+pub const COLLATERAL_POSITIONS: Map<(&Addr, Bytes), Empty> = Map::new("collateral_positions");
+// pendingPositionTransfers are keyed by the position of the sender and removed with it
+pub const PENDING_POSITION_TRANSFERS: Map<Bytes, PositionTransfer> =
+    Map::new("pending_position_transfers");
 
 // feeReserves tracks the amount of fees per token
 pub const FEE_RESERVES: Map<Addr, u128> = Map::new("fee_reserves");
@@ -183,4 +582,21 @@ pub const FEE_RESERVES: Map<Addr, u128> = Map::new("fee_reserves");
 pub const GLOBAL_SHORT_SIZES: Map<Addr, u128> = Map::new("global_Short_sizes");
 pub const GLOBAL_SHORT_AVERAGE_PRIZES: Map<Addr, u128> = Map::new("global_short_average_sizes");
 pub const MAX_GLOBAL_SHORT_SIZES: Map<Addr, u128> = Map::new("max_global_shoert_sizes");
+
+// open interest of longs per index token, capped by maxGlobalLongSizes when it is not 0
+pub const GLOBAL_LONG_SIZES: Map<Addr, u128> = Map::new("global_long_sizes");
+pub const GLOBAL_LONG_AVERAGE_PRICES: Map<Addr, u128> = Map::new("global_long_average_prices");
+pub const MAX_GLOBAL_LONG_SIZES: Map<Addr, u128> = Map::new("max_global_long_sizes");
 pub const ERRORS: Map<u128, String> = Map::new("errors");
+
+// referralCodes maps a code to the referrer that registered it
+pub const REFERRAL_CODES: Map<&str, Addr> = Map::new("referral_codes");
+pub const TRADER_REFERRAL_CODES: Map<Addr, String> = Map::new("trader_referral_codes");
+pub const REFERRAL_TIERS: Map<u64, ReferralTier> = Map::new("referral_tiers");
+pub const REFERRER_TIERS: Map<Addr, u64> = Map::new("referrer_tiers"); // tier 0 by default
+pub const REFERRER_STATS: Map<Addr, ReferralStats> = Map::new("referrer_stats");
+// (referrer, token) => rebates that the referrer can claim
+pub const REFERRER_REBATES: Map<(Addr, Addr), u128> = Map::new("referrer_rebates");
+
+// feeDistributions keeps every completed epoch so distributions can be audited later
+pub const FEE_DISTRIBUTIONS: Map<u64, FeeDistribution> = Map::new("fee_distributions");